@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::{
+    error::{FuseError, Result},
+    request::RequestBuilder,
+};
+
+struct PendingRequest {
+    request: RequestBuilder,
+    /// The jiffy after which the request is abandoned.
+    deadline: u64,
+}
+
+/// Requests assembled for the next batch kick, keyed by ticket.
+///
+/// A queued request takes no descriptor or buffer until the batch is taken
+/// for submission, so one whose deadline passes first is simply dropped and
+/// completed with [`FuseError::TimedOut`].
+pub struct RequestBatch {
+    pending: BTreeMap<u64, PendingRequest>,
+    completed: BTreeMap<u64, Result<Vec<u8>>>,
+    next_ticket: u64,
+}
+
+impl Default for RequestBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestBatch {
+    pub const fn new() -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            completed: BTreeMap::new(),
+            next_ticket: 1,
+        }
+    }
+
+    /// Queues `request` and returns the ticket to collect its reply with.
+    pub fn push(&mut self, request: RequestBuilder, deadline: u64) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.pending
+            .insert(ticket, PendingRequest { request, deadline });
+        ticket
+    }
+
+    /// Removes a queued request before it is submitted.
+    ///
+    /// Returns `false` if it was already submitted or expired.
+    pub fn cancel(&mut self, ticket: u64) -> bool {
+        self.pending.remove(&ticket).is_some()
+    }
+
+    /// Completes the queued requests whose deadline is before `now` with
+    /// [`FuseError::TimedOut`] and returns how many there were.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline < now)
+            .map(|(&ticket, _)| ticket)
+            .collect();
+        for ticket in &expired {
+            self.pending.remove(ticket);
+            self.completed.insert(*ticket, Err(FuseError::TimedOut));
+        }
+        expired.len()
+    }
+
+    /// Takes the queued requests for submission, in the order they were queued.
+    pub fn take(&mut self) -> Vec<(u64, RequestBuilder)> {
+        core::mem::take(&mut self.pending)
+            .into_iter()
+            .map(|(ticket, pending)| (ticket, pending.request))
+            .collect()
+    }
+
+    /// Records the outcome of a submitted request.
+    pub fn complete(&mut self, ticket: u64, reply: Result<Vec<u8>>) {
+        self.completed.insert(ticket, reply);
+    }
+
+    /// Takes the outcome of the request `ticket`, if it is known.
+    pub fn take_reply(&mut self, ticket: u64) -> Option<Result<Vec<u8>>> {
+        self.completed.remove(&ticket)
+    }
+
+    /// Returns the number of requests waiting for the batch kick.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::filesystem::fuse::FuseOpcode;
+
+    #[ktest]
+    fn request_times_out_before_flush() {
+        let mut batch = RequestBatch::new();
+        let late = batch.push(RequestBuilder::new(FuseOpcode::FuseGetattr, 2), 10);
+        let live = batch.push(RequestBuilder::new(FuseOpcode::FuseGetattr, 3), 100);
+
+        assert_eq!(batch.expire(50), 1);
+        assert_eq!(batch.len(), 1);
+        assert!(matches!(
+            batch.take_reply(late),
+            Some(Err(FuseError::TimedOut))
+        ));
+        // Only the live request reaches the device.
+        let submitted = batch.take();
+        assert_eq!(submitted.len(), 1);
+        assert_eq!(submitted[0].0, live);
+        assert!(batch.is_empty());
+        assert!(!batch.cancel(late));
+
+        batch.complete(live, Ok(Vec::new()));
+        assert!(matches!(batch.take_reply(live), Some(Ok(_))));
+    }
+
+    #[ktest]
+    fn cancelled_request_is_never_submitted() {
+        let mut batch = RequestBatch::new();
+        let ticket = batch.push(RequestBuilder::new(FuseOpcode::FuseGetattr, 2), 10);
+
+        assert!(batch.cancel(ticket));
+        assert!(batch.take().is_empty());
+        assert!(batch.take_reply(ticket).is_none());
+    }
+}
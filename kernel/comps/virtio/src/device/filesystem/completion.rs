@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ostd::sync::{LocalIrqDisabled, SpinLock, WaitQueue, Waiter};
+
+use super::{
+    error::{FuseError, Result},
+    request::fuse_parse_reply,
+};
+
+/// The reply to a request whose caller registered a [`FilesystemCallback`].
+#[derive(Debug)]
+pub struct FuseResponse {
+    pub unique: u64,
+    pub opcode: u32,
+    /// The reply, starting with its `FuseOutHeader`.
+    pub reply: Vec<u8>,
+}
+
+impl FuseResponse {
+    /// Returns the payload of the reply, or the error the server replied.
+    pub fn payload(&self) -> Result<&[u8]> {
+        fuse_parse_reply(&self.reply).map(|(_, payload)| payload)
+    }
+}
+
+/// Called with the reply to a request, from the completion handler.
+///
+/// It runs in interrupt context, so it must not sleep or submit requests;
+/// see [`deferred`](super::deferred).
+pub type FilesystemCallback = Box<dyn Fn(&FuseResponse) + Send + Sync>;
+
+/// The callbacks waiting for replies, keyed by the unique of their request.
+///
+/// Each callback is taken out when its reply arrives, so that it fires once
+/// and does not outlive its request.
+pub struct ReplyCallbacks {
+    callbacks: SpinLock<BTreeMap<u64, FilesystemCallback>, LocalIrqDisabled>,
+}
+
+impl ReplyCallbacks {
+    pub const fn new() -> Self {
+        Self {
+            callbacks: SpinLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Registers `callback` for the reply of the request `unique`, which is
+    /// yet to be sent.
+    pub fn register(&self, unique: u64, callback: FilesystemCallback) {
+        self.callbacks.lock().insert(unique, callback);
+    }
+
+    /// Forgets the callback of the request `unique`, which was never sent.
+    pub fn cancel(&self, unique: u64) {
+        self.callbacks.lock().remove(&unique);
+    }
+
+    /// Takes the callback of the request `unique`, if it has one.
+    pub fn take(&self, unique: u64) -> Option<FilesystemCallback> {
+        self.callbacks.lock().remove(&unique)
+    }
+
+    /// Returns the number of callbacks still waiting for their reply.
+    pub fn pending(&self) -> usize {
+        self.callbacks.lock().len()
+    }
+}
+
+/// The replies that callers sleeping in `submit_and_wait` wait for, keyed by
+/// the unique of their request.
+///
+/// The completion handler hands a reply over with [`Self::complete`] and
+/// wakes the waiters; each takes its own reply with [`Self::wait`].
+pub struct ReplyWaiters {
+    /// The reply of each awaited request, once it has arrived.
+    replies: SpinLock<BTreeMap<u64, Option<Vec<u8>>>, LocalIrqDisabled>,
+    /// Whether the device is gone, so that no more replies arrive.
+    closed: AtomicBool,
+    wait_queue: WaitQueue,
+}
+
+impl ReplyWaiters {
+    pub const fn new() -> Self {
+        Self {
+            replies: SpinLock::new(BTreeMap::new()),
+            closed: AtomicBool::new(false),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+
+    /// Starts waiting for the reply of the request `unique`, which is yet to
+    /// be sent.
+    pub fn register(&self, unique: u64) {
+        self.replies.lock().insert(unique, None);
+    }
+
+    /// Forgets the request `unique`, which was never sent.
+    pub fn cancel(&self, unique: u64) {
+        self.replies.lock().remove(&unique);
+    }
+
+    pub fn is_waiting(&self, unique: u64) -> bool {
+        self.replies.lock().contains_key(&unique)
+    }
+
+    /// Hands `reply` to the waiter of the request `unique`.
+    ///
+    /// Returns `false` if nobody waits for the request.
+    pub fn complete(&self, unique: u64, reply: Vec<u8>) -> bool {
+        match self.replies.lock().get_mut(&unique) {
+            Some(slot) => *slot = Some(reply),
+            None => return false,
+        }
+        self.wait_queue.wake_all();
+        true
+    }
+
+    /// Fails the requests still awaited, and any awaited later, with
+    /// `NoDev`, as the device was reset and will not reply.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.wait_queue.wake_all();
+    }
+
+    /// Sleeps until the reply of the request `unique` arrives and takes it.
+    ///
+    /// Once the waiters are closed, a reply that has not arrived never will,
+    /// and `NoDev` is returned instead.
+    pub fn wait(&self, unique: u64) -> Result<Vec<u8>> {
+        self.wait_queue.wait_until(|| self.take(unique))
+    }
+
+    /// Like [`Self::wait`], but stops waiting once `is_interrupted` holds,
+    /// returning `None`; the reply is then still awaited.
+    ///
+    /// `waiter` is the caller's, which whatever interrupts the caller wakes
+    /// too, so that `is_interrupted` is checked again.
+    pub fn wait_interruptible(
+        &self,
+        unique: u64,
+        waiter: &Waiter,
+        is_interrupted: impl Fn() -> bool,
+    ) -> Option<Result<Vec<u8>>> {
+        waiter
+            .wait_until_or_cancelled(
+                || {
+                    self.wait_queue.enqueue(waiter.waker());
+                    self.take(unique)
+                },
+                || if is_interrupted() { Err(()) } else { Ok(()) },
+            )
+            .ok()
+    }
+
+    /// Takes the reply of the request `unique` if it has arrived, or `NoDev`
+    /// if it never will.
+    fn take(&self, unique: u64) -> Option<Result<Vec<u8>>> {
+        let mut replies = self.replies.lock();
+        let reply = match replies.get_mut(&unique)?.take() {
+            Some(reply) => Ok(reply),
+            None if self.closed.load(Ordering::Acquire) => Err(FuseError::NoDev),
+            None => return None,
+        };
+        replies.remove(&unique);
+        Some(reply)
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use ostd::{prelude::*, Pod};
+
+    use super::*;
+    use crate::device::filesystem::fuse::FuseOutHeader;
+
+    #[ktest]
+    fn replies_reach_their_own_waiters() {
+        let waiters = ReplyWaiters::new();
+        let (first, second) = (2, 4);
+        waiters.register(first);
+        waiters.register(second);
+
+        // The replies arrive in the opposite order.
+        assert!(waiters.complete(second, b"second".to_vec()));
+        assert!(waiters.complete(first, b"first".to_vec()));
+        assert_eq!(waiters.wait(first).unwrap(), b"first");
+        assert_eq!(waiters.wait(second).unwrap(), b"second");
+        assert!(!waiters.is_waiting(first));
+
+        // A reply nobody waits for is left to the completion handler.
+        assert!(!waiters.complete(first, Vec::new()));
+        waiters.register(6);
+        waiters.cancel(6);
+        assert!(!waiters.complete(6, Vec::new()));
+    }
+
+    #[ktest]
+    fn closed_waiters_fail_with_nodev() {
+        let waiters = ReplyWaiters::new();
+        waiters.register(2);
+        waiters.register(4);
+        assert!(waiters.complete(4, b"done".to_vec()));
+
+        // A reply that arrived before the reset is still handed over.
+        waiters.close();
+        assert!(matches!(waiters.wait(2), Err(FuseError::NoDev)));
+        assert_eq!(waiters.wait(4).unwrap(), b"done");
+        assert!(!waiters.is_waiting(2));
+
+        waiters.register(6);
+        assert!(matches!(waiters.wait(6), Err(FuseError::NoDev)));
+    }
+
+    #[ktest]
+    fn interrupted_wait_leaves_the_reply_awaited() {
+        let waiters = ReplyWaiters::new();
+        waiters.register(2);
+        let (waiter, _) = Waiter::new_pair();
+
+        assert!(waiters.wait_interruptible(2, &waiter, || true).is_none());
+        assert!(waiters.is_waiting(2));
+
+        // The server replies to the interrupted request all the same.
+        assert!(waiters.complete(2, b"eintr".to_vec()));
+        let reply = waiters.wait_interruptible(2, &waiter, || true).unwrap();
+        assert_eq!(reply.unwrap(), b"eintr");
+        assert!(!waiters.is_waiting(2));
+    }
+
+    #[ktest]
+    fn callbacks_fire_once() {
+        let callbacks = ReplyCallbacks::new();
+        let fired = Arc::new(AtomicU64::new(0));
+        let seen = fired.clone();
+        callbacks.register(
+            2,
+            Box::new(move |response| {
+                assert_eq!(response.payload().unwrap(), b"data");
+                seen.store(response.unique, Ordering::Relaxed);
+            }),
+        );
+        callbacks.register(4, Box::new(|_| unreachable!()));
+        callbacks.cancel(4);
+
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + 4) as u32,
+            error: 0,
+            unique: 2,
+        };
+        let response = FuseResponse {
+            unique: 2,
+            opcode: 0,
+            reply: [header.as_bytes(), b"data"].concat(),
+        };
+        let callback = callbacks.take(2).unwrap();
+        callback(&response);
+        assert_eq!(fired.load(Ordering::Relaxed), 2);
+
+        // Nothing is left behind to fire again.
+        assert!(callbacks.take(2).is_none());
+        assert_eq!(callbacks.pending(), 0);
+    }
+}
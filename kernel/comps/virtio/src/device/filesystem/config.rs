@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: MPL-2.0
 
+use alloc::string::String;
 use core::mem::offset_of;
 
 use aster_util::safe_ptr::SafePtr;
@@ -21,6 +22,35 @@ impl FilesystemFeatures {
     }
 }
 
+/// The features the device advertised and the subset the driver accepted.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtioFilesystemFeature {
+    pub device: FilesystemFeatures,
+    pub negotiated: FilesystemFeatures,
+}
+
+impl VirtioFilesystemFeature {
+    pub fn new(device_features: u64) -> Self {
+        let device = FilesystemFeatures::from_bits_truncate(device_features);
+        Self {
+            device,
+            negotiated: device & FilesystemFeatures::supported_features(),
+        }
+    }
+
+    /// Returns whether the device has a notification queue, which shifts the
+    /// request queues up by one index.
+    pub fn has_notification_queue(&self) -> bool {
+        self.negotiated
+            .contains(FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION)
+    }
+
+    /// Returns the features the device offered that the driver could not use.
+    pub fn unsupported(&self) -> FilesystemFeatures {
+        self.device - self.negotiated
+    }
+}
+
 #[derive(Debug, Pod, Clone, Copy)]
 #[repr(C)]
 pub struct VirtioFilesystemConfig {
@@ -30,6 +60,19 @@ pub struct VirtioFilesystemConfig {
 }
 
 impl VirtioFilesystemConfig {
+    /// Returns the tag the file system is mounted by.
+    ///
+    /// The tag is padded with NULs to the width of the field, or fills all of
+    /// it; bytes that are not UTF-8 are replaced.
+    pub fn tag_str(&self) -> String {
+        let len = self
+            .tag
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(self.tag.len());
+        String::from_utf8_lossy(&self.tag[..len]).trim().into()
+    }
+
     pub(super) fn new_manager(transport: &dyn VirtioTransport) -> ConfigManager<Self> {
         let safe_ptr = transport
             .device_config_mem()
@@ -61,3 +104,39 @@ impl ConfigManager<VirtioFilesystemConfig> {
         fs_config
     }
 }
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn notification_queue_is_negotiated() {
+        let features =
+            VirtioFilesystemFeature::new(FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION.bits());
+        assert!(features.has_notification_queue());
+        assert!(features.unsupported().is_empty());
+
+        let features = VirtioFilesystemFeature::new(0);
+        assert!(!features.has_notification_queue());
+        assert!(features.unsupported().is_empty());
+    }
+
+    #[ktest]
+    fn tag_ends_at_nul_or_field_width() {
+        let mut config = VirtioFilesystemConfig {
+            tag: [0; 36],
+            num_request_queues: 1,
+            notify_buf_size: 0,
+        };
+        config.tag[..6].copy_from_slice(b"myfs \0");
+        assert_eq!(config.tag_str(), "myfs");
+
+        config.tag = [b'a'; 36];
+        assert_eq!(config.tag_str().len(), 36);
+
+        config.tag[..4].copy_from_slice(b"f\xffs\0");
+        assert_eq!(config.tag_str(), "f\u{fffd}s");
+    }
+}
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Checks the requests the driver submits byte for byte against the FUSE
+//! kernel ABI, as laid out by the Linux FUSE client on a little-endian machine.
+//!
+//! `unique` is assigned when a request is submitted, and the credentials are
+//! the caller's, stamped with [`RequestBuilder::with_context`]; the requests
+//! here have neither, so those header fields are zero.
+
+use ostd::prelude::*;
+
+use super::{fuse::*, request::*};
+
+/// Asserts that `request` sends exactly `expected` and reserves `out_len`
+/// bytes for the reply.
+fn assert_request(request: RequestBuilder, expected: &[u8], out_len: usize) {
+    let bytes = request.to_bytes();
+    assert_eq!(request.in_len(), expected.len());
+    assert_eq!(&bytes[..request.in_len()], expected);
+    assert_eq!(request.total_len() - request.in_len(), out_len);
+    assert!(bytes[request.in_len()..].iter().all(|&byte| byte == 0));
+}
+
+#[ktest]
+fn lookup_wire_format() {
+    #[rustfmt::skip]
+    let expected = [
+        // fuse_in_header
+        44, 0, 0, 0,                // len
+        1, 0, 0, 0,                 // opcode: FUSE_LOOKUP
+        0, 0, 0, 0, 0, 0, 0, 0,     // unique
+        1, 0, 0, 0, 0, 0, 0, 0,     // nodeid
+        0, 0, 0, 0,                 // uid
+        0, 0, 0, 0,                 // gid
+        0, 0, 0, 0,                 // pid
+        0, 0,                       // total_extlen
+        0, 0,                       // padding
+        // name
+        b'f', b'o', b'o', 0,
+    ];
+    assert_request(
+        fuse_lookup_request(FUSE_ROOT_ID, b"foo"),
+        &expected,
+        size_of::<FuseOutHeader>() + size_of::<FuseEntryOut>(),
+    );
+}
+
+#[ktest]
+fn mkdir_wire_format() {
+    #[rustfmt::skip]
+    let expected = [
+        // fuse_in_header
+        52, 0, 0, 0,                // len
+        9, 0, 0, 0,                 // opcode: FUSE_MKDIR
+        0, 0, 0, 0, 0, 0, 0, 0,     // unique
+        1, 0, 0, 0, 0, 0, 0, 0,     // nodeid
+        0, 0, 0, 0,                 // uid
+        0, 0, 0, 0,                 // gid
+        0, 0, 0, 0,                 // pid
+        0, 0,                       // total_extlen
+        0, 0,                       // padding
+        // fuse_mkdir_in
+        0xed, 0x01, 0, 0,           // mode: 0o755
+        0x12, 0, 0, 0,              // umask: 0o022
+        // name
+        b'd', b'i', b'r', 0,
+    ];
+    assert_request(
+        fuse_mkdir_request(FUSE_ROOT_ID, 0o755, 0o022, b"dir"),
+        &expected,
+        size_of::<FuseOutHeader>() + size_of::<FuseEntryOut>(),
+    );
+}
+
+#[ktest]
+fn write_wire_format() {
+    #[rustfmt::skip]
+    let expected = [
+        // fuse_in_header
+        85, 0, 0, 0,                // len
+        16, 0, 0, 0,                // opcode: FUSE_WRITE
+        0, 0, 0, 0, 0, 0, 0, 0,     // unique
+        2, 0, 0, 0, 0, 0, 0, 0,     // nodeid
+        0, 0, 0, 0,                 // uid
+        0, 0, 0, 0,                 // gid
+        0, 0, 0, 0,                 // pid
+        0, 0,                       // total_extlen
+        0, 0,                       // padding
+        // fuse_write_in
+        3, 0, 0, 0, 0, 0, 0, 0,     // fh
+        0, 0x10, 0, 0, 0, 0, 0, 0,  // offset: 0x1000
+        5, 0, 0, 0,                 // size
+        0, 0, 0, 0,                 // write_flags
+        0, 0, 0, 0, 0, 0, 0, 0,     // lock_owner
+        0, 0, 0, 0,                 // flags
+        0, 0, 0, 0,                 // padding
+        // data, not padded
+        b'h', b'e', b'l', b'l', b'o',
+    ];
+    assert_request(
+        fuse_write_request(2, 3, 0x1000, b"hello", WriteOrigin::Direct),
+        &expected,
+        size_of::<FuseOutHeader>() + size_of::<FuseWriteOut>(),
+    );
+}
+
+#[ktest]
+fn rename2_wire_format() {
+    #[rustfmt::skip]
+    let expected = [
+        // fuse_in_header
+        60, 0, 0, 0,                // len
+        45, 0, 0, 0,                // opcode: FUSE_RENAME2
+        0, 0, 0, 0, 0, 0, 0, 0,     // unique
+        1, 0, 0, 0, 0, 0, 0, 0,     // nodeid
+        0, 0, 0, 0,                 // uid
+        0, 0, 0, 0,                 // gid
+        0, 0, 0, 0,                 // pid
+        0, 0,                       // total_extlen
+        0, 0,                       // padding
+        // fuse_rename2_in
+        2, 0, 0, 0, 0, 0, 0, 0,     // newdir
+        2, 0, 0, 0,                 // flags: RENAME_EXCHANGE
+        0, 0, 0, 0,                 // padding
+        // oldname and newname
+        b'a', 0,
+        b'b', 0,
+    ];
+    assert_request(
+        fuse_rename2_request(FUSE_ROOT_ID, b"a", 2, b"b", 2),
+        &expected,
+        size_of::<FuseOutHeader>(),
+    );
+}
+
+#[ktest]
+fn batch_forget_wire_format() {
+    #[rustfmt::skip]
+    let expected = [
+        // fuse_in_header
+        80, 0, 0, 0,                // len
+        42, 0, 0, 0,                // opcode: FUSE_BATCH_FORGET
+        0, 0, 0, 0, 0, 0, 0, 0,     // unique
+        0, 0, 0, 0, 0, 0, 0, 0,     // nodeid
+        0, 0, 0, 0,                 // uid
+        0, 0, 0, 0,                 // gid
+        0, 0, 0, 0,                 // pid
+        0, 0,                       // total_extlen
+        0, 0,                       // padding
+        // fuse_batch_forget_in
+        2, 0, 0, 0,                 // count
+        0, 0, 0, 0,                 // dummy
+        // fuse_forget_one
+        5, 0, 0, 0, 0, 0, 0, 0,     // nodeid
+        1, 0, 0, 0, 0, 0, 0, 0,     // nlookup
+        6, 0, 0, 0, 0, 0, 0, 0,     // nodeid
+        2, 0, 0, 0, 0, 0, 0, 0,     // nlookup
+    ];
+    // FORGETs have no reply.
+    assert_request(fuse_batch_forget_request(&[(5, 1), (6, 2)]), &expected, 0);
+}
@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Work the completion handler leaves to the follow-up worker.
+//!
+//! The completion handler runs in interrupt context. The task it interrupted
+//! may be in the middle of submitting a request, so the handler must never
+//! submit one itself: the requests a reply calls for, such as a resent
+//! INTERRUPT or INIT, are queued here and sent once the handler has returned.
+
+use alloc::collections::VecDeque;
+
+use ostd::cpu_local_cell;
+
+cpu_local_cell! {
+    /// Whether this CPU is handling request completions.
+    static IN_COMPLETION: u8 = 0;
+}
+
+/// Marks the current CPU as handling request completions until dropped.
+pub struct CompletionScope {
+    _private: (),
+}
+
+impl CompletionScope {
+    pub fn enter() -> Self {
+        IN_COMPLETION.store(1);
+        Self { _private: () }
+    }
+}
+
+impl Drop for CompletionScope {
+    fn drop(&mut self) {
+        IN_COMPLETION.store(0);
+    }
+}
+
+/// Returns whether the current CPU is handling request completions.
+pub fn in_completion() -> bool {
+    IN_COMPLETION.load() != 0
+}
+
+/// Panics in debug builds if a request is submitted from the completion
+/// handler.
+#[track_caller]
+pub fn debug_assert_can_submit() {
+    debug_assert!(
+        !in_completion(),
+        "request submitted from the completion handler"
+    );
+}
+
+/// The follow-ups queued by the completion handler, in the order they were
+/// asked for.
+#[derive(Debug)]
+pub struct DeferredQueue<T> {
+    work: VecDeque<T>,
+}
+
+impl<T> DeferredQueue<T> {
+    pub const fn new() -> Self {
+        Self {
+            work: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, work: T) {
+        self.work.push_back(work);
+    }
+
+    /// Takes all the queued work, leaving the queue empty.
+    pub fn take_all(&mut self) -> VecDeque<T> {
+        core::mem::take(&mut self.work)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.work.is_empty()
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::vec::Vec;
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    /// Mimics the completion handler: follow-ups are queued, never sent.
+    fn complete(queue: &mut DeferredQueue<u64>, sent: &mut Vec<u64>, unique: u64) {
+        let _scope = CompletionScope::enter();
+        if in_completion() {
+            queue.push(unique);
+        } else {
+            sent.push(unique);
+        }
+    }
+
+    #[ktest]
+    fn completion_handler_defers_submission() {
+        let mut queue = DeferredQueue::new();
+        let mut sent = Vec::new();
+        assert!(!in_completion());
+
+        complete(&mut queue, &mut sent, 2);
+        complete(&mut queue, &mut sent, 4);
+        assert!(sent.is_empty());
+        assert!(!in_completion());
+
+        // The worker sends them in order, outside the handler.
+        debug_assert_can_submit();
+        sent.extend(queue.take_all());
+        assert_eq!(sent, [2, 4]);
+        assert!(queue.is_empty());
+    }
+}
@@ -1,461 +1,307 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
-use core::{fmt::Debug, iter::Fuse};
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Debug,
+    hint::spin_loop,
+    iter::Fuse,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
 
 use log::debug;
 use ostd::{
+    arch::timer::TIMER_FREQ,
+    cpu::PinCurrentCpu,
     early_print, early_println,
-    mm::{DmaDirection, DmaStream, DmaStreamSlice, FrameAllocOptions, VmReader, VmWriter},
-    sync::{RwLock, SpinLock},
-    trap::TrapFrame,
+    mm::{
+        DmaDirection, DmaStream, DmaStreamSlice, FrameAllocOptions, VmReader, VmWriter, PAGE_SIZE,
+    },
+    sync::{LocalIrqDisabled, RwLock, SpinLock, SpinLockGuard, WaitQueue, Waiter, Waker},
+    task::{Task, TaskOptions},
+    timer::Jiffies,
+    trap::{disable_local, TrapFrame},
     Pod,
 };
 
 use super::{
-    config::{FilesystemFeatures, VirtioFilesystemConfig},
+    batch::RequestBatch,
+    completion::{FilesystemCallback, FuseResponse, ReplyCallbacks, ReplyWaiters},
+    config::{FilesystemFeatures, VirtioFilesystemConfig, VirtioFilesystemFeature},
+    deferred::{debug_assert_can_submit, CompletionScope, DeferredQueue},
+    dir,
+    error::FuseError,
+    file,
     fuse::*,
-    request::{fuse_pad_str, AnyFuseDevice, FuseReaddirOut},
+    handle::{fuse_backing_id, fuse_retry_stale, HandleEntry, HandleKind, HandleTable},
+    init::{
+        fuse_init_out, fuse_init_request, fuse_wait_init, InitForm, NegotiatedParams,
+        FUSE_INIT_TIMEOUT, FUSE_MIN_MAX_WRITE, MIN_SUPPORTED_MINOR,
+    },
+    inode::InodeCache,
+    interrupt::{InterruptAction, InterruptTracker, INTERRUPT_RESEND_DELAY},
+    notify::{fuse_parse_notify, FuseNotification},
+    page_cache::PageCache,
+    path::walk_path,
+    poll::PollHandles,
+    request::{
+        fuse_batch_forget_request, fuse_buffer_pages, fuse_check_fallocate_mode,
+        fuse_check_mapping, fuse_check_name, fuse_check_name_len, fuse_check_rename,
+        fuse_check_rename_flags, fuse_check_reply, fuse_check_whence, fuse_checked_create_request,
+        fuse_completed_request, fuse_direntplus_is_lookup, fuse_fallocate_request,
+        fuse_forget_request, fuse_fsync_flags, fuse_fsync_request, fuse_getxattr_request,
+        fuse_interrupt_request, fuse_link_request, fuse_listxattr_request, fuse_lk_request,
+        fuse_lookup_request, fuse_lseek_request, fuse_mkdir_request, fuse_mknod_request,
+        fuse_parse_reply, fuse_poll_request, fuse_push_supp_group, fuse_read_capacity,
+        fuse_read_data, fuse_read_out_body, fuse_read_payload, fuse_read_request, fuse_read_val,
+        fuse_readlink_target, fuse_removemapping_request, fuse_removexattr_request,
+        fuse_rename2_request, fuse_rename_request, fuse_reply_header, fuse_reply_val,
+        fuse_request_slices, fuse_setupmapping_request, fuse_setxattr_request, fuse_statfs_namelen,
+        fuse_statfs_request, fuse_symlink_request, fuse_unlink_request, fuse_write_all,
+        fuse_write_payload_request, fuse_write_request, fuse_write_result, fuse_xattr_reply,
+        AnyFuseDevice, FuseContext, FuseDirentWithName, FuseReaddirOut, FuseReaddirplusOut,
+        RequestBuilder, WriteOrigin, WriteResult, XattrReply, FUSE_MAX_BATCH_FORGET,
+        FUSE_MAX_BUFFER_PAGES, FUSE_PATH_MAX,
+    },
+    retry::{fuse_retry_again, RetryPolicy},
+    routing::{
+        fuse_queue_depth, request_queue_order, request_queue_vq, QueueDepths, RoundRobin,
+        HIPRIO_QUEUE_INDEX, NOTIFICATION_QUEUE_INDEX,
+    },
+    shutdown::{fuse_drain, ShutdownState, FUSE_DRAIN_TIMEOUT},
+    slot::{BufferSlots, HiprioSlots, SlotGuard},
+    stats::QueueStats,
+    vfs,
+    writeback::WritebackErrors,
 };
 use crate::{
     device::VirtioDeviceError,
     queue::VirtQueue,
-    transport::{ConfigManager, VirtioTransport},
+    transport::{ConfigManager, DeviceStatus, VirtioTransport},
 };
 
 pub struct FilesystemDevice {
     config_manager: ConfigManager<VirtioFilesystemConfig>,
+    features: VirtioFilesystemFeature,
     transport: SpinLock<Box<dyn VirtioTransport>>,
 
     hiprio_queue: SpinLock<VirtQueue>,
     request_queues: Vec<SpinLock<VirtQueue>>,
-    // notify_queue: SpinLock<VirtQueue>,
-    hiprio_buffer: DmaStream,
-    request_buffers: Vec<DmaStream>,
-    // notify_buffer: DmaStream,
-    // callbacks: RwLock<Vec<&'static FilesystemCallback>, LocalIrqDisabled>,
+    /// The notification queue, if the device has one.
+    notify_queue: Option<SpinLock<VirtQueue>>,
+    /// The buffers posted on the notification queue for the device to fill.
+    notify_buffers: Vec<DmaStream>,
+    notify_slots: SpinLock<HiprioSlots>,
+    /// One buffer per hiprio descriptor, so that forgets need not queue up
+    /// behind each other. Each is a page, which holds the largest
+    /// BATCH_FORGET sent; see [`FUSE_MAX_BATCH_FORGET`].
+    hiprio_buffers: Vec<DmaStream>,
+    hiprio_slots: SpinLock<HiprioSlots>,
+    /// The pool of buffers of each request queue, indexed by queue and then
+    /// by slot, one per request the queue holds at a time as tracked by
+    /// `buffer_slots`. Each starts at a page and is replaced by a larger one
+    /// when a request does not fit, see [`Self::ensure_capacity`].
+    request_buffers: Vec<Vec<SpinLock<DmaStream, LocalIrqDisabled>>>,
+    buffer_slots: BufferSlots,
+    /// The buffers of the SETLKWs in flight, keyed by their queue and token,
+    /// see [`Self::submit_lock_wait`].
+    lock_waits: SpinLock<BTreeMap<(usize, u16), DmaStream>, LocalIrqDisabled>,
+    handles: SpinLock<HandleTable>,
+    inodes: SpinLock<InodeCache>,
+    /// The data of the reads `read_at` sent, see [`PageCache`].
+    pages: SpinLock<PageCache>,
+    init_params: SpinLock<Option<NegotiatedParams>>,
+    /// Whether the server answered FUSE_INIT with a version too old to use.
+    init_rejected: AtomicBool,
+    /// Whether the caller enabled the writeback cache; see
+    /// [`Self::set_writeback_cache`].
+    writeback_cache: AtomicBool,
+    /// How far [`Self::shutdown`] has got.
+    shutdown_state: ShutdownState,
+    interrupts: SpinLock<InterruptTracker>,
+    poll_handles: SpinLock<PollHandles>,
+    batch: SpinLock<RequestBatch>,
+    /// The longest name the server accepts, as reported by STATFS on mount.
+    name_max: SpinLock<Option<u32>>,
+    /// The wall clock to advance cached atimes with after reads, if enabled.
+    atime_clock: SpinLock<Option<fn() -> Duration>>,
+    writeback_errors: SpinLock<WritebackErrors>,
+    stats: QueueStats,
+    reply_waiters: ReplyWaiters,
+    /// The credentials of the requests whose caller gave none, root until set.
+    default_context: SpinLock<FuseContext>,
+    /// The unique of the next request, see [`Self::next_unique`].
+    next_unique: AtomicU64,
+    follow_ups: SpinLock<DeferredQueue<ReplyFollowUp>>,
+    next_queue: RoundRobin,
+    /// Wakes the task that sends the queued follow-ups once the completion
+    /// handler has returned; see [`Self::spawn_follow_up_worker`].
+    follow_up_wait: WaitQueue,
+    /// The callbacks of the requests sent by [`Self::submit_with_callback`].
+    callbacks: ReplyCallbacks,
 }
 
-impl AnyFuseDevice for FilesystemDevice {
-    fn init(&self) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInitIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseInit as u32,
-            unique: 0,
-            nodeid: 0,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+/// The step between the uniques of requests, which keeps [`FUSE_INT_REQ_BIT`]
+/// clear for their INTERRUPTs.
+const FUSE_REQ_ID_STEP: u64 = 1 << 1;
 
-        let initin = FuseInitIn {
-            major: FUSE_KERNEL_VERSION,
-            minor: FUSE_KERNEL_MINOR_VERSION,
-            max_readahead: 0,
-            flags: FuseInitFlags::FUSE_INIT_EXT.bits() as u32,
-            flags2: 0,
-            unused: [0u32; 11],
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let initin_bytes = initin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let initout_bytes = [0u8; 256];
-        let concat_req = [
-            headerin_bytes,
-            initin_bytes,
-            &headerout_buffer,
-            &initout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInitIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+/// What a completed reply leaves to do once the completion handler returns.
+#[derive(Debug, Default)]
+struct ReplyFollowUp {
+    resend_interrupt: Option<u64>,
+    resend_init: bool,
+    release_evicted: bool,
+}
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+impl ReplyFollowUp {
+    fn is_pending(&self) -> bool {
+        self.resend_interrupt.is_some() || self.resend_init || self.release_evicted
     }
+}
 
-    fn opendir(&self, nodeid: u64, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseOpenIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseOpendir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+impl AnyFuseDevice for FilesystemDevice {
+    fn init(&self) {
+        // A server known to predate FUSE_INIT_EXT is greeted in its own form.
+        let form = self
+            .negotiated_params()
+            .map_or(InitForm::Extended, |params| params.init_form);
+        self.send_init(form);
+    }
 
+    fn opendir(&self, nodeid: u64, flags: u32) {
         let openin = FuseOpenIn {
             flags: flags,
             open_flags: 0,
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let openin_bytes = openin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let openout_bytes = [0u8; size_of::<FuseOpenOut>()];
-        let concat_req = [
-            headerin_bytes,
-            openin_bytes,
-            &headerout_buffer,
-            &openout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseOpenIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseOpendir,
+            nodeid,
+            openin.as_bytes(),
+            size_of::<FuseOpenOut>(),
+        )
+        .ok();
     }
 
     fn readdir(&self, nodeid: u64, fh: u64, offset: u64, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseReadIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseReaddir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let readin = FuseReadIn {
-            fh: fh,
-            offset: offset,
-            size: size,
-            read_flags: 0,
-            lock_owner: 0,
-            flags: 0,
-            padding: 0,
+        let Some(fh) = self.request_fh(fh) else {
+            return;
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let readin_bytes = readin.as_bytes();
-        // let readin_bytes = [0u8; 36];
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let readout_bytes = [0u8; 1024];
-        let concat_req = [
-            headerin_bytes,
-            &readin_bytes,
-            &headerout_buffer,
-            &readout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReadIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        // The buffer taken for the request is grown up to this bound.
+        let size = size.min(fuse_read_capacity(FUSE_MAX_BUFFER_PAGES * PAGE_SIZE));
+        self.submit_on(
+            self.pick_request_queue(),
+            fuse_read_request(FuseOpcode::FuseReaddir, nodeid, fh, offset, size),
+        )
+        .ok();
     }
 
     fn read(&self, nodeid: u64, fh: u64, offset: u64, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseReadIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseRead as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let readin = FuseReadIn {
-            fh: fh,
-            offset: offset,
-            size: size,
-            read_flags: 0,
-            lock_owner: 0,
-            flags: 0,
-            padding: 0,
+        let Some(fh) = self.request_fh(fh) else {
+            return;
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let readin_bytes = readin.as_bytes();
-        // let readin_bytes = [0u8; 36];
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let readout_bytes = [0u8; 1024];
-        let concat_req = [
-            headerin_bytes,
-            &readin_bytes,
-            &headerout_buffer,
-            &readout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReadIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.submit(fuse_read_request(
+            FuseOpcode::FuseRead,
+            nodeid,
+            fh,
+            offset,
+            size,
+        ))
+        .ok();
     }
 
     fn open(&self, nodeid: u64, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseOpenIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseOpen as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
         let openin = FuseOpenIn {
             flags: flags,
             open_flags: 0,
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let openin_bytes = openin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let openout_bytes = [0u8; size_of::<FuseOpenOut>()];
-        let concat_req = [
-            headerin_bytes,
-            openin_bytes,
-            &headerout_buffer,
-            &openout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseOpenIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseOpen,
+            nodeid,
+            openin.as_bytes(),
+            size_of::<FuseOpenOut>(),
+        )
+        .ok();
     }
 
     fn flush(&self, nodeid: u64, fh: u64, lock_owner: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseFlushIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseFlush as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+        let Some(fh) = self.request_fh(fh) else {
+            return;
         };
-
         let flushin = FuseFlushIn {
             fh: fh,
             lock_owner: lock_owner,
             padding: 0,
             unused: 0,
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let flushin_bytes = flushin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        // let flushout_bytes = [0u8; size_of::<FuseFlushOut>()];
-        let concat_req = [
-            headerin_bytes,
-            flushin_bytes,
-            &headerout_buffer,
-            // &flushout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseFlushIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseFlush,
+            nodeid,
+            flushin.as_bytes(),
+            0,
+        )
+        .ok();
     }
 
     fn releasedir(&self, nodeid: u64, fh: u64, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseReleaseIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseReleasedir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+        // An evicted handle is already released.
+        let Ok(Some(fh)) = self
+            .handles
+            .disable_irq()
+            .lock()
+            .close(fh)
+            .inspect_err(|err| debug!("Request not sent: {}", err))
+        else {
+            return;
         };
-
         let releasein = FuseReleaseIn {
             fh: fh,
             flags: flags,
             release_flags: 0,
             lock_owner: 0,
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let releasein_bytes = releasein.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        // let releaseout_bytes = [0u8; size_of::<FuseReleaseOut>()];
-        let concat_req = [
-            headerin_bytes,
-            releasein_bytes,
-            &headerout_buffer,
-            // &releaseout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReleaseIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseReleasedir,
+            nodeid,
+            releasein.as_bytes(),
+            0,
+        )
+        .ok();
     }
 
     fn getattr(&self, nodeid: u64, fh: u64, flags: u32, dummy: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseGetattrIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseGetattr as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+        // The handle is only looked at if the request carries one.
+        let fh = if flags & FUSE_GETATTR_FH != 0 {
+            self.request_fh(fh)
+        } else {
+            Some(fh)
+        };
+        let Some(fh) = fh else {
+            return;
         };
-
         let getattrin = FuseGetattrIn {
             getattr_flags: flags,
             dummy: dummy,
             fh: fh,
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let getattrin_bytes = getattrin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let getattrout_bytes = [0u8; size_of::<FuseAttrOut>()];
-        let concat_req = [
-            headerin_bytes,
-            getattrin_bytes,
-            &headerout_buffer,
-            &getattrout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseGetattrIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseGetattr,
+            nodeid,
+            getattrin.as_bytes(),
+            size_of::<FuseAttrOut>(),
+        )
+        .ok();
     }
 
     fn setattr(
@@ -475,18 +321,18 @@ impl AnyFuseDevice for FilesystemDevice {
         uid: u32,
         gid: u32,
     ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32 + size_of::<FuseSetattrIn>() as u32),
-            opcode: FuseOpcode::FuseSetattr as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+        // The handle is only looked at if the request carries one.
+        let fh = if valid & FATTR_FH != 0 {
+            self.request_fh(fh)
+        } else {
+            Some(fh)
+        };
+        let Some(fh) = fh else {
+            return;
         };
+        if valid & FATTR_SIZE != 0 {
+            self.pages.disable_irq().lock().invalidate(nodeid);
+        }
         let setattrin = FuseSetattrIn {
             valid: valid,
             padding: 0,
@@ -505,1694 +351,2137 @@ impl AnyFuseDevice for FilesystemDevice {
             gid: gid,
             unused5: 0,
         };
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let setattrout_buffer = [0u8; size_of::<FuseAttrOut>()];
-
-        let headerin_bytes = headerin.as_bytes();
-        let setattrin_bytes = setattrin.as_bytes();
-        let concat_req = [
-            headerin_bytes,
-            setattrin_bytes,
-            &headerout_buffer,
-            &setattrout_buffer,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseSetattrIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseSetattr,
+            nodeid,
+            setattrin.as_bytes(),
+            size_of::<FuseAttrOut>(),
+        )
+        .ok();
     }
 
-    fn lookup(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        // // add terminating '\0' to the name
-        // let mut name = name;
-        // name.push(0);
-
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32 + prepared_name.len() as u32),
-            opcode: FuseOpcode::FuseLookup as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let lookupin_bytes = prepared_name.as_slice();
-
-        // early_println!("lookup name: {:?}", name);
-        // early_println!("headerin_bytes: {:?}", headerin_bytes);
-        // early_println!("lookupin_bytes: {:?}", lookupin_bytes);
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let lookupout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            lookupin_bytes,
-            &headerout_buffer,
-            &lookupout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+    fn lookup(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        self.submit(fuse_lookup_request(nodeid, &name))
     }
 
     fn release(&self, nodeid: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseReleaseIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseRelease as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+        // An evicted handle is already released.
+        let Ok(Some(fh)) = self
+            .handles
+            .disable_irq()
+            .lock()
+            .close(fh)
+            .inspect_err(|err| debug!("Request not sent: {}", err))
+        else {
+            return;
         };
-
         let releasein = FuseReleaseIn {
             fh: fh,
             flags: flags,
             release_flags: if flush { FUSE_RELEASE_FLUSH } else { 0 },
             lock_owner: lock_owner,
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let releasein_bytes = releasein.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        // let releaseout_bytes = [0u8; size_of::<FuseReleaseOut>()];
-        let concat_req = [
-            headerin_bytes,
-            releasein_bytes,
-            &headerout_buffer,
-            // &releaseout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseReleaseIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseRelease,
+            nodeid,
+            releasein.as_bytes(),
+            0,
+        )
+        .ok();
     }
 
     fn access(&self, nodeid: u64, mask: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseAccessIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseAccess as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
         let accessin = FuseAccessIn {
             mask: mask,
             padding: 0,
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let accessin_bytes = accessin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let accessout_bytes = [0u8; size_of::<FuseAttrOut>()];
-        let concat_req = [
-            headerin_bytes,
-            accessin_bytes,
-            &headerout_buffer,
-            &accessout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseAccessIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseAccess,
+            nodeid,
+            accessin.as_bytes(),
+            size_of::<FuseAttrOut>(),
+        )
+        .ok();
     }
 
     fn statfs(&self, nodeid: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseStatfs as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let statfsout_bytes = [0u8; size_of::<FuseStatfsOut>()];
-        let concat_req = [headerin_bytes, &headerout_buffer, &statfsout_bytes].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        self.submit(fuse_statfs_request(nodeid)).ok();
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    fn interrupt(&self, unique: u64) {
+        if self.interrupts.disable_irq().lock().interrupt(unique) == InterruptAction::Send {
+            self.send_interrupt(unique);
         }
     }
 
-    fn interrupt(&self, unique: u64) {
-        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
+    fn mkdir(
+        &self,
+        nodeid: u64,
+        mode: u32,
+        umask: u32,
+        name: Vec<u8>,
+        supp_group: Option<u32>,
+    ) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        let request = fuse_mkdir_request(nodeid, mode, umask, &name);
+        self.submit(fuse_push_supp_group(
+            request,
+            supp_group,
+            self.negotiated_flags(),
+        ))
+    }
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInterruptIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseInterrupt as u32,
-            unique: unique,
-            nodeid: 0,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    fn create(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+        supp_group: Option<u32>,
+    ) -> Result<(), FuseError> {
+        let request =
+            fuse_checked_create_request(nodeid, &name, mode, umask, flags, self.name_max())?;
+        self.submit(fuse_push_supp_group(
+            request,
+            supp_group,
+            self.negotiated_flags(),
+        ))
+    }
 
-        let interruptin = FuseInterruptIn { unique: unique };
+    fn destroy(&self) {
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseDestroy,
+            0,
+            &[],
+            0,
+        )
+        .ok();
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let interruptin_bytes = interruptin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, interruptin_bytes, &headerout_buffer].concat();
+    fn rename(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        newdir: u64,
+        newname: Vec<u8>,
+    ) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        self.check_name(&newname)?;
+
+        self.submit(fuse_rename_request(nodeid, &name, newdir, &newname))
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInterruptIn>() + size_of::<FuseInHeader>();
+    fn rename2(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        newdir: u64,
+        newname: Vec<u8>,
+        flags: u32,
+    ) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        self.check_name(&newname)?;
+        fuse_check_rename_flags(flags)?;
+
+        // A server predating RENAME2 still does a rename without flags.
+        let rename2_known = self.negotiated_params().map_or(true, |params| {
+            params.check_opcode(FuseOpcode::FuseRename2).is_ok()
+        });
+        if flags == 0 && !rename2_known {
+            return self.submit(fuse_rename_request(nodeid, &name, newdir, &newname));
+        }
+        self.submit(fuse_rename2_request(nodeid, &name, newdir, &newname, flags))
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]) -> Result<usize, FuseError> {
+        // Each chunk of at most `max_write` bytes waits for its reply before
+        // the next one is written to the request buffer.
+        fuse_write_all(offset, data, |offset, chunk| {
+            self.write_at_as(
+                &self.default_context(),
+                nodeid,
+                fh,
+                offset,
+                chunk,
+                WriteOrigin::Cache,
+            )
+        })
+    }
 
-        hiprio_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    fn forget(&self, nodeid: u64, nlookup: u64) {
+        self.submit_hiprio(fuse_forget_request(nodeid, nlookup))
+            .ok();
+    }
 
-        if hiprio_queue.should_notify() {
-            hiprio_queue.notify();
+    fn batch_forget(&self, forget_list: &[(u64, u64)]) {
+        // Each BATCH_FORGET has to fit in a hiprio buffer.
+        for forgets in forget_list.chunks(FUSE_MAX_BATCH_FORGET) {
+            self.submit_hiprio(fuse_batch_forget_request(forgets)).ok();
         }
     }
+    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>) -> Result<(), FuseError> {
+        self.check_name(&name)?;
 
-    fn mkdir(&self, nodeid: u64, mode: u32, umask: u32, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+        self.submit(fuse_link_request(nodeid, oldnodeid, &name))
+    }
+    fn unlink(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FuseError> {
+        self.check_name(&name)?;
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+        self.submit(fuse_unlink_request(FuseOpcode::FuseUnlink, nodeid, &name))
+    }
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseMkdirIn>() as u32
-                + prepared_name.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseMkdir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
+    fn bmap(&self, nodeid: u64, blocksize: u32, index: u64) {
+        let bmapin = FuseBmapIn {
+            blocksize: blocksize,
+            block: index,
             padding: 0,
         };
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseBmap,
+            nodeid,
+            bmapin.as_bytes(),
+            size_of::<FuseBmapOut>(),
+        )
+        .ok();
+    }
 
-        let mkdirin = FuseMkdirIn {
-            mode: mode,
-            umask: umask,
+    fn fallocate(&self, nodeid: u64, fh: u64, offset: u64, length: u64, mode: u32) {
+        let Ok(()) =
+            fuse_check_fallocate_mode(mode).inspect_err(|err| debug!("Request not sent: {}", err))
+        else {
+            return;
         };
+        let Some(fh) = self.request_fh(fh) else {
+            return;
+        };
+        self.pages.disable_irq().lock().invalidate(nodeid);
+        self.submit(fuse_fallocate_request(nodeid, fh, offset, length, mode))
+            .ok();
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let mkdirin_bytes = mkdirin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let mkdirout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            mkdirin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &mkdirout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseMkdirIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    fn fsync(&self, nodeid: u64, fh: u64, datasync: bool) {
+        // The kind of the handle, not the method called, picks the opcode.
+        self.submit_fsync(nodeid, fh, datasync);
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: bool) {
+        self.submit_fsync(nodeid, fh, datasync);
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+    fn getlk(&self, nodeid: u64, fh: u64, lock_owner: u64, lock: FuseFileLock, flags: u32) {
+        let Some(fh) = self.request_fh(fh) else {
+            return;
+        };
+        self.submit(fuse_lk_request(
+            FuseOpcode::FuseGetlk,
+            nodeid,
+            fh,
+            lock_owner,
+            lock,
+            flags,
+        ))
+        .ok();
     }
 
-    fn create(&self, nodeid: u64, name: Vec<u8>, mode: u32, umask: u32, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        self.submit(fuse_getxattr_request(nodeid, &name, size))
+    }
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+    fn setxattr(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        value: Vec<u8>,
+        flags: u32,
+    ) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        self.submit(fuse_setxattr_request(
+            nodeid,
+            &name,
+            &value,
+            flags,
+            self.negotiated_flags(),
+        ))
+    }
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseCreateIn>() as u32
-                + prepared_name.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseCreate as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    fn ioctl(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+    ) -> Result<(), FuseError> {
+        let flags = self
+            .handles
+            .disable_irq()
+            .lock()
+            .ioctl_flags(nodeid, fh, flags)?;
+        let fh = self.server_fh(fh)?;
 
-        let createin = FuseCreateIn {
+        let ioctlin = FuseIoctlIn {
+            fh: fh,
             flags: flags,
-            mode: mode,
-            umask: umask,
-            open_flags: 0,
+            cmd: cmd,
+            arg: 0,
+            in_size: in_data.len() as u32,
+            out_size: 0,
         };
+        self.send_request(
+            self.pick_request_queue(),
+            FuseOpcode::FuseIoctl,
+            nodeid,
+            &[ioctlin.as_bytes(), in_data].concat(),
+            size_of::<FuseIoctlOut>(),
+        )
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let createin_bytes = createin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let createout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            createin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &createout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseCreateIn>() + size_of::<FuseInHeader>();
+    fn listxattr(&self, nodeid: u64, size: u32) {
+        self.submit(fuse_listxattr_request(nodeid, size)).ok();
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    fn lseek(&self, nodeid: u64, fh: u64, offset: u64, whence: u32) {
+        let Ok(()) =
+            fuse_check_whence(whence).inspect_err(|err| debug!("Request not sent: {}", err))
+        else {
+            return;
+        };
+        let Some(fh) = self.request_fh(fh) else {
+            return;
+        };
+        self.submit(fuse_lseek_request(nodeid, fh, offset, whence))
+            .ok();
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    fn mknod(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        supp_group: Option<u32>,
+    ) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        let request = fuse_mknod_request(nodeid, &name, mode, rdev, umask);
+        self.submit(fuse_push_supp_group(
+            request,
+            supp_group,
+            self.negotiated_flags(),
+        ))
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+    fn poll(&self, nodeid: u64, fh: u64, kh: u64, flags: u32, events: u32) {
+        let Some(fh) = self.request_fh(fh) else {
+            return;
+        };
+        self.submit(fuse_poll_request(nodeid, fh, kh, flags, events))
+            .ok();
     }
 
-    fn destroy(&self) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseDestroy as u32,
-            unique: 0,
-            nodeid: 0,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+    fn readdirplus(&self, nodeid: u64, fh: u64, offset: u64, size: u32) {
+        let Some(fh) = self.request_fh(fh) else {
+            return;
         };
+        // The buffer taken for the request is grown up to this bound.
+        let size = size.min(fuse_read_capacity(FUSE_MAX_BUFFER_PAGES * PAGE_SIZE));
+        self.submit_on(
+            self.pick_request_queue(),
+            fuse_read_request(FuseOpcode::FuseReaddirplus, nodeid, fh, offset, size),
+        )
+        .ok();
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
+    fn readlink(&self, nodeid: u64) {
+        self.submit(
+            RequestBuilder::new(FuseOpcode::FuseReadlink, nodeid)
+                .reserve_reply(FUSE_PATH_MAX as u32),
+        )
+        .ok();
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
+    fn removexattr(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        self.submit(fuse_removexattr_request(nodeid, &name))
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    fn rmdir(&self, nodeid: u64, name: Vec<u8>) -> Result<(), FuseError> {
+        self.check_name(&name)?;
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+        self.submit(fuse_unlink_request(FuseOpcode::FuseRmdir, nodeid, &name))
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+    fn setlk(&self, nodeid: u64, fh: u64, lock_owner: u64, lock: FuseFileLock, flags: u32) {
+        let Some(fh) = self.request_fh(fh) else {
+            return;
+        };
+        self.submit(fuse_lk_request(
+            FuseOpcode::FuseSetlk,
+            nodeid,
+            fh,
+            lock_owner,
+            lock,
+            flags,
+        ))
+        .ok();
     }
 
-    fn rename(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        // combine the old and new names
-
-        let names = format!(
-            "{}\0{}",
-            String::from_utf8(name).unwrap(),
-            String::from_utf8(newname).unwrap()
-        );
-
-        let prepared_names = fuse_pad_str(&names, true);
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseRenameIn>() as u32
-                + prepared_names.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseRename as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+    fn setlkw(&self, nodeid: u64, fh: u64, lock_owner: u64, lock: FuseFileLock, flags: u32) {
+        let Some(fh) = self.request_fh(fh) else {
+            return;
         };
+        self.submit_lock_wait(
+            self.next_unique(),
+            fuse_lk_request(FuseOpcode::FuseSetlkw, nodeid, fh, lock_owner, lock, flags),
+        )
+        .ok();
+    }
 
-        let renamein = FuseRenameIn { newdir: newdir };
-
-        let headerin_bytes = headerin.as_bytes();
-        let renamein_bytes = renamein.as_bytes();
-        let prepared_names_bytes = prepared_names.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let renameout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            renamein_bytes,
-            prepared_names_bytes,
-            &headerout_buffer,
-            &renameout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_names.len() + size_of::<FuseRenameIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    fn symlink(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        link: Vec<u8>,
+        supp_group: Option<u32>,
+    ) -> Result<(), FuseError> {
+        self.check_name(&name)?;
+        fuse_check_name(&link)?;
+        let request = fuse_symlink_request(nodeid, &name, &link);
+        self.submit(fuse_push_supp_group(
+            request,
+            supp_group,
+            self.negotiated_flags(),
+        ))
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    fn setupmapping(
+        &self,
+        nodeid: u64,
+        fh: u64,
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+    ) -> Result<(), FuseError> {
+        let alignment = self.map_alignment()?;
+        if flags & !(FUSE_SETUPMAPPING_FLAG_READ | FUSE_SETUPMAPPING_FLAG_WRITE) != 0 {
+            return Err(FuseError::Inval);
+        }
+        fuse_check_mapping(alignment, &[foffset, len, moffset])?;
+        let fh = self.server_fh(fh)?;
+        self.submit(fuse_setupmapping_request(
+            nodeid, fh, foffset, len, flags, moffset,
+        ))
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    fn removemapping(
+        &self,
+        nodeid: u64,
+        count: u32,
+        mappings: &[FuseRemoveMappingOne],
+    ) -> Result<(), FuseError> {
+        let alignment = self.map_alignment()?;
+        if mappings.is_empty() || count as usize != mappings.len() {
+            return Err(FuseError::Inval);
         }
+        for mapping in mappings {
+            fuse_check_mapping(alignment, &[mapping.moffset, mapping.len])?;
+        }
+        self.submit(fuse_removemapping_request(nodeid, mappings))
     }
+}
 
-    fn rename2(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>, flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+impl FilesystemDevice {
+    /// Negotiate features for the device specified bits 0~23
+    pub fn negotiate_features(features: u64) -> u64 {
+        let features = VirtioFilesystemFeature::new(features);
+        debug!("features negotiated: {:?}", features.negotiated);
 
-        let names = format!(
-            "{}\0{}",
-            String::from_utf8(name).unwrap(),
-            String::from_utf8(newname).unwrap()
+        early_println!("device features: {:?}", features.device);
+        early_println!(
+            "supported features: {:?}",
+            FilesystemFeatures::supported_features()
         );
+        early_println!("features negotiated: {:?}", features.negotiated);
 
-        let prepared_names = fuse_pad_str(&names, true);
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseRename2In>() as u32
-                + prepared_names.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseRename2 as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+        features.negotiated.bits()
+    }
 
-        let rename2in = FuseRename2In {
-            newdir: newdir,
-            flags: flags,
-            padding: 0,
-        };
+    /// Returns the features the device advertised but the driver does not support.
+    pub fn unsupported_device_features(&self) -> FilesystemFeatures {
+        self.features.unsupported()
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let rename2in_bytes = rename2in.as_bytes();
-        let prepared_names_bytes = prepared_names.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let rename2out_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            rename2in_bytes,
-            prepared_names_bytes,
-            &headerout_buffer,
-            &rename2out_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_names.len() + size_of::<FuseRename2In>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-    }
-
-    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let data = [data, vec![0u8; (8 - (data.len() & 0x7)) & 0x7].as_slice()].concat();
-
-        let headerin = FuseInHeader {
-            len: size_of::<FuseInHeader>() as u32
-                + size_of::<FuseWriteIn>() as u32
-                + data.len() as u32,
-            opcode: FuseOpcode::FuseWrite as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Learns the limits of the file system once FUSE_INIT has completed.
+    ///
+    /// Device initialization calls this right after the handshake; calling
+    /// it again picks up limits the server changed since.
+    ///
+    /// A STATFS on the root tells the longest name the server accepts, so
+    /// over-long names are rejected without a round trip. A server without
+    /// STATFS is left to reject them itself.
+    pub fn mount(&self) -> Result<(), FuseError> {
+        let reply = self.submit_and_wait(fuse_statfs_request(FUSE_ROOT_ID))?;
+        *self.name_max.disable_irq().lock() = fuse_statfs_namelen(&reply)?;
+        Ok(())
+    }
 
-        let writein = FuseWriteIn {
-            fh: fh,
-            offset: offset,
-            size: data.len() as u32,
-            write_flags: FUSE_WRITE_LOCKOWNER,
-            lock_owner: 0,
-            flags: 0,
-            padding: 0,
-        };
+    /// Returns the tag the file system is mounted by.
+    pub fn tag(&self) -> String {
+        self.config_manager.read_config().tag_str()
+    }
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let writeout_buffer = [0u8; size_of::<FuseWriteOut>()];
+    /// Returns the longest name the server accepts, if known.
+    pub fn name_max(&self) -> Option<u32> {
+        *self.name_max.disable_irq().lock()
+    }
 
-        let data_bytes = data.as_slice();
-        let writein_bytes = writein.as_bytes();
-        let headerin_bytes = headerin.as_bytes();
-        let concat_req = [
-            headerin_bytes,
-            writein_bytes,
-            data_bytes,
-            &headerout_buffer,
-            &writeout_buffer,
-        ]
-        .concat();
+    /// Checks a name to be sent to the server.
+    fn check_name(&self, name: &[u8]) -> Result<(), FuseError> {
+        fuse_check_name(name)?;
+        fuse_check_name_len(name, self.name_max())
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseWriteIn>() + size_of::<FuseInHeader>() + data.len() as usize;
+    /// Caps the number of file handles kept open on the server.
+    ///
+    /// Beyond the cap, the least recently used handles that are not pinned
+    /// are released and opened again on their next use.
+    pub fn set_handle_limit(&self, limit: Option<usize>) {
+        self.handles.disable_irq().lock().set_limit(limit);
+        self.release_evicted_handles();
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in as usize);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in as usize, len);
+    /// Keeps `fh` open on the server until it is unpinned.
+    pub fn pin_handle(&self, fh: u64) -> Result<(), FuseError> {
+        self.server_fh(fh)?;
+        self.handles.disable_irq().lock().pin(fh)
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    pub fn unpin_handle(&self, fh: u64) {
+        self.handles.disable_irq().lock().unpin(fh);
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Marks the fast path for I/O on a handle with a passthrough backing file.
+    ///
+    /// The guest cannot reach the backing file itself yet, so the I/O still
+    /// goes through FUSE.
+    fn note_passthrough(&self, fh: u64) {
+        if let Some(backing_id) = self.handles.disable_irq().lock().backing_id(fh) {
+            debug!("fh {} has passthrough backing file {}", fh, backing_id);
         }
     }
 
-    fn forget(&self, nodeid: u64, nlookup: u64) {
-        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
+    /// Returns the server handle behind `fh`, opening it again if evicted.
+    ///
+    /// A handle the driver did not hand out, or one already closed, is
+    /// rejected.
+    fn server_fh(&self, fh: u64) -> Result<u64, FuseError> {
+        let mut handles = self.handles.disable_irq().lock();
+        if let Some(server_fh) = handles.use_handle(fh) {
+            return Ok(server_fh);
+        }
+        let entry = handles.evicted(fh).ok_or(FuseError::BadF)?;
+        drop(handles);
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseForgetIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseForget as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+        let openin = FuseOpenIn {
+            flags: entry.flags,
+            open_flags: 0,
         };
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(entry.open_opcode(), entry.nodeid)
+                .push_struct(&openin)
+                .reserve_reply(0),
+        )?;
+        let open_out = fuse_reply_val::<FuseOpenOut>(&reply)?;
+        self.pages
+            .disable_irq()
+            .lock()
+            .open(entry.nodeid, open_out.open_flags);
+        let recorded =
+            self.handles
+                .disable_irq()
+                .lock()
+                .reopened(fh, open_out.fh, open_out.open_flags);
+        if !recorded {
+            // Someone else opened it again first, or it was closed meanwhile.
+            self.release_server_handle(open_out.fh, &entry);
+            return self.server_fh(fh);
+        }
+        self.release_evicted_handles();
+        Ok(open_out.fh)
+    }
 
-        let forgetin = FuseForgetIn { nlookup: nlookup };
-
-        let headerin_bytes = headerin.as_bytes();
-        let forgetin_bytes = forgetin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, forgetin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseForgetIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        hiprio_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns the server handle behind `fh` for a request whose reply nobody
+    /// waits for, or logs why the request is not sent.
+    fn request_fh(&self, fh: u64) -> Option<u64> {
+        self.server_fh(fh)
+            .inspect_err(|err| debug!("Request not sent: {}", err))
+            .ok()
+    }
 
-        if hiprio_queue.should_notify() {
-            hiprio_queue.notify();
+    /// Flushes and releases the handles evicted beyond the handle limit.
+    ///
+    /// The requests are waited for, as the server may not see them in order
+    /// otherwise. Their errors are ignored: the handle is gone either way.
+    fn release_evicted_handles(&self) {
+        let evicted = self.handles.disable_irq().lock().evict();
+        for (fh, entry) in evicted {
+            if entry.kind == HandleKind::File {
+                let flushin = FuseFlushIn {
+                    fh,
+                    ..Default::default()
+                };
+                let reply = self.submit_and_wait(
+                    RequestBuilder::new(FuseOpcode::FuseFlush, entry.nodeid)
+                        .push_struct(&flushin)
+                        .reserve_reply(0),
+                );
+                if let Err(err) = reply.and_then(|reply| fuse_parse_reply(&reply).map(drop)) {
+                    self.writeback_errors
+                        .disable_irq()
+                        .lock()
+                        .record(entry.nodeid, err.errno());
+                }
+            }
+            self.release_server_handle(fh, &entry);
         }
     }
 
-    fn batch_forget(&self, forget_list: &[(u64, u64)]) {
-        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseBatchForgetIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseBatchForget as u32,
-            unique: 0,
-            nodeid: 0,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+    /// Releases the server handle `server_fh` opened for `entry`, ignoring
+    /// the error: the handle is gone either way.
+    fn release_server_handle(&self, server_fh: u64, entry: &HandleEntry) {
+        let releasein = FuseReleaseIn {
+            fh: server_fh,
+            flags: entry.flags,
+            release_flags: 0,
+            lock_owner: 0,
         };
+        let _ = self.submit_and_wait(
+            RequestBuilder::new(entry.release_opcode(), entry.nodeid)
+                .push_struct(&releasein)
+                .reserve_reply(0),
+        );
+    }
 
-        let mut forgetin_bytes = Vec::new();
-        for (nodeid, nlookup) in forget_list {
-            let forgetin = FuseForgetOne {
-                nodeid: *nodeid,
-                nlookup: *nlookup,
-            };
-            forgetin_bytes.extend_from_slice(&forgetin.as_bytes());
-        }
+    /// Reads the whole file `name` in the directory `parent`.
+    ///
+    /// The file is opened for the read and released afterwards.
+    pub fn read_file(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: &[u8],
+    ) -> Result<Vec<u8>, FuseError> {
+        file::read_file(self, ctx, parent, name)
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &forgetin_bytes, &headerout_buffer].concat();
+    /// Replaces the contents of the file `name` in the directory `parent`,
+    /// creating it with `mode` if needed.
+    pub fn write_file(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: &[u8],
+        data: &[u8],
+        mode: u32,
+    ) -> Result<(), FuseError> {
+        file::write_file(self, ctx, parent, name, data, mode)
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = forget_list.len() * size_of::<FuseForgetOne>() + size_of::<FuseInHeader>();
+    /// Queues `request` for the next [`Self::flush_batch`] and returns its ticket.
+    ///
+    /// If the batch is not flushed within `timeout`, the request is dropped
+    /// without reaching the device and completes with `TimedOut`.
+    pub fn queue_request(&self, request: RequestBuilder, timeout: Duration) -> u64 {
+        self.batch
+            .disable_irq()
+            .lock()
+            .push(request, deadline_after(timeout))
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Drops a queued request that has not been submitted yet.
+    pub fn cancel_request(&self, ticket: u64) -> bool {
+        self.batch.disable_irq().lock().cancel(ticket)
+    }
 
-        hiprio_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Submits the queued requests that have not timed out, and waits for them.
+    ///
+    /// The requests are added to a request queue under one lock and the
+    /// device is notified once for them all, before any reply is waited for.
+    /// Only those that do not fit in the queue's free buffers and descriptors
+    /// go in another round.
+    pub fn flush_batch(&self) {
+        let mut batch = self.batch.disable_irq().lock();
+        batch.expire(Jiffies::elapsed().as_u64());
+        let requests = batch.take();
+        drop(batch);
+
+        let mut requests = requests.into_iter().peekable();
+        let mut sent = Vec::new();
+        while requests.peek().is_some() {
+            let (mut slot, mut request_queue) = self.lock_request_queue(2);
+            let index = slot.queue();
+            for (ticket, request) in requests.by_ref() {
+                sent.push((
+                    ticket,
+                    self.add_awaited(slot, &mut request_queue, request, None),
+                ));
+                if request_queue.available_desc() < 2 {
+                    break;
+                }
+                match self.buffer_slots.try_acquire(index) {
+                    Some(next) => slot = next,
+                    None => break,
+                }
+            }
+            if request_queue.should_notify() {
+                request_queue.notify();
+            }
+        }
 
-        if hiprio_queue.should_notify() {
-            hiprio_queue.notify();
+        for (ticket, unique) in sent {
+            let reply = unique.and_then(|unique| self.reply_waiters.wait(unique));
+            self.batch.disable_irq().lock().complete(ticket, reply);
         }
     }
-    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseLinkIn>() as u32
-                + prepared_name.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseLink as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let linkin = FuseLinkIn {
-            oldnodeid: oldnodeid,
-        };
 
-        let headerin_bytes = headerin.as_bytes();
-        let linkin_bytes = linkin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let linkout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            linkin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &linkout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseLinkIn>() + size_of::<FuseInHeader>();
+    /// Takes the reply of the queued request `ticket`, once it has one.
+    pub fn batch_reply(&self, ticket: u64) -> Option<Result<Vec<u8>, FuseError>> {
+        let mut batch = self.batch.disable_irq().lock();
+        batch.expire(Jiffies::elapsed().as_u64());
+        batch.take_reply(ticket)
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Returns the number of request buffers holding a request in flight.
+    pub fn request_buffers_in_use(&self) -> usize {
+        self.buffer_slots.in_use()
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns the parameters of the FUSE_INIT handshake, if it has completed.
+    pub fn negotiated_params(&self) -> Option<NegotiatedParams> {
+        *self.init_params.disable_irq().lock()
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Returns the outcome of the FUSE_INIT handshake, if it has completed.
+    fn init_outcome(&self) -> Option<Result<NegotiatedParams, FuseError>> {
+        if self.init_rejected.load(Ordering::Relaxed) {
+            return Some(Err(FuseError::Unsupported));
         }
+        self.negotiated_params().map(Ok)
     }
-    fn unlink(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
 
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
+    /// Returns the init flags the server agreed to, or none before FUSE_INIT.
+    pub fn negotiated_flags(&self) -> FuseInitFlags {
+        self.negotiated_params()
+            .map_or(FuseInitFlags::empty(), |params| params.flags())
+    }
 
-        let headerin = FuseInHeader {
-            len: (prepared_name.len() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseUnlink as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Returns the byte alignment of DAX mappings.
+    ///
+    /// Mappings are only set up with a server that agreed to
+    /// FUSE_MAP_ALIGNMENT; before FUSE_INIT or otherwise this fails with
+    /// `Unsupported`.
+    fn map_alignment(&self) -> Result<u64, FuseError> {
+        self.negotiated_params()
+            .and_then(|params| params.map_alignment())
+            .ok_or(FuseError::Unsupported)
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
+    /// Returns the largest payload a WRITE may carry, which is the least any
+    /// server takes before FUSE_INIT.
+    pub fn max_write(&self) -> u32 {
+        self.negotiated_params()
+            .map_or(FUSE_MIN_MAX_WRITE, |params| params.max_write())
+    }
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let unlinkout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &unlinkout_bytes,
-        ]
-        .concat();
+    /// Returns the readahead the server agreed to, or none before FUSE_INIT.
+    pub fn max_readahead(&self) -> u32 {
+        self.negotiated_params()
+            .map_or(0, |params| params.max_readahead())
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
+    /// Sets the credentials of the requests whose caller gave none: those the
+    /// driver sends on its own, and those of the asynchronous API.
+    ///
+    /// The synchronous API takes the credentials of each call instead, so
+    /// that concurrent callers do not race on these.
+    pub fn set_default_context(&self, context: FuseContext) {
+        *self.default_context.disable_irq().lock() = context;
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    pub fn default_context(&self) -> FuseContext {
+        *self.default_context.disable_irq().lock()
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns the unique of a new request, which is never 0 and never reused.
+    ///
+    /// Replies and INTERRUPTs find their request by it.
+    fn next_unique(&self) -> u64 {
+        self.next_unique
+            .fetch_add(FUSE_REQ_ID_STEP, Ordering::Relaxed)
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Rejects `request` if the server's protocol version cannot parse it.
+    ///
+    /// Before FUSE_INIT completes the version is unknown and nothing is
+    /// rejected.
+    fn check_opcode(&self, request: &RequestBuilder) -> Result<(), FuseError> {
+        if !self.shutdown_state.admits(request.opcode()) {
+            return Err(FuseError::NoDev);
+        }
+        match self.negotiated_params() {
+            Some(params) => params.check_opcode(request.opcode()),
+            None => Ok(()),
         }
     }
 
-    fn bmap(&self, nodeid: u64, blocksize: u32, index: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseBmapIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseBmap as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Adopts parameters negotiated by an earlier device with the same backend.
+    ///
+    /// The parameters are only trusted if the tag, the number of request queues
+    /// and the negotiated features still match this device.
+    pub fn apply_negotiated_params(&self, params: NegotiatedParams) -> Result<(), FuseError> {
+        params.validate(self.features.negotiated, &self.config_manager.read_config())?;
+        *self.init_params.disable_irq().lock() = Some(params);
+        Ok(())
+    }
 
-        let bmapin = FuseBmapIn {
-            blocksize: blocksize,
-            block: index,
-            padding: 0,
+    /// Looks up `name` in the directory `parent` and returns the whole entry.
+    ///
+    /// The attributes of the entry are recorded in the inode cache.
+    pub fn lookup_entry(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: Vec<u8>,
+    ) -> Result<FuseEntryOut, FuseError> {
+        self.check_name(&name)?;
+        let reply = self.submit_and_wait(fuse_lookup_request(parent, &name).with_context(*ctx))?;
+
+        let entry = fuse_reply_val::<FuseEntryOut>(&reply)?;
+        // A zero node ID is a negative entry: the name does not exist.
+        if entry.nodeid == 0 {
+            return Err(FuseError::NoEnt);
+        }
+        self.inodes
+            .disable_irq()
+            .lock()
+            .insert_entry(&entry, monotonic_now());
+        Ok(entry)
+    }
+
+    /// Renames `name` in `olddir` to `newname` in `newdir` as `renameat2`
+    /// does with `flags`, and waits for the reply.
+    ///
+    /// Both names are looked up first and checked against the flags. A server
+    /// without RENAME2 can only do a plain rename, so any flag fails with
+    /// [`FuseError::Inval`] there.
+    pub fn rename_entry(
+        &self,
+        ctx: &FuseContext,
+        olddir: u64,
+        name: &[u8],
+        newdir: u64,
+        newname: &[u8],
+        flags: u32,
+    ) -> Result<(), FuseError> {
+        self.check_name(name)?;
+        self.check_name(newname)?;
+        let request = if flags == 0 {
+            fuse_rename_request(olddir, name, newdir, newname)
+        } else {
+            fuse_rename2_request(olddir, name, newdir, newname, flags)
+        };
+        self.check_opcode(&request).map_err(|_| FuseError::Inval)?;
+
+        let source = self.lookup_node(ctx, olddir, name)?;
+        let target = match self.lookup_node(ctx, newdir, newname) {
+            Ok(target) => target,
+            Err(err) => {
+                if let Some(nodeid) = source {
+                    self.inodes.disable_irq().lock().release_lookup(nodeid);
+                }
+                return Err(err);
+            }
         };
+        let renamed = fuse_check_rename(flags, source, target).and_then(|stale| {
+            fuse_parse_reply(&self.submit_and_wait(request.with_context(*ctx))?)?;
+            Ok(stale)
+        });
 
-        let headerin_bytes = headerin.as_bytes();
-        let bmapin_bytes = bmapin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let bmapout_bytes = [0u8; size_of::<FuseBmapOut>()];
-        let concat_req = [
-            headerin_bytes,
-            bmapin_bytes,
-            &headerout_buffer,
-            &bmapout_bytes,
-        ]
-        .concat();
+        let mut inodes = self.inodes.disable_irq().lock();
+        if let Ok(stale) = renamed {
+            inodes.renamed(olddir, newdir, stale);
+        }
+        for nodeid in [source, target].into_iter().flatten() {
+            inodes.release_lookup(nodeid);
+        }
+        renamed.map(|_| ())
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseBmapIn>() + size_of::<FuseInHeader>();
+    /// Looks up `name` in `parent`, returning `None` if it does not exist.
+    fn lookup_node(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: &[u8],
+    ) -> Result<Option<u64>, FuseError> {
+        match self.lookup_entry(ctx, parent, name.to_vec()) {
+            Ok(entry) => Ok(Some(entry.nodeid)),
+            Err(FuseError::NoEnt) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Makes the next GETATTR of `nodeid`, or of every node if `None`, go to
+    /// the server instead of the attribute cache.
+    ///
+    /// For callers that know the backend changed behind the server's back,
+    /// and for the handlers of the server's invalidation notifications.
+    pub fn invalidate_cache(&self, nodeid: Option<u64>) {
+        self.inodes.disable_irq().lock().invalidate(nodeid);
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Reads all the entries of the open directory `fh`, with as many
+    /// READDIRs as it takes; see [`dir::fuse_read_full_dir`].
+    pub fn read_full_dir(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        dedup_dots: bool,
+    ) -> Result<Vec<FuseDirentWithName>, FuseError> {
+        dir::fuse_read_full_dir(self, ctx, nodeid, fh, dedup_dots)
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Tears the device down, as for unmounting the file system.
+    ///
+    /// The lookups of abandoned nodes are returned and the requests in flight
+    /// waited for, then FUSE_DESTROY is sent and its reply awaited. Last, the
+    /// device is reset, so that it holds no descriptor of ours, and its tag
+    /// is freed. Requests made once this has begun fail with `NoDev`, other
+    /// than the FORGETs and FUSE_DESTROY it sends itself, and so do those
+    /// whose reply is still awaited at the reset. A concurrent or later call
+    /// waits until the device is reset and returns `Ok`.
+    ///
+    /// Each wait gives up after [`FUSE_DRAIN_TIMEOUT`], so that a lost
+    /// completion or a hung server cannot stall the unmount; if the requests
+    /// in flight are not done by then, FUSE_DESTROY is not sent. An error
+    /// replied to FUSE_DESTROY, or `TimedOut`, is returned, but the device is
+    /// reset all the same.
+    pub fn shutdown(&self) -> Result<(), FuseError> {
+        if !self.shutdown_state.begin() {
+            self.shutdown_state.wait_shut_down();
+            return Ok(());
         }
+        self.forget_pending();
+        let now = || Jiffies::elapsed().as_u64();
+        let destroyed = if fuse_drain(deadline_after(FUSE_DRAIN_TIMEOUT), now, || {
+            self.is_drained()
+        }) {
+            self.destroy_until(deadline_after(FUSE_DRAIN_TIMEOUT))
+        } else {
+            early_print!("Virtio-fs requests still in flight; resetting anyway\n");
+            Err(FuseError::TimedOut)
+        };
+
+        self.shutdown_state.finish();
+        self.follow_up_wait.wake_all();
+        if let Err(err) = self
+            .transport
+            .disable_irq()
+            .lock()
+            .write_device_status(DeviceStatus::empty())
+        {
+            early_print!("Virtio-fs device not reset: {:?}\n", err);
+        }
+        // The device holds no request any more; nothing awaited will arrive.
+        self.reply_waiters.close();
+        super::unregister_device(&self.tag());
+        destroyed
     }
 
-    fn fallocate(&self, nodeid: u64, fh: u64, offset: u64, length: u64, mode: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseFallocateIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseFallocate as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let fallocatein = FuseFallocateIn {
-            fh: fh,
-            offset: offset,
-            length: length,
-            mode: mode,
-            padding: 0,
-        };
+    /// Returns whether no request is in flight, reaping the hiprio messages
+    /// the device has used first.
+    fn is_drained(&self) -> bool {
+        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
+        let mut slots = self.hiprio_slots.disable_irq().lock();
+        self.reap_hiprio(&mut hiprio_queue, &mut slots);
+        if slots.in_use() != 0 {
+            return false;
+        }
+        drop(slots);
+        drop(hiprio_queue);
+        // The completion handler reaps the requests of the request queues.
+        self.request_queues.iter().all(|request_queue| {
+            let queue = request_queue.disable_irq().lock();
+            queue.available_desc() == queue.size() as usize
+        })
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let fallocatein_bytes = fallocatein.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, fallocatein_bytes, &headerout_buffer].concat();
+    /// Sends FUSE_DESTROY and waits for its reply until `deadline`.
+    fn destroy_until(&self, deadline: u64) -> Result<(), FuseError> {
+        let outcome: Arc<SpinLock<Option<Result<(), FuseError>>, LocalIrqDisabled>> =
+            Arc::new(SpinLock::new(None));
+        let unique = {
+            let outcome = outcome.clone();
+            self.submit_with_callback(
+                RequestBuilder::new(FuseOpcode::FuseDestroy, 0).reserve_reply(0),
+                Box::new(move |response| {
+                    *outcome.lock() = Some(response.payload().map(|_| ()));
+                }),
+            )?
+        };
+        let now = || Jiffies::elapsed().as_u64();
+        if !fuse_drain(deadline, now, || outcome.lock().is_some()) {
+            self.callbacks.cancel(unique);
+        }
+        let destroyed = outcome.lock().take();
+        destroyed.unwrap_or(Err(FuseError::TimedOut))
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseFallocateIn>() + size_of::<FuseInHeader>();
+    /// Returns the lookup counts of the abandoned nodes to the server.
+    pub fn forget_pending(&self) {
+        let pending = self.inodes.disable_irq().lock().forget_pending();
+        match pending.as_slice() {
+            [] => (),
+            &[(nodeid, nlookup)] => self.forget(nodeid, nlookup),
+            pending => self.batch_forget(pending),
+        }
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Looks up `name` in the directory `parent` and returns its node ID.
+    pub fn lookup_nodeid(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: Vec<u8>,
+    ) -> Result<u64, FuseError> {
+        self.lookup_entry(ctx, parent, name)
+            .map(|entry| entry.nodeid)
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Reads up to `size` bytes of the open file `fh` at `offset`, with as
+    /// many READs as it takes.
+    ///
+    /// Fewer bytes than `size` mean the end of the file was reached.
+    pub fn read_range(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: usize,
+    ) -> Result<Vec<u8>, FuseError> {
+        file::fuse_read_range(offset, size, |offset, size| {
+            self.read_at(ctx, nodeid, fh, offset, size)
+        })
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Reads up to `size` bytes of the open file `fh` at `offset`, from the
+    /// page cache if a read there is cached.
+    ///
+    /// The read is clamped to
+    /// [`FUSE_MAX_READ_SIZE`](super::request::FUSE_MAX_READ_SIZE); fewer bytes
+    /// mean the end of the file was reached.
+    pub fn read_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, FuseError> {
+        if self.handles.disable_irq().lock().get(fh).is_none() {
+            return Err(FuseError::BadF);
+        }
+        let cached = self.pages.disable_irq().lock().get(nodeid, offset, size);
+        if let Some(data) = cached {
+            self.touch_atime(nodeid, fh);
+            return Ok(data);
         }
+        let read = || -> Result<Vec<u8>, FuseError> {
+            let server_fh = self.server_fh(fh)?;
+            self.note_passthrough(fh);
+            let reply = self.submit_and_wait(
+                fuse_read_request(FuseOpcode::FuseRead, nodeid, server_fh, offset, size)
+                    .with_context(*ctx),
+            )?;
+            Ok(fuse_read_data(&reply, size)?.to_vec())
+        };
+        let data = fuse_retry_stale(read, || self.reopen(fh))?;
+        self.pages
+            .disable_irq()
+            .lock()
+            .insert(nodeid, offset, size, data.clone());
+        self.touch_atime(nodeid, fh);
+        Ok(data)
     }
 
-    fn fsync(&self, nodeid: u64, fh: u64, fsync_flags: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    /// Returns how many requests found their queue without free descriptors.
+    pub fn queue_full_count(&self) -> u64 {
+        self.stats.queue_full()
+    }
 
-        let fsyncin = FuseFsyncIn {
-            fh: fh,
-            fsync_flags: fsync_flags,
-            padding: 0,
-        };
+    /// Makes reads advance the cached atime to the time `clock` returns, so
+    /// that a `stat` right after sees it without a GETATTR.
+    ///
+    /// This is an approximation of what the server does and is off by
+    /// default; `None` turns it off again.
+    pub fn set_atime_on_read(&self, clock: Option<fn() -> Duration>) {
+        *self.atime_clock.disable_irq().lock() = clock;
+    }
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32 + size_of::<FuseFsyncIn>() as u32),
-            opcode: FuseOpcode::FuseFsyncdir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+    /// Advances the cached atime of `nodeid` after a read through `fh`, if
+    /// enabled and the file was not opened with `O_NOATIME`.
+    fn touch_atime(&self, nodeid: u64, fh: u64) {
+        let Some(clock) = *self.atime_clock.disable_irq().lock() else {
+            return;
         };
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-
-        let headerin_bytes = headerin.as_bytes();
-        let fsyncin_bytes = fsyncin.as_bytes();
+        if self.handles.disable_irq().lock().is_noatime(fh) {
+            return;
+        }
+        let time_gran = self
+            .negotiated_params()
+            .map_or(1, |params| params.init_out.time_gran);
+        self.inodes
+            .disable_irq()
+            .lock()
+            .touch_atime(nodeid, clock(), time_gran);
+    }
 
-        let concat_req = [headerin_bytes, fsyncin_bytes, &headerout_buffer].concat();
+    /// Writes `data` at `offset` of the open file `fh` with a single WRITE.
+    ///
+    /// At most [`Self::max_write`] bytes are sent, and the server may write
+    /// fewer than that; see `WriteResult::is_short`.
+    pub fn write_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<WriteResult, FuseError> {
+        self.write_at_as(ctx, nodeid, fh, offset, data, WriteOrigin::Direct)
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseFsyncIn>() + size_of::<FuseInHeader>();
+    /// Like [`Self::write_at`], with the `write_flags` of `origin`.
+    ///
+    /// [`WriteOrigin::Cache`] is sent as a direct write unless the server
+    /// agreed to the writeback cache and it is enabled; see
+    /// [`Self::set_writeback_cache`].
+    pub fn write_at_as(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+        origin: WriteOrigin,
+    ) -> Result<WriteResult, FuseError> {
+        let origin = match origin {
+            WriteOrigin::Cache => self.cached_write_origin(),
+            origin => origin,
+        };
+        let data = &data[..data.len().min(self.max_write() as usize)];
+        let write = || -> Result<WriteResult, FuseError> {
+            let server_fh = self.server_fh(fh)?;
+            self.note_passthrough(fh);
+            let reply = self.submit_and_wait(
+                fuse_write_request(nodeid, server_fh, offset, data, origin).with_context(*ctx),
+            )?;
+            fuse_write_result(data.len() as u32, &reply)
+        };
+        let result = fuse_retry_stale(write, || self.reopen(fh));
+        self.pages.disable_irq().lock().invalidate(nodeid);
+        result
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Lets writes of cached data be sent as such, if the server agreed to
+    /// `FUSE_WRITEBACK_CACHE`.
+    ///
+    /// The writeback cache is offered in FUSE_INIT, but is off until enabled
+    /// here, as its callers must then flush dirty pages before the handle they
+    /// came from is released.
+    pub fn set_writeback_cache(&self, enabled: bool) {
+        self.writeback_cache.store(enabled, Ordering::Relaxed);
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns whether writes of cached data are sent with `FUSE_WRITE_CACHE`.
+    pub fn writeback_cache(&self) -> bool {
+        self.writeback_cache.load(Ordering::Relaxed)
+            && self
+                .negotiated_flags()
+                .contains(FuseInitFlags::FUSE_WRITEBACK_CACHE)
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Returns the origin of the writes of cached data.
+    fn cached_write_origin(&self) -> WriteOrigin {
+        if self.writeback_cache() {
+            WriteOrigin::Cache
+        } else {
+            WriteOrigin::Direct
         }
     }
 
-    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
+    /// Like [`Self::write_at`], but the device reads `data` where it is
+    /// instead of it being copied into the request buffer.
+    ///
+    /// All of `data` goes in a single WRITE, so it may be no longer than
+    /// [`Self::max_write`]. This sleeps until the reply arrives, as
+    /// [`Self::submit_and_wait`] does.
+    pub fn write_at_dma(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &DmaStreamSlice<&DmaStream>,
+    ) -> Result<WriteResult, FuseError> {
+        if data.nbytes() > self.max_write() as usize {
+            return Err(FuseError::Inval);
+        }
+        data.sync()?;
+        let len = data.nbytes() as u32;
+        let write = || -> Result<WriteResult, FuseError> {
+            let server_fh = self.server_fh(fh)?;
+            self.note_passthrough(fh);
+            let request =
+                fuse_write_payload_request(nodeid, server_fh, offset, len, WriteOrigin::Direct)
+                    .with_context(*ctx);
+            let reply = self.submit_and_wait_with_payload(request, Some(data))?;
+            fuse_write_result(len, &reply)
+        };
+        let result = fuse_retry_stale(write, || self.reopen(fh));
+        self.pages.disable_irq().lock().invalidate(nodeid);
+        result
+    }
 
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseFsyncdir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Opens the caller's handle `fh` again, for the same node and flags,
+    /// under a new server handle.
+    ///
+    /// This is for a handle the server no longer honours, e.g. after it
+    /// invalidated the node; reads and writes do it by themselves when the
+    /// server calls their handle stale. The old server handle is released,
+    /// and the error of that ignored.
+    pub fn reopen(&self, fh: u64) -> Result<(), FuseError> {
+        // A handle another caller set aside already only needs opening again.
+        let invalidated = self.handles.disable_irq().lock().invalidate(fh);
+        if let Some((old_fh, entry)) = invalidated {
+            self.release_server_handle(old_fh, &entry);
+        }
+        self.server_fh(fh).map(drop)
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
+    /// Flushes the open file or directory `fh` to stable storage with FSYNC
+    /// or FSYNCDIR, whichever its handle calls for.
+    ///
+    /// A write of the file that failed after its caller returned is reported
+    /// here, once, in place of the outcome of the FSYNC itself.
+    pub fn fsync_file(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        datasync: bool,
+    ) -> Result<(), FuseError> {
+        let (opcode, fh) = self.fsync_handle(nodeid, fh)?;
+        let reply = self.submit_and_wait(
+            fuse_fsync_request(opcode, nodeid, fh, fuse_fsync_flags(datasync)).with_context(*ctx),
+        );
+        self.writeback_errors.disable_irq().lock().take(nodeid)?;
+        fuse_parse_reply(&reply?)?;
+        Ok(())
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
+    /// Returns the opcode that syncs `fh` and the server handle to send it
+    /// with.
+    ///
+    /// The handle is resolved, and opened again if evicted, before its entry
+    /// picks FSYNC or FSYNCDIR.
+    fn fsync_handle(&self, nodeid: u64, fh: u64) -> Result<(FuseOpcode, u64), FuseError> {
+        let server_fh = self.server_fh(fh)?;
+        let opcode = self.handles.disable_irq().lock().fsync_opcode(nodeid, fh)?;
+        Ok((opcode, server_fh))
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Returns the capacity and usage of the file system holding `nodeid`.
+    ///
+    /// The counts are as the server sent them; see
+    /// [`fuse_statfs_avail_bytes`](super::request::fuse_statfs_avail_bytes)
+    /// for turning them into bytes.
+    pub fn statfs_sync(&self, ctx: &FuseContext, nodeid: u64) -> Result<FuseKstatfs, FuseError> {
+        let reply = self.submit_and_wait(fuse_statfs_request(nodeid).with_context(*ctx))?;
+        Ok(fuse_reply_val::<FuseStatfsOut>(&reply)?.st)
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns a lock that conflicts with `lock` on the open file `fh`, as
+    /// F_GETLK does, or one of type `F_UNLCK` if none does.
+    pub fn getlk_sync(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        lock_owner: u64,
+        lock: FuseFileLock,
+        flags: u32,
+    ) -> Result<FuseFileLock, FuseError> {
+        let reply = self.submit_and_wait(
+            fuse_lk_request(
+                FuseOpcode::FuseGetlk,
+                nodeid,
+                self.server_fh(fh)?,
+                lock_owner,
+                lock,
+                flags,
+            )
+            .with_context(*ctx),
+        )?;
+        Ok(fuse_reply_val::<FuseLkOut>(&reply)?.lk)
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
-        }
+    /// Takes or releases `lock` on the open file `fh` for `lock_owner`.
+    ///
+    /// SETLK fails with the server's EAGAIN if a conflicting lock is held;
+    /// see [`Self::setlkw_sync`] to wait for it instead.
+    pub fn setlk_sync(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        lock_owner: u64,
+        lock: FuseFileLock,
+        flags: u32,
+    ) -> Result<(), FuseError> {
+        let reply = self.submit_and_wait(
+            fuse_lk_request(
+                FuseOpcode::FuseSetlk,
+                nodeid,
+                self.server_fh(fh)?,
+                lock_owner,
+                lock,
+                flags,
+            )
+            .with_context(*ctx),
+        )?;
+        fuse_parse_reply(&reply)?;
+        Ok(())
     }
 
-    fn getlk(
+    /// Takes `lock` on the open file `fh` for `lock_owner`, sleeping with
+    /// SETLKW until no conflicting lock is held.
+    ///
+    /// `waiter` is the caller's, woken when it is interrupted, as
+    /// `is_interrupted` then tells. The request is interrupted with
+    /// FUSE_INTERRUPT, and fails with the server's EINTR unless the lock was
+    /// taken first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn setlkw_sync(
         &self,
+        ctx: &FuseContext,
         nodeid: u64,
         fh: u64,
         lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: u32,
-        pid: u32,
-    ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseGetlk as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+        lock: FuseFileLock,
+        flags: u32,
+        waiter: &Waiter,
+        is_interrupted: impl Fn() -> bool,
+    ) -> Result<(), FuseError> {
+        let request = fuse_lk_request(
+            FuseOpcode::FuseSetlkw,
+            nodeid,
+            self.server_fh(fh)?,
+            lock_owner,
+            lock,
+            flags,
+        )
+        .with_context(*ctx);
+        let unique = self.next_unique();
+        self.reply_waiters.register(unique);
+        self.submit_lock_wait(unique, request)
+            .inspect_err(|_| self.reply_waiters.cancel(unique))?;
+        let reply = match self
+            .reply_waiters
+            .wait_interruptible(unique, waiter, is_interrupted)
+        {
+            Some(reply) => reply?,
+            None => {
+                self.interrupt(unique);
+                self.reply_waiters.wait(unique)?
+            }
         };
+        fuse_parse_reply(&reply)?;
+        Ok(())
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let getlkout_bytes = [0u8; size_of::<FuseLkOut>()];
-        let concat_req = [headerin_bytes, &headerout_buffer, &getlkout_bytes].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
+    /// Seeks in the open file `fh` with LSEEK and returns the offset the
+    /// server found.
+    ///
+    /// With `SEEK_DATA` or `SEEK_HOLE`, that is the start of the next data or
+    /// hole at or after `offset`.
+    pub fn lseek_file(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<u64, FuseError> {
+        fuse_check_whence(whence)?;
+        let reply = self.submit_and_wait(
+            fuse_lseek_request(nodeid, self.server_fh(fh)?, offset, whence).with_context(*ctx),
+        )?;
+        Ok(fuse_reply_val::<FuseLseekOut>(&reply)?.offset)
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Writes all of `data` at `offset` of the open file `fh`, issuing more
+    /// WRITEs after short ones, and returns the number of bytes written.
+    ///
+    /// Each WRITE carries at most [`Self::max_write`] bytes.
+    pub fn write_all(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, FuseError> {
+        fuse_write_all(offset, data, |offset, data| {
+            self.write_at(ctx, nodeid, fh, offset, data)
+        })
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Polls the open file `fh` for `events` and returns the ready ones.
+    ///
+    /// If none are ready, the server is asked to send FUSE_NOTIFY_POLL once
+    /// they may be, which wakes `waker`. Without a notification queue that
+    /// message cannot arrive, so nothing is scheduled and the caller has to
+    /// poll again.
+    pub fn poll_wait(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        events: u32,
+        waker: Arc<Waker>,
+    ) -> Result<u32, FuseError> {
+        let fh = self.server_fh(fh)?;
+        if self.notify_queue.is_none() {
+            let reply = self
+                .submit_and_wait(fuse_poll_request(nodeid, fh, 0, 0, events).with_context(*ctx))?;
+            return Ok(fuse_reply_val::<FusePollOut>(&reply)?.revents);
+        }
+        let kh = self.poll_handles.disable_irq().lock().register(waker);
+        let reply = self
+            .submit_and_wait(
+                fuse_poll_request(nodeid, fh, kh, FUSE_POLL_SCHEDULE_NOTIFY, events)
+                    .with_context(*ctx),
+            )
+            .and_then(|reply| fuse_reply_val::<FusePollOut>(&reply));
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+        match reply {
+            Ok(pollout) if pollout.revents == 0 => Ok(0),
+            reply => {
+                self.poll_handles.disable_irq().lock().unregister(kh);
+                reply.map(|pollout| pollout.revents)
+            }
         }
     }
 
-    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseGetxattrIn>() as u32
-                + prepared_name.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseGetxattr as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let getxattrin = FuseGetxattrIn {
-            size: size,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let getxattrin_bytes = getxattrin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let getxattrout_bytes = [0u8; size_of::<FuseGetxattrOut>()];
-        let concat_req = [
-            headerin_bytes,
-            getxattrin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &getxattrout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseGetxattrIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Handles a notification message sent by the server.
+    ///
+    /// An invalidated node has its cached attributes expired and its cached
+    /// data dropped. The inode cache keeps no names, so an invalidated entry
+    /// only expires the attributes of its directory.
+    pub fn handle_notify(&self, message: &[u8]) -> Result<(), FuseError> {
+        match fuse_parse_notify(message)? {
+            FuseNotification::Poll { .. } => {
+                self.poll_handles
+                    .disable_irq()
+                    .lock()
+                    .handle_notify(message)?;
+            }
+            FuseNotification::InvalInode { nodeid, .. } => {
+                self.invalidate_cache(Some(nodeid));
+                self.pages.disable_irq().lock().invalidate(nodeid);
+            }
+            FuseNotification::InvalEntry { parent, name } => {
+                debug!(
+                    "Entry {:?} of {} invalidated",
+                    String::from_utf8_lossy(&name),
+                    parent
+                );
+                self.invalidate_cache(Some(parent));
+            }
+            FuseNotification::Unhandled(code) => debug!("Notification {} ignored", code),
         }
+        Ok(())
     }
 
-    fn ioctl(&self, nodeid: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8]) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseIoctlIn>() as u32
-                + in_data.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseIoctl as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let ioctlin = FuseIoctlIn {
-            fh: fh,
-            flags: flags,
-            cmd: cmd,
-            arg: 0,
-            in_size: in_data.len() as u32,
-            out_size: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let ioctlin_bytes = ioctlin.as_bytes();
-        let in_data_bytes = in_data;
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let ioctlout_bytes = [0u8; size_of::<FuseIoctlOut>()];
-        let concat_req = [
-            headerin_bytes,
-            ioctlin_bytes,
-            in_data_bytes,
-            &headerout_buffer,
-            &ioctlout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = in_data.len() + size_of::<FuseIoctlIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Resolves `path`, relative to the root of the file system, to its entry.
+    pub fn resolve_path(&self, ctx: &FuseContext, path: &[u8]) -> Result<FuseEntryOut, FuseError> {
+        walk_path(FUSE_ROOT_ID, path, |parent, name| {
+            self.lookup_entry(ctx, parent, name.to_vec())
+        })
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns the whole target of the symlink `nodeid`.
+    pub fn read_link_full(&self, ctx: &FuseContext, nodeid: u64) -> Result<Vec<u8>, FuseError> {
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(FuseOpcode::FuseReadlink, nodeid)
+                .reserve_reply(FUSE_PATH_MAX as u32)
+                .with_context(*ctx),
+        )?;
+        fuse_readlink_target(&reply)
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Returns the attributes of `nodeid`, fetching them with GETATTR and
+    /// recording them in the inode cache unless the cached ones are still
+    /// within their timeout.
+    ///
+    /// For a device node, the device number is in `rdev`; see
+    /// [`InodeEntry::major`](super::inode::InodeEntry::major).
+    pub fn getattr_full(&self, ctx: &FuseContext, nodeid: u64) -> Result<FuseAttr, FuseError> {
+        // Attributes from a LOOKUP or GETATTR within their timeout are current.
+        if let Some(attr) = self
+            .inodes
+            .disable_irq()
+            .lock()
+            .fresh_attr(nodeid, monotonic_now())
+        {
+            return Ok(attr);
         }
+        Ok(self.getattr_sync(ctx, nodeid)?.attr)
     }
 
-    fn listxattr(&self, nodeid: u64, size: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseListxattr as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let listxattrout_bytes = [0u8; size_of::<FuseGetxattrOut>()];
-        let concat_req = [headerin_bytes, &headerout_buffer, &listxattrout_bytes].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Sends GETATTR of `nodeid`, bypassing the attribute cache, and sleeps
+    /// until the reply arrives.
+    ///
+    /// The attributes replied are recorded in the inode cache.
+    pub fn getattr_sync(&self, ctx: &FuseContext, nodeid: u64) -> Result<FuseAttrOut, FuseError> {
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(FuseOpcode::FuseGetattr, nodeid)
+                .push_struct(&FuseGetattrIn::default())
+                .reserve_reply(0)
+                .with_context(*ctx),
+        )?;
+
+        let attr_out = fuse_reply_val::<FuseAttrOut>(&reply)?;
+        self.inodes
+            .disable_irq()
+            .lock()
+            .refresh_attr(nodeid, &attr_out, monotonic_now());
+        Ok(attr_out)
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Fetches the size of `nodeid` and refreshes only that in the inode cache.
+    ///
+    /// Unlike a full GETATTR, the other cached attributes and their timeouts are
+    /// left untouched. If `fh` is given, the size is taken from the open file.
+    pub fn getattr_size(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: Option<u64>,
+    ) -> Result<u64, FuseError> {
+        let fh = fh.map(|fh| self.server_fh(fh)).transpose()?;
+        let getattrin = FuseGetattrIn {
+            getattr_flags: if fh.is_some() { FUSE_GETATTR_FH } else { 0 },
+            dummy: 0,
+            fh: fh.unwrap_or(0),
+        };
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(FuseOpcode::FuseGetattr, nodeid)
+                .push_struct(&getattrin)
+                .reserve_reply(0)
+                .with_context(*ctx),
+        )?;
+
+        let attr_out = fuse_reply_val::<FuseAttrOut>(&reply)?;
+        self.inodes
+            .disable_irq()
+            .lock()
+            .refresh_size(nodeid, attr_out.attr.size);
+        Ok(attr_out.attr.size)
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Takes a free buffer of a request queue with `descs` descriptors free,
+    /// preferring the queue of the current CPU, and returns it with the queue
+    /// locked.
+    ///
+    /// A queue whose buffers or descriptors are all taken by requests in
+    /// flight is skipped; while every one is, this spins.
+    fn lock_request_queue(
+        &self,
+        descs: usize,
+    ) -> (SlotGuard<'_>, SpinLockGuard<VirtQueue, LocalIrqDisabled>) {
+        debug_assert_can_submit();
+        let cpu = disable_local().current_cpu().as_usize();
+        loop {
+            for index in request_queue_order(cpu, self.request_queues.len()) {
+                if let Some(locked) = self.try_lock_request_queue(index, descs) {
+                    return locked;
+                }
+            }
+            spin_loop();
         }
     }
 
-    fn lseek(&self, nodeid: u64, fh: u64, offset: u64, whence: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseLseekIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseLseek as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let lseekin = FuseLseekIn {
-            fh: fh,
-            offset: offset,
-            whence: whence,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let lseekin_bytes = lseekin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let lseekout_bytes = [0u8; size_of::<FuseLseekOut>()];
-        let concat_req = [
-            headerin_bytes,
-            lseekin_bytes,
-            &headerout_buffer,
-            &lseekout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseLseekIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Takes a free buffer of the request queue `index` and locks the queue,
+    /// if both a buffer and `descs` descriptors of it are free.
+    fn try_lock_request_queue(
+        &self,
+        index: usize,
+        descs: usize,
+    ) -> Option<(SlotGuard<'_>, SpinLockGuard<VirtQueue, LocalIrqDisabled>)> {
+        let slot = self.buffer_slots.try_acquire(index)?;
+        let request_queue = self.request_queues[index].disable_irq().lock();
+        (request_queue.available_desc() >= descs).then_some((slot, request_queue))
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Sends FUSE_INIT in the given form, asking for the flags this driver uses.
+    fn send_init(&self, form: InitForm) {
+        let flags = FuseInitFlags::FUSE_HAS_IOCTL_DIR
+            | FuseInitFlags::FUSE_CREATE_SUPP_GROUP
+            | FuseInitFlags::FUSE_MAP_ALIGNMENT
+            | FuseInitFlags::FUSE_WRITEBACK_CACHE;
+        self.submit(fuse_init_request(form, flags)).ok();
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Adds a request written to a request buffer to `queue` and notifies the
+    /// device, returning the descriptor token.
+    ///
+    /// A full queue is counted and reported as [`FuseError::Busy`] instead of
+    /// panicking; callers that do not wait for the reply may ignore it, as it
+    /// is logged here.
+    fn add_request(
+        &self,
+        queue: &mut VirtQueue,
+        slice_in: &DmaStreamSlice<&DmaStream>,
+        slice_out: &DmaStreamSlice<&DmaStream>,
+    ) -> Result<u16, FuseError> {
+        let token = self
+            .stats
+            .add_dma_buf(queue, &[slice_in], &[slice_out])
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        if queue.should_notify() {
+            queue.notify();
         }
+        Ok(token)
     }
 
-    fn mknod(&self, nodeid: u64, name: Vec<u8>, mode: u32, rdev: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseMknodIn>() as u32
-                + prepared_name.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseMknod as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Sends the `opcode` request of `nodeid` on the request queue
+    /// `queue_idx` without waiting for the reply.
+    ///
+    /// `in_body` follows the `FuseInHeader`, and `out_capacity` bytes are
+    /// reserved for the reply after its `FuseOutHeader`. The requests built
+    /// by hand take this path, so that they are checked, tracked and notified
+    /// like those of a [`RequestBuilder`].
+    fn send_request(
+        &self,
+        queue_idx: usize,
+        opcode: FuseOpcode,
+        nodeid: u64,
+        in_body: &[u8],
+        out_capacity: usize,
+    ) -> Result<(), FuseError> {
+        let request = RequestBuilder::new(opcode, nodeid)
+            .push_bytes(in_body)
+            .reserve_out(size_of::<FuseOutHeader>() + out_capacity);
+        self.submit_on(queue_idx, request)
+    }
 
-        let mknodin = FuseMknodIn {
-            mode: mode,
-            rdev: rdev,
-            umask: 0,
-            padding: 0,
+    /// Sends FSYNC or FSYNCDIR of `fh` without waiting for the reply, as the
+    /// kind of the handle calls for.
+    fn submit_fsync(&self, nodeid: u64, fh: u64, datasync: bool) {
+        let Ok((opcode, fh)) = self
+            .fsync_handle(nodeid, fh)
+            .inspect_err(|err| debug!("Request not sent: {}", err))
+        else {
+            return;
         };
+        self.submit(fuse_fsync_request(
+            opcode,
+            nodeid,
+            fh,
+            fuse_fsync_flags(datasync),
+        ))
+        .ok();
+    }
 
-        let headerin_bytes = headerin.as_bytes();
-        let mknodin_bytes = mknodin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let mknodout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            mknodin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &mknodout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseMknodIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Sends `request` on the next request queue without waiting for the reply.
+    ///
+    /// A request that cannot be sent is logged; callers that do not care
+    /// whether it went out may ignore the error.
+    fn submit(&self, request: RequestBuilder) -> Result<(), FuseError> {
+        self.submit_on(self.pick_request_queue(), request)
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns the request queue for a request whose reply nobody waits for.
+    ///
+    /// The queues take turns, so that such requests spread over all of them.
+    /// A queue whose buffers all hold a request in flight is skipped.
+    fn pick_request_queue(&self) -> usize {
+        self.next_queue.pick(self.request_queues.len(), |index| {
+            !self.buffer_slots.has_free(index)
+        })
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Takes a free buffer of the request queue `index` and locks the queue,
+    /// waiting for requests in flight to complete first while its buffers or
+    /// `descs` of its descriptors are all taken.
+    fn lock_request_queue_at(
+        &self,
+        index: usize,
+        descs: usize,
+    ) -> (SlotGuard<'_>, SpinLockGuard<VirtQueue, LocalIrqDisabled>) {
+        loop {
+            if let Some(locked) = self.try_lock_request_queue(index, descs) {
+                return locked;
+            }
+            spin_loop();
         }
     }
 
-    fn poll(&self, nodeid: u64, fh: u64, events: u32) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FusePollIn>() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FusePoll as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    /// Returns the current buffer `slot` of the request queue `queue`.
+    fn request_buffer(&self, queue: usize, slot: usize) -> DmaStream {
+        self.request_buffers[queue][slot].lock().clone()
+    }
 
-        let pollin = FusePollIn {
-            fh: fh,
-            kh: 0,
-            flags: 0,
-            events: events,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let pollin_bytes = pollin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let pollout_bytes = [0u8; size_of::<FusePollOut>()];
-        let concat_req = [
-            headerin_bytes,
-            pollin_bytes,
-            &headerout_buffer,
-            &pollout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FusePollIn>() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns the buffer taken as `slot`, grown first if `request` does not
+    /// fit in it.
+    ///
+    /// The buffer is in no request the device holds while it is taken, so
+    /// it can be replaced at any time.
+    fn fit_request_buffer(
+        &self,
+        slot: &SlotGuard<'_>,
+        request: &RequestBuilder,
+    ) -> Result<DmaStream, FuseError> {
+        self.ensure_capacity(slot.queue(), slot.slot(), request.total_len())?;
+        Ok(self.request_buffer(slot.queue(), slot.slot()))
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Makes the buffer `slot` of the request queue `queue` hold at least
+    /// `bytes`, replacing it with a larger one if needed.
+    ///
+    /// Buffers never shrink. One that would need more than
+    /// `FUSE_MAX_BUFFER_PAGES` is left as is and `Inval` returned, as a
+    /// request that does not fit has always been. The caller has taken the
+    /// buffer, so no request in flight is in it.
+    fn ensure_capacity(&self, queue: usize, slot: usize, bytes: usize) -> Result<(), FuseError> {
+        if self.request_buffer(queue, slot).nbytes() >= bytes {
+            return Ok(());
         }
+        let pages = fuse_buffer_pages(bytes).ok_or(FuseError::Inval)?;
+        let segment = FrameAllocOptions::new().alloc_segment(pages)?;
+        let buffer = DmaStream::map(segment.into(), DmaDirection::Bidirectional, false)
+            .map_err(|_| FuseError::Inval)?;
+        debug!(
+            "Request buffer {} of queue {} grown to {} pages",
+            slot, queue, pages
+        );
+        *self.request_buffers[queue][slot].lock() = buffer;
+        Ok(())
     }
 
-    // // todo: readdirplus
-    // fn readdirplus(&self, nodeid: u64, fh: u64, offset: u64, size: u32) {
-    //     let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-    //     let headerin = FuseInHeader {
-    //         len: (size_of::<FuseReaddirplusIn>() as u32 + size_of::<FuseInHeader>() as u32),
-    //         opcode: FuseOpcode::FuseReaddirplus as u32,
-    //         unique: 0,
-    //         nodeid: nodeid,
-    //         uid: 0,
-    //         gid: 0,
-    //         pid: 0,
-    //         total_extlen: 0,
-    //         padding: 0,
-    //     };
-
-    //     let readdirplusin = FuseReaddirplusIn {
-    //         fh: fh,
-    //         offset: offset,
-    //         size: size,
-    //         padding: 0,
-    //     };
-
-    //     let headerin_bytes = headerin.as_bytes();
-    //     let readdirplusin_bytes = readdirplusin.as_bytes();
-    //     let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-    //     let readdirplusout_bytes = [0u8; size_of::<FuseReaddirplusOut>()];
-    //     let concat_req = [
-    //         headerin_bytes,
-    //         readdirplusin_bytes,
-    //         &headerout_buffer,
-    //         &readdirplusout_bytes,
-    //     ]
-    //     .concat();
-
-    //     let mut reader = VmReader::from(concat_req.as_slice());
-    //     let mut writer = self.request_buffers[0].writer().unwrap();
-    //     let len = writer.write(&mut reader);
-    //     let len_in = size_of::<FuseReaddirplusIn>() + size_of::<FuseInHeader>();
-
-    //     self.request_buffers[0].sync(0..len).unwrap();
-    //     let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-    //     let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-    //     request_queue
-    //         .add_dma_buf(&[&slice_in], &[&slice_out])
-    //         .unwrap();
-
-    //     if request_queue.should_notify() {
-    //         request_queue.notify();
-    //     }
-    // }
-
-    fn readlink(&self, nodeid: u64) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseReadlink as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Sends `request` on the request queue `index` without waiting for the
+    /// reply.
+    ///
+    /// Only requests with a reply go on the request queues, as the completion
+    /// handler is what reaps them; FORGET and BATCH_FORGET go on the hiprio
+    /// queue, see [`Self::submit_hiprio`]. Others fail with `Inval`.
+    fn submit_on(&self, index: usize, request: RequestBuilder) -> Result<(), FuseError> {
+        self.submit_on_as(index, self.next_unique(), request)
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Sends `request` without waiting for the reply, and has the completion
+    /// handler call `callback` with it instead.
+    ///
+    /// Returns the unique of the request. The callback is dropped unfired if
+    /// the request cannot be sent, or has no reply.
+    pub fn submit_with_callback(
+        &self,
+        request: RequestBuilder,
+        callback: FilesystemCallback,
+    ) -> Result<u64, FuseError> {
+        if !request.expects_reply() {
+            return Err(FuseError::Inval);
         }
+        let unique = self.next_unique();
+        self.callbacks.register(unique, callback);
+        self.submit_on_as(self.pick_request_queue(), unique, request)
+            .inspect_err(|_| self.callbacks.cancel(unique))?;
+        Ok(unique)
     }
 
-    fn removexattr(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-
-        let headerin = FuseInHeader {
-            len: (prepared_name.len() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseRemovexattr as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, prepared_name_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Like [`Self::submit_on`], but sends `request` with the given `unique`.
+    fn submit_on_as(
+        &self,
+        index: usize,
+        unique: u64,
+        request: RequestBuilder,
+    ) -> Result<(), FuseError> {
+        debug_assert_can_submit();
+        debug_assert!(request.expects_reply(), "request without a reply queued");
+        if !request.expects_reply() {
+            return Err(FuseError::Inval);
+        }
+        self.check_opcode(&request)
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        let request = request
+            .with_unique(unique)
+            .or_context(self.default_context());
+        let (slot, mut queue) = self.lock_request_queue_at(index, 2);
+        let buffer = self
+            .fit_request_buffer(&slot, &request)
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        let (len_in, len) = request
+            .write_to(&buffer)
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+
+        let (slice_in, slice_out) = fuse_request_slices(&buffer, len_in, len);
+        let token = self
+            .stats
+            .add_dma_buf(&mut queue, &[&slice_in], &[&slice_out])
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        slot.hand_off(token);
+        self.interrupts.disable_irq().lock().track(&request);
+
+        if queue.should_notify() {
+            queue.notify();
         }
+        Ok(())
     }
 
-    fn rmdir(&self, nodeid: u64, name: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-
-        let headerin = FuseInHeader {
-            len: (prepared_name.len() as u32 + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseRmdir as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
-
-        let headerin_bytes = headerin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let rmdirout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            prepared_name_bytes,
-            &headerout_buffer,
-            &rmdirout_bytes,
-        ]
-        .concat();
+    /// Sends the SETLKW `request` as `unique` from a buffer of its own,
+    /// rather than one of a request queue's pool.
+    ///
+    /// The server holds SETLKW until the lock is free, which may take any
+    /// time; a pool buffer held that long would leave its queue a request
+    /// short. The completion handler finds the buffer in `lock_waits`.
+    fn submit_lock_wait(&self, unique: u64, request: RequestBuilder) -> Result<(), FuseError> {
+        debug_assert_can_submit();
+        self.check_opcode(&request)
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        let request = request
+            .with_unique(unique)
+            .or_context(self.default_context());
+        let pages = fuse_buffer_pages(request.total_len()).ok_or(FuseError::Inval)?;
+        let segment = FrameAllocOptions::new().alloc_segment(pages)?;
+        let buffer = DmaStream::map(segment.into(), DmaDirection::Bidirectional, false)
+            .map_err(|_| FuseError::Inval)?;
+        let (len_in, len) = request.write_to(&buffer)?;
+        let (slice_in, slice_out) = fuse_request_slices(&buffer, len_in, len);
+
+        let index = self.pick_request_queue();
+        let mut queue = loop {
+            let queue = self.request_queues[index].disable_irq().lock();
+            if queue.available_desc() >= 2 {
+                break queue;
+            }
+            drop(queue);
+            spin_loop();
+        };
+        let token = self
+            .stats
+            .add_dma_buf(&mut queue, &[&slice_in], &[&slice_out])
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        self.lock_waits
+            .lock()
+            .insert((index, token), buffer.clone());
+        self.interrupts.disable_irq().lock().track(&request);
+
+        if queue.should_notify() {
+            queue.notify();
+        }
+        Ok(())
+    }
 
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + size_of::<FuseInHeader>();
+    /// Sends `request` on the hiprio queue from a hiprio buffer of its own.
+    fn submit_hiprio(&self, request: RequestBuilder) -> Result<(), FuseError> {
+        debug_assert_can_submit();
+        self.check_opcode(&request)
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        let request = request.with_unique(self.next_unique());
+        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
+        let mut slots = self.hiprio_slots.disable_irq().lock();
+        let index = self.hiprio_slot(&mut hiprio_queue, &mut slots);
+        let hiprio_buffer = &self.hiprio_buffers[index];
+        let (len_in, len) = request
+            .write_to(hiprio_buffer)
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+
+        let (slice_in, slice_out) = fuse_request_slices(hiprio_buffer, len_in, len);
+        let outputs: &[&DmaStreamSlice<&DmaStream>] = if request.expects_reply() {
+            &[&slice_out]
+        } else {
+            &[]
+        };
+        let token = self
+            .stats
+            .add_dma_buf(&mut hiprio_queue, &[&slice_in], outputs)
+            .inspect_err(|err| debug!("Request not sent: {}", err))?;
+        self.interrupts.disable_irq().lock().track(&request);
+        slots.submitted(index, token);
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+        if hiprio_queue.should_notify() {
+            hiprio_queue.notify();
+        }
+        Ok(())
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Returns a free hiprio buffer, freeing those of the messages the device
+    /// has used and waiting for one if all are still in flight.
+    fn hiprio_slot(&self, hiprio_queue: &mut VirtQueue, slots: &mut HiprioSlots) -> usize {
+        loop {
+            self.reap_hiprio(hiprio_queue, slots);
+            if let Some(index) = slots.free_slot() {
+                return index;
+            }
+            spin_loop();
+        }
+    }
 
-        if request_queue.should_notify() {
-            request_queue.notify();
+    /// Frees the hiprio buffers of the messages the device has used.
+    ///
+    /// FORGET and BATCH_FORGET have no reply; the reply to an INTERRUPT is
+    /// read before its buffer is freed, and a resend it calls for is left to
+    /// the follow-up worker.
+    fn reap_hiprio(&self, hiprio_queue: &mut VirtQueue, slots: &mut HiprioSlots) {
+        while let Ok((token, len)) = hiprio_queue.pop_used() {
+            let Some(index) = slots.reaped(token) else {
+                continue;
+            };
+            let Ok((headerin, reader)) =
+                fuse_completed_request(&self.hiprio_buffers[index], len as usize)
+            else {
+                early_print!("Malformed completed message in hiprio buffer {:?}\n", index);
+                continue;
+            };
+            if headerin.opcode != FuseOpcode::FuseInterrupt as u32 {
+                continue;
+            }
+            match self.handle_reply(FuseOpcode::FuseInterrupt, &headerin, reader) {
+                Ok(follow_up) if follow_up.is_pending() => {
+                    self.follow_ups.disable_irq().lock().push(follow_up);
+                    self.follow_up_wait.wake_one();
+                }
+                Ok(_) => (),
+                Err(err) => early_print!(
+                    "Interrupt reply {:?} is malformed: {}\n",
+                    headerin.unique,
+                    err
+                ),
+            }
         }
     }
 
-    fn setlk(
-        &self,
-        nodeid: u64,
-        fh: u64,
-        lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: u32,
-        pid: u32,
-        sleep: u32,
-    ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseSetlk as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+    /// Hands all the notification buffers to the device to fill.
+    fn post_notify_buffers(&self) {
+        let Some(notify_queue) = &self.notify_queue else {
+            return;
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
+        let mut queue = notify_queue.disable_irq().lock();
+        let mut slots = self.notify_slots.disable_irq().lock();
+        for index in 0..self.notify_buffers.len() {
+            self.post_notify_buffer(&mut queue, &mut slots, index);
+        }
+        if queue.should_notify() {
+            queue.notify();
         }
     }
 
-    fn setlkw(
-        &self,
-        nodeid: u64,
-        fh: u64,
-        lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: u32,
-        pid: u32,
-        sleep: u32,
-    ) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let headerin = FuseInHeader {
-            len: (size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseSetlkw as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
-        };
+    fn post_notify_buffer(&self, queue: &mut VirtQueue, slots: &mut HiprioSlots, index: usize) {
+        let buffer = &self.notify_buffers[index];
+        let slice = DmaStreamSlice::new(buffer, 0, buffer.nbytes());
+        match queue.add_dma_buf(&[], &[&slice]) {
+            Ok(token) => slots.submitted(index, token),
+            Err(err) => early_print!("Notification buffer {:?} not posted: {:?}\n", index, err),
+        }
+    }
 
-        let lk = FuseFileLock {
-            start: start,
-            end: end,
-            type_: typ,
-            pid: pid,
+    /// Handles the notifications the device has put in the posted buffers,
+    /// posting each buffer again once it is read.
+    fn handle_notify_irq(&self) {
+        let Some(notify_queue) = &self.notify_queue else {
+            return;
         };
-
-        let headerin_bytes = headerin.as_bytes();
-        let setlkin_bytes = lk.as_bytes();
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let concat_req = [headerin_bytes, setlkin_bytes, &headerout_buffer].concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = size_of::<FuseInHeader>();
-
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
-
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
-
-        if request_queue.should_notify() {
-            request_queue.notify();
+        let _scope = CompletionScope::enter();
+        let mut queue = notify_queue.disable_irq().lock();
+        let mut slots = self.notify_slots.disable_irq().lock();
+        while let Ok((token, len)) = queue.pop_used() {
+            let Some(index) = slots.reaped(token) else {
+                continue;
+            };
+            let buffer = &self.notify_buffers[index];
+            let len = (len as usize).min(buffer.nbytes());
+            let message = buffer.sync(0..len).and_then(|()| {
+                let mut message = vec![0u8; len];
+                buffer
+                    .reader()?
+                    .read(&mut VmWriter::from(message.as_mut_slice()));
+                Ok(message)
+            });
+            match message.map_err(FuseError::from) {
+                Ok(message) => {
+                    if let Err(err) = self.handle_notify(&message) {
+                        early_print!("Malformed notification: {}\n", err);
+                    }
+                }
+                Err(err) => early_print!("Notification buffer {:?} unreadable: {}\n", index, err),
+            }
+            self.post_notify_buffer(&mut queue, &mut slots, index);
+        }
+        if queue.should_notify() {
+            queue.notify();
         }
     }
 
-    fn symlink(&self, nodeid: u64, name: Vec<u8>, link: Vec<u8>) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-
-        let prepared_name = fuse_pad_str(&String::from_utf8(name).unwrap(), true);
-        let prepared_link = fuse_pad_str(&String::from_utf8(link).unwrap(), true);
+    /// Handles the messages the device has used on the hiprio queue.
+    fn handle_hiprio_irq(&self) {
+        let _scope = CompletionScope::enter();
+        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
+        let mut slots = self.hiprio_slots.disable_irq().lock();
+        self.reap_hiprio(&mut hiprio_queue, &mut slots);
+    }
 
-        let headerin = FuseInHeader {
-            len: (prepared_name.len() as u32
-                + prepared_link.len() as u32
-                + size_of::<FuseInHeader>() as u32),
-            opcode: FuseOpcode::FuseSymlink as u32,
-            unique: 0,
-            nodeid: nodeid,
-            uid: 0,
-            gid: 0,
-            pid: 0,
-            total_extlen: 0,
-            padding: 0,
+    /// Sends FUSE_INTERRUPT for the request `unique` on the hiprio queue.
+    fn send_interrupt(&self, unique: u64) {
+        debug_assert_can_submit();
+        if !self.shutdown_state.admits(FuseOpcode::FuseInterrupt) {
+            return;
+        }
+        let mut hiprio_queue = self.hiprio_queue.disable_irq().lock();
+        let mut slots = self.hiprio_slots.disable_irq().lock();
+        let index = self.hiprio_slot(&mut hiprio_queue, &mut slots);
+        let hiprio_buffer = &self.hiprio_buffers[index];
+
+        let Ok((len_in, len)) = fuse_interrupt_request(unique)
+            .write_to(hiprio_buffer)
+            .inspect_err(|err| debug!("Request not sent: {}", err))
+        else {
+            return;
         };
 
-        let headerin_bytes = headerin.as_bytes();
-        let prepared_name_bytes = prepared_name.as_slice();
-        let prepared_link_bytes = prepared_link.as_slice();
+        let (slice_in, slice_out) = fuse_request_slices(hiprio_buffer, len_in, len);
 
-        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
-        let symlinkout_bytes = [0u8; size_of::<FuseEntryOut>()];
-        let concat_req = [
-            headerin_bytes,
-            prepared_name_bytes,
-            prepared_link_bytes,
-            &headerout_buffer,
-            &symlinkout_bytes,
-        ]
-        .concat();
-
-        let mut reader = VmReader::from(concat_req.as_slice());
-        let mut writer = self.request_buffers[0].writer().unwrap();
-        let len = writer.write(&mut reader);
-        let len_in = prepared_name.len() + prepared_link.len() + size_of::<FuseInHeader>();
+        let Ok(token) = self.add_request(&mut hiprio_queue, &slice_in, &slice_out) else {
+            return;
+        };
+        slots.submitted(index, token);
+    }
 
-        self.request_buffers[0].sync(0..len).unwrap();
-        let slice_in = DmaStreamSlice::new(&self.request_buffers[0], 0, len_in);
-        let slice_out = DmaStreamSlice::new(&self.request_buffers[0], len_in, len);
+    /// Like [`Self::submit_and_wait`], but sends `request` again after an
+    /// EAGAIN reply, as often as `policy` allows.
+    ///
+    /// Each resend is a new request with a unique of its own. Unlike
+    /// `submit_and_wait`, an error reply is returned as the error.
+    pub fn submit_retrying(
+        &self,
+        request: RequestBuilder,
+        policy: RetryPolicy,
+    ) -> Result<Vec<u8>, FuseError> {
+        fuse_retry_again(
+            policy,
+            || {
+                let reply = self.submit_and_wait(request.clone())?;
+                fuse_parse_reply(&reply)?;
+                Ok(reply)
+            },
+            sleep_for,
+        )
+    }
 
-        request_queue
-            .add_dma_buf(&[&slice_in], &[&slice_out])
-            .unwrap();
+    /// Sends `request` and sleeps until the completion handler hands over its
+    /// reply.
+    ///
+    /// The request buffer stays taken until the reply arrives. The returned
+    /// reply starts with the `FuseOutHeader`.
+    pub fn submit_and_wait(&self, request: RequestBuilder) -> Result<Vec<u8>, FuseError> {
+        self.submit_and_wait_with_payload(request, None)
+    }
 
+    /// Like [`Self::submit_and_wait`], but hands the device `payload` as the
+    /// input that follows what was pushed to `request`, instead of it being
+    /// copied into the request buffer.
+    ///
+    /// `request` has reserved the payload's length; see
+    /// [`RequestBuilder::reserve_payload`]. The reply follows that reserved
+    /// gap in the request buffer, where the completion handler reads it.
+    fn submit_and_wait_with_payload(
+        &self,
+        request: RequestBuilder,
+        payload: Option<&DmaStreamSlice<&DmaStream>>,
+    ) -> Result<Vec<u8>, FuseError> {
+        if request.payload_len() != payload.map_or(0, |payload| payload.nbytes()) {
+            return Err(FuseError::Inval);
+        }
+        self.check_opcode(&request)?;
+        let (slot, mut request_queue) = self.lock_request_queue(2 + payload.is_some() as usize);
+        let unique = self.add_awaited(slot, &mut request_queue, request, payload)?;
         if request_queue.should_notify() {
             request_queue.notify();
         }
-    }
-}
+        drop(request_queue);
 
-impl FilesystemDevice {
-    /// Negotiate features for the device specified bits 0~23
-    pub fn negotiate_features(features: u64) -> u64 {
-        let device_features = FilesystemFeatures::from_bits_truncate(features);
-        let supported_features = FilesystemFeatures::supported_features();
-        let filesystem_features = device_features & supported_features;
-        debug!("features negotiated: {:?}", filesystem_features);
+        self.reply_waiters.wait(unique)
+    }
 
-        early_println!("device features: {:?}", device_features);
-        early_println!("supported features: {:?}", supported_features);
-        early_println!("features negotiated: {:?}", filesystem_features);
+    /// Writes `request` to the buffer taken as `slot` and adds it to `queue`,
+    /// the locked queue of that buffer, for its reply to be waited for with
+    /// `reply_waiters`.
+    ///
+    /// Returns the unique the request was sent with. The queue is not
+    /// notified, so that several requests may be added first.
+    fn add_awaited(
+        &self,
+        slot: SlotGuard<'_>,
+        queue: &mut VirtQueue,
+        request: RequestBuilder,
+        payload: Option<&DmaStreamSlice<&DmaStream>>,
+    ) -> Result<u64, FuseError> {
+        self.check_opcode(&request)?;
+        let unique = self.next_unique();
+        let request = request
+            .with_unique(unique)
+            .or_context(self.default_context());
+        let request_buffer = self.fit_request_buffer(&slot, &request)?;
+        let (len_in, len) = request.write_to(&request_buffer)?;
+        let (_, slice_out) = fuse_request_slices(&request_buffer, len_in, len);
+        let slice_in = DmaStreamSlice::new(&request_buffer, 0, request.head_len());
+        let mut inputs = vec![&slice_in];
+        inputs.extend(payload);
+        // The queue is locked, so the reply cannot arrive before this.
+        self.reply_waiters.register(unique);
+        let token = self
+            .stats
+            .add_dma_buf(queue, &inputs, &[&slice_out])
+            .inspect_err(|_| self.reply_waiters.cancel(unique))?;
+        slot.hand_off(token);
+        self.interrupts.disable_irq().lock().track(&request);
+        Ok(unique)
+    }
 
-        filesystem_features.bits()
+    pub fn init(transport: Box<dyn VirtioTransport>) -> Result<(), VirtioDeviceError> {
+        Self::init_with_depths(transport, QueueDepths::DEFAULT)
     }
 
-    pub fn init(mut transport: Box<dyn VirtioTransport>) -> Result<(), VirtioDeviceError> {
+    /// Like [`Self::init`], with queues of the given `depths`, each clamped to
+    /// what the transport allows.
+    pub fn init_with_depths(
+        mut transport: Box<dyn VirtioTransport>,
+        depths: QueueDepths,
+    ) -> Result<(), VirtioDeviceError> {
         let config_manager = VirtioFilesystemConfig::new_manager(transport.as_ref());
+        let features = VirtioFilesystemFeature::new(transport.read_device_features());
         let fs_config: VirtioFilesystemConfig = config_manager.read_config();
         early_print!(
             "virtio_filesystem_config_notify_buf_size = {:?}\n",
@@ -2204,93 +2493,360 @@ impl FilesystemDevice {
         );
         early_print!("virtio_filesystem_config_tag = {:?}\n", fs_config.tag);
 
-        const HIPRIO_QUEUE_INDEX: u16 = 0;
-        // const NOTIFICATION_QUEUE_INDEX: u16 = 1;
-        const REQUEST_QUEUE_BASE_INDEX: u16 = 1;
-        let hiprio_queue =
-            SpinLock::new(VirtQueue::new(HIPRIO_QUEUE_INDEX, 2, transport.as_mut()).unwrap());
-        // let notification_queue= SpinLock::new(VirtQueue::new(NOTIFICATION_QUEUE_INDEX, 2, transport.as_mut()).unwrap());
+        let hiprio_queue = new_queue(transport.as_mut(), HIPRIO_QUEUE_INDEX, depths.hiprio)?;
+        let has_notification_queue = features.has_notification_queue();
+        let notify_queue = if has_notification_queue {
+            Some(new_queue(
+                transport.as_mut(),
+                NOTIFICATION_QUEUE_INDEX,
+                depths.notification,
+            )?)
+        } else {
+            None
+        };
         let mut request_queues = Vec::new();
         for i in 0..fs_config.num_request_queues {
-            request_queues.push(SpinLock::new(
-                VirtQueue::new(REQUEST_QUEUE_BASE_INDEX + (i as u16), 4, transport.as_mut())
-                    .unwrap(),
-            ))
+            let vq = request_queue_vq(i as usize, has_notification_queue);
+            request_queues.push(new_queue(transport.as_mut(), vq, depths.request)?);
         }
 
-        let hiprio_buffer = {
-            let vm_segment = FrameAllocOptions::new().alloc_segment(3).unwrap();
-            DmaStream::map(vm_segment.into(), DmaDirection::Bidirectional, false).unwrap()
-        };
-
+        let num_hiprio_buffers = hiprio_queue.lock().size() as usize;
+        let hiprio_buffers = (0..num_hiprio_buffers)
+            .map(|_| alloc_dma_stream(1, DmaDirection::Bidirectional))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Each holds a whole message of up to `notify_buf_size` bytes.
+        let num_notify_buffers = notify_queue
+            .as_ref()
+            .map_or(0, |queue| queue.lock().size() as usize);
+        let notify_buf_pages = (fs_config.notify_buf_size as usize)
+            .div_ceil(PAGE_SIZE)
+            .max(1);
+        let notify_buffers = (0..num_notify_buffers)
+            .map(|_| alloc_dma_stream(notify_buf_pages, DmaDirection::FromDevice))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // A request takes two descriptors, or three with a payload, so each
+        // queue has a buffer per two. They start small, as most requests and
+        // replies fit in a page, and grow for the ones that do not.
+        let pool_size = request_queues
+            .iter()
+            .map(|queue| queue.lock().size() as usize / 2)
+            .min()
+            .unwrap_or(0)
+            .max(1);
         let mut request_buffers = Vec::new();
-        for _ in 0..fs_config.num_request_queues {
-            let request_buffer = {
-                let vm_segment = FrameAllocOptions::new().alloc_segment(3).unwrap();
-                DmaStream::map(vm_segment.into(), DmaDirection::Bidirectional, false).unwrap()
-            };
-            request_buffers.push(request_buffer);
+        for _ in 0..request_queues.len() {
+            let pool = (0..pool_size)
+                .map(|_| alloc_dma_stream(1, DmaDirection::Bidirectional).map(SpinLock::new))
+                .collect::<Result<Vec<_>, _>>()?;
+            request_buffers.push(pool);
         }
 
-        let device = Arc::new(Self {
+        let device = Arc::new_cyclic(|device: &Weak<Self>| Self {
             config_manager: config_manager,
+            features,
             transport: SpinLock::new(transport),
             hiprio_queue: hiprio_queue,
-            // notification_queue: notification_queue,
             request_queues: request_queues,
-            hiprio_buffer: hiprio_buffer,
+            notify_slots: SpinLock::new(HiprioSlots::new(notify_buffers.len())),
+            notify_queue,
+            notify_buffers,
+            hiprio_slots: SpinLock::new(HiprioSlots::new(hiprio_buffers.len())),
+            hiprio_buffers: hiprio_buffers,
+            buffer_slots: BufferSlots::new(request_buffers.len(), pool_size),
+            lock_waits: SpinLock::new(BTreeMap::new()),
             request_buffers: request_buffers,
+            handles: SpinLock::new(HandleTable::new()),
+            inodes: SpinLock::new(InodeCache::new()),
+            pages: SpinLock::new(PageCache::new()),
+            init_params: SpinLock::new(None),
+            init_rejected: AtomicBool::new(false),
+            writeback_cache: AtomicBool::new(false),
+            shutdown_state: ShutdownState::new(),
+            interrupts: SpinLock::new(InterruptTracker::new()),
+            poll_handles: SpinLock::new(PollHandles::new()),
+            batch: SpinLock::new(RequestBatch::new()),
+            name_max: SpinLock::new(None),
+            atime_clock: SpinLock::new(None),
+            writeback_errors: SpinLock::new(WritebackErrors::new()),
+            stats: QueueStats::new(),
+            reply_waiters: ReplyWaiters::new(),
+            callbacks: ReplyCallbacks::new(),
+            default_context: SpinLock::new(FuseContext::default()),
+            next_unique: AtomicU64::new(FUSE_REQ_ID_STEP),
+            follow_ups: SpinLock::new(DeferredQueue::new()),
+            next_queue: RoundRobin::new(),
+            follow_up_wait: WaitQueue::new(),
         });
-        let handle_request = {
-            let device = device.clone();
-            move |_: &TrapFrame| device.handle_recv_irq()
-        };
+        Self::spawn_follow_up_worker(&device)?;
         let config_space_change = |_: &TrapFrame| early_print!("Config Changed\n");
         let mut transport = device.transport.disable_irq().lock();
-        transport
-            .register_queue_callback(
-                REQUEST_QUEUE_BASE_INDEX + 0,
-                Box::new(handle_request),
+        // Each request queue completes on its own callback, which knows its index.
+        for index in 0..device.request_queues.len() {
+            let handle_request = {
+                let device = device.clone();
+                move |_: &TrapFrame| device.handle_recv_irq(index)
+            };
+            let vq = request_queue_vq(index, device.notify_queue.is_some());
+            transport.register_queue_callback(vq, Box::new(handle_request), false)?;
+        }
+        if device.notify_queue.is_some() {
+            let handle_notify = {
+                let device = device.clone();
+                move |_: &TrapFrame| device.handle_notify_irq()
+            };
+            transport.register_queue_callback(
+                NOTIFICATION_QUEUE_INDEX,
+                Box::new(handle_notify),
                 false,
-            )
-            .unwrap();
-        transport
-            .register_cfg_callback(Box::new(config_space_change))
-            .unwrap();
+            )?;
+        }
+        // Without this, used FORGETs would only be reaped when the next one
+        // finds the hiprio buffers all taken.
+        let handle_hiprio = {
+            let device = device.clone();
+            move |_: &TrapFrame| device.handle_hiprio_irq()
+        };
+        transport.register_queue_callback(HIPRIO_QUEUE_INDEX, Box::new(handle_hiprio), false)?;
+        transport.register_cfg_callback(Box::new(config_space_change))?;
         transport.finish_init();
         drop(transport);
-
-        // device.init();
+        device.post_notify_buffers();
+
+        // A backend that never answers must not hang device enumeration.
+        device.init();
+        let deadline = deadline_after(FUSE_INIT_TIMEOUT);
+        fuse_wait_init(
+            deadline,
+            || Jiffies::elapsed().as_u64(),
+            || device.init_outcome(),
+        )
+        .map_err(|err| match err {
+            FuseError::TimedOut => VirtioDeviceError::InitTimeout,
+            _ => VirtioDeviceError::UnsupportedVersion,
+        })?;
+        // Without the limits, names are only checked by the server.
+        if let Err(err) = device.mount() {
+            early_println!("Virtio-fs limits not learned: {}", err);
+        }
+        let tag = device.tag();
+        if !super::register_device(tag.clone(), device.clone()) {
+            early_println!("Virtio-fs tag {:?} is taken; device not registered", tag);
+        }
         test_device(&device);
 
         Ok(())
     }
 
-    fn handle_recv_irq(&self) {
-        let mut request_queue = self.request_queues[0].disable_irq().lock();
-        let Ok((_, len)) = request_queue.pop_used() else {
+    /// Handles the completed requests of the request queue `index`.
+    ///
+    /// This runs in interrupt context and submits nothing; see
+    /// [`deferred`](super::deferred) for where follow-up requests go.
+    fn handle_recv_irq(&self, index: usize) {
+        let _scope = CompletionScope::enter();
+        while self.request_queues[index].disable_irq().lock().can_pop() {
+            self.handle_completion(index);
+        }
+        if !self.follow_ups.lock().is_empty() {
+            self.follow_up_wait.wake_one();
+        }
+    }
+
+    /// Starts the task that sends the follow-up requests the completion
+    /// handler queues, until the device is shut down.
+    ///
+    /// Unlike the handler, the task may sleep, both to wait before resending
+    /// an INTERRUPT and for the replies of the requests it sends.
+    fn spawn_follow_up_worker(device: &Arc<Self>) -> Result<(), VirtioDeviceError> {
+        let device = Arc::downgrade(device);
+        TaskOptions::new(move || {
+            while let Some(device) = device.upgrade() {
+                device.follow_up_wait.wait_until(|| {
+                    let pending = !device.follow_ups.disable_irq().lock().is_empty();
+                    (pending || device.shutdown_state.is_shut_down()).then_some(())
+                });
+                if device.shutdown_state.is_shut_down() {
+                    return;
+                }
+                device.run_follow_ups();
+            }
+        })
+        .spawn()
+        .map_err(|_| VirtioDeviceError::TaskSpawnError)?;
+        Ok(())
+    }
+
+    /// Sends the follow-up requests the completion handler has queued.
+    fn run_follow_ups(&self) {
+        let follow_ups = self.follow_ups.disable_irq().lock().take_all();
+        for follow_up in follow_ups {
+            if let Some(unique) = follow_up.resend_interrupt {
+                // Give the server time to pick up the original request; it
+                // may complete meanwhile, leaving nothing to interrupt.
+                sleep_for(INTERRUPT_RESEND_DELAY);
+                if self.interrupts.disable_irq().lock().is_interrupted(unique) {
+                    self.send_interrupt(unique);
+                }
+            }
+            if follow_up.resend_init {
+                self.send_init(InitForm::Legacy);
+            }
+            if follow_up.release_evicted {
+                self.release_evicted_handles();
+            }
+        }
+    }
+
+    /// Handles one completed request of the request queue `index`.
+    fn handle_completion(&self, index: usize) {
+        let mut request_queue = self.request_queues[index].disable_irq().lock();
+        let Ok((token, len)) = request_queue.pop_used() else {
+            return;
+        };
+        // The buffer the request was handed off in is neither reused nor
+        // replaced until `slot` is dropped, once the reply is read. A SETLKW
+        // was sent from a buffer of its own instead.
+        let slot = self.buffer_slots.reap(index, token);
+        let request_buffer = match &slot {
+            Some(slot) => self.request_buffer(index, slot.slot()),
+            None => match self.lock_waits.lock().remove(&(index, token)) {
+                Some(buffer) => buffer,
+                None => {
+                    early_print!(
+                        "Completion {:?} on queue {:?} has no buffer\n",
+                        token,
+                        index
+                    );
+                    return;
+                }
+            },
+        };
+        let Ok((headerin, reader)) = fuse_completed_request(&request_buffer, len as usize) else {
+            early_print!("Malformed completed request on queue {:?}\n", index);
+            return;
+        };
+        if headerin.opcode != FuseOpcode::FuseInterrupt as u32 {
+            self.interrupts.lock().complete(headerin.unique);
+        }
+        let take_reply = |reader: VmReader<'_, ostd::mm::Infallible>| {
+            let mut reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+            let mut reply = vec![0u8; reader.remain()];
+            reader.read(&mut VmWriter::from(reply.as_mut_slice()));
+            reply
+        };
+        // A caller sleeping in `submit_and_wait` decodes the reply itself.
+        if self.reply_waiters.is_waiting(headerin.unique) {
+            let reply = take_reply(reader);
+            drop(request_queue);
+            self.reply_waiters.complete(headerin.unique, reply);
+            return;
+        }
+        if let Some(callback) = self.callbacks.take(headerin.unique) {
+            let response = FuseResponse {
+                unique: headerin.unique,
+                opcode: headerin.opcode,
+                reply: take_reply(reader),
+            };
+            drop(request_queue);
+            callback(&response);
+            return;
+        }
+        let Ok(opcode) = FuseOpcode::try_from(headerin.opcode) else {
+            early_print!("Unknown opcode {:?} completed\n", headerin.opcode);
+            return;
+        };
+        // An error reply has no body, so a failed request ends here. INIT and
+        // INTERRUPT act on their errors below.
+        let Ok(headerout) = fuse_reply_header(&request_buffer, &headerin) else {
+            early_print!("Malformed reply on queue {:?}\n", index);
             return;
         };
-        self.request_buffers[0].sync(0..len as usize).unwrap();
-        let mut reader = self.request_buffers[0].reader().unwrap();
-        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        if !matches!(opcode, FuseOpcode::FuseInit | FuseOpcode::FuseInterrupt) {
+            if let Err(err) = fuse_check_reply(&headerout) {
+                early_print!("{:?} failed: {}\n", opcode, err);
+                // Nobody waits for these; the next fsync or close reports them.
+                if matches!(opcode, FuseOpcode::FuseWrite | FuseOpcode::FuseFlush) {
+                    self.writeback_errors
+                        .lock()
+                        .record(headerin.nodeid, -headerout.error);
+                }
+                return;
+            }
+        }
+        let follow_up = match self.handle_reply(opcode, &headerin, reader) {
+            Ok(follow_up) => follow_up,
+            Err(err) => {
+                early_print!(
+                    "{:?} reply {:?} is malformed: {}\n",
+                    opcode,
+                    headerin.unique,
+                    err
+                );
+                return;
+            }
+        };
+        drop(request_queue);
+        if follow_up.is_pending() {
+            self.follow_ups.lock().push(follow_up);
+        }
+    }
 
-        match FuseOpcode::try_from(headerin.opcode).unwrap() {
+    /// Acts on the reply to a completed request that did not fail.
+    ///
+    /// `reader` starts at the request's input after its header. A reply cut
+    /// short fails with [`FuseError::Io`] rather than being read past its end.
+    fn handle_reply(
+        &self,
+        opcode: FuseOpcode,
+        headerin: &FuseInHeader,
+        mut reader: VmReader<'_, ostd::mm::Infallible>,
+    ) -> Result<ReplyFollowUp, FuseError> {
+        let mut follow_up = ReplyFollowUp::default();
+        match opcode {
             FuseOpcode::FuseInit => {
-                let _datain = reader.read_val::<FuseInitIn>().unwrap();
-                let _headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseInitOut>().unwrap();
-                early_print!("Received Init Msg\n");
-                early_print!("major:{:?}\n", dataout.major);
-                early_print!("minor:{:?}\n", dataout.minor);
-                early_print!("flags:{:?}\n", dataout.flags);
-                early_println!();
+                let in_len = headerin.len as usize - size_of::<FuseInHeader>();
+                let sent = InitForm::from_in_len(in_len);
+                reader = reader.skip(in_len);
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                if headerout.error != 0 {
+                    early_print!("Init failed: error = {:?}\n", headerout.error);
+                    // A server that cannot parse the extended request may
+                    // still accept the legacy one.
+                    if sent == InitForm::Extended {
+                        follow_up.resend_init = true;
+                    }
+                } else {
+                    let payload = fuse_read_payload(&mut reader, headerout)?;
+                    let (init_out, form) = match fuse_init_out(&payload, sent) {
+                        Err(FuseError::Unsupported) => {
+                            early_print!(
+                                "Init failed: the server predates protocol 7.{}\n",
+                                MIN_SUPPORTED_MINOR
+                            );
+                            self.init_rejected.store(true, Ordering::Relaxed);
+                            return Ok(follow_up);
+                        }
+                        negotiated => negotiated?,
+                    };
+                    *self.init_params.lock() = Some(NegotiatedParams::new(
+                        init_out,
+                        form,
+                        self.features.negotiated,
+                        &self.config_manager.read_config(),
+                    ));
+                    early_print!("Received Init Msg\n");
+                    early_print!("major:{:?}\n", init_out.major);
+                    early_print!("minor:{:?}\n", init_out.minor);
+                    early_print!("flags:{:?}\n", init_out.flags);
+                    early_println!();
+                }
             }
             FuseOpcode::FuseReaddir => {
                 // 这里的datain千万不要注释，注释掉会出bug！！！！
-                let _datain = reader.read_val::<FuseReadIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout);
+                let _datain = fuse_read_val::<FuseReadIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout)?;
 
                 early_print!(
                     "Readdir response received: len = {:?}, error = {:?}\n",
@@ -2299,42 +2855,83 @@ impl FilesystemDevice {
                 );
                 for dirent_name in readdir_out.dirents {
                     let dirent = dirent_name.dirent;
-                    let name = String::from_utf8(dirent_name.name).unwrap();
+                    let name = String::from_utf8_lossy(&dirent_name.name);
                     early_print!("Readdir response received: inode={:?}, off={:?}, namelen={:?}, type:{:?}, filename={:?}\n", 
                         dirent.ino, dirent.off, dirent.namelen, dirent.type_, name);
                 }
                 early_println!();
             }
+            FuseOpcode::FuseReaddirplus => {
+                let _datain = fuse_read_val::<FuseReadIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let readdirplus_out = FuseReaddirplusOut::read_direntplus(&mut reader, headerout)?;
+
+                // Each entry is a lookup, so that listing a directory spares
+                // one LOOKUP per name.
+                let now = monotonic_now();
+                let mut inodes = self.inodes.lock();
+                for entry in &readdirplus_out.dirents {
+                    if fuse_direntplus_is_lookup(entry) {
+                        inodes.insert_entry(&entry.entry_out, now);
+                    }
+                }
+                early_print!(
+                    "Readdirplus response received: {:?} entries\n",
+                    readdirplus_out.dirents.len()
+                );
+            }
             FuseOpcode::FuseOpendir => {
-                let _datain = reader.read_val::<FuseOpenIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseOpenOut>().unwrap();
+                let datain = fuse_read_val::<FuseOpenIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseOpenOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
+                let fh = self.handles.lock().insert(
+                    dataout.fh,
+                    headerin.nodeid,
+                    HandleKind::Dir,
+                    datain.flags,
+                    dataout.open_flags,
+                );
+                follow_up.release_evicted = true;
                 early_print!(
                     "Opendir response received: len = {:?}, error = {:?}\n",
                     headerout.len,
                     headerout.error
                 );
-                early_print!("fh:{:?}\n", dataout.fh);
+                early_print!("fh:{:?}\n", fh);
                 early_print!("open_flags:{:?}\n", dataout.open_flags);
                 early_print!("backing_id:{:?}\n", dataout.backing_id);
                 early_println!();
             }
             FuseOpcode::FuseOpen => {
-                let _datain = reader.read_val::<FuseOpenIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseOpenOut>().unwrap();
+                let datain = fuse_read_val::<FuseOpenIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseOpenOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
+                let fh = self.handles.lock().insert(
+                    dataout.fh,
+                    headerin.nodeid,
+                    HandleKind::File,
+                    datain.flags,
+                    dataout.open_flags,
+                );
+                if let Some(backing_id) = fuse_backing_id(&dataout, self.negotiated_flags()) {
+                    self.handles.lock().set_backing_id(fh, backing_id);
+                }
+                follow_up.release_evicted = true;
+                self.pages.lock().open(headerin.nodeid, dataout.open_flags);
                 early_print!(
                     "Open response received: len = {:?}, error = {:?}\n",
                     headerout.len,
                     headerout.error
                 );
-                early_print!("fh:{:?}\n", dataout.fh);
+                early_print!("fh:{:?}\n", fh);
                 early_print!("open_flags:{:?}\n", dataout.open_flags);
                 early_print!("backing_id:{:?}\n", dataout.backing_id);
             }
             FuseOpcode::FuseRead => {
-                let _datain = reader.read_val::<FuseReadIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let datain = fuse_read_val::<FuseReadIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 //The requested action is to read up to size bytes of the file or directory, starting at offset. The bytes should be returned directly following the usual reply header.
                 // let dataout = reader.read_val::<Vec<u8>>().unwrap();
                 early_print!(
@@ -2345,17 +2942,16 @@ impl FilesystemDevice {
                 // early_println!();
                 // if the file is not empty
                 if headerout.len > size_of::<FuseOutHeader>() as u32 {
-                    let data_len = headerout.len - size_of::<FuseOutHeader>() as u32;
-                    let mut dataout_buf = vec![0u8; data_len as usize];
-                    let mut writer = VmWriter::from(dataout_buf.as_mut_slice());
-                    writer.write(&mut reader);
-                    let data_utf8 = String::from_utf8(dataout_buf).unwrap();
+                    let mut dataout_buf = fuse_read_payload(&mut reader, headerout)?;
+                    dataout_buf.truncate(datain.size as usize);
+                    let data_utf8 = String::from_utf8_lossy(&dataout_buf);
                     early_print!("Read response received: data={:?}\n", data_utf8);
                 }
                 // early_print!("Read data: {:?}", dataout);
             }
             FuseOpcode::FuseFlush => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "Flush response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2364,8 +2960,8 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseReleasedir => {
-                let _datain = reader.read_val::<FuseReleaseIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let _datain = fuse_read_val::<FuseReleaseIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 // let dataout = reader.read_val::<FuseReleaseOut>().unwrap();
                 early_print!(
                     "Releasedir response received: len = {:?}, error = {:?}\n",
@@ -2376,9 +2972,10 @@ impl FilesystemDevice {
                 // early_print!("fh:{:?}\n", dataout.fh);
             }
             FuseOpcode::FuseGetattr => {
-                let _datain = reader.read_val::<FuseGetattrIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseAttrOut>().unwrap();
+                let _datain = fuse_read_val::<FuseGetattrIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseAttrOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
                     "Getattr response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2390,9 +2987,10 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseSetattr => {
-                let _datain = reader.read_val::<FuseSetattrIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseAttrOut>().unwrap();
+                let _datain = fuse_read_val::<FuseSetattrIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseAttrOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
                     "Setattr response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2404,9 +3002,10 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseLookup => {
-                let _name = reader.read_val::<FuseInHeader>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseEntryOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
                     "Lookup response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2423,8 +3022,8 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseRelease => {
-                let _datain = reader.read_val::<FuseReleaseIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let _datain = fuse_read_val::<FuseReleaseIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 // let dataout = reader.read_val::<FuseReleaseOut>().unwrap();
                 early_print!(
                     "Release response received: len = {:?}, error = {:?}\n",
@@ -2435,20 +3034,20 @@ impl FilesystemDevice {
                 // early_print!("fh:{:?}\n", dataout.fh);
             }
             FuseOpcode::FuseWrite => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "Write response received: len={:?}, error={:?}\n",
                     headerout.len,
                     headerout.error
                 );
-                if headerout.len > size_of::<FuseOutHeader>() as u32 {
-                    let writeout = reader.read_val::<FuseWriteOut>().unwrap();
+                if let Some(writeout) = fuse_read_out_body::<FuseWriteOut>(&mut reader, headerout) {
                     early_print!("Write response received: size={:?}\n", writeout.size);
                 }
             }
             FuseOpcode::FuseAccess => {
-                let _datain = reader.read_val::<FuseAccessIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let _datain = fuse_read_val::<FuseAccessIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 // let dataout = reader.read_val::<FuseAttrOut>().unwrap();
                 early_print!(
                     "Access response received: len = {:?}, error = {:?}\n",
@@ -2461,9 +3060,10 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseStatfs => {
-                let _datain = reader.read_val::<FuseInHeader>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseStatfsOut>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseStatfsOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
                     "Statfs response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2477,25 +3077,32 @@ impl FilesystemDevice {
                 early_print!("bsize:{:?}\n", dataout.st.bsize);
                 early_print!("namelen:{:?}\n", dataout.st.namelen);
                 early_print!("frsize:{:?}\n", dataout.st.frsize);
-                early_print!("padding:{:?}\n", dataout.st.padding);
-                early_print!("spare:{:?}\n", dataout.st.spare);
+                // `padding` and `spare` are reserved and carry nothing.
 
                 early_println!();
             }
             FuseOpcode::FuseInterrupt => {
-                let _datain = reader.read_val::<FuseInterruptIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let datain = fuse_read_val::<FuseInterruptIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "Interrupt response received: len = {:?}, error = {:?}\n",
                     headerout.len,
                     headerout.error
                 );
                 early_println!();
+                let action = self
+                    .interrupts
+                    .lock()
+                    .interrupt_replied(datain.unique, headerout.error);
+                if action == InterruptAction::Send {
+                    follow_up.resend_interrupt = Some(datain.unique);
+                }
             }
             FuseOpcode::FuseMkdir => {
-                let _datain = reader.read_val::<FuseMkdirIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseEntryOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
                     "Mkdir response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2511,9 +3118,10 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseCreate => {
-                let _datain = reader.read_val::<FuseCreateIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseEntryOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
                     "Create response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2529,7 +3137,7 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseDestroy => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "Destroy response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2538,44 +3146,28 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseRename => {
-                let _datain = reader.read_val::<FuseRenameIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "Rename response received: len = {:?}, error = {:?}\n",
                     headerout.len,
                     headerout.error
                 );
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
                 early_println!();
             }
             FuseOpcode::FuseRename2 => {
-                let _datain = reader.read_val::<FuseRename2In>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "Rename2 response received: len = {:?}, error = {:?}\n",
                     headerout.len,
                     headerout.error
                 );
-                early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
-                early_print!("attr:{:?}\n", dataout.attr);
                 early_println!();
             }
             FuseOpcode::FuseForget => {
-                let _datain = reader.read_val::<FuseForgetIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let _datain = fuse_read_val::<FuseForgetIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "Forget response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2584,8 +3176,8 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseBatchForget => {
-                let _datain = reader.read_val::<FuseBatchForgetIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+                let _datain = fuse_read_val::<FuseBatchForgetIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
                 early_print!(
                     "BatchForget response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2594,9 +3186,10 @@ impl FilesystemDevice {
                 early_println!();
             }
             FuseOpcode::FuseLink => {
-                let _datain = reader.read_val::<FuseLinkIn>().unwrap();
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseEntryOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
                     "Link response received: len = {:?}, error = {:?}\n",
                     headerout.len,
@@ -2611,31 +3204,420 @@ impl FilesystemDevice {
                 early_print!("attr:{:?}\n", dataout.attr);
                 early_println!();
             }
-            FuseOpcode::FuseUnlink => {
-                let headerout = reader.read_val::<FuseOutHeader>().unwrap();
-                let dataout = reader.read_val::<FuseEntryOut>().unwrap();
+            FuseOpcode::FuseGetxattr | FuseOpcode::FuseListxattr => {
+                let datain = fuse_read_val::<FuseGetxattrIn>(&mut reader)?;
+                // GETXATTR names the attribute after the `FuseGetxattrIn`.
+                let name_len = (headerin.len as usize)
+                    .checked_sub(size_of::<FuseInHeader>() + size_of::<FuseGetxattrIn>())
+                    .ok_or(FuseError::Io)?;
+                reader = reader.skip(name_len);
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let payload = fuse_read_payload(&mut reader, headerout)?;
+                early_print!(
+                    "{:?} response received: len = {:?}, error = {:?}\n",
+                    opcode,
+                    headerout.len,
+                    headerout.error
+                );
+                match fuse_xattr_reply(datain.size, &payload)? {
+                    XattrReply::Size(size) => early_print!("size:{:?}\n", size),
+                    XattrReply::Data(data) => {
+                        early_print!("data:{:?}\n", String::from_utf8_lossy(&data))
+                    }
+                }
+                early_println!();
+            }
+            FuseOpcode::FuseSetxattr
+            | FuseOpcode::FuseRemovexattr
+            | FuseOpcode::FuseSetupmapping
+            | FuseOpcode::FuseRemovemapping => {
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                early_print!(
+                    "{:?} response received: len = {:?}, error = {:?}\n",
+                    opcode,
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseGetlk => {
+                let datain = fuse_read_val::<FuseLkIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout =
+                    fuse_read_out_body::<FuseLkOut>(&mut reader, headerout).ok_or(FuseError::Io)?;
+                early_print!(
+                    "Getlk response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("owner:{:?}, lock:{:?}\n", datain.owner, dataout.lk);
+                early_println!();
+            }
+            FuseOpcode::FuseSetlk | FuseOpcode::FuseSetlkw => {
+                let datain = fuse_read_val::<FuseLkIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                early_print!(
+                    "{:?} response received: len = {:?}, error = {:?}\n",
+                    opcode,
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("owner:{:?}, lock:{:?}\n", datain.owner, datain.lk);
+                early_println!();
+            }
+            FuseOpcode::FuseLseek => {
+                let datain = fuse_read_val::<FuseLseekIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseLseekOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
+                early_print!(
+                    "Lseek response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!(
+                    "whence:{:?}, offset:{:?} -> {:?}\n",
+                    datain.whence,
+                    datain.offset,
+                    dataout.offset
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseFallocate => {
+                let datain = fuse_read_val::<FuseFallocateIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                early_print!(
+                    "Fallocate response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!(
+                    "offset:{:?}, length:{:?}, mode:{:#x}\n",
+                    datain.offset,
+                    datain.length,
+                    datain.mode
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseFsync | FuseOpcode::FuseFsyncdir => {
+                let _datain = fuse_read_val::<FuseFsyncIn>(&mut reader)?;
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                early_print!(
+                    "{:?} response received: len = {:?}, error = {:?}\n",
+                    opcode,
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
+            FuseOpcode::FuseMknod => {
+                // The name follows the `FuseMknodIn`, so skip the input by its length.
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseEntryOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
+                early_print!(
+                    "Mknod response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("nodeid:{:?}\n", dataout.nodeid);
+                early_print!("attr:{:?}\n", dataout.attr);
+                early_println!();
+            }
+            FuseOpcode::FuseReadlink => {
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                // The target is not NUL-terminated; the reply length bounds it.
+                let target = fuse_read_payload(&mut reader, headerout)?;
+                early_print!(
+                    "Readlink response received: len = {:?}, error = {:?}\n",
+                    headerout.len,
+                    headerout.error
+                );
+                early_print!("target:{:?}\n", String::from_utf8_lossy(&target));
+                early_println!();
+            }
+            FuseOpcode::FuseSymlink => {
+                // The input is the name and the target, each NUL-terminated.
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                let dataout = fuse_read_out_body::<FuseEntryOut>(&mut reader, headerout)
+                    .ok_or(FuseError::Io)?;
                 early_print!(
-                    "Unlink response received: len = {:?}, error = {:?}\n",
+                    "Symlink response received: len = {:?}, error = {:?}\n",
                     headerout.len,
                     headerout.error
                 );
                 early_print!("nodeid:{:?}\n", dataout.nodeid);
-                early_print!("generation:{:?}\n", dataout.generation);
-                early_print!("entry_valid:{:?}\n", dataout.entry_valid);
-                early_print!("attr_valid:{:?}\n", dataout.attr_valid);
-                early_print!("entry_valid_nsec:{:?}\n", dataout.entry_valid_nsec);
-                early_print!("attr_valid_nsec:{:?}\n", dataout.attr_valid_nsec);
                 early_print!("attr:{:?}\n", dataout.attr);
                 early_println!();
             }
+            FuseOpcode::FuseUnlink | FuseOpcode::FuseRmdir => {
+                reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+                let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+                early_print!(
+                    "{:?} response received: len = {:?}, error = {:?}\n",
+                    opcode,
+                    headerout.len,
+                    headerout.error
+                );
+                early_println!();
+            }
             _ => {}
         }
-        drop(request_queue);
-        test_device(&self);
+        Ok(follow_up)
+    }
+}
+
+// The trait is not imported, so that its methods do not clash with those of
+// `AnyFuseDevice` in this file.
+impl file::FileOps for FilesystemDevice {
+    fn lookup(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: &[u8],
+    ) -> Result<FuseEntryOut, FuseError> {
+        self.lookup_entry(ctx, parent, name.to_vec())
+    }
+
+    fn open(&self, ctx: &FuseContext, nodeid: u64, flags: u32) -> Result<u64, FuseError> {
+        let openin = FuseOpenIn {
+            flags,
+            open_flags: 0,
+        };
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(FuseOpcode::FuseOpen, nodeid)
+                .push_struct(&openin)
+                .reserve_reply(0)
+                .with_context(*ctx),
+        )?;
+        let open_out = fuse_reply_val::<FuseOpenOut>(&reply)?;
+        self.pages
+            .disable_irq()
+            .lock()
+            .open(nodeid, open_out.open_flags);
+        let fh = self.handles.disable_irq().lock().insert(
+            open_out.fh,
+            nodeid,
+            HandleKind::File,
+            flags,
+            open_out.open_flags,
+        );
+        Ok(fh)
+    }
+
+    fn create(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: &[u8],
+        mode: u32,
+        flags: u32,
+    ) -> Result<(u64, u64), FuseError> {
+        let request = fuse_checked_create_request(parent, name, mode, 0, flags, self.name_max())?;
+        let reply = self.submit_and_wait(request.with_context(*ctx))?;
+
+        let (_, payload) = fuse_parse_reply(&reply)?;
+        let mut reader = VmReader::from(payload);
+        let (Ok(entry), Ok(open_out)) = (
+            reader.read_val::<FuseEntryOut>(),
+            reader.read_val::<FuseOpenOut>(),
+        ) else {
+            return Err(FuseError::Io);
+        };
+        self.inodes
+            .disable_irq()
+            .lock()
+            .insert_entry(&entry, monotonic_now());
+        self.pages
+            .disable_irq()
+            .lock()
+            .open(entry.nodeid, open_out.open_flags);
+        let fh = self.handles.disable_irq().lock().insert(
+            open_out.fh,
+            entry.nodeid,
+            HandleKind::File,
+            flags,
+            open_out.open_flags,
+        );
+        Ok((entry.nodeid, fh))
+    }
+
+    fn read_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, FuseError> {
+        FilesystemDevice::read_at(self, ctx, nodeid, fh, offset, size)
+    }
+
+    fn write_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<WriteResult, FuseError> {
+        FilesystemDevice::write_at(self, ctx, nodeid, fh, offset, data)
+    }
+
+    fn release(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        flags: u32,
+    ) -> Result<(), FuseError> {
+        // An evicted handle is already released.
+        let Some(fh) = self.handles.disable_irq().lock().close(fh)? else {
+            return Ok(());
+        };
+        let releasein = FuseReleaseIn {
+            fh,
+            flags,
+            release_flags: 0,
+            lock_owner: 0,
+        };
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(FuseOpcode::FuseRelease, nodeid)
+                .push_struct(&releasein)
+                .reserve_reply(0)
+                .with_context(*ctx),
+        );
+        fuse_parse_reply(&reply?)?;
+        self.writeback_errors.disable_irq().lock().take(nodeid)
+    }
+
+    fn abandon(&self, nodeid: u64) {
+        self.inodes.disable_irq().lock().release_lookup(nodeid);
+    }
+}
+
+impl dir::DirOps for FilesystemDevice {
+    fn open_dir(&self, ctx: &FuseContext, nodeid: u64) -> Result<u64, FuseError> {
+        let openin = FuseOpenIn {
+            flags: file::O_RDONLY,
+            open_flags: 0,
+        };
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(FuseOpcode::FuseOpendir, nodeid)
+                .push_struct(&openin)
+                .reserve_reply(0)
+                .with_context(*ctx),
+        )?;
+        let open_out = fuse_reply_val::<FuseOpenOut>(&reply)?;
+        let fh = self.handles.disable_irq().lock().insert(
+            open_out.fh,
+            nodeid,
+            HandleKind::Dir,
+            openin.flags,
+            open_out.open_flags,
+        );
+        Ok(fh)
+    }
+
+    fn read_dir_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<FuseDirentWithName>, FuseError> {
+        let fh = self.server_fh(fh)?;
+        // `submit_and_wait` picks the queue, and grows its buffer up to this bound.
+        let size = size.min(fuse_read_capacity(FUSE_MAX_BUFFER_PAGES * PAGE_SIZE));
+        let reply = self.submit_and_wait(
+            fuse_read_request(FuseOpcode::FuseReaddir, nodeid, fh, offset, size).with_context(*ctx),
+        )?;
+        let (headerout, payload) = fuse_parse_reply(&reply)?;
+        let readdir_out = FuseReaddirOut::read_dirent(&mut VmReader::from(payload), headerout)?;
+        Ok(readdir_out.dirents)
+    }
+
+    fn release_dir(&self, ctx: &FuseContext, nodeid: u64, fh: u64) -> Result<(), FuseError> {
+        // An evicted handle is already released.
+        let Some(fh) = self.handles.disable_irq().lock().close(fh)? else {
+            return Ok(());
+        };
+        let releasein = FuseReleaseIn {
+            fh,
+            flags: file::O_RDONLY,
+            release_flags: 0,
+            lock_owner: 0,
+        };
+        let reply = self.submit_and_wait(
+            RequestBuilder::new(FuseOpcode::FuseReleasedir, nodeid)
+                .push_struct(&releasein)
+                .reserve_reply(0)
+                .with_context(*ctx),
+        );
+        fuse_parse_reply(&reply?)?;
+        Ok(())
+    }
+}
+
+impl vfs::InodeOps for FilesystemDevice {
+    fn getattr(&self, ctx: &FuseContext, nodeid: u64) -> Result<FuseAttr, FuseError> {
+        self.getattr_full(ctx, nodeid)
     }
 }
 
 static TEST_COUNTER: RwLock<u32> = RwLock::new(0);
+
+/// Returns the time since boot, which attribute timeouts are measured in.
+fn monotonic_now() -> Duration {
+    Jiffies::elapsed().as_duration()
+}
+
+/// Waits for `duration`, letting other tasks run meanwhile.
+fn sleep_for(duration: Duration) {
+    let deadline = deadline_after(duration);
+    while Jiffies::elapsed().as_u64() < deadline {
+        Task::yield_now();
+    }
+}
+
+/// Sets up the virtqueue `idx` with `depth` descriptors, or as many as the
+/// transport allows.
+fn new_queue(
+    transport: &mut dyn VirtioTransport,
+    idx: u16,
+    depth: u16,
+) -> Result<SpinLock<VirtQueue>, VirtioDeviceError> {
+    let mut max = transport
+        .max_queue_size(idx)
+        .map_err(|_| VirtioDeviceError::QueueUnknownError)?;
+    // Legacy queues are laid out for at most 128 descriptors.
+    if transport.is_legacy_version() {
+        max = max.min(128);
+    }
+    let size = fuse_queue_depth(depth, max).ok_or(VirtioDeviceError::QueueUnknownError)?;
+    VirtQueue::new(idx, size, transport)
+        .map(SpinLock::new)
+        .map_err(|_| VirtioDeviceError::QueueUnknownError)
+}
+
+/// Allocates a buffer of `pages` pages and maps it for the device.
+fn alloc_dma_stream(pages: usize, direction: DmaDirection) -> Result<DmaStream, VirtioDeviceError> {
+    let segment = FrameAllocOptions::new()
+        .alloc_segment(pages)
+        .map_err(|_| VirtioDeviceError::BufferAllocError)?;
+    DmaStream::map(segment.into(), direction, false)
+        .map_err(|_| VirtioDeviceError::BufferAllocError)
+}
+
+/// Returns the jiffies at which `timeout` from now has passed.
+fn deadline_after(timeout: Duration) -> u64 {
+    Jiffies::elapsed().as_u64() + timeout.as_millis() as u64 * TIMER_FREQ / 1000
+}
+
 pub fn test_device(device: &FilesystemDevice) {
     let mut test_counter = TEST_COUNTER.write();
     *test_counter += 1;
@@ -2669,7 +3651,7 @@ pub fn test_device(device: &FilesystemDevice) {
 
         // test create
         // 1 => device.lookup(1, "testdir".as_bytes().to_vec()),
-        // 2 => device.create(2, "test_create".as_bytes().to_vec(), 0o755, 0o777, 2),
+        // 2 => device.create(2, "test_create".as_bytes().to_vec(), 0o755, 0o777, 2, None),
 
         // test flush
         // 1 => device.lookup(1, "testf01".as_bytes().to_vec()),
@@ -2705,7 +3687,7 @@ pub fn test_device(device: &FilesystemDevice) {
         // 1 => device.interrupt(0),
 
         // test mkdir
-        // 1 => device.mkdir(1, 0o755, 0o777, "test_mkdir".as_bytes().to_vec()),
+        // 1 => device.mkdir(1, 0o755, 0o777, "test_mkdir".as_bytes().to_vec(), None),
 
         // test destroy
         // 1 => device.destroy(),
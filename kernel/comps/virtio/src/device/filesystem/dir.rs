@@ -0,0 +1,236 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+use log::debug;
+
+use super::{
+    error::Result,
+    request::{FuseContext, FuseDirentWithName},
+};
+
+/// The number of bytes of entries asked for by each READDIR of a stream.
+pub const FUSE_READDIR_SIZE: u32 = 4096;
+
+/// The synchronous operations directory listing is built from.
+///
+/// Each request is sent with the credentials of `ctx`, the caller's.
+pub trait DirOps {
+    /// Opens the directory `nodeid` and returns the directory handle.
+    fn open_dir(&self, ctx: &FuseContext, nodeid: u64) -> Result<u64>;
+    /// Reads the entries of `fh` after the one at `offset`, returning none at
+    /// the end of the directory.
+    fn read_dir_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<FuseDirentWithName>>;
+    fn release_dir(&self, ctx: &FuseContext, nodeid: u64, fh: u64) -> Result<()>;
+}
+
+/// The entries of a directory, read with as many READDIRs as it takes.
+///
+/// A stream that opened the directory itself releases the handle when it is
+/// dropped, whether or not it was read to the end.
+pub struct ReaddirStream<'a, O: DirOps> {
+    ops: &'a O,
+    /// The credentials of the caller, which every request is sent with.
+    ctx: FuseContext,
+    nodeid: u64,
+    fh: u64,
+    /// Whether the handle was opened by the stream rather than its caller.
+    owned: bool,
+    offset: u64,
+    entries: VecDeque<FuseDirentWithName>,
+    done: bool,
+}
+
+impl<'a, O: DirOps> ReaddirStream<'a, O> {
+    /// Opens the directory `nodeid` and lists it from the start.
+    pub fn open(ops: &'a O, ctx: &FuseContext, nodeid: u64) -> Result<Self> {
+        let fh = ops.open_dir(ctx, nodeid)?;
+        Ok(Self::new(ops, ctx, nodeid, fh, true))
+    }
+
+    /// Lists the directory `nodeid` through `fh`, which the caller keeps
+    /// open and releases.
+    pub fn with_handle(ops: &'a O, ctx: &FuseContext, nodeid: u64, fh: u64) -> Self {
+        Self::new(ops, ctx, nodeid, fh, false)
+    }
+
+    fn new(ops: &'a O, ctx: &FuseContext, nodeid: u64, fh: u64, owned: bool) -> Self {
+        Self {
+            ops,
+            ctx: *ctx,
+            nodeid,
+            fh,
+            owned,
+            offset: 0,
+            entries: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<O: DirOps> Iterator for ReaddirStream<'_, O> {
+    type Item = Result<FuseDirentWithName>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.entries.is_empty() && !self.done {
+            match self.ops.read_dir_at(
+                &self.ctx,
+                self.nodeid,
+                self.fh,
+                self.offset,
+                FUSE_READDIR_SIZE,
+            ) {
+                Ok(entries) => {
+                    self.done = entries.is_empty();
+                    self.entries.extend(entries);
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        let entry = self.entries.pop_front()?;
+        self.offset = entry.dirent.off;
+        Some(Ok(entry))
+    }
+}
+
+/// Reads all the entries of the directory `nodeid` through `fh`, which the
+/// caller keeps open, from the start.
+///
+/// With `dedup_dots` set, `.` and `..` are kept once each, even if the server
+/// lists them again in a later batch.
+pub fn fuse_read_full_dir<O: DirOps>(
+    ops: &O,
+    ctx: &FuseContext,
+    nodeid: u64,
+    fh: u64,
+    dedup_dots: bool,
+) -> Result<Vec<FuseDirentWithName>> {
+    let mut seen_dots = [false; 2];
+    let mut entries = Vec::new();
+    for entry in ReaddirStream::with_handle(ops, ctx, nodeid, fh) {
+        let entry = entry?;
+        let dot = match entry.name.as_slice() {
+            b"." => Some(0),
+            b".." => Some(1),
+            _ => None,
+        };
+        if let Some(dot) = dot.filter(|_| dedup_dots) {
+            if core::mem::replace(&mut seen_dots[dot], true) {
+                continue;
+            }
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+impl<O: DirOps> Drop for ReaddirStream<'_, O> {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        // The device may be gone already; there is nobody to report to.
+        if let Err(err) = self.ops.release_dir(&self.ctx, self.nodeid, self.fh) {
+            debug!("Directory handle {} not released: {}", self.fh, err);
+        }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::filesystem::{
+        fuse::FUSE_ROOT_ID,
+        mock::{mock_fh, MockServer},
+    };
+
+    /// Fills the root of `server` with five files, `b` to `f`, which READDIR
+    /// returns two at a time.
+    fn with_five_files(server: MockServer) -> MockServer {
+        for name in [b"b", b"c", b"d", b"e", b"f"] {
+            server.add_file(name, &[]);
+        }
+        server
+    }
+
+    #[ktest]
+    fn stream_lists_whole_directory() {
+        let server = with_five_files(MockServer::default());
+        let inos = ReaddirStream::open(&server, &FuseContext::default(), FUSE_ROOT_ID)
+            .unwrap()
+            .map(|entry| entry.unwrap().dirent.ino)
+            .collect::<Vec<_>>();
+
+        assert_eq!(inos, [2, 3, 4, 5, 6]);
+        assert_eq!(*server.dir_reads.borrow(), 4);
+        assert_eq!(*server.released_dirs.borrow(), [mock_fh(FUSE_ROOT_ID)]);
+    }
+
+    #[ktest]
+    fn full_dir_is_read_across_batches() {
+        let server = with_five_files(MockServer {
+            repeats_dot: true,
+            ..Default::default()
+        });
+        let fh = mock_fh(FUSE_ROOT_ID);
+        let names = |dedup_dots| {
+            fuse_read_full_dir(
+                &server,
+                &FuseContext::default(),
+                FUSE_ROOT_ID,
+                fh,
+                dedup_dots,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(true),
+            [&b"."[..], b"b", b"c", b"d", b"e", b"f"].map(<[u8]>::to_vec)
+        );
+        assert_eq!(names(false).iter().filter(|name| *name == b".").count(), 3);
+        // The handle is the caller's to release.
+        assert!(server.released_dirs.borrow().is_empty());
+    }
+
+    #[ktest]
+    fn dropped_stream_releases_its_handle() {
+        let server = with_five_files(MockServer::default());
+        let ctx = FuseContext::default();
+        let mut stream = ReaddirStream::open(&server, &ctx, FUSE_ROOT_ID).unwrap();
+        assert_eq!(stream.next().unwrap().unwrap().name, b"b");
+        assert!(server.released_dirs.borrow().is_empty());
+
+        drop(stream);
+        assert_eq!(*server.released_dirs.borrow(), [mock_fh(FUSE_ROOT_ID)]);
+
+        // A handle of the caller stays open.
+        let mut stream = ReaddirStream::with_handle(&server, &ctx, FUSE_ROOT_ID, 103);
+        assert!(stream.next().is_some());
+        drop(stream);
+        assert_eq!(*server.released_dirs.borrow(), [mock_fh(FUSE_ROOT_ID)]);
+
+        // Neither does a device that went away fail the drop.
+        let server = with_five_files(MockServer {
+            gone: true,
+            ..Default::default()
+        });
+        drop(ReaddirStream::open(&server, &ctx, FUSE_ROOT_ID).unwrap());
+        assert!(server.released_dirs.borrow().is_empty());
+    }
+}
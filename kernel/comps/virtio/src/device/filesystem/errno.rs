@@ -0,0 +1,23 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! The errnos of the driver's errors and of the servers' replies, as Linux
+//! numbers them.
+
+pub const ENOENT: i32 = 2;
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
+pub const EAGAIN: i32 = 11;
+pub const EACCES: i32 = 13;
+pub const EBUSY: i32 = 16;
+pub const EEXIST: i32 = 17;
+pub const ENODEV: i32 = 19;
+pub const ENOTDIR: i32 = 20;
+pub const EINVAL: i32 = 22;
+pub const ENOSPC: i32 = 28;
+pub const ENAMETOOLONG: i32 = 36;
+pub const ENOSYS: i32 = 38;
+pub const ETIMEDOUT: i32 = 110;
+pub const ESTALE: i32 = 116;
+
+/// The largest errno; a reply's `error` beyond it is no errno at all.
+pub const MAX_ERRNO: i32 = 4095;
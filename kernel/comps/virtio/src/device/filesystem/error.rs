@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::{fmt, result};
+
+use super::errno::*;
+use crate::queue::QueueError;
+
+/// The error type of the virtio-fs driver.
+///
+/// Most variants mirror the errno a FUSE operation would fail with on Linux.
+#[derive(Debug)]
+pub enum FuseError {
+    /// Invalid argument.
+    Inval,
+    /// The file handle is not open, or does not belong to the node.
+    BadF,
+    /// No such file or directory.
+    NoEnt,
+    /// The name exists already.
+    Exist,
+    /// A component used as a directory is not a directory.
+    NotDir,
+    /// A name or path is too long.
+    NameTooLong,
+    /// The server sent a malformed reply.
+    Io,
+    /// The request was abandoned before it was sent.
+    TimedOut,
+    /// The server's protocol version predates the operation.
+    Unsupported,
+    /// The queue has no free descriptors; the request may be retried later.
+    Busy,
+    /// The device has been shut down.
+    NoDev,
+    /// The server failed the request with the given errno, one that no other
+    /// variant stands for; see [`FuseError::from_errno`].
+    ServerError(i32),
+    /// A request buffer could not be accessed.
+    Dma(ostd::Error),
+    /// Queue error.
+    QueueError(QueueError),
+}
+
+impl FuseError {
+    /// Returns the error a server means by failing a request with `errno`.
+    ///
+    /// An errno with a variant of its own is turned into it, so that, e.g., a
+    /// missing name is always [`FuseError::NoEnt`], whether the driver or the
+    /// server found it missing. The other errnos are kept as they are.
+    pub fn from_errno(errno: i32) -> Self {
+        match errno {
+            EINVAL => Self::Inval,
+            EBADF => Self::BadF,
+            ENOENT => Self::NoEnt,
+            EEXIST => Self::Exist,
+            ENOTDIR => Self::NotDir,
+            ENAMETOOLONG => Self::NameTooLong,
+            errno => Self::ServerError(errno),
+        }
+    }
+
+    /// Returns the errno the VFS reports the error as.
+    pub fn errno(&self) -> i32 {
+        match self {
+            Self::Inval => EINVAL,
+            Self::BadF => EBADF,
+            Self::NoEnt => ENOENT,
+            Self::Exist => EEXIST,
+            Self::NotDir => ENOTDIR,
+            Self::NameTooLong => ENAMETOOLONG,
+            Self::TimedOut => ETIMEDOUT,
+            Self::Unsupported => ENOSYS,
+            Self::Busy => EBUSY,
+            Self::NoDev => ENODEV,
+            Self::ServerError(errno) => *errno,
+            Self::Io | Self::Dma(_) | Self::QueueError(_) => EIO,
+        }
+    }
+}
+
+/// Turns the `error` of a reply header into the error it stands for.
+///
+/// The server sends the negated errno; a positive value or one beyond the
+/// errno range is no errno at all, and is taken as a malformed reply.
+pub fn errno_to_fuse_error(error: i32) -> FuseError {
+    match error.checked_neg() {
+        Some(errno @ 1..=MAX_ERRNO) => FuseError::from_errno(errno),
+        _ => FuseError::Io,
+    }
+}
+
+impl From<ostd::Error> for FuseError {
+    fn from(value: ostd::Error) -> Self {
+        Self::Dma(value)
+    }
+}
+
+impl From<QueueError> for FuseError {
+    fn from(value: QueueError) -> Self {
+        match value {
+            QueueError::BufferTooSmall => Self::Busy,
+            value => Self::QueueError(value),
+        }
+    }
+}
+
+impl fmt::Display for FuseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Inval => write!(f, "Invalid argument"),
+            Self::BadF => write!(f, "Bad file handle"),
+            Self::NoEnt => write!(f, "No such file or directory"),
+            Self::Exist => write!(f, "File exists"),
+            Self::NotDir => write!(f, "Not a directory"),
+            Self::NameTooLong => write!(f, "File name too long"),
+            Self::Io => write!(f, "Malformed reply from the server"),
+            Self::TimedOut => write!(f, "Request timed out"),
+            Self::Unsupported => write!(f, "Operation not supported by the server"),
+            Self::Busy => write!(f, "No free descriptors in the queue"),
+            Self::NoDev => write!(f, "The device has been shut down"),
+            Self::ServerError(errno) => {
+                write!(f, "The server failed the request with errno {errno}")
+            }
+            Self::Dma(err) => write!(f, "Request buffer not accessible: {:?}", err),
+            Self::QueueError(_) => write!(f, "Error encountered out of virtio-fs itself!"),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, FuseError>;
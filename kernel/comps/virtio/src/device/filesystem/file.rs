@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::vec::Vec;
+
+use super::{
+    error::{FuseError, Result},
+    fuse::FuseEntryOut,
+    request::{fuse_write_all, FuseContext, WriteResult, FUSE_MAX_READ_SIZE},
+};
+
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_NOATIME: u32 = 0o1000000;
+
+/// The synchronous operations one-shot file access is built from.
+///
+/// Each request is sent with the credentials of `ctx`, the caller's.
+pub trait FileOps {
+    fn lookup(&self, ctx: &FuseContext, parent: u64, name: &[u8]) -> Result<FuseEntryOut>;
+    /// Opens `nodeid` and returns the file handle.
+    fn open(&self, ctx: &FuseContext, nodeid: u64, flags: u32) -> Result<u64>;
+    /// Creates and opens `name`, returning its node ID and file handle.
+    fn create(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: &[u8],
+        mode: u32,
+        flags: u32,
+    ) -> Result<(u64, u64)>;
+    fn read_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>>;
+    fn write_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<WriteResult>;
+    fn release(&self, ctx: &FuseContext, nodeid: u64, fh: u64, flags: u32) -> Result<()>;
+    /// Gives back the one lookup of a node found or created above, which is
+    /// forgotten once no other lookup of it remains.
+    fn abandon(&self, nodeid: u64);
+}
+
+/// Reads from `offset` on with `read` until it returns no data.
+pub fn fuse_read_all<F>(mut offset: u64, mut read: F) -> Result<Vec<u8>>
+where
+    F: FnMut(u64) -> Result<Vec<u8>>,
+{
+    let mut data = Vec::new();
+    loop {
+        let chunk = read(offset)?;
+        if chunk.is_empty() {
+            return Ok(data);
+        }
+        offset += chunk.len() as u64;
+        data.extend_from_slice(&chunk);
+    }
+}
+
+/// Reads up to `size` bytes from `offset` on with `read`, which is asked for
+/// at most [`FUSE_MAX_READ_SIZE`] bytes at a time.
+///
+/// The chunks are read at increasing offsets until `size` bytes are in or a
+/// chunk comes back empty at the end of the file.
+pub fn fuse_read_range<F>(offset: u64, size: usize, mut read: F) -> Result<Vec<u8>>
+where
+    F: FnMut(u64, u32) -> Result<Vec<u8>>,
+{
+    let end = offset.saturating_add(size as u64);
+    fuse_read_all(offset, |offset| {
+        let size = (end - offset).min(FUSE_MAX_READ_SIZE as u64) as u32;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        read(offset, size)
+    })
+}
+
+/// Reads the whole file `name` in the directory `parent`.
+pub fn read_file(
+    ops: &impl FileOps,
+    ctx: &FuseContext,
+    parent: u64,
+    name: &[u8],
+) -> Result<Vec<u8>> {
+    let nodeid = ops.lookup(ctx, parent, name)?.nodeid;
+    let data = ops.open(ctx, nodeid, O_RDONLY).and_then(|fh| {
+        let data = fuse_read_all(0, |offset| {
+            ops.read_at(ctx, nodeid, fh, offset, FUSE_MAX_READ_SIZE)
+        });
+        let released = ops.release(ctx, nodeid, fh, O_RDONLY);
+        let data = data?;
+        released?;
+        Ok(data)
+    });
+    ops.abandon(nodeid);
+    data
+}
+
+/// Replaces the contents of the file `name` in the directory `parent` with
+/// `data`, creating it with `mode` if it does not exist.
+pub fn write_file(
+    ops: &impl FileOps,
+    ctx: &FuseContext,
+    parent: u64,
+    name: &[u8],
+    data: &[u8],
+    mode: u32,
+) -> Result<()> {
+    let flags = O_WRONLY | O_TRUNC;
+    let (nodeid, fh) = match ops.lookup(ctx, parent, name) {
+        Ok(entry) => match ops.open(ctx, entry.nodeid, flags) {
+            Ok(fh) => (entry.nodeid, fh),
+            Err(err) => {
+                ops.abandon(entry.nodeid);
+                return Err(err);
+            }
+        },
+        Err(FuseError::NoEnt) => ops.create(ctx, parent, name, mode, flags)?,
+        Err(err) => return Err(err),
+    };
+
+    let written = fuse_write_all(0, data, |offset, data| {
+        ops.write_at(ctx, nodeid, fh, offset, data)
+    });
+    let released = ops.release(ctx, nodeid, fh, flags);
+    ops.abandon(nodeid);
+    written?;
+    released
+}
+
+#[cfg(ktest)]
+mod test {
+    use core::cell::RefCell;
+
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::filesystem::{
+        errno::{EACCES, ENOSPC},
+        mock::MockServer,
+    };
+
+    const CTX: FuseContext = FuseContext {
+        uid: 1000,
+        gid: 1000,
+        pid: 1,
+    };
+
+    #[ktest]
+    fn file_contents_round_trip() {
+        let server = MockServer::default();
+        let contents = b"hello, virtio-fs world";
+
+        write_file(&server, &CTX, 1, b"greeting", contents, 0o644).unwrap();
+        assert_eq!(read_file(&server, &CTX, 1, b"greeting").unwrap(), contents);
+
+        // Writing again truncates rather than appends.
+        write_file(&server, &CTX, 1, b"greeting", b"bye", 0o644).unwrap();
+        assert_eq!(read_file(&server, &CTX, 1, b"greeting").unwrap(), b"bye");
+        assert!(server.open.borrow().is_empty());
+        // One CREATE and three LOOKUPs of node 2.
+        assert_eq!(server.inodes.borrow_mut().forget_pending(), [(2, 4)]);
+
+        assert!(matches!(
+            read_file(&server, &CTX, 1, b"missing"),
+            Err(FuseError::NoEnt)
+        ));
+    }
+
+    #[ktest]
+    fn large_read_is_split_into_chunks() {
+        let file: Vec<u8> = (0..3 * FUSE_MAX_READ_SIZE).map(|i| i as u8).collect();
+        let sizes = RefCell::new(Vec::new());
+        let read = |offset: u64, size: u32| {
+            sizes.borrow_mut().push(size);
+            let start = (offset as usize).min(file.len());
+            let end = (start + size as usize).min(file.len());
+            Ok(file[start..end].to_vec())
+        };
+
+        let size = 2 * FUSE_MAX_READ_SIZE as usize + 100;
+        let data = fuse_read_range(10, size, read).unwrap();
+        assert_eq!(data, file[10..10 + size]);
+        assert_eq!(
+            *sizes.borrow(),
+            [FUSE_MAX_READ_SIZE, FUSE_MAX_READ_SIZE, 100]
+        );
+
+        // The end of the file stops the read short.
+        sizes.borrow_mut().clear();
+        let offset = file.len() as u64 - 5;
+        assert_eq!(fuse_read_range(offset, size, read).unwrap().len(), 5);
+        assert_eq!(*sizes.borrow(), [FUSE_MAX_READ_SIZE, FUSE_MAX_READ_SIZE]);
+    }
+
+    #[ktest]
+    fn failed_open_forgets_lookup() {
+        let server = MockServer {
+            fail_opens: true,
+            ..Default::default()
+        };
+        assert_eq!(server.add_file(b"secret", b"data"), 2);
+
+        assert!(matches!(
+            read_file(&server, &CTX, 1, b"secret"),
+            Err(FuseError::ServerError(EACCES))
+        ));
+        assert!(matches!(
+            write_file(&server, &CTX, 1, b"secret", b"new", 0o644),
+            Err(FuseError::ServerError(EACCES))
+        ));
+        assert_eq!(server.inodes.borrow_mut().forget_pending(), [(2, 2)]);
+    }
+
+    #[ktest]
+    fn failed_write_still_releases() {
+        let server = MockServer {
+            fail_writes: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            write_file(&server, &CTX, 1, b"full", b"data", 0o644),
+            Err(FuseError::ServerError(ENOSPC))
+        ));
+        assert!(server.open.borrow().is_empty());
+    }
+}
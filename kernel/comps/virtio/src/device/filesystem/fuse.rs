@@ -229,6 +229,14 @@ use core::convert::TryFrom;
 
 use ostd::Pod;
 
+// FUSE messages are little-endian on the wire, but the structures here are
+// read and written in native byte order through `Pod`. Supporting big-endian
+// targets means converting each multi-byte field where messages are encoded
+// (`RequestBuilder::to_bytes`) and decoded (`fuse_reply_val` and the reply
+// readers), e.g. by wrapping the fields in little-endian integer types.
+#[cfg(target_endian = "big")]
+compile_error!("virtio-fs supports only little-endian targets: FUSE fields are not byte-swapped");
+
 /** Version number of this interface */
 pub const FUSE_KERNEL_VERSION: u32 = 7;
 /** Minor version number of this interface */
@@ -462,6 +470,12 @@ pub const FUSE_HAS_RESEND: u64 = 1u64 << 39;
 bitflags::bitflags! {
     pub struct FuseInitFlags: u64 {
         const FUSE_INIT_EXT = FUSE_INIT_EXT;
+        const FUSE_HAS_IOCTL_DIR = FUSE_HAS_IOCTL_DIR;
+        const FUSE_CREATE_SUPP_GROUP = FUSE_CREATE_SUPP_GROUP;
+        const FUSE_PASSTHROUGH = FUSE_PASSTHROUGH;
+        const FUSE_SETXATTR_EXT = FUSE_SETXATTR_EXT;
+        const FUSE_MAP_ALIGNMENT = FUSE_MAP_ALIGNMENT;
+        const FUSE_WRITEBACK_CACHE = FUSE_WRITEBACK_CACHE;
     }
 }
 
@@ -833,6 +847,17 @@ pub struct FuseRename2In {
     pub padding: u32,
 }
 
+/**
+ * Rename2 flags, as defined for renameat2()
+ *
+ * RENAME_NOREPLACE: fail if the target name exists
+ * RENAME_EXCHANGE: atomically swap the source and the target
+ * RENAME_WHITEOUT: leave a whiteout in place of the source
+ */
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+pub const RENAME_EXCHANGE: u32 = 1 << 1;
+pub const RENAME_WHITEOUT: u32 = 1 << 2;
+
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod)]
 pub struct FuseLinkIn {
@@ -943,6 +968,10 @@ pub struct FuseStatfsOut {
     pub st: FuseKstatfs,
 }
 
+// Every field after a mismatch with the kernel's 80-byte `fuse_kstatfs` would
+// be read from the wrong offset.
+const _: () = assert!(size_of::<FuseStatfsOut>() == 80);
+
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod)]
 pub struct FuseFsyncIn {
@@ -962,6 +991,9 @@ pub struct FuseSetxattrIn {
     pub padding: u32,
 }
 
+/// The size of `FuseSetxattrIn` before `FUSE_SETXATTR_EXT` extended it.
+pub const FUSE_COMPAT_SETXATTR_IN_SIZE: usize = 8;
+
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod)]
 pub struct FuseGetxattrIn {
@@ -1062,6 +1094,13 @@ pub struct FuseInterruptIn {
     pub unique: u64,
 }
 
+/**
+ * Request unique ID bit of interrupt requests
+ *
+ * The INTERRUPT of a request carries the request's unique with this bit set.
+ */
+pub const FUSE_INT_REQ_BIT: u64 = 1 << 0;
+
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod)]
 pub struct FuseBmapIn {
@@ -1135,6 +1174,17 @@ pub struct FuseFallocateIn {
     pub padding: u32,
 }
 
+/**
+ * Fallocate modes, as defined for fallocate()
+ *
+ * FALLOC_FL_KEEP_SIZE: do not change the file size
+ * FALLOC_FL_PUNCH_HOLE: deallocate the range, reading back as zeros
+ * FALLOC_FL_ZERO_RANGE: zero the range, keeping it allocated
+ */
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+
 /**
  * FUSE request unique ID flag
  *
@@ -1190,6 +1240,10 @@ pub struct FuseDirent {
     pub name: [u8; 0],
 }
 
+// The name follows the kernel's 24-byte `fuse_dirent` header; any padding here
+// would shift it and every entry after it.
+const _: () = assert!(size_of::<FuseDirent>() == 24);
+
 /* Align variable length records to 64bit boundary */
 pub const fn fuse_rec_align(x: usize) -> usize {
     (x + core::mem::size_of::<u64>() - 1) & !(core::mem::size_of::<u64>() - 1)
@@ -1300,6 +1354,18 @@ pub struct FuseLseekOut {
     pub offset: u64,
 }
 
+/**
+ * Lseek whence values, as defined for lseek()
+ *
+ * SEEK_DATA: the next offset at or after the given one holding data
+ * SEEK_HOLE: the next offset at or after the given one in a hole
+ */
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+pub const SEEK_DATA: u32 = 3;
+pub const SEEK_HOLE: u32 = 4;
+
 #[repr(C)]
 #[derive(Default, Debug, Clone, Copy, Pod)]
 pub struct FuseCopyFileRangeIn {
@@ -0,0 +1,556 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::{
+    errno::ESTALE,
+    error::{FuseError, Result},
+    file::O_NOATIME,
+    fuse::{FuseInitFlags, FuseOpcode, FuseOpenOut, FOPEN_PASSTHROUGH, FUSE_IOCTL_DIR},
+};
+
+/// The kind of node a FUSE file handle was opened on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleKind {
+    /// Opened with `FUSE_OPEN` or `FUSE_CREATE`.
+    File,
+    /// Opened with `FUSE_OPENDIR`.
+    Dir,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HandleEntry {
+    pub nodeid: u64,
+    pub kind: HandleKind,
+    /// The `flags` the handle was opened with, to open it again.
+    pub flags: u32,
+    /// The `open_flags` of the OPEN or OPENDIR reply.
+    pub open_flags: u32,
+    /// The backing file registered for passthrough I/O, if any.
+    pub backing_id: Option<i32>,
+    /// The handle the server knows this one as, or `None` while evicted.
+    pub server_fh: Option<u64>,
+    last_used: u64,
+    pinned: u32,
+}
+
+impl HandleEntry {
+    /// Returns the opcode that opens a handle of this kind.
+    pub fn open_opcode(&self) -> FuseOpcode {
+        match self.kind {
+            HandleKind::File => FuseOpcode::FuseOpen,
+            HandleKind::Dir => FuseOpcode::FuseOpendir,
+        }
+    }
+
+    /// Returns the opcode that releases a handle of this kind.
+    pub fn release_opcode(&self) -> FuseOpcode {
+        match self.kind {
+            HandleKind::File => FuseOpcode::FuseRelease,
+            HandleKind::Dir => FuseOpcode::FuseReleasedir,
+        }
+    }
+
+    /// Returns the opcode that syncs a handle of this kind.
+    pub fn fsync_opcode(&self) -> FuseOpcode {
+        match self.kind {
+            HandleKind::File => FuseOpcode::FuseFsync,
+            HandleKind::Dir => FuseOpcode::FuseFsyncdir,
+        }
+    }
+}
+
+/// The file handles opened on the server and not yet closed by the caller.
+///
+/// Callers know a handle by a local ID the table hands out, which maps to the
+/// handle the server knows it as. IDs are never reused, so an ID the caller
+/// still holds never comes to name another open file.
+///
+/// With a limit set, the least recently used handles beyond it are evicted:
+/// the caller releases them on the server, and the next use of one opens it
+/// again under a new server handle, behind the same local ID.
+#[derive(Debug)]
+pub struct HandleTable {
+    /// Open and evicted handles, by local ID.
+    entries: BTreeMap<u64, HandleEntry>,
+    limit: Option<usize>,
+    clock: u64,
+    next_id: u64,
+}
+
+impl Default for HandleTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandleTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            limit: None,
+            clock: 0,
+            next_id: 1,
+        }
+    }
+
+    /// Caps the number of open handles, or lifts the cap with `None`.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Records the server handle `server_fh` just opened, and returns the
+    /// local ID to hand to the caller.
+    pub fn insert(
+        &mut self,
+        server_fh: u64,
+        nodeid: u64,
+        kind: HandleKind,
+        flags: u32,
+        open_flags: u32,
+    ) -> u64 {
+        let fh = self.next_id;
+        self.next_id += 1;
+        self.clock += 1;
+        self.entries.insert(
+            fh,
+            HandleEntry {
+                nodeid,
+                kind,
+                flags,
+                open_flags,
+                backing_id: None,
+                server_fh: Some(server_fh),
+                last_used: self.clock,
+                pinned: 0,
+            },
+        );
+        fh
+    }
+
+    /// Forgets the handle `fh` as the caller closes it.
+    ///
+    /// Returns the server handle to release, or `None` if `fh` was evicted
+    /// and so is already released.
+    pub fn close(&mut self, fh: u64) -> Result<Option<u64>> {
+        let entry = self.entries.remove(&fh).ok_or(FuseError::BadF)?;
+        Ok(entry.server_fh)
+    }
+
+    /// Records the backing file the server opened `fh` with for passthrough.
+    pub fn set_backing_id(&mut self, fh: u64, backing_id: i32) {
+        if let Some(entry) = self.entries.get_mut(&fh) {
+            entry.backing_id = Some(backing_id);
+        }
+    }
+
+    /// Returns the passthrough backing file of `fh`, if it has one.
+    pub fn backing_id(&self, fh: u64) -> Option<i32> {
+        self.entries.get(&fh)?.backing_id
+    }
+
+    /// Returns whether reads through `fh` leave the atime alone, because it
+    /// was opened with `O_NOATIME`.
+    pub fn is_noatime(&self, fh: u64) -> bool {
+        self.entries
+            .get(&fh)
+            .is_some_and(|entry| entry.flags & O_NOATIME != 0)
+    }
+
+    /// Returns the server handle for `fh` and marks it used.
+    ///
+    /// Returns `None` if `fh` is evicted or unknown.
+    pub fn use_handle(&mut self, fh: u64) -> Option<u64> {
+        let entry = self.entries.get_mut(&fh)?;
+        let server_fh = entry.server_fh?;
+        self.clock += 1;
+        entry.last_used = self.clock;
+        Some(server_fh)
+    }
+
+    /// Exempts `fh` from eviction until a matching [`Self::unpin`].
+    pub fn pin(&mut self, fh: u64) -> Result<()> {
+        let entry = self.entries.get_mut(&fh).ok_or(FuseError::BadF)?;
+        entry.pinned += 1;
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, fh: u64) {
+        if let Some(entry) = self.entries.get_mut(&fh) {
+            entry.pinned = entry.pinned.saturating_sub(1);
+        }
+    }
+
+    /// Evicts the least recently used unpinned handles beyond the limit.
+    ///
+    /// Returns the server handles to flush and release.
+    pub fn evict(&mut self) -> Vec<(u64, HandleEntry)> {
+        let mut released = Vec::new();
+        let Some(limit) = self.limit else {
+            return released;
+        };
+        let mut open = self
+            .entries
+            .values()
+            .filter(|entry| entry.server_fh.is_some())
+            .count();
+        while open > limit {
+            let Some((&fh, _)) = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.server_fh.is_some() && entry.pinned == 0)
+                .min_by_key(|(_, entry)| entry.last_used)
+            else {
+                break;
+            };
+            released.push(self.invalidate(fh).unwrap());
+            open -= 1;
+        }
+        released
+    }
+
+    /// Marks the handle `fh` stale, e.g. after the server invalidated its
+    /// node.
+    ///
+    /// The handle is set aside like an evicted one, so that its next use opens
+    /// it again. Returns the server handle to release, and its entry.
+    pub fn invalidate(&mut self, fh: u64) -> Option<(u64, HandleEntry)> {
+        let entry = self.entries.get_mut(&fh)?;
+        let server_fh = entry.server_fh.take()?;
+        Some((server_fh, *entry))
+    }
+
+    /// Returns the entry of `fh` if it is evicted, so that it can be reopened.
+    pub fn evicted(&self, fh: u64) -> Option<HandleEntry> {
+        self.entries
+            .get(&fh)
+            .filter(|entry| entry.server_fh.is_none())
+            .copied()
+    }
+
+    /// Records that the evicted `fh` is open again as `server_fh`.
+    ///
+    /// Returns `false` if `fh` was reopened by someone else or closed in the
+    /// meantime, in which case `server_fh` is the caller's to release.
+    pub fn reopened(&mut self, fh: u64, server_fh: u64, open_flags: u32) -> bool {
+        let Some(entry) = self
+            .entries
+            .get_mut(&fh)
+            .filter(|entry| entry.server_fh.is_none())
+        else {
+            return false;
+        };
+        self.clock += 1;
+        entry.server_fh = Some(server_fh);
+        entry.open_flags = open_flags;
+        entry.last_used = self.clock;
+        true
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&HandleEntry> {
+        self.entries.get(&fh)
+    }
+
+    /// Returns the handle `fh`, open or evicted, if it is one of `nodeid`.
+    fn open_entry(&self, nodeid: u64, fh: u64) -> Result<&HandleEntry> {
+        self.get(fh)
+            .filter(|entry| entry.nodeid == nodeid)
+            .ok_or(FuseError::BadF)
+    }
+
+    /// Returns the `FuseIoctlIn::flags` to send for an ioctl on `fh`.
+    ///
+    /// `FUSE_IOCTL_DIR` is set exactly when `fh` is a directory handle; a caller
+    /// asking for it on a regular file handle is rejected.
+    pub fn ioctl_flags(&self, nodeid: u64, fh: u64, flags: u32) -> Result<u32> {
+        let entry = self.open_entry(nodeid, fh)?;
+        match entry.kind {
+            HandleKind::Dir => Ok(flags | FUSE_IOCTL_DIR),
+            HandleKind::File if flags & FUSE_IOCTL_DIR != 0 => Err(FuseError::Inval),
+            HandleKind::File => Ok(flags),
+        }
+    }
+
+    /// Returns whether `fh` is synced with FSYNC or FSYNCDIR.
+    ///
+    /// A handle the table does not know is rejected rather than guessed at.
+    pub fn fsync_opcode(&self, nodeid: u64, fh: u64) -> Result<FuseOpcode> {
+        self.open_entry(nodeid, fh)
+            .map(|entry| entry.fsync_opcode())
+    }
+}
+
+/// Returns whether `err` says the server no longer knows the handle used.
+pub fn fuse_is_stale(err: &FuseError) -> bool {
+    matches!(err, FuseError::BadF | FuseError::ServerError(ESTALE))
+}
+
+/// Runs `op`, and if it fails on a stale handle, runs it once more after
+/// `reopen` has opened the handle again.
+pub fn fuse_retry_stale<T>(
+    mut op: impl FnMut() -> Result<T>,
+    reopen: impl FnOnce() -> Result<()>,
+) -> Result<T> {
+    match op() {
+        Err(err) if fuse_is_stale(&err) => {
+            reopen()?;
+            op()
+        }
+        result => result,
+    }
+}
+
+/// Returns the backing file an OPEN reply registers for passthrough I/O.
+///
+/// The `backing_id` only means something if passthrough was negotiated and
+/// the reply asks for it with `FOPEN_PASSTHROUGH`.
+pub fn fuse_backing_id(open_out: &FuseOpenOut, init_flags: FuseInitFlags) -> Option<i32> {
+    if init_flags.contains(FuseInitFlags::FUSE_PASSTHROUGH)
+        && open_out.open_flags & FOPEN_PASSTHROUGH != 0
+    {
+        Some(open_out.backing_id)
+    } else {
+        None
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use core::{
+        cell::{Cell, RefCell},
+        time::Duration,
+    };
+
+    use ostd::{mm::VmReader, prelude::*};
+
+    use super::*;
+    use crate::device::filesystem::{
+        fuse::{FuseEntryOut, FuseInHeader},
+        inode::InodeCache,
+        request::fuse_fsync_request,
+    };
+
+    #[ktest]
+    fn directory_ioctl_sets_dir_flag() {
+        let mut handles = HandleTable::new();
+        let fh = handles.insert(7, 2, HandleKind::Dir, 0, 0);
+
+        let flags = handles.ioctl_flags(2, fh, 0).unwrap();
+        assert_ne!(flags & FUSE_IOCTL_DIR, 0);
+    }
+
+    #[ktest]
+    fn file_ioctl_rejects_dir_flag() {
+        let mut handles = HandleTable::new();
+        let fh = handles.insert(3, 4, HandleKind::File, 0, 0);
+
+        assert_eq!(handles.ioctl_flags(4, fh, 0).unwrap(), 0);
+        assert!(matches!(
+            handles.ioctl_flags(4, fh, FUSE_IOCTL_DIR),
+            Err(FuseError::Inval)
+        ));
+    }
+
+    #[ktest]
+    fn directory_fsync_uses_fsyncdir() {
+        let mut handles = HandleTable::new();
+        let dir = handles.insert(7, 2, HandleKind::Dir, 0, 0);
+        let file = handles.insert(3, 4, HandleKind::File, 0, 0);
+
+        let opcode = handles.fsync_opcode(2, dir).unwrap();
+        let bytes = fuse_fsync_request(opcode, 2, 7, 0).to_bytes();
+        let headerin = VmReader::from(bytes.as_slice())
+            .read_val::<FuseInHeader>()
+            .unwrap();
+        assert_eq!(headerin.opcode, FuseOpcode::FuseFsyncdir as u32);
+
+        assert!(matches!(
+            handles.fsync_opcode(4, file),
+            Ok(FuseOpcode::FuseFsync)
+        ));
+        assert!(matches!(handles.fsync_opcode(2, 99), Err(FuseError::BadF)));
+        assert!(matches!(handles.fsync_opcode(4, dir), Err(FuseError::BadF)));
+    }
+
+    #[ktest]
+    fn unknown_handle_is_rejected() {
+        let mut handles = HandleTable::new();
+        let fh = handles.insert(3, 4, HandleKind::Dir, 0, 0);
+
+        assert!(matches!(
+            handles.ioctl_flags(4, 99, 0),
+            Err(FuseError::BadF)
+        ));
+        assert!(matches!(
+            handles.ioctl_flags(5, fh, 0),
+            Err(FuseError::BadF)
+        ));
+        assert_eq!(handles.close(fh).unwrap(), Some(3));
+        assert!(matches!(
+            handles.ioctl_flags(4, fh, 0),
+            Err(FuseError::BadF)
+        ));
+        assert!(matches!(handles.close(fh), Err(FuseError::BadF)));
+        assert!(handles.use_handle(fh).is_none());
+    }
+
+    #[ktest]
+    fn oldest_idle_handle_is_evicted() {
+        let mut handles = HandleTable::new();
+        handles.set_limit(Some(2));
+        let fhs: Vec<u64> = (1..=3)
+            .map(|server_fh| handles.insert(server_fh, server_fh + 10, HandleKind::File, 2, 0))
+            .collect();
+        // The first handle is in use and the second was used last.
+        handles.pin(fhs[0]).unwrap();
+        handles.use_handle(fhs[1]);
+
+        let released = handles.evict();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].0, 3);
+        assert_eq!(released[0].1.nodeid, 13);
+        assert!(handles.use_handle(fhs[2]).is_none());
+        assert!(handles.evict().is_empty());
+
+        // The next use of the third handle opens it again as server handle 7.
+        let entry = handles.evicted(fhs[2]).unwrap();
+        assert_eq!(entry.open_opcode(), FuseOpcode::FuseOpen);
+        assert_eq!(entry.flags, 2);
+        assert!(handles.reopened(fhs[2], 7, 0));
+        assert_eq!(handles.use_handle(fhs[2]), Some(7));
+
+        // That makes the first handle, once unpinned, the oldest idle one.
+        handles.unpin(fhs[0]);
+        let released = handles.evict();
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].0, 1);
+    }
+
+    #[ktest]
+    fn reopened_handle_keeps_its_id() {
+        let mut handles = HandleTable::new();
+        handles.set_limit(Some(1));
+        let first = handles.insert(1, 10, HandleKind::Dir, 0, 0);
+        let second = handles.insert(2, 20, HandleKind::Dir, 0, 0);
+        assert_eq!(handles.evict()[0].0, 1);
+
+        assert!(handles.reopened(first, 5, 0));
+        // Reopening went over the limit again, evicting the other handle.
+        assert_eq!(handles.evict()[0].0, 2);
+        assert_eq!(handles.ioctl_flags(10, first, 0).unwrap(), FUSE_IOCTL_DIR);
+
+        // A concurrent reopen of the same handle leaves its server handle to
+        // the caller.
+        assert!(handles.reopened(second, 6, 0));
+        assert!(!handles.reopened(second, 8, 0));
+        assert_eq!(handles.evict()[0].0, 5);
+        assert_eq!(handles.close(first).unwrap(), None);
+        assert_eq!(handles.close(second).unwrap(), Some(6));
+
+        // The server may hand out its handles again, but the IDs are new.
+        let third = handles.insert(1, 10, HandleKind::Dir, 0, 0);
+        assert!(third != first && third != second);
+        assert!(!handles.reopened(first, 9, 0));
+    }
+
+    #[ktest]
+    fn stale_handle_reopens_on_next_read() {
+        let handles = RefCell::new(HandleTable::new());
+        let fh = handles.borrow_mut().insert(5, 2, HandleKind::File, 0, 0);
+        let invalidated = Cell::new(false);
+
+        // The server forgets handle 5 as it invalidates node 2.
+        let read = || {
+            let server_fh = handles.borrow_mut().use_handle(fh).unwrap();
+            if server_fh == 5 && invalidated.get() {
+                return Err(FuseError::ServerError(ESTALE));
+            }
+            Ok(server_fh)
+        };
+        let reopen = || -> Result<()> {
+            let mut handles = handles.borrow_mut();
+            let (old_fh, _) = handles.invalidate(fh).ok_or(FuseError::BadF)?;
+            assert_eq!(old_fh, 5);
+            assert!(handles.reopened(fh, 9, 0));
+            Ok(())
+        };
+
+        assert_eq!(fuse_retry_stale(read, reopen).unwrap(), 5);
+        invalidated.set(true);
+        assert_eq!(fuse_retry_stale(read, reopen).unwrap(), 9);
+        assert_eq!(handles.borrow_mut().use_handle(fh), Some(9));
+        assert_eq!(handles.borrow_mut().close(fh).unwrap(), Some(9));
+
+        // Other errors are left to the caller.
+        let mut reopened = false;
+        let result = fuse_retry_stale(
+            || Err::<(), _>(FuseError::ServerError(5)),
+            || {
+                reopened = true;
+                Ok(())
+            },
+        );
+        assert!(matches!(result, Err(FuseError::ServerError(5))));
+        assert!(!reopened);
+    }
+
+    #[ktest]
+    fn noatime_read_keeps_cached_atime() {
+        let mut handles = HandleTable::new();
+        let noatime = handles.insert(5, 42, HandleKind::File, O_NOATIME, 0);
+        let plain = handles.insert(6, 42, HandleKind::File, 0, 0);
+        let mut inodes = InodeCache::new();
+        inodes.insert_entry(
+            &FuseEntryOut {
+                nodeid: 42,
+                ..Default::default()
+            },
+            Duration::ZERO,
+        );
+
+        // Reads touch the atime as the device does.
+        let mut read = |fh: u64, now: u64| {
+            if !handles.is_noatime(fh) {
+                inodes.touch_atime(42, Duration::from_secs(now), 1);
+            }
+        };
+        read(noatime, 100);
+        read(plain, 200);
+        read(noatime, 300);
+        assert_eq!(inodes.get(42).unwrap().attr.atime, 200);
+
+        // The flag stays with the handle when it is opened again.
+        handles.invalidate(noatime).unwrap();
+        assert!(handles.reopened(noatime, 9, 0));
+        assert!(handles.is_noatime(noatime));
+        assert!(!handles.is_noatime(plain));
+    }
+
+    #[ktest]
+    fn passthrough_open_records_backing_id() {
+        let open_out = FuseOpenOut {
+            fh: 4,
+            open_flags: FOPEN_PASSTHROUGH,
+            backing_id: 9,
+        };
+        assert_eq!(fuse_backing_id(&open_out, FuseInitFlags::empty()), None);
+        let backing_id = fuse_backing_id(&open_out, FuseInitFlags::FUSE_PASSTHROUGH).unwrap();
+
+        let mut handles = HandleTable::new();
+        let passthrough = handles.insert(4, 2, HandleKind::File, 0, open_out.open_flags);
+        let plain = handles.insert(5, 3, HandleKind::File, 0, 0);
+        handles.set_backing_id(passthrough, backing_id);
+        assert_eq!(handles.backing_id(passthrough), Some(9));
+        assert_eq!(handles.backing_id(plain), None);
+
+        // A plain open carries no backing file, whatever `backing_id` says.
+        let open_out = FuseOpenOut {
+            open_flags: 0,
+            ..open_out
+        };
+        assert_eq!(
+            fuse_backing_id(&open_out, FuseInitFlags::FUSE_PASSTHROUGH),
+            None
+        );
+    }
+}
@@ -0,0 +1,530 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::{cmp::min, hint::spin_loop, mem::offset_of, time::Duration};
+
+use ostd::Pod;
+
+use super::{
+    config::{FilesystemFeatures, VirtioFilesystemConfig},
+    error::{FuseError, Result},
+    fuse::{
+        FuseInitFlags, FuseInitIn, FuseInitOut, FuseOpcode, FUSE_COMPAT_INIT_OUT_SIZE,
+        FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION,
+    },
+    request::{fuse_check_minor, RequestBuilder, FUSE_MAX_WRITE_SIZE},
+};
+
+/// The first minor version whose FUSE_INIT carries `flags2`.
+pub const FUSE_INIT_EXT_MINOR: u32 = 36;
+
+/// The oldest protocol minor version the driver talks to.
+///
+/// The attribute, entry and write layouts it reads and writes are those of
+/// 7.9; an older server would parse every request wrong.
+pub const MIN_SUPPORTED_MINOR: u32 = 9;
+
+/// The size of `FuseInitIn` before `flags2` was added.
+pub const FUSE_COMPAT_INIT_IN_SIZE: usize = 16;
+
+/// The layout of the FUSE_INIT request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitForm {
+    /// The full `FuseInitIn`, with `FUSE_INIT_EXT` and `flags2`.
+    Extended,
+    /// The 16-byte `FuseInitIn` understood by servers older than 7.36.
+    Legacy,
+}
+
+impl InitForm {
+    /// Returns the form to use with a server of the given minor version.
+    pub fn for_minor(minor: u32) -> Self {
+        if minor < FUSE_INIT_EXT_MINOR {
+            Self::Legacy
+        } else {
+            Self::Extended
+        }
+    }
+
+    /// Returns the form of a sent request from the length of its `FuseInitIn`.
+    pub fn from_in_len(len: usize) -> Self {
+        if len < size_of::<FuseInitIn>() {
+            Self::Legacy
+        } else {
+            Self::Extended
+        }
+    }
+}
+
+/// Builds a FUSE_INIT request asking for `flags` in the given form.
+///
+/// The legacy form drops `FUSE_INIT_EXT` and every flag of the second word.
+pub fn fuse_init_request(form: InitForm, flags: FuseInitFlags) -> RequestBuilder {
+    let request = RequestBuilder::new(FuseOpcode::FuseInit, 0).reserve_reply(0);
+    match form {
+        InitForm::Extended => request.push_struct(&FuseInitIn {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 0,
+            flags: (flags | FuseInitFlags::FUSE_INIT_EXT).bits() as u32,
+            flags2: ((flags | FuseInitFlags::FUSE_INIT_EXT).bits() >> 32) as u32,
+            unused: [0u32; 11],
+        }),
+        InitForm::Legacy => {
+            let initin = FuseInitIn {
+                major: FUSE_KERNEL_VERSION,
+                minor: FUSE_KERNEL_MINOR_VERSION,
+                max_readahead: 0,
+                flags: (flags - FuseInitFlags::FUSE_INIT_EXT).bits() as u32,
+                ..Default::default()
+            };
+            request.push_bytes(&initin.as_bytes()[..FUSE_COMPAT_INIT_IN_SIZE])
+        }
+    }
+}
+
+/// The smallest `max_write` taken from a server, as on Linux.
+pub const FUSE_MIN_MAX_WRITE: u32 = 4096;
+
+/// The request size limit, in pages, of a server that does not send `max_pages`.
+pub const FUSE_DEFAULT_MAX_PAGES_PER_REQ: u16 = 32;
+
+/// Parses the payload of a FUSE_INIT reply, whose size depends on the server.
+///
+/// Older servers send it short and newer ones may send more than is known
+/// here. The fields present are kept, the extra bytes are ignored, and the
+/// fields missing take the values Linux assumes for them: a `time_gran` of
+/// one nanosecond, [`FUSE_DEFAULT_MAX_PAGES_PER_REQ`], and zero for the rest.
+///
+/// Returns the reply and the form in effect. A server older than 7.36
+/// ignores `flags2`, so it is cleared. A server older than
+/// [`MIN_SUPPORTED_MINOR`] fails the handshake with
+/// [`FuseError::Unsupported`].
+pub fn fuse_init_out(payload: &[u8], sent: InitForm) -> Result<(FuseInitOut, InitForm)> {
+    if payload.len() < FUSE_COMPAT_INIT_OUT_SIZE as usize {
+        return Err(FuseError::Io);
+    }
+    let mut init_out = FuseInitOut::default();
+    let len = min(payload.len(), size_of::<FuseInitOut>());
+    init_out.as_bytes_mut()[..len].copy_from_slice(&payload[..len]);
+    if init_out.minor < MIN_SUPPORTED_MINOR {
+        return Err(FuseError::Unsupported);
+    }
+    if len < offset_of!(FuseInitOut, max_pages) {
+        init_out.time_gran = 1;
+    }
+    if len < offset_of!(FuseInitOut, map_alignment) {
+        init_out.max_pages = FUSE_DEFAULT_MAX_PAGES_PER_REQ;
+    }
+
+    let form = match sent {
+        InitForm::Extended => InitForm::for_minor(init_out.minor),
+        InitForm::Legacy => InitForm::Legacy,
+    };
+    if form == InitForm::Legacy {
+        init_out.flags &= !(FuseInitFlags::FUSE_INIT_EXT.bits() as u32);
+        init_out.flags2 = 0;
+    }
+    Ok((init_out, form))
+}
+
+/// How long probing waits for the server to answer FUSE_INIT.
+pub const FUSE_INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for the FUSE_INIT handshake to complete.
+///
+/// `negotiated` is polled until it returns the outcome of the handshake, or
+/// until `now` reaches `deadline`, which fails with `TimedOut`.
+pub fn fuse_wait_init(
+    deadline: u64,
+    now: impl Fn() -> u64,
+    mut negotiated: impl FnMut() -> Option<Result<NegotiatedParams>>,
+) -> Result<NegotiatedParams> {
+    loop {
+        if let Some(outcome) = negotiated() {
+            return outcome;
+        }
+        if now() >= deadline {
+            return Err(FuseError::TimedOut);
+        }
+        spin_loop();
+    }
+}
+
+/// The outcome of the FUSE_INIT handshake and the device it was made with.
+///
+/// A device re-attached to an unchanged backend can adopt these instead of
+/// negotiating again.
+#[derive(Debug, Clone, Copy)]
+pub struct NegotiatedParams {
+    pub init_out: FuseInitOut,
+    /// The form of the FUSE_INIT request the server accepted.
+    pub init_form: InitForm,
+    pub features: FilesystemFeatures,
+    pub tag: [u8; 36],
+    pub num_request_queues: u32,
+}
+
+impl NegotiatedParams {
+    pub fn new(
+        init_out: FuseInitOut,
+        init_form: InitForm,
+        features: FilesystemFeatures,
+        config: &VirtioFilesystemConfig,
+    ) -> Self {
+        Self {
+            init_out,
+            init_form,
+            features,
+            tag: config.tag,
+            num_request_queues: config.num_request_queues,
+        }
+    }
+
+    /// Returns the init flags the server agreed to, from both flag words.
+    pub fn flags(&self) -> FuseInitFlags {
+        FuseInitFlags::from_bits_truncate(
+            self.init_out.flags as u64 | (self.init_out.flags2 as u64) << 32,
+        )
+    }
+
+    /// Returns the largest payload a WRITE may carry.
+    ///
+    /// A smaller `max_write` than [`FUSE_MIN_MAX_WRITE`] is raised to it, and
+    /// a larger one than fits a request buffer is cut to
+    /// [`FUSE_MAX_WRITE_SIZE`].
+    pub fn max_write(&self) -> u32 {
+        self.init_out
+            .max_write
+            .clamp(FUSE_MIN_MAX_WRITE, FUSE_MAX_WRITE_SIZE)
+    }
+
+    /// Returns how far ahead of a read the server lets the driver read.
+    pub fn max_readahead(&self) -> u32 {
+        self.init_out.max_readahead
+    }
+
+    /// Returns the byte alignment of the offsets of DAX mappings, if the
+    /// server agreed to FUSE_MAP_ALIGNMENT.
+    pub fn map_alignment(&self) -> Option<u64> {
+        if !self.flags().contains(FuseInitFlags::FUSE_MAP_ALIGNMENT) {
+            return None;
+        }
+        1u64.checked_shl(self.init_out.map_alignment as u32)
+    }
+
+    /// Checks that the server's protocol version knows `opcode`.
+    pub fn check_opcode(&self, opcode: FuseOpcode) -> Result<()> {
+        fuse_check_minor(opcode, self.init_out.minor)
+    }
+
+    /// Checks that the parameters were negotiated with a device like this one.
+    pub fn validate(
+        &self,
+        features: FilesystemFeatures,
+        config: &VirtioFilesystemConfig,
+    ) -> Result<()> {
+        if self.tag != config.tag
+            || self.num_request_queues != config.num_request_queues
+            || self.features != features
+        {
+            return Err(FuseError::Inval);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use core::cell::Cell;
+
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::filesystem::{
+        fuse::{FuseInHeader, FUSE_COMPAT_22_INIT_OUT_SIZE},
+        request::fuse_lseek_request,
+    };
+
+    fn mock_config(tag: &[u8], num_request_queues: u32) -> VirtioFilesystemConfig {
+        let mut config = VirtioFilesystemConfig {
+            tag: [0; 36],
+            num_request_queues,
+            notify_buf_size: 0,
+        };
+        config.tag[..tag.len()].copy_from_slice(tag);
+        config
+    }
+
+    #[ktest]
+    fn saved_params_apply_to_matching_device() {
+        let init_out = FuseInitOut {
+            major: 7,
+            minor: 41,
+            max_write: 1 << 20,
+            ..Default::default()
+        };
+        let params = NegotiatedParams::new(
+            init_out,
+            InitForm::Extended,
+            FilesystemFeatures::empty(),
+            &mock_config(b"myfs", 1),
+        );
+
+        assert!(params
+            .validate(FilesystemFeatures::empty(), &mock_config(b"myfs", 1))
+            .is_ok());
+        assert_eq!(params.init_out.minor, 41);
+        assert_eq!(params.init_out.max_write, 1 << 20);
+    }
+
+    #[ktest]
+    fn flags_combine_both_words() {
+        let init_out = FuseInitOut {
+            flags: FuseInitFlags::FUSE_INIT_EXT.bits() as u32,
+            flags2: (FuseInitFlags::FUSE_CREATE_SUPP_GROUP.bits() >> 32) as u32,
+            ..Default::default()
+        };
+        let params = NegotiatedParams::new(
+            init_out,
+            InitForm::Extended,
+            FilesystemFeatures::empty(),
+            &mock_config(b"myfs", 1),
+        );
+        assert_eq!(
+            params.flags(),
+            FuseInitFlags::FUSE_INIT_EXT | FuseInitFlags::FUSE_CREATE_SUPP_GROUP
+        );
+    }
+
+    #[ktest]
+    fn max_write_fits_the_request_buffer() {
+        let params = |max_write| {
+            let init_out = FuseInitOut {
+                max_write,
+                max_readahead: 128 * 1024,
+                ..Default::default()
+            };
+            NegotiatedParams::new(
+                init_out,
+                InitForm::Extended,
+                FilesystemFeatures::empty(),
+                &mock_config(b"myfs", 1),
+            )
+        };
+        assert_eq!(params(8192).max_write(), 8192);
+        assert_eq!(params(1 << 20).max_write(), FUSE_MAX_WRITE_SIZE);
+        assert_eq!(params(0).max_write(), FUSE_MIN_MAX_WRITE);
+        assert_eq!(params(8192).max_readahead(), 128 * 1024);
+    }
+
+    #[ktest]
+    fn old_server_rejects_newer_opcodes() {
+        let init_out = FuseInitOut {
+            major: 7,
+            minor: 23,
+            ..Default::default()
+        };
+        let params = NegotiatedParams::new(
+            init_out,
+            InitForm::Legacy,
+            FilesystemFeatures::empty(),
+            &mock_config(b"myfs", 1),
+        );
+
+        let lseek = fuse_lseek_request(2, 1, 0, 3);
+        assert!(matches!(
+            params.check_opcode(lseek.opcode()),
+            Err(FuseError::Unsupported)
+        ));
+        assert!(params.check_opcode(FuseOpcode::FuseRename2).is_ok());
+        assert!(params.check_opcode(FuseOpcode::FuseRead).is_ok());
+    }
+
+    #[ktest]
+    fn silent_server_times_out_init() {
+        let ticks = Cell::new(0);
+        let now = || {
+            ticks.set(ticks.get() + 1);
+            ticks.get()
+        };
+        let mut polls = 0;
+
+        let result = fuse_wait_init(100, now, || {
+            polls += 1;
+            None
+        });
+        assert!(matches!(result, Err(FuseError::TimedOut)));
+        assert_eq!(polls, 100);
+
+        // A reply that arrives in time ends the wait.
+        let params = NegotiatedParams::new(
+            FuseInitOut::default(),
+            InitForm::Extended,
+            FilesystemFeatures::empty(),
+            &mock_config(b"myfs", 1),
+        );
+        let mut polls = 0;
+        let result = fuse_wait_init(
+            u64::MAX,
+            || 0,
+            || {
+                polls += 1;
+                (polls == 3).then_some(Ok(params))
+            },
+        );
+        assert!(result.is_ok());
+    }
+
+    #[ktest]
+    fn too_old_server_fails_handshake() {
+        let reply = FuseInitOut {
+            major: 7,
+            minor: MIN_SUPPORTED_MINOR - 1,
+            ..Default::default()
+        };
+        let payload = &reply.as_bytes()[..FUSE_COMPAT_INIT_OUT_SIZE as usize];
+        let outcome = fuse_init_out(payload, InitForm::Legacy);
+        assert!(matches!(outcome, Err(FuseError::Unsupported)));
+
+        // The wait ends with the failure instead of running into the deadline.
+        let mut outcome = Some(outcome.map(|_| unreachable!()));
+        let result = fuse_wait_init(u64::MAX, || 0, || outcome.take());
+        assert!(matches!(result, Err(FuseError::Unsupported)));
+
+        // The oldest supported version is accepted.
+        let reply = FuseInitOut {
+            minor: MIN_SUPPORTED_MINOR,
+            ..reply
+        };
+        assert!(fuse_init_out(reply.as_bytes(), InitForm::Legacy).is_ok());
+    }
+
+    #[ktest]
+    fn saved_params_reject_changed_device() {
+        let params = NegotiatedParams::new(
+            FuseInitOut::default(),
+            InitForm::Extended,
+            FilesystemFeatures::empty(),
+            &mock_config(b"myfs", 1),
+        );
+
+        assert!(matches!(
+            params.validate(FilesystemFeatures::empty(), &mock_config(b"other", 1)),
+            Err(FuseError::Inval)
+        ));
+        assert!(matches!(
+            params.validate(FilesystemFeatures::empty(), &mock_config(b"myfs", 2)),
+            Err(FuseError::Inval)
+        ));
+        assert!(matches!(
+            params.validate(
+                FilesystemFeatures::VIRTIO_FS_F_NOTIFICATION,
+                &mock_config(b"myfs", 1)
+            ),
+            Err(FuseError::Inval)
+        ));
+    }
+
+    #[ktest]
+    fn old_server_takes_legacy_path() {
+        // A 7.22 server replies with the 24-byte FuseInitOut of its time and
+        // leaves what would be `flags2` as garbage.
+        let reply = FuseInitOut {
+            major: 7,
+            minor: 22,
+            flags: (FuseInitFlags::FUSE_INIT_EXT | FuseInitFlags::FUSE_HAS_IOCTL_DIR).bits() as u32,
+            max_write: 4096,
+            flags2: 0xffff_ffff,
+            ..Default::default()
+        };
+        let payload = &reply.as_bytes()[..24];
+
+        let (init_out, form) = fuse_init_out(payload, InitForm::Extended).unwrap();
+        assert_eq!(form, InitForm::Legacy);
+        assert_eq!(init_out.minor, 22);
+        assert_eq!(init_out.max_write, 4096);
+        assert_eq!(init_out.flags2, 0);
+        let params = NegotiatedParams::new(
+            init_out,
+            form,
+            FilesystemFeatures::empty(),
+            &mock_config(b"myfs", 1),
+        );
+        assert_eq!(params.flags(), FuseInitFlags::FUSE_HAS_IOCTL_DIR);
+
+        // Talking to it again uses the short request.
+        let request = fuse_init_request(
+            InitForm::for_minor(init_out.minor),
+            FuseInitFlags::FUSE_INIT_EXT | FuseInitFlags::FUSE_CREATE_SUPP_GROUP,
+        );
+        assert_eq!(
+            request.in_len(),
+            size_of::<FuseInHeader>() + FUSE_COMPAT_INIT_IN_SIZE
+        );
+        let bytes = request.to_bytes();
+        let flags = u32::from_le_bytes(
+            bytes[size_of::<FuseInHeader>() + 12..][..4]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(flags, 0);
+    }
+
+    #[ktest]
+    fn init_reply_size_follows_the_server() {
+        let reply = FuseInitOut {
+            major: 7,
+            minor: 41,
+            max_write: 1 << 20,
+            time_gran: 1000,
+            max_pages: 256,
+            map_alignment: 12,
+            max_stack_depth: 2,
+            ..Default::default()
+        };
+
+        // A 7.22 server stops before `time_gran`.
+        let (short, _) = fuse_init_out(
+            &reply.as_bytes()[..FUSE_COMPAT_22_INIT_OUT_SIZE as usize],
+            InitForm::Legacy,
+        )
+        .unwrap();
+        assert_eq!(short.max_write, 1 << 20);
+        assert_eq!(short.time_gran, 1);
+        assert_eq!(short.max_pages, FUSE_DEFAULT_MAX_PAGES_PER_REQ);
+        assert_eq!(short.map_alignment, 0);
+        assert_eq!(short.max_stack_depth, 0);
+
+        // A server newer than this driver sends fields it does not know.
+        let long = [reply.as_bytes(), &[0xff; 16]].concat();
+        let (long, form) = fuse_init_out(&long, InitForm::Extended).unwrap();
+        assert_eq!(form, InitForm::Extended);
+        assert_eq!(long.time_gran, 1000);
+        assert_eq!(long.max_pages, 256);
+        assert_eq!(long.map_alignment, 12);
+        assert_eq!(long.max_stack_depth, 2);
+        assert_eq!(long.unused, [0; 6]);
+    }
+
+    #[ktest]
+    fn new_server_keeps_extended_path() {
+        let reply = FuseInitOut {
+            major: 7,
+            minor: 40,
+            flags2: (FuseInitFlags::FUSE_CREATE_SUPP_GROUP.bits() >> 32) as u32,
+            ..Default::default()
+        };
+        let (init_out, form) = fuse_init_out(reply.as_bytes(), InitForm::Extended).unwrap();
+        assert_eq!(form, InitForm::Extended);
+        assert_eq!(init_out.flags2, reply.flags2);
+
+        assert!(matches!(
+            fuse_init_out(&reply.as_bytes()[..4], InitForm::Extended),
+            Err(FuseError::Io)
+        ));
+        assert_eq!(
+            fuse_init_request(InitForm::Extended, FuseInitFlags::empty()).in_len(),
+            size_of::<FuseInHeader>() + size_of::<FuseInitIn>()
+        );
+    }
+}
@@ -0,0 +1,508 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::time::Duration;
+
+use super::fuse::{FuseAttr, FuseAttrOut, FuseEntryOut};
+
+/// Returns the major number of a device number as FUSE encodes it.
+///
+/// The encoding is Linux's `new_encode_dev`: 12 bits of major and 20 bits of
+/// minor, the low 8 bits of the minor coming first.
+pub const fn dev_major(dev: u32) -> u32 {
+    (dev & 0xfff00) >> 8
+}
+
+/// Returns the minor number of a device number as FUSE encodes it.
+pub const fn dev_minor(dev: u32) -> u32 {
+    (dev & 0xff) | ((dev >> 12) & 0xfff00)
+}
+
+/// Encodes a device number the way FUSE expects it in `rdev`.
+pub const fn makedev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
+/// Splits `time` into seconds and nanoseconds, dropping what is finer than
+/// `time_gran` nanoseconds.
+///
+/// As in Linux, a granularity of zero or one keeps nanoseconds and one of a
+/// second or more keeps whole seconds.
+pub fn fuse_truncate_time(time: Duration, time_gran: u32) -> (u64, u32) {
+    let nsec = time.subsec_nanos();
+    let nsec = match time_gran {
+        0 | 1 => nsec,
+        1_000_000_000.. => 0,
+        gran => nsec - nsec % gran,
+    };
+    (time.as_secs(), nsec)
+}
+
+/// What the driver knows about a node the server has looked up for it.
+#[derive(Debug, Clone, Copy)]
+pub struct InodeEntry {
+    pub generation: u64,
+    pub attr: FuseAttr,
+    pub attr_valid: u64,
+    pub attr_valid_nsec: u32,
+    /// The monotonic time until which `attr` needs no GETATTR.
+    pub attr_expiry: Duration,
+    /// The number of lookups the guest still holds on the node.
+    pub nlookup: u64,
+}
+
+impl InodeEntry {
+    /// Returns the major number of a character or block device node.
+    pub fn major(&self) -> u32 {
+        dev_major(self.attr.rdev)
+    }
+
+    /// Returns the minor number of a character or block device node.
+    pub fn minor(&self) -> u32 {
+        dev_minor(self.attr.rdev)
+    }
+}
+
+/// Returns when attributes received at `now` with the given timeout expire.
+fn attr_expiry(now: Duration, attr_valid: u64, attr_valid_nsec: u32) -> Duration {
+    now.saturating_add(Duration::from_secs(attr_valid))
+        .saturating_add(Duration::from_nanos(attr_valid_nsec as u64))
+}
+
+/// The attributes of the nodes returned by LOOKUP and friends, keyed by node ID.
+#[derive(Debug, Default)]
+pub struct InodeCache {
+    entries: BTreeMap<u64, InodeEntry>,
+    /// The lookups given back on nodes still cached, returned with FORGET
+    /// once the last one is.
+    released: BTreeMap<u64, u64>,
+    /// The lookup counts of abandoned nodes, yet to be returned with FORGET.
+    forgotten: BTreeMap<u64, u64>,
+}
+
+impl InodeCache {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            released: BTreeMap::new(),
+            forgotten: BTreeMap::new(),
+        }
+    }
+
+    /// Records an entry replied by LOOKUP, MKNOD, MKDIR, SYMLINK, LINK or CREATE.
+    ///
+    /// Each such reply counts as one lookup on the server side. `now` is the
+    /// monotonic time the reply arrived, which its attribute timeout counts
+    /// from.
+    pub fn insert_entry(&mut self, entry: &FuseEntryOut, now: Duration) {
+        if entry.nodeid == 0 {
+            return;
+        }
+        let nlookup = self
+            .entries
+            .get(&entry.nodeid)
+            .map_or(0, |inode| inode.nlookup)
+            + 1;
+        self.entries.insert(
+            entry.nodeid,
+            InodeEntry {
+                generation: entry.generation,
+                attr: entry.attr,
+                attr_valid: entry.attr_valid,
+                attr_valid_nsec: entry.attr_valid_nsec,
+                attr_expiry: attr_expiry(now, entry.attr_valid, entry.attr_valid_nsec),
+                nlookup,
+            },
+        );
+    }
+
+    /// Refreshes only the size of a cached node, keeping its other attributes.
+    ///
+    /// Returns `false` if the node is not cached.
+    pub fn refresh_size(&mut self, nodeid: u64, size: u64) -> bool {
+        let Some(inode) = self.entries.get_mut(&nodeid) else {
+            return false;
+        };
+        inode.attr.size = size;
+        true
+    }
+
+    /// Replaces the attributes of a cached node with a GETATTR or SETATTR reply
+    /// that arrived at `now`.
+    ///
+    /// Returns `false` if the node is not cached.
+    pub fn refresh_attr(&mut self, nodeid: u64, attr_out: &FuseAttrOut, now: Duration) -> bool {
+        let Some(inode) = self.entries.get_mut(&nodeid) else {
+            return false;
+        };
+        inode.attr = attr_out.attr;
+        inode.attr_valid = attr_out.attr_valid;
+        inode.attr_valid_nsec = attr_out.attr_valid_nsec;
+        inode.attr_expiry = attr_expiry(now, attr_out.attr_valid, attr_out.attr_valid_nsec);
+        true
+    }
+
+    /// Returns the cached attributes of a node if their timeout has not
+    /// passed at `now`.
+    pub fn fresh_attr(&self, nodeid: u64, now: Duration) -> Option<FuseAttr> {
+        self.entries
+            .get(&nodeid)
+            .filter(|inode| now < inode.attr_expiry)
+            .map(|inode| inode.attr)
+    }
+
+    /// Expires the cached attributes of `nodeid`, or of every node if `None`,
+    /// so that the next GETATTR goes to the server.
+    ///
+    /// The nodes stay cached with their lookup counts, which the server still
+    /// holds.
+    pub fn invalidate(&mut self, nodeid: Option<u64>) {
+        let expire = |inode: &mut InodeEntry| inode.attr_expiry = Duration::ZERO;
+        match nodeid {
+            Some(nodeid) => self.entries.get_mut(&nodeid).into_iter().for_each(expire),
+            None => self.entries.values_mut().for_each(expire),
+        }
+    }
+
+    /// Advances the cached atime of a node to `now`, truncated to the
+    /// server's `time_gran` in nanoseconds.
+    ///
+    /// This guesses at what a read did on the server; the next GETATTR
+    /// corrects it. The atime never moves backwards. Returns `false` if the
+    /// node is not cached.
+    pub fn touch_atime(&mut self, nodeid: u64, now: Duration, time_gran: u32) -> bool {
+        let Some(inode) = self.entries.get_mut(&nodeid) else {
+            return false;
+        };
+        let (atime, atimensec) = fuse_truncate_time(now, time_gran);
+        if (atime, atimensec) > (inode.attr.atime, inode.attr.atimensec) {
+            inode.attr.atime = atime;
+            inode.attr.atimensec = atimensec;
+        }
+        true
+    }
+
+    /// Gives back the one lookup of `nodeid` that the caller holds.
+    ///
+    /// The node stays cached while other lookups of it remain. Once the last
+    /// one is given back, the node is dropped and all the lookups the server
+    /// counted for it are left for [`Self::forget_pending`].
+    pub fn release_lookup(&mut self, nodeid: u64) {
+        let Some(inode) = self.entries.get_mut(&nodeid) else {
+            return;
+        };
+        inode.nlookup -= 1;
+        let released = self.released.entry(nodeid).or_insert(0);
+        *released += 1;
+        if inode.nlookup > 0 {
+            return;
+        }
+        let released = *released;
+        self.entries.remove(&nodeid);
+        self.released.remove(&nodeid);
+        *self.forgotten.entry(nodeid).or_insert(0) += released;
+    }
+
+    /// Records a rename between `olddir` and `newdir` that left `stale`, if
+    /// any, with outdated attributes.
+    ///
+    /// Both directories changed. The renamed nodes keep their attributes,
+    /// so an exchange leaves the two nodes cached as they were.
+    pub fn renamed(&mut self, olddir: u64, newdir: u64, stale: Option<u64>) {
+        for nodeid in [Some(olddir), Some(newdir), stale].into_iter().flatten() {
+            self.invalidate(Some(nodeid));
+        }
+    }
+
+    /// Takes the `(nodeid, nlookup)` pairs of the abandoned nodes, to be sent
+    /// with FORGET or BATCH_FORGET.
+    pub fn forget_pending(&mut self) -> Vec<(u64, u64)> {
+        core::mem::take(&mut self.forgotten).into_iter().collect()
+    }
+
+    pub fn get(&self, nodeid: u64) -> Option<&InodeEntry> {
+        self.entries.get(&nodeid)
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use core::cell::Cell;
+
+    use ostd::{mm::VmReader, prelude::*, Pod};
+
+    use super::*;
+    use crate::device::filesystem::{
+        fuse::{FuseInHeader, FuseMknodIn, FuseOutHeader},
+        request::{fuse_mknod_request, fuse_reply_val},
+    };
+
+    fn mock_entry() -> FuseEntryOut {
+        FuseEntryOut {
+            nodeid: 42,
+            generation: 7,
+            entry_valid: 1,
+            attr_valid: 2,
+            entry_valid_nsec: 3,
+            attr_valid_nsec: 4,
+            attr: FuseAttr {
+                ino: 42,
+                size: 4096,
+                mode: 0o100644,
+                nlink: 1,
+                uid: 1000,
+                gid: 1000,
+                blksize: 512,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn mock_reply(entry: &FuseEntryOut) -> Vec<u8> {
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + size_of::<FuseEntryOut>()) as u32,
+            error: 0,
+            unique: 0,
+        };
+        [header.as_bytes(), entry.as_bytes()].concat()
+    }
+
+    #[ktest]
+    fn lookup_reply_returns_whole_entry() {
+        let expected = mock_entry();
+        let entry = fuse_reply_val::<FuseEntryOut>(&mock_reply(&expected)).unwrap();
+
+        assert_eq!(entry.nodeid, 42);
+        assert_eq!(entry.generation, 7);
+        assert_eq!(entry.entry_valid, 1);
+        assert_eq!(entry.attr_valid, 2);
+        assert_eq!(entry.entry_valid_nsec, 3);
+        assert_eq!(entry.attr_valid_nsec, 4);
+        assert_eq!(entry.attr.as_bytes(), expected.attr.as_bytes());
+    }
+
+    #[ktest]
+    fn lookup_reply_populates_cache() {
+        let entry = mock_entry();
+        let mut cache = InodeCache::new();
+
+        cache.insert_entry(&entry, Duration::ZERO);
+        let inode = cache.get(42).unwrap();
+        assert_eq!(inode.generation, 7);
+        assert_eq!(inode.attr.size, 4096);
+        assert_eq!(inode.attr_valid, 2);
+        assert_eq!(inode.attr_valid_nsec, 4);
+        assert_eq!(inode.nlookup, 1);
+
+        cache.insert_entry(&entry, Duration::ZERO);
+        assert_eq!(cache.get(42).unwrap().nlookup, 2);
+    }
+
+    #[ktest]
+    fn fresh_lookup_answers_getattr() {
+        // LOOKUP at 10s gives attributes valid for 2s and 4ns.
+        let entry = fuse_reply_val::<FuseEntryOut>(&mock_reply(&mock_entry())).unwrap();
+        let mut cache = InodeCache::new();
+        cache.insert_entry(&entry, Duration::from_secs(10));
+
+        // A GETATTR within the window needs no request.
+        let getattrs = Cell::new(0);
+        let getattr = |cache: &mut InodeCache, now: Duration| {
+            if let Some(attr) = cache.fresh_attr(42, now) {
+                return attr;
+            }
+            getattrs.set(getattrs.get() + 1);
+            let attr_out = FuseAttrOut {
+                attr_valid: 2,
+                attr: entry.attr,
+                ..Default::default()
+            };
+            cache.refresh_attr(42, &attr_out, now);
+            attr_out.attr
+        };
+        assert_eq!(getattr(&mut cache, Duration::from_secs(11)).size, 4096);
+        assert_eq!(getattr(&mut cache, Duration::new(12, 3)).size, 4096);
+        assert_eq!(getattrs.get(), 0);
+
+        // Past it, the server is asked again.
+        getattr(&mut cache, Duration::new(12, 4));
+        assert_eq!(getattrs.get(), 1);
+        assert!(cache.fresh_attr(42, Duration::from_secs(13)).is_some());
+        assert!(cache.fresh_attr(43, Duration::ZERO).is_none());
+    }
+
+    #[ktest]
+    fn invalidated_attr_is_fetched_again() {
+        let mut cache = InodeCache::new();
+        let mut entry = mock_entry();
+        entry.attr_valid = 60;
+        cache.insert_entry(&entry, Duration::ZERO);
+        entry.nodeid = 43;
+        cache.insert_entry(&entry, Duration::ZERO);
+        let now = Duration::from_secs(1);
+
+        // The backend changed node 42 behind the server's back.
+        cache.invalidate(Some(42));
+        assert!(cache.fresh_attr(42, now).is_none());
+        assert!(cache.fresh_attr(43, now).is_some());
+        assert_eq!(cache.get(42).unwrap().nlookup, 1);
+
+        // The GETATTR that follows makes it fresh again.
+        let attr_out = FuseAttrOut {
+            attr_valid: 60,
+            attr: entry.attr,
+            ..Default::default()
+        };
+        cache.refresh_attr(42, &attr_out, now);
+        assert!(cache.fresh_attr(42, now).is_some());
+
+        cache.invalidate(None);
+        assert!(cache.fresh_attr(42, now).is_none());
+        assert!(cache.fresh_attr(43, now).is_none());
+    }
+
+    #[ktest]
+    fn abandoned_lookups_are_forgotten_once() {
+        let entry = mock_entry();
+        let mut cache = InodeCache::new();
+        cache.insert_entry(&entry, Duration::ZERO);
+        cache.insert_entry(&entry, Duration::ZERO);
+        assert!(cache.forget_pending().is_empty());
+
+        cache.release_lookup(42);
+        cache.release_lookup(42);
+        assert!(cache.get(42).is_none());
+
+        // Looked up again and abandoned again, the counts add up.
+        cache.insert_entry(&entry, Duration::ZERO);
+        cache.release_lookup(42);
+        assert_eq!(cache.forget_pending(), [(42, 3)]);
+        assert!(cache.forget_pending().is_empty());
+    }
+
+    #[ktest]
+    fn size_refresh_keeps_other_fields() {
+        let entry = mock_entry();
+        let mut cache = InodeCache::new();
+        cache.insert_entry(&entry, Duration::ZERO);
+
+        assert!(cache.refresh_size(42, 8192));
+        let inode = cache.get(42).unwrap();
+        assert_eq!(inode.attr.size, 8192);
+        assert_eq!(inode.attr.mode, entry.attr.mode);
+        assert_eq!(inode.attr.uid, entry.attr.uid);
+        assert_eq!(inode.attr.blksize, entry.attr.blksize);
+        assert_eq!(inode.attr_valid, entry.attr_valid);
+        assert_eq!(inode.attr_valid_nsec, entry.attr_valid_nsec);
+        assert_eq!(inode.nlookup, 1);
+
+        assert!(!cache.refresh_size(43, 8192));
+        assert!(cache.get(43).is_none());
+    }
+
+    #[ktest]
+    fn read_advances_cached_atime() {
+        let mut entry = mock_entry();
+        entry.attr.atime = 100;
+        entry.attr.atimensec = 500;
+        let mut cache = InodeCache::new();
+        cache.insert_entry(&entry, Duration::ZERO);
+
+        // A server with microsecond timestamps.
+        assert!(cache.touch_atime(42, Duration::new(200, 123_456_789), 1000));
+        let attr = cache.get(42).unwrap().attr;
+        assert_eq!((attr.atime, attr.atimensec), (200, 123_456_000));
+        assert_eq!(attr.mtime, entry.attr.mtime);
+
+        // A stale clock leaves it alone.
+        assert!(cache.touch_atime(42, Duration::new(150, 0), 1000));
+        let attr = cache.get(42).unwrap().attr;
+        assert_eq!((attr.atime, attr.atimensec), (200, 123_456_000));
+
+        assert_eq!(
+            fuse_truncate_time(Duration::new(7, 999), 1_000_000_000),
+            (7, 0)
+        );
+        assert!(!cache.touch_atime(43, Duration::new(200, 0), 1));
+    }
+
+    #[ktest]
+    fn char_device_keeps_rdev() {
+        const S_IFCHR: u32 = 0o020000;
+        // A minor above 255 exercises both halves of the encoding.
+        let rdev = makedev(136, 300);
+        assert_eq!((dev_major(rdev), dev_minor(rdev)), (136, 300));
+
+        let request = fuse_mknod_request(1, b"tty", S_IFCHR | 0o620, rdev, 0o022);
+        let bytes = request.to_bytes();
+        let mknodin = VmReader::from(&bytes[size_of::<FuseInHeader>()..])
+            .read_val::<FuseMknodIn>()
+            .unwrap();
+        assert_eq!(mknodin.rdev, rdev);
+        assert_eq!(mknodin.umask, 0o022);
+
+        // Play the server: MKNOD creates node 42, GETATTR reads it back.
+        let mut entry = mock_entry();
+        entry.attr.mode = S_IFCHR | 0o620;
+        entry.attr.rdev = rdev;
+        let mut cache = InodeCache::new();
+        cache.insert_entry(&entry, Duration::ZERO);
+        let attr_out = FuseAttrOut {
+            attr_valid: 5,
+            attr: entry.attr,
+            ..Default::default()
+        };
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + size_of::<FuseAttrOut>()) as u32,
+            error: 0,
+            unique: 0,
+        };
+        let reply = [header.as_bytes(), attr_out.as_bytes()].concat();
+        let attr_out = fuse_reply_val::<FuseAttrOut>(&reply).unwrap();
+
+        assert!(cache.refresh_attr(42, &attr_out, Duration::ZERO));
+        let inode = cache.get(42).unwrap();
+        assert_eq!(inode.attr_valid, 5);
+        assert_eq!(inode.major(), 136);
+        assert_eq!(inode.minor(), 300);
+    }
+
+    #[ktest]
+    fn exchange_keeps_both_nodes_fresh() {
+        let mut cache = InodeCache::new();
+        let mut entry = mock_entry();
+        entry.attr_valid = 60;
+        for nodeid in [1, 42, 43] {
+            entry.nodeid = nodeid;
+            cache.insert_entry(&entry, Duration::ZERO);
+        }
+        let now = Duration::from_secs(1);
+
+        // RENAME_EXCHANGE of nodes 42 and 43 within directory 1.
+        cache.renamed(1, 1, None);
+        assert!(cache.fresh_attr(1, now).is_none());
+        assert!(cache.fresh_attr(42, now).is_some());
+        assert!(cache.fresh_attr(43, now).is_some());
+
+        // A plain rename of 42 over 43 leaves 43 with one link less.
+        cache.renamed(1, 1, Some(43));
+        assert!(cache.fresh_attr(42, now).is_some());
+        assert!(cache.fresh_attr(43, now).is_none());
+    }
+
+    #[ktest]
+    fn released_lookup_keeps_node_cached() {
+        let entry = mock_entry();
+        let mut cache = InodeCache::new();
+        cache.insert_entry(&entry, Duration::ZERO);
+        cache.insert_entry(&entry, Duration::ZERO);
+
+        cache.release_lookup(42);
+        assert_eq!(cache.get(42).unwrap().nlookup, 1);
+        // The server keeps the node while the guest holds a lookup of it.
+        assert!(cache.forget_pending().is_empty());
+        cache.release_lookup(42);
+        assert!(cache.get(42).is_none());
+        assert_eq!(cache.forget_pending(), [(42, 2)]);
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+use super::{errno::EAGAIN, request::RequestBuilder};
+
+/// The number of times an interrupt is sent again at most, after EAGAIN.
+pub const MAX_INTERRUPT_RESENDS: u32 = 8;
+
+/// How long to give the server to pick up the original request before the
+/// interrupt is sent again.
+pub const INTERRUPT_RESEND_DELAY: Duration = Duration::from_millis(5);
+
+/// What to do with a FUSE_INTERRUPT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptAction {
+    /// Send the interrupt to the server.
+    Send,
+    /// The request is not in flight, or already interrupted; send nothing.
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestState {
+    /// Submitted, and its reply has not arrived.
+    InFlight,
+    /// Submitted and interrupted; `resends` counts the times the interrupt was
+    /// sent again after an EAGAIN.
+    Interrupted { resends: u32 },
+}
+
+/// The requests that are in flight, keyed by their `unique`.
+///
+/// FUSE_INTERRUPT may only name a request the server is still working on. If
+/// the server has not picked that request up yet, it replies EAGAIN and the
+/// interrupt is sent again after [`INTERRUPT_RESEND_DELAY`], up to
+/// [`MAX_INTERRUPT_RESENDS`] times. Once the original reply arrives, with its
+/// result or EINTR, the interrupt is dropped.
+#[derive(Debug, Default)]
+pub struct InterruptTracker {
+    requests: BTreeMap<u64, RequestState>,
+}
+
+impl InterruptTracker {
+    pub const fn new() -> Self {
+        Self {
+            requests: BTreeMap::new(),
+        }
+    }
+
+    /// Records that the request `unique` has been submitted.
+    pub fn submit(&mut self, unique: u64) {
+        self.requests.insert(unique, RequestState::InFlight);
+    }
+
+    /// Records that `request` has been submitted, unless it has no reply to
+    /// wait for.
+    pub fn track(&mut self, request: &RequestBuilder) {
+        if request.expects_reply() {
+            self.submit(request.unique());
+        }
+    }
+
+    /// Returns the number of requests in flight.
+    pub fn in_flight(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Records that the reply to the request `unique` has arrived.
+    pub fn complete(&mut self, unique: u64) {
+        self.requests.remove(&unique);
+    }
+
+    /// Decides whether the request `unique` should be interrupted.
+    pub fn interrupt(&mut self, unique: u64) -> InterruptAction {
+        match self.requests.get_mut(&unique) {
+            Some(state @ RequestState::InFlight) => {
+                *state = RequestState::Interrupted { resends: 0 };
+                InterruptAction::Send
+            }
+            _ => InterruptAction::Drop,
+        }
+    }
+
+    /// Decides what to do when the server replies `error` to the interrupt of
+    /// the request `unique`.
+    pub fn interrupt_replied(&mut self, unique: u64, error: i32) -> InterruptAction {
+        match self.requests.get_mut(&unique) {
+            Some(RequestState::Interrupted { resends })
+                if error == -EAGAIN && *resends < MAX_INTERRUPT_RESENDS =>
+            {
+                *resends += 1;
+                InterruptAction::Send
+            }
+            _ => InterruptAction::Drop,
+        }
+    }
+
+    /// Returns whether the request `unique` is interrupted and its reply has
+    /// not arrived, so that a resent interrupt still has a target.
+    pub fn is_interrupted(&self, unique: u64) -> bool {
+        matches!(
+            self.requests.get(&unique),
+            Some(RequestState::Interrupted { .. })
+        )
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::{mm::VmReader, prelude::*};
+
+    use super::*;
+    use crate::device::filesystem::{
+        fuse::{FuseInHeader, FuseInterruptIn, FUSE_INT_REQ_BIT},
+        request::{
+            fuse_batch_forget_request, fuse_forget_request, fuse_interrupt_request,
+            fuse_lookup_request,
+        },
+    };
+
+    #[ktest]
+    fn eagain_resends_interrupt_until_bound() {
+        let mut tracker = InterruptTracker::new();
+        tracker.submit(8);
+
+        assert_eq!(tracker.interrupt(8), InterruptAction::Send);
+        assert_eq!(tracker.interrupt(8), InterruptAction::Drop);
+        for _ in 0..MAX_INTERRUPT_RESENDS {
+            assert_eq!(tracker.interrupt_replied(8, -EAGAIN), InterruptAction::Send);
+        }
+        assert_eq!(tracker.interrupt_replied(8, -EAGAIN), InterruptAction::Drop);
+        assert!(tracker.is_interrupted(8));
+
+        // The original request then completes, interrupted or not.
+        tracker.complete(8);
+        assert!(!tracker.is_interrupted(8));
+        assert_eq!(tracker.interrupt(8), InterruptAction::Drop);
+    }
+
+    #[ktest]
+    fn completion_stops_resending() {
+        let mut tracker = InterruptTracker::new();
+        tracker.submit(8);
+        assert_eq!(tracker.interrupt(8), InterruptAction::Send);
+        assert_eq!(tracker.interrupt_replied(8, -EAGAIN), InterruptAction::Send);
+
+        // The original reply, EINTR here, arrives before the resend is due.
+        tracker.complete(8);
+        assert!(!tracker.is_interrupted(8));
+        assert_eq!(tracker.interrupt_replied(8, -EAGAIN), InterruptAction::Drop);
+    }
+
+    #[ktest]
+    fn completed_request_is_not_interrupted() {
+        let mut tracker = InterruptTracker::new();
+        tracker.submit(8);
+        tracker.complete(8);
+
+        assert_eq!(tracker.interrupt(8), InterruptAction::Drop);
+        assert_eq!(tracker.interrupt_replied(8, -EAGAIN), InterruptAction::Drop);
+        assert_eq!(tracker.interrupt(9), InterruptAction::Drop);
+    }
+
+    #[ktest]
+    fn forget_is_not_pending() {
+        let mut tracker = InterruptTracker::new();
+        let forget = fuse_forget_request(42, 1);
+        assert_eq!(forget.total_len(), forget.in_len());
+
+        tracker.track(&forget);
+        tracker.track(&fuse_batch_forget_request(&[(42, 1), (43, 2)]));
+        assert_eq!(tracker.in_flight(), 0);
+
+        tracker.track(&fuse_lookup_request(1, b"file"));
+        assert_eq!(tracker.in_flight(), 1);
+    }
+
+    #[ktest]
+    fn interrupt_names_its_target() {
+        let mut tracker = InterruptTracker::new();
+        let lookup = fuse_lookup_request(1, b"file").with_unique(8);
+        tracker.track(&lookup);
+        assert_eq!(tracker.interrupt(lookup.unique()), InterruptAction::Send);
+
+        let bytes = fuse_interrupt_request(lookup.unique()).to_bytes();
+        let mut reader = VmReader::from(bytes.as_slice());
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        let interruptin = reader.read_val::<FuseInterruptIn>().unwrap();
+        assert_eq!(headerin.unique, 8 | FUSE_INT_REQ_BIT);
+        assert_eq!(interruptin.unique, 8);
+    }
+}
@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! An in-memory server that the tests of the synchronous operations share.
+//!
+//! The root directory holds regular files only. Reads and writes move at most
+//! [`MOCK_IO_SIZE`] bytes and READDIR returns [`MOCK_READDIR_ENTRIES`]
+//! entries, so that callers have to loop as they would against a real server.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{cell::RefCell, time::Duration};
+
+use super::{
+    dir::DirOps,
+    errno::{EACCES, ENOSPC},
+    error::{FuseError, Result},
+    file::{FileOps, O_TRUNC},
+    fuse::{FuseAttr, FuseDirent, FuseEntryOut, FUSE_ROOT_ID},
+    inode::InodeCache,
+    request::{FuseContext, FuseDirentWithName, WriteResult},
+    vfs::InodeOps,
+};
+
+/// The most bytes a READ or WRITE moves.
+pub const MOCK_IO_SIZE: usize = 5;
+/// The most entries a READDIR returns.
+pub const MOCK_READDIR_ENTRIES: usize = 2;
+
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+
+/// The handle `open` and `open_dir` return for `nodeid`.
+pub const fn mock_fh(nodeid: u64) -> u64 {
+    nodeid + 100
+}
+
+#[derive(Default)]
+pub struct MockServer {
+    /// The files of the root, by name: their node ID and contents.
+    pub files: RefCell<BTreeMap<Vec<u8>, (u64, Vec<u8>)>>,
+    /// The nodes of the files open.
+    pub open: RefCell<Vec<u64>>,
+    /// The lookups the server counted, given back on `abandon`.
+    pub inodes: RefCell<InodeCache>,
+    /// The directory handles released.
+    pub released_dirs: RefCell<Vec<u64>>,
+    /// The number of READDIRs served.
+    pub dir_reads: RefCell<usize>,
+    /// The credentials of every request.
+    pub callers: RefCell<Vec<FuseContext>>,
+    /// Whether OPEN fails with `EACCES`.
+    pub fail_opens: bool,
+    /// Whether WRITE fails with `ENOSPC`.
+    pub fail_writes: bool,
+    /// Whether RELEASEDIR fails, as if the device went away.
+    pub gone: bool,
+    /// Whether each READDIR starts with `.` again.
+    pub repeats_dot: bool,
+}
+
+impl MockServer {
+    /// Adds the file `name` holding `data` to the root, and returns its node ID.
+    pub fn add_file(&self, name: &[u8], data: &[u8]) -> u64 {
+        let mut files = self.files.borrow_mut();
+        let nodeid = files.len() as u64 + 2;
+        files.insert(name.to_vec(), (nodeid, data.to_vec()));
+        nodeid
+    }
+
+    fn name(&self, nodeid: u64) -> Result<Vec<u8>> {
+        let files = self.files.borrow();
+        let (name, _) = files
+            .iter()
+            .find(|(_, (id, _))| *id == nodeid)
+            .ok_or(FuseError::NoEnt)?;
+        Ok(name.clone())
+    }
+
+    fn attr(&self, nodeid: u64) -> Result<FuseAttr> {
+        let (mode, size) = if nodeid == FUSE_ROOT_ID {
+            (S_IFDIR | 0o755, 0)
+        } else {
+            let files = self.files.borrow();
+            let data = &files[&self.name(nodeid)?].1;
+            (S_IFREG | 0o644, data.len() as u64)
+        };
+        Ok(FuseAttr {
+            ino: nodeid,
+            mode,
+            size,
+            ..Default::default()
+        })
+    }
+
+    /// Replies with the entry of `nodeid`, counting a lookup of it.
+    fn entry(&self, nodeid: u64) -> Result<FuseEntryOut> {
+        let entry = FuseEntryOut {
+            nodeid,
+            attr: self.attr(nodeid)?,
+            ..Default::default()
+        };
+        self.inodes
+            .borrow_mut()
+            .insert_entry(&entry, Duration::ZERO);
+        Ok(entry)
+    }
+
+    fn called(&self, ctx: &FuseContext) {
+        self.callers.borrow_mut().push(*ctx);
+    }
+}
+
+impl FileOps for MockServer {
+    fn lookup(&self, ctx: &FuseContext, parent: u64, name: &[u8]) -> Result<FuseEntryOut> {
+        self.called(ctx);
+        if parent != FUSE_ROOT_ID {
+            return Err(FuseError::NoEnt);
+        }
+        let nodeid = self.files.borrow().get(name).ok_or(FuseError::NoEnt)?.0;
+        self.entry(nodeid)
+    }
+
+    fn open(&self, ctx: &FuseContext, nodeid: u64, flags: u32) -> Result<u64> {
+        self.called(ctx);
+        if self.fail_opens {
+            return Err(FuseError::ServerError(EACCES));
+        }
+        let name = self.name(nodeid)?;
+        if flags & O_TRUNC != 0 {
+            self.files.borrow_mut().get_mut(&name).unwrap().1.clear();
+        }
+        self.open.borrow_mut().push(nodeid);
+        Ok(mock_fh(nodeid))
+    }
+
+    fn create(
+        &self,
+        ctx: &FuseContext,
+        parent: u64,
+        name: &[u8],
+        _mode: u32,
+        flags: u32,
+    ) -> Result<(u64, u64)> {
+        self.called(ctx);
+        if parent != FUSE_ROOT_ID {
+            return Err(FuseError::NoEnt);
+        }
+        let nodeid = self.add_file(name, &[]);
+        self.entry(nodeid)?;
+        Ok((nodeid, self.open(ctx, nodeid, flags)?))
+    }
+
+    fn read_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>> {
+        self.called(ctx);
+        assert_eq!(fh, mock_fh(nodeid));
+        let files = self.files.borrow();
+        let data = &files[&self.name(nodeid)?].1;
+        let start = (offset as usize).min(data.len());
+        let end = (start + MOCK_IO_SIZE.min(size as usize)).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn write_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<WriteResult> {
+        self.called(ctx);
+        assert_eq!(fh, mock_fh(nodeid));
+        if self.fail_writes {
+            return Err(FuseError::ServerError(ENOSPC));
+        }
+        let name = self.name(nodeid)?;
+        let mut files = self.files.borrow_mut();
+        let file = &mut files.get_mut(&name).unwrap().1;
+        let len = data.len().min(MOCK_IO_SIZE);
+        file.resize((offset as usize + len).max(file.len()), 0);
+        file[offset as usize..][..len].copy_from_slice(&data[..len]);
+        Ok(WriteResult {
+            requested: data.len() as u32,
+            written: len as u32,
+        })
+    }
+
+    fn release(&self, ctx: &FuseContext, nodeid: u64, _fh: u64, _flags: u32) -> Result<()> {
+        self.called(ctx);
+        let mut open = self.open.borrow_mut();
+        let index = open.iter().position(|&id| id == nodeid).unwrap();
+        open.remove(index);
+        Ok(())
+    }
+
+    fn abandon(&self, nodeid: u64) {
+        self.inodes.borrow_mut().release_lookup(nodeid);
+    }
+}
+
+impl DirOps for MockServer {
+    fn open_dir(&self, ctx: &FuseContext, nodeid: u64) -> Result<u64> {
+        self.called(ctx);
+        if nodeid != FUSE_ROOT_ID {
+            return Err(FuseError::NotDir);
+        }
+        Ok(mock_fh(nodeid))
+    }
+
+    fn read_dir_at(
+        &self,
+        ctx: &FuseContext,
+        nodeid: u64,
+        _fh: u64,
+        offset: u64,
+        _size: u32,
+    ) -> Result<Vec<FuseDirentWithName>> {
+        self.called(ctx);
+        if nodeid != FUSE_ROOT_ID {
+            return Err(FuseError::NotDir);
+        }
+        *self.dir_reads.borrow_mut() += 1;
+        let entry = |ino, off, name: &[u8]| FuseDirentWithName {
+            dirent: FuseDirent {
+                ino,
+                off,
+                namelen: name.len() as u32,
+                ..Default::default()
+            },
+            name: name.to_vec(),
+        };
+        // The offset of an entry is its position in the directory, from 1.
+        let mut entries: Vec<_> = self
+            .files
+            .borrow()
+            .iter()
+            .zip(1..)
+            .skip(offset as usize)
+            .take(MOCK_READDIR_ENTRIES)
+            .map(|((name, (nodeid, _)), off)| entry(*nodeid, off, name.as_slice()))
+            .collect();
+        if self.repeats_dot && !entries.is_empty() {
+            entries.insert(0, entry(FUSE_ROOT_ID, offset, b"."));
+        }
+        Ok(entries)
+    }
+
+    fn release_dir(&self, ctx: &FuseContext, _nodeid: u64, fh: u64) -> Result<()> {
+        self.called(ctx);
+        if self.gone {
+            return Err(FuseError::Io);
+        }
+        self.released_dirs.borrow_mut().push(fh);
+        Ok(())
+    }
+}
+
+impl InodeOps for MockServer {
+    fn getattr(&self, ctx: &FuseContext, nodeid: u64) -> Result<FuseAttr> {
+        self.called(ctx);
+        self.attr(nodeid)
+    }
+}
@@ -1,8 +1,72 @@
 // SPDX-License-Identifier: MPL-2.0
 
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use ostd::sync::{LocalIrqDisabled, SpinLock};
+
+use self::device::FilesystemDevice;
+
+pub mod batch;
+pub mod completion;
 pub mod config;
+#[cfg(ktest)]
+mod conformance;
+pub mod deferred;
 pub mod device;
+pub mod dir;
+pub mod errno;
+pub mod error;
+pub mod file;
 pub mod fuse;
+pub mod handle;
+pub mod init;
+pub mod inode;
+pub mod interrupt;
+#[cfg(ktest)]
+mod mock;
+pub mod notify;
+pub mod page_cache;
+pub mod path;
+pub mod poll;
 pub mod request;
+pub mod retry;
+pub mod routing;
+pub mod shutdown;
+pub mod slot;
+pub mod stats;
+pub mod vfs;
+pub mod writeback;
 
 pub static DEVICE_NAME: &str = "Virtio-fs";
+
+/// The initialized devices, keyed by the tag they are mounted by.
+static DEVICE_TABLE: SpinLock<BTreeMap<String, Arc<FilesystemDevice>>, LocalIrqDisabled> =
+    SpinLock::new(BTreeMap::new());
+
+/// Makes `device` available to be mounted by `tag`.
+///
+/// Tags should be unique; a device whose tag is taken already is not
+/// registered, and `false` is returned.
+pub fn register_device(tag: String, device: Arc<FilesystemDevice>) -> bool {
+    let mut devices = DEVICE_TABLE.lock();
+    if devices.contains_key(&tag) {
+        return false;
+    }
+    devices.insert(tag, device);
+    true
+}
+
+/// Forgets the device mounted by `tag`, returning it.
+pub fn unregister_device(tag: &str) -> Option<Arc<FilesystemDevice>> {
+    DEVICE_TABLE.lock().remove(tag)
+}
+
+/// Returns the device mounted by `tag`.
+pub fn get_device(tag: &str) -> Option<Arc<FilesystemDevice>> {
+    DEVICE_TABLE.lock().get(tag).cloned()
+}
+
+/// Returns the tags of all the devices.
+pub fn all_tags() -> Vec<String> {
+    DEVICE_TABLE.lock().keys().cloned().collect()
+}
@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{vec, vec::Vec};
+
+use ostd::mm::{VmReader, VmWriter};
+
+use super::{
+    error::{FuseError, Result},
+    fuse::{
+        FuseNotifyCode, FuseNotifyInvalEntryOut, FuseNotifyInvalInodeOut, FuseNotifyPollWakeupOut,
+        FuseOutHeader,
+    },
+    request::fuse_read_val,
+};
+
+/// A message the server sent on the notification queue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FuseNotification {
+    /// The events polled for with the poll handle `kh` may be ready.
+    Poll { kh: u64 },
+    /// The cached data of `nodeid` from `offset` on, `len` bytes of it or all
+    /// if negative, and its attributes are stale.
+    InvalInode { nodeid: u64, offset: i64, len: i64 },
+    /// The entry `name` of the directory `parent` is stale.
+    InvalEntry { parent: u64, name: Vec<u8> },
+    /// A notification the driver does not act on, by its code.
+    Unhandled(i32),
+}
+
+/// Parses a notification message.
+///
+/// Notifications carry no `unique`, and their code in place of `error`; a
+/// message with a `unique` is a reply and fails with `Io`.
+pub fn fuse_parse_notify(message: &[u8]) -> Result<FuseNotification> {
+    let mut reader = VmReader::from(message);
+    let headerout = fuse_read_val::<FuseOutHeader>(&mut reader)?;
+    let len = headerout.len as usize;
+    if headerout.unique != 0 || len < size_of::<FuseOutHeader>() || len > message.len() {
+        return Err(FuseError::Io);
+    }
+    let mut reader = reader.limit(len - size_of::<FuseOutHeader>());
+
+    let notification = match headerout.error {
+        code if code == FuseNotifyCode::FuseNotifyPoll as i32 => {
+            let wakeup = fuse_read_val::<FuseNotifyPollWakeupOut>(&mut reader)?;
+            FuseNotification::Poll { kh: wakeup.kh }
+        }
+        code if code == FuseNotifyCode::FuseNotifyInvalInode as i32 => {
+            let inval = fuse_read_val::<FuseNotifyInvalInodeOut>(&mut reader)?;
+            FuseNotification::InvalInode {
+                nodeid: inval.ino,
+                offset: inval.off,
+                len: inval.len,
+            }
+        }
+        code if code == FuseNotifyCode::FuseNotifyInvalEntry as i32 => {
+            let inval = fuse_read_val::<FuseNotifyInvalEntryOut>(&mut reader)?;
+            // The name is followed by a NUL, which is not counted in `namelen`.
+            if reader.remain() < inval.namelen as usize {
+                return Err(FuseError::Io);
+            }
+            let mut name = vec![0u8; inval.namelen as usize];
+            reader.read(&mut VmWriter::from(name.as_mut_slice()));
+            FuseNotification::InvalEntry {
+                parent: inval.parent,
+                name,
+            }
+        }
+        code => FuseNotification::Unhandled(code),
+    };
+    Ok(notification)
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::{prelude::*, Pod};
+
+    use super::*;
+
+    fn message(code: FuseNotifyCode, body: &[u8]) -> Vec<u8> {
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + body.len()) as u32,
+            error: code as i32,
+            unique: 0,
+        };
+        [header.as_bytes(), body].concat()
+    }
+
+    #[ktest]
+    fn invalidations_are_parsed() {
+        let inval = FuseNotifyInvalInodeOut {
+            ino: 5,
+            off: 0,
+            len: -1,
+        };
+        let parsed = fuse_parse_notify(&message(
+            FuseNotifyCode::FuseNotifyInvalInode,
+            inval.as_bytes(),
+        ));
+        assert_eq!(
+            parsed.unwrap(),
+            FuseNotification::InvalInode {
+                nodeid: 5,
+                offset: 0,
+                len: -1
+            }
+        );
+
+        let inval = FuseNotifyInvalEntryOut {
+            parent: 1,
+            namelen: 5,
+            flags: 0,
+        };
+        let body = [inval.as_bytes(), b"hello\0"].concat();
+        let parsed = fuse_parse_notify(&message(FuseNotifyCode::FuseNotifyInvalEntry, &body));
+        assert_eq!(
+            parsed.unwrap(),
+            FuseNotification::InvalEntry {
+                parent: 1,
+                name: b"hello".to_vec()
+            }
+        );
+
+        // A name running past the message is rejected.
+        let body = [inval.as_bytes(), b"hel"].concat();
+        let parsed = fuse_parse_notify(&message(FuseNotifyCode::FuseNotifyInvalEntry, &body));
+        assert!(matches!(parsed, Err(FuseError::Io)));
+    }
+
+    #[ktest]
+    fn replies_are_not_notifications() {
+        let mut reply = message(FuseNotifyCode::FuseNotifyStore, &[]);
+        assert_eq!(
+            fuse_parse_notify(&reply).unwrap(),
+            FuseNotification::Unhandled(FuseNotifyCode::FuseNotifyStore as i32)
+        );
+        // Set the `unique` of the header.
+        reply[8] = 2;
+        assert!(matches!(fuse_parse_notify(&reply), Err(FuseError::Io)));
+    }
+}
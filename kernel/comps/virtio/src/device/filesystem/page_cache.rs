@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use super::fuse::FOPEN_KEEP_CACHE;
+
+/// The most bytes of file data the page cache holds by default.
+pub const FUSE_PAGE_CACHE_LIMIT: usize = 4 * 1024 * 1024;
+
+/// The data one READ returned, and the size it asked for.
+#[derive(Debug)]
+struct CachedRead {
+    data: Vec<u8>,
+    /// The size asked for; data shorter than it ended at the end of the file.
+    size: u32,
+    /// When the read was last hit, by the clock of the cache.
+    last_used: u64,
+}
+
+/// The file data read from the server, keyed by node ID and then by offset.
+///
+/// At most `limit` bytes are held; past that, the reads hit longest ago are
+/// dropped first. A node's data is dropped as soon as it may have changed:
+/// on a write or truncate through the driver, and on an open without
+/// `FOPEN_KEEP_CACHE`.
+#[derive(Debug)]
+pub struct PageCache {
+    files: BTreeMap<u64, BTreeMap<u64, CachedRead>>,
+    bytes: usize,
+    limit: usize,
+    clock: u64,
+}
+
+impl PageCache {
+    pub const fn new() -> Self {
+        Self::with_limit(FUSE_PAGE_CACHE_LIMIT)
+    }
+
+    /// Creates a cache that holds at most `limit` bytes of file data.
+    pub const fn with_limit(limit: usize) -> Self {
+        Self {
+            files: BTreeMap::new(),
+            bytes: 0,
+            limit,
+            clock: 0,
+        }
+    }
+
+    /// Caches `data`, which a READ of `size` bytes at `offset` of `nodeid`
+    /// returned.
+    pub fn insert(&mut self, nodeid: u64, offset: u64, size: u32, data: Vec<u8>) {
+        if data.len() > self.limit {
+            return;
+        }
+        self.clock += 1;
+        let read = CachedRead {
+            data,
+            size,
+            last_used: self.clock,
+        };
+        self.bytes += read.data.len();
+        if let Some(old) = self.files.entry(nodeid).or_default().insert(offset, read) {
+            self.bytes -= old.data.len();
+        }
+        while self.bytes > self.limit {
+            self.evict();
+        }
+    }
+
+    /// Returns the cached data a READ of `size` bytes at `offset` of `nodeid`
+    /// would return, if a read of at least as many bytes there is cached.
+    pub fn get(&mut self, nodeid: u64, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let read = self.files.get_mut(&nodeid)?.get_mut(&offset)?;
+        if read.size < size {
+            return None;
+        }
+        self.clock += 1;
+        read.last_used = self.clock;
+        Some(read.data[..read.data.len().min(size as usize)].to_vec())
+    }
+
+    /// Returns the number of bytes of file data held.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Drops all the cached data of `nodeid`.
+    pub fn invalidate(&mut self, nodeid: u64) {
+        if let Some(reads) = self.files.remove(&nodeid) {
+            self.bytes -= reads.values().map(|read| read.data.len()).sum::<usize>();
+        }
+    }
+
+    /// Applies the `open_flags` of an OPEN or CREATE reply for `nodeid`.
+    ///
+    /// Unless the server sets `FOPEN_KEEP_CACHE`, the file may have changed
+    /// since it was last cached, so the cached data is dropped.
+    pub fn open(&mut self, nodeid: u64, open_flags: u32) {
+        if open_flags & FOPEN_KEEP_CACHE == 0 {
+            self.invalidate(nodeid);
+        }
+    }
+
+    /// Drops the read hit longest ago.
+    fn evict(&mut self) {
+        let Some((nodeid, offset)) = self
+            .files
+            .iter()
+            .flat_map(|(&nodeid, reads)| {
+                reads
+                    .iter()
+                    .map(move |(&offset, read)| (read.last_used, nodeid, offset))
+            })
+            .min()
+            .map(|(_, nodeid, offset)| (nodeid, offset))
+        else {
+            return;
+        };
+        let reads = self.files.get_mut(&nodeid).unwrap();
+        if let Some(read) = reads.remove(&offset) {
+            self.bytes -= read.data.len();
+        }
+        if reads.is_empty() {
+            self.files.remove(&nodeid);
+        }
+    }
+}
+
+impl Default for PageCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::vec;
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn reopen_honors_keep_cache() {
+        let mut cache = PageCache::new();
+        cache.insert(5, 0, 3, vec![1, 2, 3]);
+
+        cache.open(5, FOPEN_KEEP_CACHE);
+        assert_eq!(cache.get(5, 0, 3), Some(vec![1, 2, 3]));
+
+        cache.open(5, 0);
+        assert_eq!(cache.get(5, 0, 3), None);
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[ktest]
+    fn open_leaves_other_nodes_cached() {
+        let mut cache = PageCache::new();
+        cache.insert(5, 0, 1, vec![1]);
+        cache.insert(6, 4096, 1, vec![2]);
+
+        cache.open(5, 0);
+        assert_eq!(cache.get(5, 0, 1), None);
+        assert_eq!(cache.get(6, 4096, 1), Some(vec![2]));
+    }
+
+    #[ktest]
+    fn larger_read_than_cached_misses() {
+        let mut cache = PageCache::new();
+        cache.insert(5, 0, 4, vec![1, 2, 3, 4]);
+        // A short read ended at the end of the file, so it serves larger ones.
+        cache.insert(5, 8, 16, vec![9]);
+
+        assert_eq!(cache.get(5, 0, 2), Some(vec![1, 2]));
+        assert_eq!(cache.get(5, 0, 8), None);
+        assert_eq!(cache.get(5, 8, 16), Some(vec![9]));
+        assert_eq!(cache.get(5, 4, 4), None);
+    }
+
+    #[ktest]
+    fn least_recently_hit_read_is_evicted() {
+        let mut cache = PageCache::with_limit(4);
+        cache.insert(5, 0, 2, vec![1, 2]);
+        cache.insert(6, 0, 2, vec![3, 4]);
+        assert!(cache.get(5, 0, 2).is_some());
+
+        cache.insert(7, 0, 2, vec![5, 6]);
+        assert_eq!(cache.bytes(), 4);
+        assert_eq!(cache.get(6, 0, 2), None);
+        assert!(cache.get(5, 0, 2).is_some());
+        assert!(cache.get(7, 0, 2).is_some());
+
+        // Data larger than the whole cache is not kept at all.
+        cache.insert(8, 0, 8, vec![0; 8]);
+        assert_eq!(cache.get(8, 0, 8), None);
+        assert_eq!(cache.bytes(), 4);
+    }
+}
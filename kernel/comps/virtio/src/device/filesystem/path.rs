@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use super::{
+    error::{FuseError, Result},
+    fuse::{FuseAttr, FuseEntryOut},
+};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+pub fn fuse_attr_is_dir(attr: &FuseAttr) -> bool {
+    attr.mode & S_IFMT == S_IFDIR
+}
+
+/// Walks `path` from the directory `root`, looking up one component at a time.
+///
+/// Empty and `.` components are skipped; the path must name at least one other
+/// component. Every component but the last must be a directory, so the walk
+/// fails with `NotDir` instead of looking up under a non-directory.
+pub fn walk_path<F>(root: u64, path: &[u8], mut lookup: F) -> Result<FuseEntryOut>
+where
+    F: FnMut(u64, &[u8]) -> Result<FuseEntryOut>,
+{
+    let mut components = path
+        .split(|&byte| byte == b'/')
+        .filter(|name| !name.is_empty() && *name != b".")
+        .peekable();
+
+    let mut parent = root;
+    while let Some(name) = components.next() {
+        let entry = lookup(parent, name)?;
+        if components.peek().is_none() {
+            return Ok(entry);
+        }
+        if !fuse_attr_is_dir(&entry.attr) {
+            return Err(FuseError::NotDir);
+        }
+        parent = entry.nodeid;
+    }
+    Err(FuseError::Inval)
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::vec::Vec;
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    /// A tree of `/file` and `/dir/child`, with the root being node 1.
+    fn mock_lookup(
+        lookups: &mut Vec<(u64, Vec<u8>)>,
+        parent: u64,
+        name: &[u8],
+    ) -> Result<FuseEntryOut> {
+        lookups.push((parent, name.to_vec()));
+        let (nodeid, mode) = match (parent, name) {
+            (1, b"file") => (2, 0o100644),
+            (1, b"dir") => (3, 0o040755),
+            (3, b"child") => (4, 0o100644),
+            _ => return Err(FuseError::NoEnt),
+        };
+        Ok(FuseEntryOut {
+            nodeid,
+            attr: FuseAttr {
+                ino: nodeid,
+                mode,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[ktest]
+    fn walk_under_regular_file_is_not_dir() {
+        let mut lookups = Vec::new();
+        let result = walk_path(1, b"file/child", |parent, name| {
+            mock_lookup(&mut lookups, parent, name)
+        });
+
+        assert!(matches!(result, Err(FuseError::NotDir)));
+        assert_eq!(lookups, [(1, b"file".to_vec())]);
+    }
+
+    #[ktest]
+    fn walk_resolves_nested_path() {
+        let mut lookups = Vec::new();
+        let entry = walk_path(1, b"/dir/./child", |parent, name| {
+            mock_lookup(&mut lookups, parent, name)
+        })
+        .unwrap();
+
+        assert_eq!(entry.nodeid, 4);
+        assert_eq!(lookups, [(1, b"dir".to_vec()), (3, b"child".to_vec())]);
+    }
+}
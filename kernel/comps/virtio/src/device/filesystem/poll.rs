@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use ostd::{mm::VmReader, sync::Waker};
+
+use super::{
+    error::{FuseError, Result},
+    fuse::{FuseNotifyCode, FuseNotifyPollWakeupOut, FuseOutHeader},
+};
+
+/// The pollers waiting for FUSE_NOTIFY_POLL, keyed by the poll handle (`kh`)
+/// given to the server in their FUSE_POLL.
+pub struct PollHandles {
+    wakers: BTreeMap<u64, Arc<Waker>>,
+    next_kh: u64,
+}
+
+impl Default for PollHandles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PollHandles {
+    pub const fn new() -> Self {
+        Self {
+            wakers: BTreeMap::new(),
+            next_kh: 1,
+        }
+    }
+
+    /// Registers a poller and returns the poll handle to send with its FUSE_POLL.
+    pub fn register(&mut self, waker: Arc<Waker>) -> u64 {
+        let kh = self.next_kh;
+        self.next_kh += 1;
+        self.wakers.insert(kh, waker);
+        kh
+    }
+
+    /// Forgets a poller that no longer waits, e.g. because FUSE_POLL reported
+    /// the events as ready already.
+    pub fn unregister(&mut self, kh: u64) {
+        self.wakers.remove(&kh);
+    }
+
+    /// Handles a notification from the server.
+    ///
+    /// A FUSE_NOTIFY_POLL wakes and forgets the poller of its `kh`; other
+    /// notifications are ignored. Returns whether a poller was woken.
+    pub fn handle_notify(&mut self, message: &[u8]) -> Result<bool> {
+        let mut reader = VmReader::from(message);
+        let headerout = reader
+            .read_val::<FuseOutHeader>()
+            .map_err(|_| FuseError::Io)?;
+        // Notifications carry no `unique`, and their code in place of `error`.
+        if headerout.unique != 0 || headerout.error != FuseNotifyCode::FuseNotifyPoll as i32 {
+            return Ok(false);
+        }
+        let wakeup = reader
+            .read_val::<FuseNotifyPollWakeupOut>()
+            .map_err(|_| FuseError::Io)?;
+
+        let Some(waker) = self.wakers.remove(&wakeup.kh) else {
+            return Ok(false);
+        };
+        waker.wake_up();
+        Ok(true)
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::vec::Vec;
+
+    use ostd::{prelude::*, sync::Waiter, Pod};
+
+    use super::*;
+
+    fn notify_poll(kh: u64) -> Vec<u8> {
+        let wakeup = FuseNotifyPollWakeupOut { kh };
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + size_of::<FuseNotifyPollWakeupOut>()) as u32,
+            error: FuseNotifyCode::FuseNotifyPoll as i32,
+            unique: 0,
+        };
+        [header.as_bytes(), wakeup.as_bytes()].concat()
+    }
+
+    #[ktest]
+    fn notify_poll_wakes_poller() {
+        let mut handles = PollHandles::new();
+        let (waiter, waker) = Waiter::new_pair();
+        let kh = handles.register(waker.clone());
+
+        assert!(handles.handle_notify(&notify_poll(kh)).unwrap());
+        // Already woken, so waiting returns at once.
+        assert!(!waker.wake_up());
+        waiter.wait();
+
+        // The handle is drained by the notification.
+        assert!(!handles.handle_notify(&notify_poll(kh)).unwrap());
+    }
+
+    #[ktest]
+    fn notify_poll_for_unknown_handle() {
+        let mut handles = PollHandles::new();
+        let (_waiter, waker) = Waiter::new_pair();
+        let kh = handles.register(waker.clone());
+        handles.unregister(kh);
+
+        assert!(!handles.handle_notify(&notify_poll(kh)).unwrap());
+        assert!(waker.wake_up());
+    }
+}
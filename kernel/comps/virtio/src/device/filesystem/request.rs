@@ -3,13 +3,18 @@
 use alloc::{vec, vec::Vec};
 use core::fmt::Debug;
 
+use log::warn;
 use ostd::{
     early_print,
-    mm::{VmReader, VmWriter},
+    mm::{DmaStream, DmaStreamSlice, VmReader, VmWriter, PAGE_SIZE},
     Pod,
 };
 
-use super::fuse::*;
+use super::{
+    errno::ENOSYS,
+    error::{errno_to_fuse_error, FuseError, Result},
+    fuse::*,
+};
 
 pub trait AnyFuseDevice {
     // Send Init Request to Device.
@@ -38,76 +43,186 @@ pub trait AnyFuseDevice {
         uid: u32,
         gid: u32,
     );
-    fn lookup(&self, nodeid: u64, name: Vec<u8>);
+    fn lookup(&self, nodeid: u64, name: Vec<u8>) -> Result<()>;
     fn release(&self, nodeid: u64, fh: u64, flags: u32, lock_owner: u64, flush: bool);
     fn access(&self, nodeid: u64, mask: u32);
     fn statfs(&self, nodeid: u64);
+    /// Interrupts the request in flight whose unique is `unique`.
     fn interrupt(&self, unique: u64);
-    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]);
+    /// Writes all of `data` at `offset` of the open file `fh` and returns the
+    /// number of bytes the server wrote.
+    fn write(&self, nodeid: u64, fh: u64, offset: u64, data: &[u8]) -> Result<usize>;
     // fn interrupt(&self, nodeid: u64, fh: u64, lock_owner: u64, unique: u64);
-    fn mkdir(&self, nodeid: u64, mode: u32, umask: u32, name: Vec<u8>);
-    fn create(&self, nodeid: u64, name: Vec<u8>, mode: u32, umask: u32, flags: u32);
+    fn mkdir(
+        &self,
+        nodeid: u64,
+        mode: u32,
+        umask: u32,
+        name: Vec<u8>,
+        supp_group: Option<u32>,
+    ) -> Result<()>;
+    fn create(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        umask: u32,
+        flags: u32,
+        supp_group: Option<u32>,
+    ) -> Result<()>;
     fn destroy(&self);
-    fn rename(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>);
-    fn rename2(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>, flags: u32);
+    fn rename(&self, nodeid: u64, name: Vec<u8>, newdir: u64, newname: Vec<u8>) -> Result<()>;
+    fn rename2(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        newdir: u64,
+        newname: Vec<u8>,
+        flags: u32,
+    ) -> Result<()>;
     fn forget(&self, nodeid: u64, nlookup: u64);
     fn batch_forget(&self, forget_list: &[(u64, u64)]);
-    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>);
-    fn unlink(&self, nodeid: u64, name: Vec<u8>);
+    fn link(&self, nodeid: u64, oldnodeid: u64, name: Vec<u8>) -> Result<()>;
+    fn unlink(&self, nodeid: u64, name: Vec<u8>) -> Result<()>;
 
     fn bmap(&self, nodeid: u64, blocksize: u32, index: u64);
     fn fallocate(&self, nodeid: u64, fh: u64, offset: u64, length: u64, mode: u32);
-    fn fsync(&self, nodeid: u64, fh: u64, datasync: u32);
-    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: u32);
-    fn getlk(
-        &self,
-        nodeid: u64,
-        fh: u64,
-        lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: u32,
-        pid: u32,
-    );
-    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32);
-    fn ioctl(&self, nodeid: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8]);
+    fn fsync(&self, nodeid: u64, fh: u64, datasync: bool);
+    fn fsyncdir(&self, nodeid: u64, fh: u64, datasync: bool);
+    fn getlk(&self, nodeid: u64, fh: u64, lock_owner: u64, lock: FuseFileLock, flags: u32);
+    fn getxattr(&self, nodeid: u64, name: Vec<u8>, size: u32) -> Result<()>;
+    fn setxattr(&self, nodeid: u64, name: Vec<u8>, value: Vec<u8>, flags: u32) -> Result<()>;
+    fn ioctl(&self, nodeid: u64, fh: u64, flags: u32, cmd: u32, in_data: &[u8]) -> Result<()>;
     fn listxattr(&self, nodeid: u64, size: u32);
     fn lseek(&self, nodeid: u64, fh: u64, offset: u64, whence: u32);
-    fn mknod(&self, nodeid: u64, name: Vec<u8>, mode: u32, rdev: u32);
-    fn poll(&self, nodeid: u64, fh: u64, events: u32);
-    // fn readdirplus(&self, nodeid: u64, fh: u64, offset: u64, size: u32);
+    fn mknod(
+        &self,
+        nodeid: u64,
+        name: Vec<u8>,
+        mode: u32,
+        rdev: u32,
+        umask: u32,
+        supp_group: Option<u32>,
+    ) -> Result<()>;
+    fn poll(&self, nodeid: u64, fh: u64, kh: u64, flags: u32, events: u32);
+    fn readdirplus(&self, nodeid: u64, fh: u64, offset: u64, size: u32);
     fn readlink(&self, nodeid: u64);
-    fn removexattr(&self, nodeid: u64, name: Vec<u8>);
-    fn rmdir(&self, nodeid: u64, name: Vec<u8>);
-    fn setlk(
+    fn removexattr(&self, nodeid: u64, name: Vec<u8>) -> Result<()>;
+    fn rmdir(&self, nodeid: u64, name: Vec<u8>) -> Result<()>;
+    fn setlk(&self, nodeid: u64, fh: u64, lock_owner: u64, lock: FuseFileLock, flags: u32);
+    fn setlkw(&self, nodeid: u64, fh: u64, lock_owner: u64, lock: FuseFileLock, flags: u32);
+    fn symlink(
         &self,
         nodeid: u64,
-        fh: u64,
-        lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: u32,
-        pid: u32,
-        sleep: u32,
-    );
-    fn setlkw(
+        name: Vec<u8>,
+        link: Vec<u8>,
+        supp_group: Option<u32>,
+    ) -> Result<()>;
+    fn setupmapping(
         &self,
         nodeid: u64,
         fh: u64,
-        lock_owner: u64,
-        start: u64,
-        end: u64,
-        typ: u32,
-        pid: u32,
-        sleep: u32,
-    );
-    fn symlink(&self, nodeid: u64, name: Vec<u8>, link: Vec<u8>);
+        foffset: u64,
+        len: u64,
+        flags: u64,
+        moffset: u64,
+    ) -> Result<()>;
+    fn removemapping(
+        &self,
+        nodeid: u64,
+        count: u32,
+        mappings: &[FuseRemoveMappingOne],
+    ) -> Result<()>;
 }
 
-pub fn fuse_pad_str(name: &str, repr_c: bool) -> Vec<u8> {
+/// Rejects an empty name or a name containing a NUL byte.
+///
+/// Names are sent NUL-terminated, so the server would silently truncate the
+/// name at the first interior NUL and operate on a different file. An empty
+/// name never names a file, and would only be sent as a lone NUL.
+pub fn fuse_check_name(name: &[u8]) -> Result<()> {
+    if name.is_empty() || name.contains(&0) {
+        return Err(FuseError::Inval);
+    }
+    Ok(())
+}
+
+/// Rejects a name longer than `name_max`, the limit the server reported in
+/// STATFS, if it is known.
+pub fn fuse_check_name_len(name: &[u8], name_max: Option<u32>) -> Result<()> {
+    match name_max {
+        Some(name_max) if name.len() > name_max as usize => Err(FuseError::NameTooLong),
+        _ => Ok(()),
+    }
+}
+
+pub fn fuse_statfs_request(nodeid: u64) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseStatfs, nodeid).reserve_reply(0)
+}
+
+/// Returns the bytes available to unprivileged users, as `df` reports them.
+///
+/// Block counts are in units of `frsize`; a server that leaves it zero means
+/// `bsize`, as Linux takes it.
+pub fn fuse_statfs_avail_bytes(st: &FuseKstatfs) -> u64 {
+    let frsize = match st.frsize {
+        0 => st.bsize,
+        frsize => frsize,
+    };
+    st.bavail.saturating_mul(frsize as u64)
+}
+
+/// Returns the longest name the server accepts from a STATFS reply.
+///
+/// A server that does not implement STATFS, or reports no limit, yields `None`.
+pub fn fuse_statfs_namelen(reply: &[u8]) -> Result<Option<u32>> {
+    match fuse_reply_val::<FuseStatfsOut>(reply) {
+        Ok(statfs) => Ok(Some(statfs.st.namelen).filter(|&namelen| namelen != 0)),
+        Err(FuseError::ServerError(ENOSYS)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Splits a reply into its header and payload.
+///
+/// A reply carrying a non-zero `error` is turned into the matching error.
+pub fn fuse_parse_reply(reply: &[u8]) -> Result<(FuseOutHeader, &[u8])> {
+    let header = VmReader::from(reply)
+        .read_val::<FuseOutHeader>()
+        .map_err(|_| FuseError::Io)?;
+    fuse_check_reply(&header)?;
+    let len = header.len as usize;
+    if len < size_of::<FuseOutHeader>() || len > reply.len() {
+        return Err(FuseError::Io);
+    }
+    Ok((header, &reply[size_of::<FuseOutHeader>()..len]))
+}
+
+/// Fails with the errno of an error reply, which carries no body to read.
+pub fn fuse_check_reply(header: &FuseOutHeader) -> Result<()> {
+    if header.error != 0 {
+        return Err(errno_to_fuse_error(header.error));
+    }
+    Ok(())
+}
+
+/// Reads the fixed-size payload of a reply.
+pub fn fuse_reply_val<T: Pod>(reply: &[u8]) -> Result<T> {
+    let (_, payload) = fuse_parse_reply(reply)?;
+    VmReader::from(payload)
+        .read_val::<T>()
+        .map_err(|_| FuseError::Io)
+}
+
+/// Returns `name` as sent on the wire, NUL-terminated if `repr_c` is set and
+/// padded to a multiple of 8 bytes.
+///
+/// Names are raw bytes: any byte but `/` and NUL may appear in them, whether
+/// or not they form valid UTF-8.
+pub fn fuse_pad_str(name: &[u8], repr_c: bool) -> Vec<u8> {
     let name_len = name.len() as u32 + if repr_c { 1 } else { 0 };
     let name_pad_len = name_len + ((8 - (name_len & 0x7)) & 0x7); //Pad to multiple of 8 bytes
-    let mut prepared_name: Vec<u8> = name.as_bytes().to_vec();
+    let mut prepared_name: Vec<u8> = name.to_vec();
     prepared_name.resize(name_pad_len as usize, 0);
     prepared_name
 }
@@ -125,20 +240,1022 @@ pub struct VirtioFsReq {
 }
 
 impl VirtioFsReq {
+    /// Returns the length of the device-readable part: the header and `datain`.
+    pub fn in_len(&self) -> usize {
+        size_of::<FuseInHeader>() + self.datain.len()
+    }
+
+    /// Returns the length of the device-writable part: the reply header and
+    /// `dataout`.
+    pub fn out_len(&self) -> usize {
+        size_of::<FuseOutHeader>() + self.dataout.len()
+    }
+
+    /// Returns the device-readable part, to be submitted as its own slice.
+    pub fn in_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.in_len());
+        bytes.extend_from_slice(self.headerin.as_bytes());
+        bytes.extend_from_slice(&self.datain);
+        bytes
+    }
+
+    /// Returns the device-writable part, to be submitted as its own slice.
+    pub fn out_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.out_len());
+        bytes.extend_from_slice(self.headerout.as_bytes());
+        bytes.extend_from_slice(&self.dataout);
+        bytes
+    }
+
+    /// Returns the whole request: the readable part followed by the writable
+    /// part, which starts at [`Self::in_len`].
     pub fn into_bytes(&self) -> Vec<u8> {
-        let fuse_in_header = self.headerin.as_bytes();
-        let datain = self.datain.as_slice();
-        let fuse_out_header = self.headerout.as_bytes();
-        let dataout = self.dataout.as_slice();
+        let mut bytes = self.in_bytes();
+        bytes.extend_from_slice(&self.out_bytes());
+        bytes
+    }
+}
+
+/// The size of the longest symlink target, including its terminating NUL.
+pub const FUSE_PATH_MAX: usize = 4096;
+
+/// Returns the symlink target carried by a READLINK reply.
+///
+/// The reply region holds `FUSE_PATH_MAX` bytes of payload and a target is
+/// always shorter, so a full region means the target was truncated.
+pub fn fuse_readlink_target(reply: &[u8]) -> Result<Vec<u8>> {
+    let (_, payload) = fuse_parse_reply(reply)?;
+    if payload.len() >= FUSE_PATH_MAX {
+        return Err(FuseError::NameTooLong);
+    }
+    Ok(payload.to_vec())
+}
+
+/// Returns the data of a READ reply of `size` bytes asked for.
+///
+/// A server that sends more than that is cut short, so that the caller never
+/// sees bytes outside the window it read.
+pub fn fuse_read_data(reply: &[u8], size: u32) -> Result<&[u8]> {
+    let (_, data) = fuse_parse_reply(reply)?;
+    if data.len() > size as usize {
+        warn!(
+            "READ of {} bytes answered with {}; dropping the rest",
+            size,
+            data.len()
+        );
+        return Ok(&data[..size as usize]);
+    }
+    Ok(data)
+}
+
+/// The largest payload a single READ or READDIR request asks for.
+pub const FUSE_MAX_READ_SIZE: u32 = 64 * 1024;
+
+/// The pages a request buffer needs for the largest READ or READDIR reply
+/// plus the request headers.
+pub const FUSE_REQUEST_BUFFER_PAGES: usize = FUSE_MAX_READ_SIZE as usize / PAGE_SIZE + 1;
+
+/// The most pages a request buffer grows to for a request that does not fit.
+pub const FUSE_MAX_BUFFER_PAGES: usize = 256;
+
+/// Returns the pages a request buffer needs to hold a request of `len` bytes,
+/// or `None` if that is more than [`FUSE_MAX_BUFFER_PAGES`].
+pub fn fuse_buffer_pages(len: usize) -> Option<usize> {
+    Some(len.div_ceil(PAGE_SIZE)).filter(|&pages| pages <= FUSE_MAX_BUFFER_PAGES)
+}
+
+/// Returns the largest READ or READDIR size whose request and reply fit in a
+/// request buffer of `capacity` bytes.
+pub fn fuse_read_capacity(capacity: usize) -> u32 {
+    let overhead = size_of::<FuseInHeader>() + size_of::<FuseReadIn>() + size_of::<FuseOutHeader>();
+    capacity
+        .saturating_sub(overhead)
+        .min(FUSE_MAX_READ_SIZE as usize) as u32
+}
+
+/// Builds a READ or READDIR request whose reply region holds exactly `size`
+/// bytes of payload.
+///
+/// `size` is clamped to `FUSE_MAX_READ_SIZE`; the server may return less than
+/// asked for anyway.
+pub fn fuse_read_request(
+    opcode: FuseOpcode,
+    nodeid: u64,
+    fh: u64,
+    offset: u64,
+    size: u32,
+) -> RequestBuilder {
+    let size = size.min(FUSE_MAX_READ_SIZE);
+    let readin = FuseReadIn {
+        fh: fh,
+        offset: offset,
+        size: size,
+        read_flags: 0,
+        lock_owner: 0,
+        flags: 0,
+        padding: 0,
+    };
+    RequestBuilder::new(opcode, nodeid)
+        .push_struct(&readin)
+        .reserve_reply(size)
+}
+
+/// Returns whether the server replies to `opcode`.
+///
+/// FORGET and BATCH_FORGET are never answered; they get no reply region, and
+/// nothing waits on them.
+pub fn fuse_has_reply(opcode: FuseOpcode) -> bool {
+    !matches!(opcode, FuseOpcode::FuseForget | FuseOpcode::FuseBatchForget)
+}
+
+/// Returns the first protocol minor version that knows `opcode`.
+///
+/// Opcodes not listed date from before 7.11 and are taken as always known.
+pub fn fuse_min_minor(opcode: FuseOpcode) -> u32 {
+    match opcode {
+        FuseOpcode::FuseIoctl | FuseOpcode::FusePoll => 11,
+        FuseOpcode::FuseNotifyReply => 15,
+        FuseOpcode::FuseBatchForget => 16,
+        FuseOpcode::FuseFallocate => 19,
+        FuseOpcode::FuseReaddirplus => 21,
+        FuseOpcode::FuseRename2 => 23,
+        FuseOpcode::FuseLseek => 24,
+        FuseOpcode::FuseCopyFileRange => 28,
+        FuseOpcode::FuseSetupmapping | FuseOpcode::FuseRemovemapping => 31,
+        FuseOpcode::FuseSyncfs => 34,
+        FuseOpcode::FuseTmpfile => 37,
+        FuseOpcode::FuseStatx => 39,
+        _ => 0,
+    }
+}
+
+/// Fails with [`FuseError::Unsupported`] if a server speaking minor version
+/// `minor` cannot parse `opcode`.
+pub fn fuse_check_minor(opcode: FuseOpcode, minor: u32) -> Result<()> {
+    if fuse_min_minor(opcode) > minor {
+        return Err(FuseError::Unsupported);
+    }
+    Ok(())
+}
+
+/// Returns the size of the reply region to reserve for `opcode`.
+///
+/// A reply of a fixed structure needs only that, while one carrying data
+/// needs room for the `requested` bytes.
+pub fn fuse_out_capacity(opcode: FuseOpcode, requested: u32) -> usize {
+    if !fuse_has_reply(opcode) {
+        return 0;
+    }
+    let body = match opcode {
+        FuseOpcode::FuseLookup
+        | FuseOpcode::FuseMknod
+        | FuseOpcode::FuseMkdir
+        | FuseOpcode::FuseSymlink
+        | FuseOpcode::FuseLink => size_of::<FuseEntryOut>(),
+        FuseOpcode::FuseCreate => size_of::<FuseEntryOut>() + size_of::<FuseOpenOut>(),
+        FuseOpcode::FuseGetattr | FuseOpcode::FuseSetattr => size_of::<FuseAttrOut>(),
+        FuseOpcode::FuseOpen | FuseOpcode::FuseOpendir => size_of::<FuseOpenOut>(),
+        FuseOpcode::FuseStatfs => size_of::<FuseStatfsOut>(),
+        FuseOpcode::FuseWrite => size_of::<FuseWriteOut>(),
+        FuseOpcode::FuseInit => size_of::<FuseInitOut>(),
+        FuseOpcode::FusePoll => size_of::<FusePollOut>(),
+        FuseOpcode::FuseLseek => size_of::<FuseLseekOut>(),
+        FuseOpcode::FuseBmap => size_of::<FuseBmapOut>(),
+        FuseOpcode::FuseGetlk => size_of::<FuseLkOut>(),
+        // A zero size asks only for the size of the attribute or list.
+        FuseOpcode::FuseGetxattr | FuseOpcode::FuseListxattr if requested == 0 => {
+            size_of::<FuseGetxattrOut>()
+        }
+        FuseOpcode::FuseRead
+        | FuseOpcode::FuseReaddir
+        | FuseOpcode::FuseReaddirplus
+        | FuseOpcode::FuseReadlink
+        | FuseOpcode::FuseGetxattr
+        | FuseOpcode::FuseListxattr => requested as usize,
+        _ => 0,
+    };
+    size_of::<FuseOutHeader>() + body
+}
+
+pub fn fuse_lookup_request(nodeid: u64, name: &[u8]) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseLookup, nodeid)
+        .push_name(name)
+        .reserve_reply(0)
+}
+
+pub fn fuse_mkdir_request(nodeid: u64, mode: u32, umask: u32, name: &[u8]) -> RequestBuilder {
+    let mkdirin = FuseMkdirIn {
+        mode: mode,
+        umask: umask,
+    };
+    RequestBuilder::new(FuseOpcode::FuseMkdir, nodeid)
+        .push_struct(&mkdirin)
+        .push_name(name)
+        .reserve_reply(0)
+}
+
+pub fn fuse_create_request(
+    nodeid: u64,
+    name: &[u8],
+    mode: u32,
+    umask: u32,
+    flags: u32,
+) -> RequestBuilder {
+    let createin = FuseCreateIn {
+        flags: flags,
+        mode: mode,
+        umask: umask,
+        open_flags: 0,
+    };
+    RequestBuilder::new(FuseOpcode::FuseCreate, nodeid)
+        .push_struct(&createin)
+        .push_name(name)
+        .reserve_reply(0)
+}
+
+/// Like [`fuse_create_request`], but checks `name` first, as every
+/// name-taking operation does, against `name_max` if it is known.
+pub fn fuse_checked_create_request(
+    nodeid: u64,
+    name: &[u8],
+    mode: u32,
+    umask: u32,
+    flags: u32,
+    name_max: Option<u32>,
+) -> Result<RequestBuilder> {
+    fuse_check_name(name)?;
+    fuse_check_name_len(name, name_max)?;
+    Ok(fuse_create_request(nodeid, name, mode, umask, flags))
+}
+
+pub fn fuse_mknod_request(
+    nodeid: u64,
+    name: &[u8],
+    mode: u32,
+    rdev: u32,
+    umask: u32,
+) -> RequestBuilder {
+    let mknodin = FuseMknodIn {
+        mode: mode,
+        rdev: rdev,
+        umask: umask,
+        padding: 0,
+    };
+    RequestBuilder::new(FuseOpcode::FuseMknod, nodeid)
+        .push_struct(&mknodin)
+        .push_name(name)
+        .reserve_reply(0)
+}
+
+pub fn fuse_symlink_request(nodeid: u64, name: &[u8], link: &[u8]) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseSymlink, nodeid)
+        .push_name(name)
+        .push_name(link)
+        .reserve_reply(0)
+}
+
+/// Builds a SETXATTR of `name` to `value` on `nodeid`.
+///
+/// The value follows the NUL-terminated name directly, as the server finds
+/// it by the name's length. A server without `FUSE_SETXATTR_EXT` is sent the
+/// short form of `FuseSetxattrIn`.
+pub fn fuse_setxattr_request(
+    nodeid: u64,
+    name: &[u8],
+    value: &[u8],
+    flags: u32,
+    init_flags: FuseInitFlags,
+) -> RequestBuilder {
+    let setxattrin = FuseSetxattrIn {
+        size: value.len() as u32,
+        flags,
+        setxattr_flags: 0,
+        padding: 0,
+    };
+    let setxattrin = if init_flags.contains(FuseInitFlags::FUSE_SETXATTR_EXT) {
+        setxattrin.as_bytes()
+    } else {
+        &setxattrin.as_bytes()[..FUSE_COMPAT_SETXATTR_IN_SIZE]
+    };
+    RequestBuilder::new(FuseOpcode::FuseSetxattr, nodeid)
+        .push_bytes(setxattrin)
+        .push_name(name)
+        .push_bytes(value)
+        .reserve_reply(0)
+}
+
+/// Builds a GETXATTR of `name`, or a query of its size if `size` is 0.
+pub fn fuse_getxattr_request(nodeid: u64, name: &[u8], size: u32) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseGetxattr, nodeid)
+        .push_struct(&FuseGetxattrIn { size, padding: 0 })
+        .push_name(name)
+        .reserve_reply(size)
+}
+
+/// Builds a LISTXATTR of `nodeid`, or a query of the list's size if `size`
+/// is 0.
+pub fn fuse_listxattr_request(nodeid: u64, size: u32) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseListxattr, nodeid)
+        .push_struct(&FuseGetxattrIn { size, padding: 0 })
+        .reserve_reply(size)
+}
+
+pub fn fuse_removexattr_request(nodeid: u64, name: &[u8]) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseRemovexattr, nodeid)
+        .push_name(name)
+        .reserve_reply(0)
+}
+
+/// The reply to a GETXATTR or LISTXATTR.
+#[derive(Debug, PartialEq, Eq)]
+pub enum XattrReply {
+    /// The size of the value or list, answering a request of size 0.
+    Size(u32),
+    /// The value, or the NUL-separated names of the list.
+    Data(Vec<u8>),
+}
+
+/// Decodes the payload of a GETXATTR or LISTXATTR reply to a request of
+/// `size` bytes.
+///
+/// The size asked for, not the reply length, tells the two forms apart: an
+/// 8-byte value is as long as a `FuseGetxattrOut`.
+pub fn fuse_xattr_reply(size: u32, payload: &[u8]) -> Result<XattrReply> {
+    if size != 0 {
+        if payload.len() > size as usize {
+            return Err(FuseError::Io);
+        }
+        return Ok(XattrReply::Data(payload.to_vec()));
+    }
+    if payload.len() != size_of::<FuseGetxattrOut>() {
+        return Err(FuseError::Io);
+    }
+    let getxattr_out = VmReader::from(payload)
+        .read_val::<FuseGetxattrOut>()
+        .map_err(|_| FuseError::Io)?;
+    Ok(XattrReply::Size(getxattr_out.size))
+}
+
+/// Appends the supplementary group extension carrying `group` to a CREATE,
+/// MKDIR, MKNOD or SYMLINK, if the server negotiated `FUSE_CREATE_SUPP_GROUP`.
+pub fn fuse_push_supp_group(
+    request: RequestBuilder,
+    group: Option<u32>,
+    flags: FuseInitFlags,
+) -> RequestBuilder {
+    match group {
+        Some(group) if flags.contains(FuseInitFlags::FUSE_CREATE_SUPP_GROUP) => {
+            request.push_supp_groups(&[group])
+        }
+        _ => request,
+    }
+}
+
+pub fn fuse_link_request(nodeid: u64, oldnodeid: u64, name: &[u8]) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseLink, nodeid)
+        .push_struct(&FuseLinkIn { oldnodeid })
+        .push_name(name)
+        .reserve_reply(0)
+}
+
+/// Builds an UNLINK or RMDIR of `name`, as `opcode` says.
+///
+/// Neither has a body before the name.
+pub fn fuse_unlink_request(opcode: FuseOpcode, nodeid: u64, name: &[u8]) -> RequestBuilder {
+    RequestBuilder::new(opcode, nodeid)
+        .push_name(name)
+        .reserve_reply(0)
+}
+
+pub fn fuse_rename_request(
+    nodeid: u64,
+    name: &[u8],
+    newdir: u64,
+    newname: &[u8],
+) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseRename, nodeid)
+        .push_struct(&FuseRenameIn { newdir })
+        .push_name(name)
+        .push_name(newname)
+        .reserve_reply(0)
+}
+
+/// Checks that `flags` hold only known `RENAME_*` bits, in a combination
+/// that makes sense: an exchange cannot also be told not to replace.
+pub fn fuse_check_rename_flags(flags: u32) -> Result<()> {
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE | RENAME_WHITEOUT) != 0 {
+        return Err(FuseError::Inval);
+    }
+    if flags & RENAME_EXCHANGE != 0 && flags != RENAME_EXCHANGE {
+        return Err(FuseError::Inval);
+    }
+    Ok(())
+}
 
-        let total_len = fuse_in_header.len() + datain.len() + fuse_out_header.len() + dataout.len();
+/// Checks a rename against its `RENAME_*` flags, given the node IDs the source
+/// and the target names were found to have, and returns the node the rename
+/// leaves with stale attributes.
+///
+/// An exchange keeps both nodes linked, under each other's names; a plain
+/// rename unlinks the node it replaces.
+pub fn fuse_check_rename(
+    flags: u32,
+    source: Option<u64>,
+    target: Option<u64>,
+) -> Result<Option<u64>> {
+    fuse_check_rename_flags(flags)?;
+    if flags & RENAME_EXCHANGE != 0 {
+        return match (source, target) {
+            (Some(_), Some(_)) => Ok(None),
+            _ => Err(FuseError::NoEnt),
+        };
+    }
+    if source.is_none() {
+        return Err(FuseError::NoEnt);
+    }
+    if flags & RENAME_NOREPLACE != 0 && target.is_some() {
+        return Err(FuseError::Exist);
+    }
+    Ok(target)
+}
 
-        let mut concat_req = vec![0u8; total_len];
-        concat_req[0..fuse_in_header.len()].copy_from_slice(fuse_in_header);
-        concat_req[fuse_in_header.len()..(fuse_in_header.len() + datain.len())]
-            .copy_from_slice(datain);
+pub fn fuse_rename2_request(
+    nodeid: u64,
+    name: &[u8],
+    newdir: u64,
+    newname: &[u8],
+    flags: u32,
+) -> RequestBuilder {
+    let rename2in = FuseRename2In {
+        newdir: newdir,
+        flags: flags,
+        padding: 0,
+    };
+    RequestBuilder::new(FuseOpcode::FuseRename2, nodeid)
+        .push_struct(&rename2in)
+        .push_name(name)
+        .push_name(newname)
+        .reserve_reply(0)
+}
 
-        concat_req
+/// Checks the `FALLOC_FL_*` bits of a fallocate.
+///
+/// A hole is only punched within the file, so `FALLOC_FL_PUNCH_HOLE` comes
+/// with `FALLOC_FL_KEEP_SIZE` and without `FALLOC_FL_ZERO_RANGE`.
+pub fn fuse_check_fallocate_mode(mode: u32) -> Result<()> {
+    if mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+        return Err(FuseError::Inval);
+    }
+    if mode & FALLOC_FL_PUNCH_HOLE != 0 && mode != FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE {
+        return Err(FuseError::Inval);
+    }
+    Ok(())
+}
+
+pub fn fuse_fallocate_request(
+    nodeid: u64,
+    fh: u64,
+    offset: u64,
+    length: u64,
+    mode: u32,
+) -> RequestBuilder {
+    let fallocatein = FuseFallocateIn {
+        fh,
+        offset,
+        length,
+        mode,
+        padding: 0,
+    };
+    RequestBuilder::new(FuseOpcode::FuseFallocate, nodeid)
+        .push_struct(&fallocatein)
+        .reserve_reply(0)
+}
+
+/// Builds a GETLK, SETLK or SETLKW request for `lock` on the open file `fh`.
+///
+/// `lock_owner` identifies who holds or asks for the lock, so that the server
+/// tells the locks of different owners on the same file apart. `lk_flags` is
+/// `FUSE_LK_FLOCK` for a `flock()` lock and 0 for a POSIX one.
+pub fn fuse_lk_request(
+    opcode: FuseOpcode,
+    nodeid: u64,
+    fh: u64,
+    lock_owner: u64,
+    lock: FuseFileLock,
+    lk_flags: u32,
+) -> RequestBuilder {
+    debug_assert!(matches!(
+        opcode,
+        FuseOpcode::FuseGetlk | FuseOpcode::FuseSetlk | FuseOpcode::FuseSetlkw
+    ));
+    let lkin = FuseLkIn {
+        fh,
+        owner: lock_owner,
+        lk: lock,
+        lk_flags,
+        padding: 0,
+    };
+    RequestBuilder::new(opcode, nodeid)
+        .push_struct(&lkin)
+        .reserve_reply(0)
+}
+
+/// Builds a POLL request for `events` on the open file `fh`.
+///
+/// With `FUSE_POLL_SCHEDULE_NOTIFY` in `flags`, the server sends
+/// FUSE_NOTIFY_POLL with `kh` once the events may be ready.
+pub fn fuse_poll_request(nodeid: u64, fh: u64, kh: u64, flags: u32, events: u32) -> RequestBuilder {
+    let pollin = FusePollIn {
+        fh,
+        kh,
+        flags,
+        events,
+    };
+    RequestBuilder::new(FuseOpcode::FusePoll, nodeid)
+        .push_struct(&pollin)
+        .reserve_reply(0)
+}
+
+/// Checks the offsets and lengths of a DAX mapping, which must be multiples
+/// of `alignment`, the byte alignment the server agreed to in FUSE_INIT.
+pub fn fuse_check_mapping(alignment: u64, values: &[u64]) -> Result<()> {
+    if values.iter().any(|value| value % alignment != 0) {
+        return Err(FuseError::Inval);
+    }
+    Ok(())
+}
+
+pub fn fuse_setupmapping_request(
+    nodeid: u64,
+    fh: u64,
+    foffset: u64,
+    len: u64,
+    flags: u64,
+    moffset: u64,
+) -> RequestBuilder {
+    let setupin = FuseSetupMappingIn {
+        fh,
+        foffset,
+        len,
+        flags,
+        moffset,
+    };
+    RequestBuilder::new(FuseOpcode::FuseSetupmapping, nodeid)
+        .push_struct(&setupin)
+        .reserve_reply(0)
+}
+
+/// Builds a REMOVEMAPPING request for the ranges of the DAX window in
+/// `mappings`, which follow their count.
+pub fn fuse_removemapping_request(
+    nodeid: u64,
+    mappings: &[FuseRemoveMappingOne],
+) -> RequestBuilder {
+    let removein = FuseRemoveMappingIn {
+        count: mappings.len() as u32,
+    };
+    mappings
+        .iter()
+        .fold(
+            RequestBuilder::new(FuseOpcode::FuseRemovemapping, nodeid).push_struct(&removein),
+            |request, mapping| request.push_struct(mapping),
+        )
+        .reserve_reply(0)
+}
+
+/// Checks that `whence` is one of the `SEEK_*` values.
+pub fn fuse_check_whence(whence: u32) -> Result<()> {
+    match whence {
+        SEEK_SET | SEEK_CUR | SEEK_END | SEEK_DATA | SEEK_HOLE => Ok(()),
+        _ => Err(FuseError::Inval),
+    }
+}
+
+pub fn fuse_lseek_request(nodeid: u64, fh: u64, offset: u64, whence: u32) -> RequestBuilder {
+    let lseekin = FuseLseekIn {
+        fh,
+        offset,
+        whence,
+        padding: 0,
+    };
+    RequestBuilder::new(FuseOpcode::FuseLseek, nodeid)
+        .push_struct(&lseekin)
+        .reserve_reply(0)
+}
+
+/// On whose behalf a WRITE is sent, which sets its `write_flags`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteOrigin {
+    /// A write of the caller, through a handle it opened.
+    #[default]
+    Direct,
+    /// A write of the caller holding POSIX locks as `lock_owner`, which the
+    /// server checks the write against.
+    LockOwner(u64),
+    /// A delayed write of cached pages; only with `FUSE_WRITEBACK_CACHE`
+    /// negotiated, as the server then takes the handle as a guess.
+    Cache,
+}
+
+impl WriteOrigin {
+    /// Returns the `write_flags` and `lock_owner` of the `FuseWriteIn`.
+    pub fn write_flags(self) -> (u32, u64) {
+        match self {
+            Self::Direct => (0, 0),
+            Self::LockOwner(lock_owner) => (FUSE_WRITE_LOCKOWNER, lock_owner),
+            Self::Cache => (FUSE_WRITE_CACHE, 0),
+        }
+    }
+}
+
+pub fn fuse_write_request(
+    nodeid: u64,
+    fh: u64,
+    offset: u64,
+    data: &[u8],
+    origin: WriteOrigin,
+) -> RequestBuilder {
+    let (write_flags, lock_owner) = origin.write_flags();
+    let writein = FuseWriteIn {
+        fh: fh,
+        offset: offset,
+        size: data.len() as u32,
+        write_flags,
+        lock_owner,
+        flags: 0,
+        padding: 0,
+    };
+    RequestBuilder::new(FuseOpcode::FuseWrite, nodeid)
+        .push_struct(&writein)
+        .push_bytes(data)
+        .reserve_reply(0)
+}
+
+/// Builds a WRITE of `len` bytes that the caller hands the device from a DMA
+/// buffer of its own; see [`RequestBuilder::reserve_payload`].
+pub fn fuse_write_payload_request(
+    nodeid: u64,
+    fh: u64,
+    offset: u64,
+    len: u32,
+    origin: WriteOrigin,
+) -> RequestBuilder {
+    let (write_flags, lock_owner) = origin.write_flags();
+    let writein = FuseWriteIn {
+        fh,
+        offset,
+        size: len,
+        write_flags,
+        lock_owner,
+        flags: 0,
+        padding: 0,
+    };
+    RequestBuilder::new(FuseOpcode::FuseWrite, nodeid)
+        .push_struct(&writein)
+        .reserve_payload(len as usize)
+        .reserve_reply(0)
+}
+
+/// Builds an FSYNC or FSYNCDIR of `fh`, as `opcode` says.
+pub fn fuse_fsync_request(
+    opcode: FuseOpcode,
+    nodeid: u64,
+    fh: u64,
+    fsync_flags: u32,
+) -> RequestBuilder {
+    let fsyncin = FuseFsyncIn {
+        fh,
+        fsync_flags,
+        padding: 0,
+    };
+    RequestBuilder::new(opcode, nodeid)
+        .push_struct(&fsyncin)
+        .reserve_reply(0)
+}
+
+/// Returns the `fsync_flags` of an FSYNC or FSYNCDIR; `datasync` leaves out
+/// the metadata not needed to read the data back.
+pub fn fuse_fsync_flags(datasync: bool) -> u32 {
+    if datasync {
+        FUSE_FSYNC_FDATASYNC
+    } else {
+        0
+    }
+}
+
+/// Builds a FORGET of `nlookup` lookups of `nodeid`, which has no reply.
+pub fn fuse_forget_request(nodeid: u64, nlookup: u64) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseForget, nodeid).push_struct(&FuseForgetIn { nlookup })
+}
+
+/// Builds the INTERRUPT of the request `unique`.
+///
+/// Its own unique is the request's with [`FUSE_INT_REQ_BIT`] set, which no
+/// other request has.
+pub fn fuse_interrupt_request(unique: u64) -> RequestBuilder {
+    RequestBuilder::new(FuseOpcode::FuseInterrupt, 0)
+        .with_unique(unique | FUSE_INT_REQ_BIT)
+        .push_struct(&FuseInterruptIn { unique })
+        .reserve_out(size_of::<FuseOutHeader>())
+}
+
+/// The most pairs a BATCH_FORGET carries, so that it fits in a one-page
+/// hiprio buffer; longer lists are sent in several.
+pub const FUSE_MAX_BATCH_FORGET: usize =
+    (PAGE_SIZE - size_of::<FuseInHeader>() - size_of::<FuseBatchForgetIn>())
+        / size_of::<FuseForgetOne>();
+
+/// Builds a BATCH_FORGET of `(nodeid, nlookup)` pairs, which has no reply.
+///
+/// At most [`FUSE_MAX_BATCH_FORGET`] pairs fit in a hiprio buffer.
+pub fn fuse_batch_forget_request(forget_list: &[(u64, u64)]) -> RequestBuilder {
+    let batch_forget_in = FuseBatchForgetIn {
+        count: forget_list.len() as u32,
+        dummy: 0,
+    };
+    forget_list.iter().fold(
+        RequestBuilder::new(FuseOpcode::FuseBatchForget, 0).push_struct(&batch_forget_in),
+        |request, &(nodeid, nlookup)| request.push_struct(&FuseForgetOne { nodeid, nlookup }),
+    )
+}
+
+/// The largest payload a single WRITE request carries, which fits a request
+/// buffer like the largest READ reply.
+pub const FUSE_MAX_WRITE_SIZE: u32 = 64 * 1024;
+
+/// The outcome of a WRITE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteResult {
+    /// The number of bytes sent to the server.
+    pub requested: u32,
+    /// The number of bytes the server wrote.
+    pub written: u32,
+}
+
+impl WriteResult {
+    /// Returns whether the server wrote less than it was sent.
+    pub fn is_short(&self) -> bool {
+        self.written < self.requested
+    }
+}
+
+/// Parses the reply to a WRITE of `requested` bytes.
+pub fn fuse_write_result(requested: u32, reply: &[u8]) -> Result<WriteResult> {
+    let writeout = fuse_reply_val::<FuseWriteOut>(reply)?;
+    if writeout.size > requested {
+        return Err(FuseError::Io);
+    }
+    Ok(WriteResult {
+        requested,
+        written: writeout.size,
+    })
+}
+
+/// Writes all of `data` at `offset` with `write`, continuing after short writes,
+/// and returns the number of bytes the server wrote.
+///
+/// The count is the sum of what each WRITE reply reports. A write that makes
+/// no progress fails with `Io` instead of being retried forever.
+pub fn fuse_write_all<F>(mut offset: u64, mut data: &[u8], mut write: F) -> Result<usize>
+where
+    F: FnMut(u64, &[u8]) -> Result<WriteResult>,
+{
+    let mut total = 0;
+    while !data.is_empty() {
+        let written = write(offset, data)?.written as usize;
+        if written == 0 {
+            return Err(FuseError::Io);
+        }
+        offset += written as u64;
+        data = &data[written..];
+        total += written;
+    }
+    Ok(total)
+}
+
+/// The credentials a request is made with, which the server checks
+/// permissions against.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FuseContext {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+/// Assembles a request: the device-readable input followed by a
+/// device-writable region reserved for the reply.
+///
+/// `FuseInHeader::len` is filled in from the pushed input.
+#[derive(Debug, Clone)]
+pub struct RequestBuilder {
+    headerin: FuseInHeader,
+    datain: Vec<u8>,
+    /// The extensions following the input, padded to 8-byte units.
+    extensions: Vec<u8>,
+    /// The length of the input the caller sends from a DMA buffer of its own,
+    /// which follows `datain`.
+    payload_len: usize,
+    out_len: usize,
+    /// The credentials of the caller, if it gave any.
+    context: Option<FuseContext>,
+}
+
+impl RequestBuilder {
+    pub fn new(opcode: FuseOpcode, nodeid: u64) -> Self {
+        Self {
+            headerin: FuseInHeader {
+                len: 0,
+                opcode: opcode as u32,
+                unique: 0,
+                nodeid,
+                uid: 0,
+                gid: 0,
+                pid: 0,
+                total_extlen: 0,
+                padding: 0,
+            },
+            datain: Vec::new(),
+            extensions: Vec::new(),
+            payload_len: 0,
+            out_len: 0,
+            context: None,
+        }
+    }
+
+    pub fn push_struct<T: Pod>(self, val: &T) -> Self {
+        self.push_bytes(val.as_bytes())
+    }
+
+    pub fn push_bytes(mut self, bytes: &[u8]) -> Self {
+        self.datain.extend_from_slice(bytes);
+        self
+    }
+
+    /// Appends a NUL-terminated name, as the Linux FUSE client sends it.
+    pub fn push_name(mut self, name: &[u8]) -> Self {
+        self.datain.extend_from_slice(name);
+        self.datain.push(0);
+        self
+    }
+
+    /// Appends a NUL-terminated name and pads the input to a multiple of 8 bytes.
+    pub fn push_padded_name(mut self, name: &[u8]) -> Self {
+        self.datain.extend_from_slice(name);
+        self.datain.push(0);
+        self.datain.resize(self.datain.len().next_multiple_of(8), 0);
+        self
+    }
+
+    /// Appends the supplementary group extension, which follows all the input.
+    pub fn push_supp_groups(mut self, groups: &[u32]) -> Self {
+        let len = size_of::<FuseExtHeader>()
+            + size_of::<FuseSuppGroups>()
+            + size_of::<u32>() * groups.len();
+        let ext_header = FuseExtHeader {
+            size: len.next_multiple_of(8) as u32,
+            type_: FuseExtType::FuseExtGroups as u32,
+        };
+        let supp_groups = FuseSuppGroups {
+            nr_groups: groups.len() as u32,
+            groups: [],
+        };
+        self.extensions.extend_from_slice(ext_header.as_bytes());
+        self.extensions.extend_from_slice(supp_groups.as_bytes());
+        for group in groups {
+            self.extensions.extend_from_slice(&group.to_ne_bytes());
+        }
+        self.extensions
+            .resize(self.extensions.len().next_multiple_of(8), 0);
+        self
+    }
+
+    /// Counts `len` bytes of input that the caller hands the device as a
+    /// separate buffer, after all that was pushed.
+    ///
+    /// The request buffer leaves a gap of that size before the reply, which
+    /// is never written, so that the reply is still found `headerin.len`
+    /// bytes in. Extensions would have to follow the payload, so a request
+    /// with one has none.
+    pub fn reserve_payload(mut self, len: usize) -> Self {
+        debug_assert!(self.extensions.is_empty());
+        self.payload_len = len;
+        self
+    }
+
+    /// Reserves `len` bytes for the reply, including its `FuseOutHeader`.
+    pub fn reserve_out(mut self, len: usize) -> Self {
+        self.out_len = len;
+        self
+    }
+
+    /// Reserves what the reply to this opcode needs, with `requested` bytes
+    /// of data for the opcodes replying with data.
+    pub fn reserve_reply(self, requested: u32) -> Self {
+        let opcode = FuseOpcode::try_from(self.headerin.opcode).unwrap();
+        self.reserve_out(fuse_out_capacity(opcode, requested))
+    }
+
+    /// Sets the unique the reply to this request is matched up by.
+    pub fn with_unique(mut self, unique: u64) -> Self {
+        self.headerin.unique = unique;
+        self
+    }
+
+    /// Sends the request with the credentials of `context`.
+    pub fn with_context(mut self, context: FuseContext) -> Self {
+        self.headerin.uid = context.uid;
+        self.headerin.gid = context.gid;
+        self.headerin.pid = context.pid;
+        self.context = Some(context);
+        self
+    }
+
+    /// Sends the request with the credentials of `context` unless the caller
+    /// gave its own.
+    pub fn or_context(self, context: FuseContext) -> Self {
+        match self.context {
+            Some(_) => self,
+            None => self.with_context(context),
+        }
+    }
+
+    pub fn context(&self) -> Option<FuseContext> {
+        self.context
+    }
+
+    pub fn opcode(&self) -> FuseOpcode {
+        FuseOpcode::try_from(self.headerin.opcode).unwrap()
+    }
+
+    pub fn unique(&self) -> u64 {
+        self.headerin.unique
+    }
+
+    /// Returns whether the server will reply to this request.
+    pub fn expects_reply(&self) -> bool {
+        FuseOpcode::try_from(self.headerin.opcode).is_ok_and(fuse_has_reply)
+    }
+
+    pub fn in_len(&self) -> usize {
+        self.head_len() + self.payload_len
+    }
+
+    /// Returns the length of the input written to the request buffer, which
+    /// is all of it but the payload.
+    pub fn head_len(&self) -> usize {
+        size_of::<FuseInHeader>() + self.datain.len() + self.extensions.len()
+    }
+
+    pub fn payload_len(&self) -> usize {
+        self.payload_len
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.in_len() + self.out_len
+    }
+
+    /// Returns the whole request, with the payload and reply regions zeroed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head_bytes();
+        bytes.resize(self.total_len(), 0);
+        bytes
+    }
+
+    /// Returns the input written to the request buffer.
+    fn head_bytes(&self) -> Vec<u8> {
+        let mut headerin = self.headerin;
+        headerin.len = self.in_len() as u32;
+        headerin.total_extlen = (self.extensions.len() / 8) as u16;
+
+        let mut bytes = Vec::with_capacity(self.total_len());
+        bytes.extend_from_slice(headerin.as_bytes());
+        bytes.extend_from_slice(&self.datain);
+        bytes.extend_from_slice(&self.extensions);
+        debug_assert_eq!(bytes.len() + self.payload_len, headerin.len as usize);
+        bytes
+    }
+
+    /// Writes the request to the start of `stream`, returning `(in_len, total_len)`.
+    ///
+    /// A request whose reply region would run past the end of `stream` is
+    /// rejected, so that the device never writes beyond it. The gap left for
+    /// the payload is skipped rather than written.
+    pub fn write_to(&self, stream: &DmaStream) -> Result<(usize, usize)> {
+        if self.total_len() > stream.nbytes() {
+            return Err(FuseError::Inval);
+        }
+        if self.payload_len == 0 {
+            fuse_write_request_bytes(stream, &self.to_bytes())?;
+        } else {
+            let mut writer = stream.writer()?;
+            writer.write(&mut VmReader::from(self.head_bytes().as_slice()));
+            let mut writer = writer.skip(self.payload_len);
+            writer
+                .fill_zeros(self.out_len)
+                .map_err(|(err, _)| FuseError::Dma(err))?;
+            stream.sync(0..self.total_len())?;
+        }
+        Ok((self.in_len(), self.total_len()))
     }
 }
 
@@ -154,36 +1271,1547 @@ pub struct FuseReaddirOut {
 }
 impl FuseReaddirOut {
     /// Read all directory entries from the buffer
+    ///
+    /// The entries must fit in both `out_header.len` and the reader; a reply
+    /// claiming more is rejected instead of being read past its end.
     pub fn read_dirent(
         reader: &mut VmReader<'_, ostd::mm::Infallible>,
         out_header: FuseOutHeader,
-    ) -> FuseReaddirOut {
-        let mut len = out_header.len as i32 - size_of::<FuseOutHeader>() as i32;
+    ) -> Result<FuseReaddirOut> {
+        let mut len = fuse_payload_len(reader, out_header)?;
         let mut dirents: Vec<FuseDirentWithName> = Vec::new();
         // For paddings between dirents
         let mut padding: Vec<u8> = vec![0 as u8; 8];
         while len > 0 {
-            let dirent = reader.read_val::<FuseDirent>().unwrap();
-            let mut file_name: Vec<u8>;
+            if len < size_of::<FuseDirent>() {
+                return Err(FuseError::Io);
+            }
+            let dirent = fuse_read_val::<FuseDirent>(reader)?;
+            let pad_len = ((8 - (dirent.namelen & 0x7)) & 0x7) as usize; // pad to multiple of 8 bytes
+            let dirent_len = size_of::<FuseDirent>() + dirent.namelen as usize + pad_len;
+            if dirent_len > len {
+                return Err(FuseError::Io);
+            }
 
-            file_name = vec![0 as u8; dirent.namelen as usize];
+            let mut file_name = vec![0 as u8; dirent.namelen as usize];
             let mut writer = VmWriter::from(file_name.as_mut_slice());
             writer.write(reader);
-            let pad_len = (8 - (dirent.namelen & 0x7)) & 0x7; // pad to multiple of 8 bytes
-            let mut pad_writer = VmWriter::from(&mut padding[0..pad_len as usize]);
+            let mut pad_writer = VmWriter::from(&mut padding[0..pad_len]);
             pad_writer.write(reader);
             dirents.push(FuseDirentWithName {
                 dirent: dirent,
                 name: file_name,
             });
-            early_print!(
-                "len: {:?} ,dirlen: {:?}, name_len: {:?}\n",
-                len,
-                size_of::<FuseDirent>() as u32 + dirent.namelen,
-                dirent.namelen
+            len -= dirent_len;
+        }
+        Ok(FuseReaddirOut { dirents: dirents })
+    }
+}
+
+///FuseDirentplus with the file name
+pub struct FuseDirentplusWithName {
+    pub entry_out: FuseEntryOut,
+    pub dirent: FuseDirent,
+    pub name: Vec<u8>,
+}
+
+///Contain all directory entries for one directory, with their attributes
+pub struct FuseReaddirplusOut {
+    pub dirents: Vec<FuseDirentplusWithName>,
+}
+impl FuseReaddirplusOut {
+    /// Read all directory entries and their lookup replies from the buffer
+    ///
+    /// Each entry is a `FuseEntryOut` followed by a `FuseDirent` and its name,
+    /// padded to 8 bytes like a READDIR entry. As in
+    /// [`FuseReaddirOut::read_dirent`], a reply claiming more than it holds is
+    /// rejected.
+    pub fn read_direntplus(
+        reader: &mut VmReader<'_, ostd::mm::Infallible>,
+        out_header: FuseOutHeader,
+    ) -> Result<FuseReaddirplusOut> {
+        let mut len = fuse_payload_len(reader, out_header)?;
+        let mut dirents: Vec<FuseDirentplusWithName> = Vec::new();
+        // For paddings between dirents
+        let mut padding: Vec<u8> = vec![0 as u8; 8];
+        while len > 0 {
+            if len < size_of::<FuseDirentplus>() {
+                return Err(FuseError::Io);
+            }
+            let direntplus = fuse_read_val::<FuseDirentplus>(reader)?;
+            let dirent = direntplus.dirent;
+            let pad_len = ((8 - (dirent.namelen & 0x7)) & 0x7) as usize; // pad to multiple of 8 bytes
+            let dirent_len = size_of::<FuseDirentplus>() + dirent.namelen as usize + pad_len;
+            if dirent_len > len {
+                return Err(FuseError::Io);
+            }
+
+            let mut file_name = vec![0 as u8; dirent.namelen as usize];
+            let mut writer = VmWriter::from(file_name.as_mut_slice());
+            writer.write(reader);
+            let mut pad_writer = VmWriter::from(&mut padding[0..pad_len]);
+            pad_writer.write(reader);
+            dirents.push(FuseDirentplusWithName {
+                entry_out: direntplus.entry_out,
+                dirent: dirent,
+                name: file_name,
+            });
+            len -= dirent_len;
+        }
+        Ok(FuseReaddirplusOut { dirents: dirents })
+    }
+}
+
+/// Returns whether a READDIRPLUS entry counts as a lookup on the server.
+///
+/// A zero node ID carries no attributes, and `.` and `..` are never looked up,
+/// as on Linux.
+pub fn fuse_direntplus_is_lookup(entry: &FuseDirentplusWithName) -> bool {
+    entry.entry_out.nodeid != 0 && !matches!(entry.name.as_slice(), b"." | b"..")
+}
+
+/// Returns the header of the completed request in `buffer` and a reader over
+/// the rest of the request and its reply, starting with the request's input.
+///
+/// `used_len` is the number of reply bytes the device wrote; the reader ends
+/// there, so that a short reply cannot be read on into stale bytes.
+pub fn fuse_completed_request(
+    buffer: &DmaStream,
+    used_len: usize,
+) -> Result<(FuseInHeader, VmReader<'_, ostd::mm::Infallible>)> {
+    buffer.sync(0..size_of::<FuseInHeader>())?;
+    let mut reader = buffer.reader()?;
+    let headerin = fuse_read_val::<FuseInHeader>(&mut reader)?;
+
+    if (headerin.len as usize) < size_of::<FuseInHeader>() {
+        return Err(FuseError::Io);
+    }
+    let len = (headerin.len as usize)
+        .checked_add(used_len)
+        .filter(|&len| len <= buffer.nbytes())
+        .ok_or(FuseError::Io)?;
+    buffer.sync(0..len)?;
+    Ok((headerin, reader.limit(len - size_of::<FuseInHeader>())))
+}
+
+/// Reads a value of a completed request or its reply, failing if the reader
+/// ends first.
+pub fn fuse_read_val<T: Pod>(reader: &mut VmReader<'_, ostd::mm::Infallible>) -> Result<T> {
+    reader.read_val::<T>().map_err(|_| FuseError::Io)
+}
+
+/// Reads the fixed-size body following `headerout` in a reply.
+///
+/// Returns `None` if the reply is an error or `headerout.len` is too short to
+/// hold a `T`, whatever else the reader holds past the reply.
+pub fn fuse_read_out_body<T: Pod>(
+    reader: &mut VmReader<'_, ostd::mm::Infallible>,
+    headerout: FuseOutHeader,
+) -> Option<T> {
+    if headerout.error != 0
+        || (headerout.len as usize) < size_of::<FuseOutHeader>() + size_of::<T>()
+    {
+        return None;
+    }
+    reader.read_val::<T>().ok()
+}
+
+/// Writes an assembled request to the start of `stream` and returns its length.
+///
+/// Fails instead of panicking if the stream cannot be written by the CPU.
+pub fn fuse_write_request_bytes(stream: &DmaStream, bytes: &[u8]) -> Result<usize> {
+    let mut writer = stream.writer()?;
+    let len = writer.write(&mut VmReader::from(bytes));
+    stream.sync(0..len)?;
+    Ok(len)
+}
+
+/// Returns whether two slices of the same stream share any byte.
+pub fn fuse_slices_overlap(a: &DmaStreamSlice<&DmaStream>, b: &DmaStreamSlice<&DmaStream>) -> bool {
+    a.offset() < b.offset() + b.nbytes() && b.offset() < a.offset() + a.nbytes()
+}
+
+/// Checks in debug builds that the device will not write into the request
+/// it is reading.
+pub fn fuse_debug_assert_disjoint(
+    slice_in: &DmaStreamSlice<&DmaStream>,
+    slice_out: &DmaStreamSlice<&DmaStream>,
+) {
+    debug_assert!(
+        !fuse_slices_overlap(slice_in, slice_out),
+        "request slice {}..{} overlaps reply slice {}..{}",
+        slice_in.offset(),
+        slice_in.offset() + slice_in.nbytes(),
+        slice_out.offset(),
+        slice_out.offset() + slice_out.nbytes()
+    );
+}
+
+/// Splits a request of `len` bytes written to `stream` into the `len_in`
+/// bytes the device reads and the rest, where it writes the reply.
+pub fn fuse_request_slices(
+    stream: &DmaStream,
+    len_in: usize,
+    len: usize,
+) -> (DmaStreamSlice<&DmaStream>, DmaStreamSlice<&DmaStream>) {
+    let slice_in = DmaStreamSlice::new(stream, 0, len_in);
+    let slice_out = DmaStreamSlice::new(stream, len_in, len - len_in);
+    fuse_debug_assert_disjoint(&slice_in, &slice_out);
+    (slice_in, slice_out)
+}
+
+/// Reads the reply header of a request completed in `buffer`.
+///
+/// The header follows the `headerin.len` bytes of the request.
+pub fn fuse_reply_header(buffer: &DmaStream, headerin: &FuseInHeader) -> Result<FuseOutHeader> {
+    let mut reader = buffer.reader()?;
+    if reader.remain() < headerin.len as usize {
+        return Err(FuseError::Io);
+    }
+    reader
+        .skip(headerin.len as usize)
+        .read_val::<FuseOutHeader>()
+        .map_err(|_| FuseError::Io)
+}
+
+/// Reads the data following the header of a READ reply.
+pub fn fuse_read_payload(
+    reader: &mut VmReader<'_, ostd::mm::Infallible>,
+    out_header: FuseOutHeader,
+) -> Result<Vec<u8>> {
+    let len = fuse_payload_len(reader, out_header)?;
+    let mut data = vec![0u8; len];
+    let mut writer = VmWriter::from(data.as_mut_slice());
+    writer.write(reader);
+    Ok(data)
+}
+
+/// Returns the length of the payload following `out_header`, which must not
+/// run past the end of the reader.
+fn fuse_payload_len(
+    reader: &VmReader<'_, ostd::mm::Infallible>,
+    out_header: FuseOutHeader,
+) -> Result<usize> {
+    let len = (out_header.len as usize)
+        .checked_sub(size_of::<FuseOutHeader>())
+        .ok_or(FuseError::Io)?;
+    if len > reader.remain() {
+        return Err(FuseError::Io);
+    }
+    Ok(len)
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::{
+        mm::{DmaDirection, FrameAllocOptions},
+        prelude::*,
+    };
+
+    use super::*;
+    use crate::device::filesystem::{errno::*, slot::BufferSlots};
+
+    /// Maps two pages and places `reply` at `offset` in them.
+    fn two_page_stream(offset: usize, reply: &[u8]) -> DmaStream {
+        let segment = FrameAllocOptions::new().alloc_segment(2).unwrap();
+        let stream = DmaStream::map(segment.into(), DmaDirection::Bidirectional, false).unwrap();
+        let mut writer = stream.writer().unwrap().skip(offset);
+        writer.write(&mut VmReader::from(reply));
+        stream
+    }
+
+    fn dirent_bytes(ino: u64, name: &[u8]) -> Vec<u8> {
+        let dirent = FuseDirent {
+            ino,
+            off: ino,
+            namelen: name.len() as u32,
+            type_: 0,
+            name: [],
+        };
+        let mut bytes = [dirent.as_bytes(), name].concat();
+        bytes.resize(bytes.len().next_multiple_of(8), 0);
+        bytes
+    }
+
+    fn reply_bytes(payload: &[u8]) -> Vec<u8> {
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + payload.len()) as u32,
+            error: 0,
+            unique: 0,
+        };
+        [header.as_bytes(), payload].concat()
+    }
+
+    #[ktest]
+    fn readdir_reply_across_page_boundary() {
+        let names: [&[u8]; 3] = [b"a", b"straddles_the_page_boundary", b"tail"];
+        let payload: Vec<u8> = names
+            .iter()
+            .enumerate()
+            .flat_map(|(i, name)| dirent_bytes(i as u64 + 1, name))
+            .collect();
+        // The page boundary falls five bytes into the second name.
+        let offset = PAGE_SIZE
+            - size_of::<FuseOutHeader>()
+            - dirent_bytes(1, names[0]).len()
+            - size_of::<FuseDirent>()
+            - 5;
+        let stream = two_page_stream(offset, &reply_bytes(&payload));
+
+        let mut reader = stream.reader().unwrap().skip(offset);
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        let readdir_out = FuseReaddirOut::read_dirent(&mut reader, headerout).unwrap();
+
+        assert_eq!(readdir_out.dirents.len(), names.len());
+        for (i, (dirent, name)) in readdir_out.dirents.iter().zip(names).enumerate() {
+            assert_eq!(dirent.dirent.ino, i as u64 + 1);
+            assert_eq!(dirent.name.as_slice(), name);
+        }
+    }
+
+    #[ktest]
+    fn readdirplus_entries_carry_attributes() {
+        let names: [&[u8]; 3] = [b".", b"eight_by", b"odd"];
+        let payload: Vec<u8> = names
+            .iter()
+            .enumerate()
+            .flat_map(|(i, name)| {
+                let entry_out = FuseEntryOut {
+                    nodeid: i as u64 + 10,
+                    attr: FuseAttr {
+                        size: i as u64 * 100,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                [entry_out.as_bytes(), &dirent_bytes(i as u64 + 10, name)].concat()
+            })
+            .collect();
+        // An entry is padded by its name alone, as the header is 8-aligned.
+        assert_eq!(payload.len(), 3 * size_of::<FuseDirentplus>() + 8 + 8 + 8);
+        let stream = two_page_stream(0, &reply_bytes(&payload));
+
+        let mut reader = stream.reader().unwrap();
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        let out = FuseReaddirplusOut::read_direntplus(&mut reader, headerout).unwrap();
+        assert_eq!(out.dirents.len(), names.len());
+        for (i, (entry, name)) in out.dirents.iter().zip(names).enumerate() {
+            assert_eq!(entry.entry_out.nodeid, i as u64 + 10);
+            assert_eq!(entry.entry_out.attr.size, i as u64 * 100);
+            assert_eq!(entry.dirent.ino, i as u64 + 10);
+            assert_eq!(entry.name.as_slice(), name);
+        }
+        assert!(!fuse_direntplus_is_lookup(&out.dirents[0]));
+        assert!(fuse_direntplus_is_lookup(&out.dirents[1]));
+
+        // An entry whose name and padding are cut off is malformed.
+        let stream = two_page_stream(0, &reply_bytes(&payload[..payload.len() - 8]));
+        let mut reader = stream.reader().unwrap();
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        assert!(matches!(
+            FuseReaddirplusOut::read_direntplus(&mut reader, headerout),
+            Err(FuseError::Io)
+        ));
+    }
+
+    #[ktest]
+    fn read_reply_across_page_boundary() {
+        let data: Vec<u8> = (0..300).map(|i| (i % 251) as u8).collect();
+        let offset = PAGE_SIZE - size_of::<FuseOutHeader>() - 100;
+        let stream = two_page_stream(offset, &reply_bytes(&data));
+
+        let mut reader = stream.reader().unwrap().skip(offset);
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        assert_eq!(fuse_read_payload(&mut reader, headerout).unwrap(), data);
+    }
+
+    #[ktest]
+    fn read_reply_fills_requested_size() {
+        const SIZE: u32 = 32 * 1024;
+        let request = fuse_read_request(FuseOpcode::FuseRead, 2, 3, 0, SIZE);
+        let (len_in, len) = (request.in_len(), request.total_len());
+        assert_eq!(len - len_in, size_of::<FuseOutHeader>() + SIZE as usize);
+
+        let segment = FrameAllocOptions::new()
+            .alloc_segment(FUSE_REQUEST_BUFFER_PAGES)
+            .unwrap();
+        let stream = DmaStream::map(segment.into(), DmaDirection::Bidirectional, false).unwrap();
+        assert_eq!(request.write_to(&stream).unwrap(), (len_in, len));
+
+        // Play the server, filling the whole reply region.
+        let data: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+        let mut writer = stream.writer().unwrap().skip(len_in);
+        writer.write(&mut VmReader::from(reply_bytes(&data).as_slice()));
+
+        let mut reader = stream.reader().unwrap().skip(len_in).limit(len - len_in);
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        assert_eq!(fuse_read_payload(&mut reader, headerout).unwrap(), data);
+    }
+
+    #[ktest]
+    fn oversized_read_reply_is_cut_short() {
+        let data: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        let reply = reply_bytes(&data);
+        assert_eq!(fuse_read_data(&reply, 1000).unwrap(), &data[..1000]);
+        assert_eq!(fuse_read_data(&reply, 4096).unwrap(), data);
+    }
+
+    #[ktest]
+    fn read_size_is_clamped() {
+        let request = fuse_read_request(FuseOpcode::FuseReaddir, 2, 3, 0, u32::MAX);
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>() + FUSE_MAX_READ_SIZE as usize
+        );
+        assert!(request.total_len() <= FUSE_REQUEST_BUFFER_PAGES * PAGE_SIZE);
+    }
+
+    #[ktest]
+    fn dirent_matches_kernel_layout() {
+        #[rustfmt::skip]
+        let payload = [
+            // fuse_dirent
+            42, 0, 0, 0, 0, 0, 0, 0,                            // ino
+            0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01,     // off
+            3, 0, 0, 0,                                         // namelen
+            4, 0, 0, 0,                                         // type: DT_DIR
+            b'd', b'i', b'r', 0, 0, 0, 0, 0,                    // name, padded
+            // fuse_dirent
+            43, 0, 0, 0, 0, 0, 0, 0,                            // ino
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,     // off
+            8, 0, 0, 0,                                         // namelen
+            10, 0, 0, 0,                                        // type: DT_LNK
+            b's', b'y', b'm', b'l', b'i', b'n', b'k', b's',     // name
+        ];
+        let reply = reply_bytes(&payload);
+        let mut reader = VmReader::from(reply.as_slice());
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        let dirents = FuseReaddirOut::read_dirent(&mut reader, headerout)
+            .unwrap()
+            .dirents;
+
+        let fields: Vec<_> = dirents
+            .iter()
+            .map(|entry| (entry.dirent.ino, entry.dirent.off, entry.dirent.type_))
+            .collect();
+        assert_eq!(
+            fields,
+            [(42, 0x0123_4567_89ab_cdef, 4), (43, i64::MAX as u64, 10)]
+        );
+        assert_eq!(dirents[0].name, b"dir");
+        assert_eq!(dirents[1].name, b"symlinks");
+    }
+
+    #[ktest]
+    fn readdir_size_fits_the_buffer() {
+        let stream = two_page_stream(0, &[]);
+
+        // Asked for as is, the reply would not fit two pages.
+        let request = fuse_read_request(FuseOpcode::FuseReaddir, 2, 3, 0, 16 * 1024);
+        assert!(matches!(request.write_to(&stream), Err(FuseError::Inval)));
+
+        let size = (16 * 1024).min(fuse_read_capacity(stream.nbytes()));
+        assert!(size < 16 * 1024);
+        let request = fuse_read_request(FuseOpcode::FuseReaddir, 2, 3, 0, size);
+        let (_, len) = request.write_to(&stream).unwrap();
+        assert_eq!(len, stream.nbytes());
+
+        assert_eq!(
+            fuse_read_capacity(FUSE_REQUEST_BUFFER_PAGES * PAGE_SIZE),
+            FUSE_MAX_READ_SIZE
+        );
+    }
+
+    #[ktest]
+    fn caller_credentials_override_the_default() {
+        let default = FuseContext {
+            uid: 1000,
+            gid: 100,
+            pid: 1,
+        };
+        let caller = FuseContext {
+            uid: 0,
+            gid: 0,
+            pid: 42,
+        };
+        let header = |request: RequestBuilder| {
+            VmReader::from(request.to_bytes().as_slice())
+                .read_val::<FuseInHeader>()
+                .unwrap()
+        };
+
+        let headerin = header(fuse_lookup_request(1, b"a").or_context(default));
+        assert_eq!((headerin.uid, headerin.gid, headerin.pid), (1000, 100, 1));
+
+        let request = fuse_lookup_request(1, b"a")
+            .with_context(caller)
+            .or_context(default);
+        assert_eq!(request.context(), Some(caller));
+        let headerin = header(request);
+        assert_eq!((headerin.uid, headerin.gid, headerin.pid), (0, 0, 42));
+    }
+
+    #[ktest]
+    fn lock_requests_carry_the_owner() {
+        let lock = FuseFileLock {
+            start: 0,
+            end: 4095,
+            type_: 1,
+            pid: 42,
+        };
+        let request = fuse_lk_request(FuseOpcode::FuseSetlkw, 2, 3, 0xdead_beef, lock, 0);
+        let bytes = request.to_bytes();
+        let lkin = VmReader::from(&bytes[size_of::<FuseInHeader>()..])
+            .read_val::<FuseLkIn>()
+            .unwrap();
+        assert_eq!((lkin.fh, lkin.owner, lkin.lk_flags), (3, 0xdead_beef, 0));
+        assert_eq!((lkin.lk.start, lkin.lk.end, lkin.lk.pid), (0, 4095, 42));
+        // Only GETLK is answered with a lock.
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>()
+        );
+        let request = fuse_lk_request(FuseOpcode::FuseGetlk, 2, 3, 7, lock, FUSE_LK_FLOCK);
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>() + size_of::<FuseLkOut>()
+        );
+    }
+
+    #[ktest]
+    fn mappings_follow_their_count() {
+        let mappings = [
+            FuseRemoveMappingOne {
+                moffset: 0,
+                len: 0x20_0000,
+            },
+            FuseRemoveMappingOne {
+                moffset: 0x40_0000,
+                len: 0x20_0000,
+            },
+        ];
+        let request = fuse_removemapping_request(2, &mappings);
+        assert_eq!(
+            request.in_len(),
+            size_of::<FuseInHeader>()
+                + size_of::<FuseRemoveMappingIn>()
+                + 2 * size_of::<FuseRemoveMappingOne>()
+        );
+        let bytes = request.to_bytes();
+        let mut reader = VmReader::from(&bytes[size_of::<FuseInHeader>()..]);
+        assert_eq!(reader.read_val::<FuseRemoveMappingIn>().unwrap().count, 2);
+        let second = reader
+            .skip(size_of::<FuseRemoveMappingOne>())
+            .read_val::<FuseRemoveMappingOne>()
+            .unwrap();
+        assert_eq!(second.moffset, 0x40_0000);
+
+        assert!(fuse_check_mapping(4096, &[0, 0x20_0000, 8192]).is_ok());
+        assert!(matches!(
+            fuse_check_mapping(4096, &[0, 0x20_0000, 100]),
+            Err(FuseError::Inval)
+        ));
+    }
+
+    #[ktest]
+    fn poll_request_carries_the_handle() {
+        let request = fuse_poll_request(2, 3, 7, FUSE_POLL_SCHEDULE_NOTIFY, 1);
+        let bytes = request.to_bytes();
+        let pollin = VmReader::from(&bytes[size_of::<FuseInHeader>()..])
+            .read_val::<FusePollIn>()
+            .unwrap();
+        assert_eq!(
+            (pollin.fh, pollin.kh, pollin.flags, pollin.events),
+            (3, 7, FUSE_POLL_SCHEDULE_NOTIFY, 1)
+        );
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>() + size_of::<FusePollOut>()
+        );
+    }
+
+    #[ktest]
+    fn lseek_reply_carries_the_offset() {
+        for whence in [SEEK_SET, SEEK_CUR, SEEK_END, SEEK_DATA, SEEK_HOLE] {
+            assert!(fuse_check_whence(whence).is_ok());
+        }
+        assert!(matches!(fuse_check_whence(5), Err(FuseError::Inval)));
+
+        let request = fuse_lseek_request(2, 3, 4096, SEEK_HOLE);
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>() + size_of::<FuseLseekOut>()
+        );
+
+        let header = FuseOutHeader {
+            len: (size_of::<FuseOutHeader>() + size_of::<FuseLseekOut>()) as u32,
+            error: 0,
+            unique: 2,
+        };
+        let mut reply = header.as_bytes().to_vec();
+        reply.extend_from_slice(FuseLseekOut { offset: 8192 }.as_bytes());
+        assert_eq!(fuse_reply_val::<FuseLseekOut>(&reply).unwrap().offset, 8192);
+    }
+
+    #[ktest]
+    fn fallocate_modes_are_checked() {
+        for mode in [
+            0,
+            FALLOC_FL_KEEP_SIZE,
+            FALLOC_FL_ZERO_RANGE,
+            FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE,
+            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+        ] {
+            assert!(fuse_check_fallocate_mode(mode).is_ok(), "{:#x}", mode);
+        }
+        for mode in [
+            FALLOC_FL_PUNCH_HOLE,
+            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE | FALLOC_FL_ZERO_RANGE,
+            0x04,
+        ] {
+            assert!(matches!(
+                fuse_check_fallocate_mode(mode),
+                Err(FuseError::Inval)
+            ));
+        }
+
+        let mode = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+        let request = fuse_fallocate_request(2, 3, 4096, 8192, mode);
+        assert_eq!(
+            request.in_len(),
+            size_of::<FuseInHeader>() + size_of::<FuseFallocateIn>()
+        );
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>()
+        );
+        let bytes = request.to_bytes();
+        let fallocatein = VmReader::from(&bytes[size_of::<FuseInHeader>()..])
+            .read_val::<FuseFallocateIn>()
+            .unwrap();
+        assert_eq!(
+            (
+                fallocatein.fh,
+                fallocatein.offset,
+                fallocatein.length,
+                fallocatein.mode
+            ),
+            (3, 4096, 8192, mode)
+        );
+    }
+
+    #[ktest]
+    fn virtio_fs_req_lays_out_all_regions() {
+        let req = VirtioFsReq {
+            headerin: FuseInHeader {
+                opcode: FuseOpcode::FuseGetattr as u32,
+                unique: 2,
+                ..Default::default()
+            },
+            datain: vec![1; 16],
+            headerout: FuseOutHeader {
+                len: 24,
+                ..Default::default()
+            },
+            dataout: vec![2; 8],
+        };
+        let (len_in, len_out) = (req.in_len(), req.out_len());
+        assert_eq!(len_in, size_of::<FuseInHeader>() + 16);
+        assert_eq!(len_out, size_of::<FuseOutHeader>() + 8);
+
+        let bytes = req.into_bytes();
+        assert_eq!(bytes.len(), len_in + len_out);
+        assert_eq!(&bytes[..len_in], req.in_bytes().as_slice());
+        assert_eq!(&bytes[len_in..], req.out_bytes().as_slice());
+        assert_eq!(&bytes[size_of::<FuseInHeader>()..len_in], [1; 16]);
+        assert_eq!(
+            &bytes[len_in..len_in + size_of::<FuseOutHeader>()],
+            req.headerout.as_bytes()
+        );
+        assert_eq!(&bytes[len_in + size_of::<FuseOutHeader>()..], [2; 8]);
+    }
+
+    #[ktest]
+    fn buffer_grows_to_whole_pages() {
+        assert_eq!(fuse_buffer_pages(1), Some(1));
+        assert_eq!(fuse_buffer_pages(PAGE_SIZE), Some(1));
+        assert_eq!(fuse_buffer_pages(PAGE_SIZE + 1), Some(2));
+        assert_eq!(
+            fuse_buffer_pages(FUSE_MAX_BUFFER_PAGES * PAGE_SIZE),
+            Some(FUSE_MAX_BUFFER_PAGES)
+        );
+        assert_eq!(
+            fuse_buffer_pages(FUSE_MAX_BUFFER_PAGES * PAGE_SIZE + 1),
+            None
+        );
+    }
+
+    #[ktest]
+    fn readlink_returns_long_target() {
+        let target: Vec<u8> = (0..FUSE_PATH_MAX - 1)
+            .map(|i| b'a' + (i % 26) as u8)
+            .collect();
+        assert_eq!(fuse_readlink_target(&reply_bytes(&target)).unwrap(), target);
+
+        let truncated = vec![b'a'; FUSE_PATH_MAX];
+        assert!(matches!(
+            fuse_readlink_target(&reply_bytes(&truncated)),
+            Err(FuseError::NameTooLong)
+        ));
+    }
+
+    #[ktest]
+    fn readlink_target_ends_at_reply_length() {
+        let request = RequestBuilder::new(FuseOpcode::FuseReadlink, 3);
+        let reply = reply_bytes(b"../target");
+        // Stale bytes follow the target, with no NUL in between.
+        let stream = two_page_stream(0, &[0xffu8; 64]);
+        let mut writer = stream.writer().unwrap();
+        writer.write(&mut VmReader::from(request.to_bytes().as_slice()));
+        writer.write(&mut VmReader::from(reply.as_slice()));
+
+        let (_, mut reader) = fuse_completed_request(&stream, reply.len()).unwrap();
+        let headerout = fuse_read_val::<FuseOutHeader>(&mut reader).unwrap();
+        assert_eq!(
+            fuse_read_payload(&mut reader, headerout).unwrap(),
+            b"../target"
+        );
+    }
+
+    #[ktest]
+    fn datasync_flushes_data_only() {
+        for (datasync, fsync_flags) in [(false, 0), (true, FUSE_FSYNC_FDATASYNC)] {
+            let request =
+                fuse_fsync_request(FuseOpcode::FuseFsync, 2, 7, fuse_fsync_flags(datasync));
+            let bytes = request.to_bytes();
+            let fsyncin = VmReader::from(&bytes[size_of::<FuseInHeader>()..])
+                .read_val::<FuseFsyncIn>()
+                .unwrap();
+            assert_eq!((fsyncin.fh, fsyncin.fsync_flags), (7, fsync_flags));
+        }
+    }
+
+    #[ktest]
+    fn setxattr_value_follows_name() {
+        let request = fuse_setxattr_request(2, b"user.a", b"xyz", 0, FuseInitFlags::empty());
+        let bytes = request.to_bytes();
+        let body = &bytes[size_of::<FuseInHeader>()..request.in_len()];
+        assert_eq!(&body[..4], 3u32.as_bytes());
+        assert_eq!(&body[FUSE_COMPAT_SETXATTR_IN_SIZE..], b"user.a\0xyz");
+
+        // The extended header only goes to a server that asked for it.
+        let request =
+            fuse_setxattr_request(2, b"user.a", b"xyz", 0, FuseInitFlags::FUSE_SETXATTR_EXT);
+        let bytes = request.to_bytes();
+        let body = &bytes[size_of::<FuseInHeader>()..request.in_len()];
+        assert_eq!(&body[size_of::<FuseSetxattrIn>()..], b"user.a\0xyz");
+    }
+
+    #[ktest]
+    fn xattr_size_query_is_told_from_data() {
+        let getxattr_out = FuseGetxattrOut {
+            size: 42,
+            padding: 0,
+        };
+        assert_eq!(
+            fuse_xattr_reply(0, getxattr_out.as_bytes()).unwrap(),
+            XattrReply::Size(42)
+        );
+        // An 8-byte value asked for is data, however long it is.
+        assert_eq!(
+            fuse_xattr_reply(8, b"12345678").unwrap(),
+            XattrReply::Data(b"12345678".to_vec())
+        );
+        assert_eq!(
+            fuse_xattr_reply(64, b"user.a\0user.b\0").unwrap(),
+            XattrReply::Data(b"user.a\0user.b\0".to_vec())
+        );
+        assert!(matches!(fuse_xattr_reply(0, b"1234"), Err(FuseError::Io)));
+        assert!(matches!(fuse_xattr_reply(2, b"123"), Err(FuseError::Io)));
+
+        // A size query reserves room for the `FuseGetxattrOut` only.
+        let request = fuse_listxattr_request(2, 0);
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>() + size_of::<FuseGetxattrOut>()
+        );
+    }
+
+    #[ktest]
+    fn short_write_is_surfaced() {
+        let writeout = FuseWriteOut {
+            size: 100,
+            padding: 0,
+        };
+        let result = fuse_write_result(4096, &reply_bytes(writeout.as_bytes())).unwrap();
+        assert_eq!(
+            result,
+            WriteResult {
+                requested: 4096,
+                written: 100
+            }
+        );
+        assert!(result.is_short());
+
+        assert!(matches!(
+            fuse_write_result(50, &reply_bytes(writeout.as_bytes())),
+            Err(FuseError::Io)
+        ));
+    }
+
+    #[ktest]
+    fn write_all_resumes_after_short_writes() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut written = Vec::new();
+        // The mock server takes at most four bytes per write.
+        let total = fuse_write_all(100, &data, |offset, chunk| {
+            assert_eq!(offset, 100 + written.len() as u64);
+            let len = chunk.len().min(4);
+            written.extend_from_slice(&chunk[..len]);
+            Ok(WriteResult {
+                requested: chunk.len() as u32,
+                written: len as u32,
+            })
+        })
+        .unwrap();
+        assert_eq!(written, data);
+        assert_eq!(total, data.len());
+
+        let stalled = fuse_write_all(0, &data, |_, chunk| {
+            Ok(WriteResult {
+                requested: chunk.len() as u32,
+                written: 0,
+            })
+        });
+        assert!(matches!(stalled, Err(FuseError::Io)));
+    }
+
+    #[ktest]
+    fn completion_is_read_from_the_buffer_of_its_token() {
+        let buffers: Vec<DmaStream> = (0..2)
+            .map(|nodeid| {
+                let request = RequestBuilder::new(FuseOpcode::FuseGetattr, nodeid + 10)
+                    .push_struct(&FuseGetattrIn::default())
+                    .reserve_out(size_of::<FuseOutHeader>() + size_of::<FuseAttrOut>());
+                let stream = two_page_stream(0, &request.to_bytes());
+                // Play the server, replying with the node ID as the size.
+                let attrout = FuseAttrOut {
+                    attr: FuseAttr {
+                        size: nodeid + 10,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let mut writer = stream.writer().unwrap().skip(request.in_len());
+                writer.write(&mut VmReader::from(
+                    reply_bytes(attrout.as_bytes()).as_slice(),
+                ));
+                stream
+            })
+            .collect();
+        let used_len = size_of::<FuseOutHeader>() + size_of::<FuseAttrOut>();
+        let slots = BufferSlots::new(1, 2);
+        slots.try_acquire(0).unwrap().hand_off(4);
+        slots.try_acquire(0).unwrap().hand_off(6);
+
+        // Token 6 completes in the second buffer of the pool, which alone
+        // holds it.
+        let slot = slots.reap(0, 6).unwrap();
+        let (headerin, mut reader) =
+            fuse_completed_request(&buffers[slot.slot()], used_len).unwrap();
+        assert_eq!(headerin.nodeid, 11);
+        reader.read_val::<FuseGetattrIn>().unwrap();
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        assert_eq!(headerout.len as usize, used_len);
+        assert_eq!(reader.read_val::<FuseAttrOut>().unwrap().attr.size, 11);
+    }
+
+    /// Completes `request` with a reply carrying `payload`, of which the device
+    /// reports `cut` bytes fewer than it wrote, and reads it as the device does.
+    fn cut_short<T: Pod>(request: RequestBuilder, payload: &T, cut: usize) -> Result<T> {
+        let reply = reply_bytes(payload.as_bytes());
+        let stream = two_page_stream(0, &request.to_bytes());
+        let mut writer = stream.writer().unwrap().skip(request.in_len());
+        writer.write(&mut VmReader::from(reply.as_slice()));
+
+        let (headerin, reader) = fuse_completed_request(&stream, reply.len() - cut)?;
+        let mut reader = reader.skip(headerin.len as usize - size_of::<FuseInHeader>());
+        fuse_read_val::<FuseOutHeader>(&mut reader)?;
+        fuse_read_val::<T>(&mut reader)
+    }
+
+    #[ktest]
+    fn truncated_replies_are_malformed() {
+        let getattr = || {
+            RequestBuilder::new(FuseOpcode::FuseGetattr, 2).push_struct(&FuseGetattrIn::default())
+        };
+        let open = |opcode| RequestBuilder::new(opcode, 2).push_struct(&FuseOpenIn::default());
+        let statfs = || RequestBuilder::new(FuseOpcode::FuseStatfs, 1);
+
+        assert!(cut_short(getattr(), &FuseAttrOut::default(), 0).is_ok());
+        assert!(matches!(
+            cut_short(getattr(), &FuseAttrOut::default(), 1),
+            Err(FuseError::Io)
+        ));
+        for opcode in [FuseOpcode::FuseOpen, FuseOpcode::FuseOpendir] {
+            assert!(matches!(
+                cut_short(open(opcode), &FuseOpenOut::default(), 1),
+                Err(FuseError::Io)
+            ));
+        }
+        assert!(matches!(
+            cut_short(
+                statfs(),
+                &FuseStatfsOut::default(),
+                size_of::<FuseStatfsOut>()
+            ),
+            Err(FuseError::Io)
+        ));
+        // Not even the reply header arrived.
+        let cut = size_of::<FuseOutHeader>() + size_of::<FuseStatfsOut>() - 1;
+        assert!(matches!(
+            cut_short(statfs(), &FuseStatfsOut::default(), cut),
+            Err(FuseError::Io)
+        ));
+
+        // A READDIR reply whose header claims more entries than arrived.
+        let request =
+            RequestBuilder::new(FuseOpcode::FuseReaddir, 2).push_struct(&FuseReadIn::default());
+        let reply = reply_bytes(&dirent_bytes(5, b"name"));
+        let stream = two_page_stream(0, &request.to_bytes());
+        let mut writer = stream.writer().unwrap().skip(request.in_len());
+        writer.write(&mut VmReader::from(reply.as_slice()));
+        let (_, mut reader) = fuse_completed_request(&stream, reply.len() - 8).unwrap();
+        fuse_read_val::<FuseReadIn>(&mut reader).unwrap();
+        let headerout = fuse_read_val::<FuseOutHeader>(&mut reader).unwrap();
+        assert!(matches!(
+            FuseReaddirOut::read_dirent(&mut reader, headerout),
+            Err(FuseError::Io)
+        ));
+    }
+
+    #[ktest]
+    fn symlink_reply_follows_both_names() {
+        let request = fuse_symlink_request(1, b"link", b"../target");
+        let bytes = request.to_bytes();
+        assert_eq!(
+            &bytes[size_of::<FuseInHeader>()..request.in_len()],
+            b"link\0../target\0"
+        );
+
+        let entry = FuseEntryOut {
+            nodeid: 7,
+            ..Default::default()
+        };
+        assert_eq!(cut_short(request, &entry, 0).unwrap().nodeid, 7);
+    }
+
+    /// Completes `request` in a fresh buffer with an error reply of `errno`.
+    fn failed_request(request: RequestBuilder, errno: i32) -> (FuseInHeader, FuseOutHeader) {
+        let header = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -errno,
+            unique: 0,
+        };
+        let stream = two_page_stream(0, &request.to_bytes());
+        let mut writer = stream.writer().unwrap().skip(request.in_len());
+        writer.write(&mut VmReader::from(header.as_bytes()));
+
+        let (headerin, _) = fuse_completed_request(&stream, header.len as usize).unwrap();
+        (headerin, fuse_reply_header(&stream, &headerin).unwrap())
+    }
+
+    #[ktest]
+    fn error_replies_surface_the_errno() {
+        let getattr = RequestBuilder::new(FuseOpcode::FuseGetattr, 3)
+            .push_struct(&FuseGetattrIn::default())
+            .reserve_out(size_of::<FuseOutHeader>() + size_of::<FuseAttrOut>());
+        let create = fuse_create_request(1, b"file", 0o644, 0, 0);
+        let read = fuse_read_request(FuseOpcode::FuseRead, 3, 1, 0, 4096);
+
+        for (request, opcode, errno) in [
+            (getattr, FuseOpcode::FuseGetattr, ENOENT),
+            (create, FuseOpcode::FuseCreate, EACCES),
+            (read, FuseOpcode::FuseRead, EIO),
+        ] {
+            let (headerin, headerout) = failed_request(request, errno);
+            assert_eq!(headerin.opcode, opcode as u32);
+            assert!(matches!(
+                fuse_check_reply(&headerout),
+                Err(err) if err.errno() == errno
+            ));
+            assert!(matches!(
+                fuse_reply_val::<FuseAttrOut>(headerout.as_bytes()),
+                Err(err) if err.errno() == errno
+            ));
+        }
+    }
+
+    #[ktest]
+    fn server_errnos_take_the_named_variants() {
+        // One errno has one representation, whoever found the error.
+        assert!(matches!(errno_to_fuse_error(-ENOENT), FuseError::NoEnt));
+        assert!(matches!(errno_to_fuse_error(-EBADF), FuseError::BadF));
+        assert!(matches!(
+            errno_to_fuse_error(-ENAMETOOLONG),
+            FuseError::NameTooLong
+        ));
+        assert!(matches!(
+            errno_to_fuse_error(-EACCES),
+            FuseError::ServerError(EACCES)
+        ));
+        for errno in [ENOENT, EBADF, EINVAL, EEXIST, ENOTDIR, EACCES, EIO] {
+            assert_eq!(FuseError::from_errno(errno).errno(), errno);
+        }
+    }
+
+    #[ktest]
+    fn error_outside_errno_range_is_malformed() {
+        assert!(matches!(errno_to_fuse_error(-ENOENT), FuseError::NoEnt));
+        for error in [ENOENT, -4096, i32::MIN] {
+            assert!(matches!(errno_to_fuse_error(error), FuseError::Io));
+        }
+    }
+
+    #[ktest]
+    fn header_is_little_endian_on_the_wire() {
+        let request = fuse_read_request(FuseOpcode::FuseRead, 0x0102_0304_0506_0708, 1, 0, 16);
+        let bytes = request.to_bytes();
+
+        assert_eq!(bytes[0..4], (request.in_len() as u32).to_le_bytes());
+        assert_eq!(bytes[4..8], (FuseOpcode::FuseRead as u32).to_le_bytes());
+        assert_eq!(bytes[8..16], 0u64.to_le_bytes());
+        assert_eq!(bytes[16..24], [8, 7, 6, 5, 4, 3, 2, 1]);
+
+        // So are the handle and size of the READ that follows.
+        let readin = &bytes[size_of::<FuseInHeader>()..];
+        assert_eq!(readin[0..8], 1u64.to_le_bytes());
+        assert_eq!(readin[16..20], 16u32.to_le_bytes());
+    }
+
+    #[ktest]
+    fn request_and_reply_slices_are_disjoint() {
+        let stream = two_page_stream(0, &[]);
+        let request = fuse_read_request(FuseOpcode::FuseRead, 3, 1, 0, 4096);
+        let (slice_in, slice_out) =
+            fuse_request_slices(&stream, request.in_len(), request.total_len());
+        assert!(!fuse_slices_overlap(&slice_in, &slice_out));
+        assert_eq!(slice_out.offset(), request.in_len());
+
+        // A reply slice starting inside the request is caught.
+        let slice_out = DmaStreamSlice::new(&stream, request.in_len() - 8, 64);
+        assert!(fuse_slices_overlap(&slice_in, &slice_out));
+    }
+
+    #[cfg(debug_assertions)]
+    #[ktest]
+    #[should_panic(expected = "overlaps reply slice")]
+    fn miscomputed_lengths_trip_the_assertion() {
+        let stream = two_page_stream(0, &[]);
+        let request = fuse_read_request(FuseOpcode::FuseRead, 3, 1, 0, 4096);
+        // The reply placed after the headers only, forgetting the `FuseReadIn`.
+        let len_in = request.in_len();
+        let slice_in = DmaStreamSlice::new(&stream, 0, len_in);
+        let slice_out = DmaStreamSlice::new(
+            &stream,
+            size_of::<FuseInHeader>() + size_of::<FuseOutHeader>(),
+            request.total_len() - len_in,
+        );
+        fuse_debug_assert_disjoint(&slice_in, &slice_out);
+    }
+
+    #[ktest]
+    fn name_requests_agree_on_their_length() {
+        let name = b"file";
+        let nul_name = name.len() + 1;
+        for (request, body) in [
+            (fuse_lookup_request(1, name), nul_name),
+            (
+                fuse_unlink_request(FuseOpcode::FuseUnlink, 1, name),
+                nul_name,
+            ),
+            (
+                fuse_unlink_request(FuseOpcode::FuseRmdir, 1, name),
+                nul_name,
+            ),
+            (
+                fuse_link_request(1, 5, name),
+                size_of::<FuseLinkIn>() + nul_name,
+            ),
+            (
+                fuse_mkdir_request(1, 0o755, 0, name),
+                size_of::<FuseMkdirIn>() + nul_name,
+            ),
+            (
+                fuse_rename_request(1, name, 2, b"new"),
+                size_of::<FuseRenameIn>() + nul_name + 4,
+            ),
+            (
+                fuse_rename2_request(1, name, 2, b"new", 0),
+                size_of::<FuseRename2In>() + nul_name + 4,
+            ),
+            (fuse_symlink_request(1, name, b"target"), nul_name + 7),
+        ] {
+            let bytes = request.to_bytes();
+            let headerin = VmReader::from(bytes.as_slice())
+                .read_val::<FuseInHeader>()
+                .unwrap();
+            assert_eq!(request.in_len(), size_of::<FuseInHeader>() + body);
+            assert_eq!(headerin.len as usize, request.in_len());
+            assert_eq!(bytes.len(), request.total_len());
+        }
+    }
+
+    #[ktest]
+    fn write_flags_follow_the_origin() {
+        for (origin, write_flags, lock_owner) in [
+            (WriteOrigin::Direct, 0, 0),
+            (WriteOrigin::LockOwner(7), FUSE_WRITE_LOCKOWNER, 7),
+            (WriteOrigin::Cache, FUSE_WRITE_CACHE, 0),
+        ] {
+            let bytes = fuse_write_request(2, 3, 0, b"data", origin).to_bytes();
+            let writein = VmReader::from(&bytes[size_of::<FuseInHeader>()..])
+                .read_val::<FuseWriteIn>()
+                .unwrap();
+            assert_eq!(
+                (writein.write_flags, writein.lock_owner),
+                (write_flags, lock_owner)
             );
-            len -= size_of::<FuseDirent>() as i32 + dirent.namelen as i32 + pad_len as i32;
         }
-        FuseReaddirOut { dirents: dirents }
+    }
+
+    #[ktest]
+    fn unlink_is_header_and_name() {
+        let request = fuse_unlink_request(FuseOpcode::FuseUnlink, 1, b"victim");
+        assert_eq!(
+            request.in_len(),
+            size_of::<FuseInHeader>() + b"victim\0".len()
+        );
+
+        // The name follows the header directly, with no struct before it.
+        let bytes = request.to_bytes();
+        assert_eq!(
+            &bytes[size_of::<FuseInHeader>()..request.in_len()],
+            b"victim\0"
+        );
+        assert_eq!(
+            request.total_len() - request.in_len(),
+            size_of::<FuseOutHeader>()
+        );
+    }
+
+    #[ktest]
+    fn write_payload_is_left_out_of_the_buffer() {
+        let stream = two_page_stream(0, &[0xaa; 4096]);
+        let request = fuse_write_payload_request(3, 1, 0, 100, WriteOrigin::Direct);
+        let head_len = size_of::<FuseInHeader>() + size_of::<FuseWriteIn>();
+        assert_eq!(request.head_len(), head_len);
+        assert_eq!(request.in_len(), head_len + 100);
+
+        let (len_in, len) = request.write_to(&stream).unwrap();
+        assert_eq!(len_in, head_len + 100);
+        let mut reader = stream.reader().unwrap();
+        let headerin = reader.read_val::<FuseInHeader>().unwrap();
+        assert_eq!(headerin.len as usize, len_in);
+        let writein = reader.read_val::<FuseWriteIn>().unwrap();
+        assert_eq!(writein.size, 100);
+
+        // The payload gap keeps what was there; the reply region is zeroed.
+        let mut rest = vec![0u8; len - head_len];
+        reader.read(&mut VmWriter::from(rest.as_mut_slice()));
+        assert!(rest[..100].iter().all(|&byte| byte == 0xaa));
+        assert!(rest[100..].iter().all(|&byte| byte == 0));
+    }
+
+    #[ktest]
+    fn unwritable_buffer_fails_cleanly() {
+        let segment = FrameAllocOptions::new().alloc_segment(1).unwrap();
+        // The CPU may not write a stream only the device writes to.
+        let stream = DmaStream::map(segment.into(), DmaDirection::FromDevice, false).unwrap();
+
+        let request = fuse_lookup_request(1, b"file");
+        assert!(matches!(
+            request.write_to(&stream),
+            Err(FuseError::Dma(ostd::Error::AccessDenied))
+        ));
+    }
+
+    #[ktest]
+    fn out_capacity_fits_the_reply() {
+        let header = size_of::<FuseOutHeader>();
+        assert_eq!(
+            fuse_out_capacity(FuseOpcode::FuseGetattr, 4096),
+            header + size_of::<FuseAttrOut>()
+        );
+        assert_eq!(
+            fuse_out_capacity(FuseOpcode::FuseLookup, 0),
+            header + size_of::<FuseEntryOut>()
+        );
+        assert_eq!(fuse_out_capacity(FuseOpcode::FuseRead, 4096), header + 4096);
+        assert_eq!(
+            fuse_out_capacity(FuseOpcode::FuseReaddir, 512),
+            header + 512
+        );
+        assert_eq!(fuse_out_capacity(FuseOpcode::FuseFlush, 0), header);
+
+        let request = fuse_read_request(FuseOpcode::FuseRead, 3, 1, 0, 4096);
+        assert_eq!(request.total_len() - request.in_len(), header + 4096);
+    }
+
+    #[ktest]
+    fn statfs_reply_is_carried_whole() {
+        let st = FuseKstatfs {
+            blocks: 100,
+            bavail: 10,
+            bsize: 4096,
+            frsize: 512,
+            spare: [1, 2, 3, 4, 5, 6],
+            ..Default::default()
+        };
+        let reply = reply_bytes(FuseStatfsOut { st }.as_bytes());
+        let parsed = fuse_reply_val::<FuseStatfsOut>(&reply).unwrap().st;
+        assert_eq!(parsed.spare, st.spare);
+        assert_eq!((parsed.bsize, parsed.frsize), (4096, 512));
+        assert_eq!(fuse_statfs_avail_bytes(&parsed), 10 * 512);
+
+        let unset = FuseKstatfs { frsize: 0, ..st };
+        assert_eq!(fuse_statfs_avail_bytes(&unset), 10 * 4096);
+    }
+
+    #[ktest]
+    fn statfs_completion_is_decoded_past_the_request() {
+        let statfs = FuseStatfsOut {
+            st: FuseKstatfs {
+                blocks: 1000,
+                bsize: 4096,
+                namelen: 255,
+                ..Default::default()
+            },
+        };
+        // STATFS has no input past its header, so the reply follows it.
+        let request = fuse_statfs_request(FUSE_ROOT_ID);
+        assert_eq!(request.in_len(), size_of::<FuseInHeader>());
+        let decoded = cut_short(request, &statfs, 0).unwrap();
+        assert_eq!(decoded.as_bytes(), statfs.as_bytes());
+    }
+
+    #[ktest]
+    fn statfs_namelen_limits_names() {
+        let statfs = FuseStatfsOut {
+            st: FuseKstatfs {
+                namelen: 8,
+                ..Default::default()
+            },
+        };
+        let name_max = fuse_statfs_namelen(&reply_bytes(statfs.as_bytes())).unwrap();
+        assert_eq!(name_max, Some(8));
+        assert!(fuse_check_name_len(b"8_bytes!", name_max).is_ok());
+        assert!(matches!(
+            fuse_check_name_len(b"nine_byte", name_max),
+            Err(FuseError::NameTooLong)
+        ));
+
+        // Without STATFS, no limit is known and the server has the last word.
+        let mut enosys = reply_bytes(&[]);
+        enosys[4..8].copy_from_slice(&(-38i32).to_ne_bytes());
+        let name_max = fuse_statfs_namelen(&enosys).unwrap();
+        assert_eq!(name_max, None);
+        assert!(fuse_check_name_len(b"nine_byte", name_max).is_ok());
+    }
+
+    #[ktest]
+    fn statfs_reply_matches_kernel_layout() {
+        assert_eq!(size_of::<FuseStatfsOut>(), 80);
+
+        // A `fuse_kstatfs` as the kernel lays it out, field by field.
+        let mut payload = Vec::new();
+        for val in [1000u64, 600, 500, 64, 32] {
+            payload.extend_from_slice(&val.to_ne_bytes());
+        }
+        for val in [4096u32, 255, 512, 0xdead, 1, 2, 3, 4, 5, 6] {
+            payload.extend_from_slice(&val.to_ne_bytes());
+        }
+        assert_eq!(payload.len(), 80);
+
+        let statfs = fuse_reply_val::<FuseStatfsOut>(&reply_bytes(&payload)).unwrap();
+        assert_eq!(
+            (statfs.st.blocks, statfs.st.bfree, statfs.st.bavail),
+            (1000, 600, 500)
+        );
+        assert_eq!((statfs.st.files, statfs.st.ffree), (64, 32));
+        assert_eq!(
+            (statfs.st.bsize, statfs.st.namelen, statfs.st.frsize),
+            (4096, 255, 512)
+        );
+        assert_eq!(statfs.st.spare, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(statfs.as_bytes(), payload.as_slice());
+    }
+
+    #[ktest]
+    fn reply_past_stream_end_is_rejected() {
+        let offset = 2 * PAGE_SIZE - size_of::<FuseOutHeader>() - 8;
+        let mut reply = reply_bytes(&[0u8; 8]);
+        reply[0..4].copy_from_slice(&64u32.to_ne_bytes());
+        let stream = two_page_stream(offset, &reply);
+
+        let mut reader = stream.reader().unwrap().skip(offset);
+        let headerout = reader.read_val::<FuseOutHeader>().unwrap();
+        assert!(matches!(
+            fuse_read_payload(&mut reader, headerout),
+            Err(FuseError::Io)
+        ));
+        assert!(matches!(
+            FuseReaddirOut::read_dirent(&mut reader, headerout),
+            Err(FuseError::Io)
+        ));
+    }
+
+    fn manual_request(
+        opcode: FuseOpcode,
+        nodeid: u64,
+        datain: &[&[u8]],
+        out_len: usize,
+    ) -> Vec<u8> {
+        let datain = datain.concat();
+        let headerin = FuseInHeader {
+            len: (size_of::<FuseInHeader>() as u32 + datain.len() as u32),
+            opcode: opcode as u32,
+            unique: 0,
+            nodeid: nodeid,
+            uid: 0,
+            gid: 0,
+            pid: 0,
+            total_extlen: 0,
+            padding: 0,
+        };
+        let headerout_buffer = [0u8; size_of::<FuseOutHeader>()];
+        let dataout_bytes = vec![0u8; out_len - size_of::<FuseOutHeader>()];
+        [
+            headerin.as_bytes(),
+            datain.as_slice(),
+            &headerout_buffer,
+            &dataout_bytes,
+        ]
+        .concat()
+    }
+
+    #[ktest]
+    fn builder_matches_lookup_layout() {
+        let out_len = size_of::<FuseOutHeader>() + size_of::<FuseEntryOut>();
+        let prepared_name = fuse_pad_str(b"hello.txt", true);
+        let expected = manual_request(FuseOpcode::FuseLookup, 1, &[&prepared_name], out_len);
+
+        let builder = RequestBuilder::new(FuseOpcode::FuseLookup, 1)
+            .push_padded_name(b"hello.txt")
+            .reserve_out(out_len);
+        assert_eq!(builder.to_bytes(), expected);
+        assert_eq!(
+            builder.in_len(),
+            size_of::<FuseInHeader>() + prepared_name.len()
+        );
+        assert_eq!(builder.total_len(), expected.len());
+    }
+
+    #[ktest]
+    fn builder_matches_mkdir_layout() {
+        let out_len = size_of::<FuseOutHeader>() + size_of::<FuseEntryOut>();
+        let mkdirin = FuseMkdirIn {
+            mode: 0o755,
+            umask: 0o022,
+        };
+        let prepared_name = fuse_pad_str(b"subdir", true);
+        let expected = manual_request(
+            FuseOpcode::FuseMkdir,
+            1,
+            &[mkdirin.as_bytes(), &prepared_name],
+            out_len,
+        );
+
+        let builder = RequestBuilder::new(FuseOpcode::FuseMkdir, 1)
+            .push_struct(&mkdirin)
+            .push_padded_name(b"subdir")
+            .reserve_out(out_len);
+        assert_eq!(builder.to_bytes(), expected);
+    }
+
+    #[ktest]
+    fn builder_matches_rename_layout() {
+        let out_len = size_of::<FuseOutHeader>();
+        let renamein = FuseRenameIn { newdir: 3 };
+        let prepared_names = fuse_pad_str(b"old\0new_name", true);
+        let expected = manual_request(
+            FuseOpcode::FuseRename,
+            2,
+            &[renamein.as_bytes(), &prepared_names],
+            out_len,
+        );
+
+        let builder = RequestBuilder::new(FuseOpcode::FuseRename, 2)
+            .push_struct(&renamein)
+            .push_bytes(b"old\0")
+            .push_padded_name(b"new_name")
+            .reserve_out(out_len);
+        assert_eq!(builder.to_bytes(), expected);
+    }
+
+    #[ktest]
+    fn exchange_needs_both_names() {
+        assert!(matches!(
+            fuse_check_rename(RENAME_EXCHANGE, Some(42), Some(43)),
+            Ok(None)
+        ));
+        assert!(matches!(
+            fuse_check_rename(RENAME_EXCHANGE, Some(42), None),
+            Err(FuseError::NoEnt)
+        ));
+        assert!(matches!(
+            fuse_check_rename(RENAME_EXCHANGE | RENAME_NOREPLACE, Some(42), Some(43)),
+            Err(FuseError::Inval)
+        ));
+        assert!(matches!(
+            fuse_check_rename(RENAME_EXCHANGE | RENAME_WHITEOUT, Some(42), Some(43)),
+            Err(FuseError::Inval)
+        ));
+        assert!(matches!(
+            fuse_check_rename(1 << 3, Some(42), Some(43)),
+            Err(FuseError::Inval)
+        ));
+
+        // The flags alone are checked before anything is looked up.
+        assert!(fuse_check_rename_flags(RENAME_NOREPLACE | RENAME_WHITEOUT).is_ok());
+        assert!(matches!(
+            fuse_check_rename_flags(RENAME_EXCHANGE | RENAME_NOREPLACE),
+            Err(FuseError::Inval)
+        ));
+    }
+
+    #[ktest]
+    fn noreplace_keeps_existing_target() {
+        assert!(matches!(
+            fuse_check_rename(RENAME_NOREPLACE, Some(42), Some(43)),
+            Err(FuseError::Exist)
+        ));
+        assert!(matches!(
+            fuse_check_rename(RENAME_NOREPLACE, Some(42), None),
+            Ok(None)
+        ));
+        assert!(matches!(
+            fuse_check_rename(RENAME_NOREPLACE, None, None),
+            Err(FuseError::NoEnt)
+        ));
+
+        // Without the flag the target is replaced, and goes stale.
+        assert!(matches!(
+            fuse_check_rename(0, Some(42), Some(43)),
+            Ok(Some(43))
+        ));
+    }
+
+    #[ktest]
+    fn supp_group_only_when_negotiated() {
+        let flags = FuseInitFlags::FUSE_INIT_EXT;
+        let request = fuse_push_supp_group(
+            fuse_mkdir_request(1, 0o755, 0o022, b"dir"),
+            Some(100),
+            flags,
+        );
+        assert_eq!(
+            request.to_bytes(),
+            fuse_mkdir_request(1, 0o755, 0o022, b"dir").to_bytes()
+        );
+
+        let flags = flags | FuseInitFlags::FUSE_CREATE_SUPP_GROUP;
+        let plain = fuse_mkdir_request(1, 0o755, 0o022, b"dir");
+        let request = fuse_push_supp_group(
+            fuse_mkdir_request(1, 0o755, 0o022, b"dir"),
+            Some(100),
+            flags,
+        );
+        let bytes = request.to_bytes();
+        let ext = &bytes[plain.in_len()..request.in_len()];
+
+        let headerin = VmReader::from(bytes.as_slice())
+            .read_val::<FuseInHeader>()
+            .unwrap();
+        assert_eq!(headerin.len as usize, plain.in_len() + 16);
+        assert_eq!(headerin.total_extlen, 2);
+        let mut reader = VmReader::from(ext);
+        let ext_header = reader.read_val::<FuseExtHeader>().unwrap();
+        assert_eq!(ext_header.size, 16);
+        assert_eq!(ext_header.type_, FuseExtType::FuseExtGroups as u32);
+        assert_eq!(reader.read_val::<u32>().unwrap(), 1);
+        assert_eq!(reader.read_val::<u32>().unwrap(), 100);
+
+        let request = fuse_push_supp_group(fuse_symlink_request(1, b"l", b"t"), None, flags);
+        assert_eq!(
+            request.to_bytes(),
+            fuse_symlink_request(1, b"l", b"t").to_bytes()
+        );
+    }
+
+    #[ktest]
+    fn interior_nul_is_rejected() {
+        assert!(matches!(
+            fuse_check_name(b"foo\0bar"),
+            Err(FuseError::Inval)
+        ));
+        assert!(matches!(fuse_check_name(b"foo\0"), Err(FuseError::Inval)));
+        assert!(fuse_check_name(b"foobar").is_ok());
+    }
+
+    #[ktest]
+    fn non_utf8_name_is_sent_verbatim() {
+        let name = b"caf\xe9\xff\xfe";
+        assert!(fuse_check_name(name).is_ok());
+        assert_eq!(fuse_pad_str(name, true), b"caf\xe9\xff\xfe\0");
+
+        let bytes = fuse_lookup_request(1, name).to_bytes();
+        let datain = &bytes[size_of::<FuseInHeader>()..];
+        assert_eq!(&datain[..name.len() + 1], b"caf\xe9\xff\xfe\0");
+    }
+
+    #[ktest]
+    fn empty_name_is_rejected() {
+        assert!(matches!(fuse_check_name(b""), Err(FuseError::Inval)));
+        assert!(fuse_check_name(b"a").is_ok());
+
+        // `create` builds its request through the check.
+        let create = |name: &[u8]| fuse_checked_create_request(1, name, 0o644, 0, 0, Some(4));
+        assert!(matches!(create(b""), Err(FuseError::Inval)));
+        assert!(matches!(create(b"hello"), Err(FuseError::NameTooLong)));
+        let bytes = create(b"a").unwrap().to_bytes();
+        let name = &bytes[size_of::<FuseInHeader>() + size_of::<FuseCreateIn>()..];
+        assert_eq!(&name[..2], b"a\0");
+    }
+
+    #[ktest]
+    fn largest_batch_forget_fits_a_page() {
+        let forgets = [(2, 1); FUSE_MAX_BATCH_FORGET + 1];
+        let largest = fuse_batch_forget_request(&forgets[..FUSE_MAX_BATCH_FORGET]);
+        assert!(largest.total_len() <= PAGE_SIZE);
+        assert!(fuse_batch_forget_request(&forgets).total_len() > PAGE_SIZE);
+    }
+
+    #[ktest]
+    fn out_body_respects_header_len() {
+        let header = |len: usize, error: i32| FuseOutHeader {
+            len: len as u32,
+            error,
+            unique: 2,
+        };
+        let writeout = FuseWriteOut {
+            size: 5,
+            ..Default::default()
+        };
+        // The buffer holds a body past the reply, as a reused one may.
+        let bytes = writeout.as_bytes();
+        let full = size_of::<FuseOutHeader>() + size_of::<FuseWriteOut>();
+
+        let read =
+            |headerout| fuse_read_out_body::<FuseWriteOut>(&mut VmReader::from(bytes), headerout);
+        assert_eq!(read(header(full, 0)).unwrap().size, 5);
+        assert!(read(header(size_of::<FuseOutHeader>(), 0)).is_none());
+        assert!(read(header(full - 1, 0)).is_none());
+        assert!(read(header(full, -2)).is_none());
     }
 }
@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::time::Duration;
+
+use super::{
+    errno::EAGAIN,
+    error::{FuseError, Result},
+};
+
+/// How an operation is sent again after the server replied EAGAIN.
+///
+/// Only operations the caller knows to be safe to repeat should retry; for
+/// the others EAGAIN reaches the caller like any other error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of times the operation is sent at most, the first included.
+    pub max_attempts: u32,
+    /// How long to wait before each resend.
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Sends the operation once.
+    pub const NEVER: Self = Self {
+        max_attempts: 1,
+        delay: Duration::ZERO,
+    };
+
+    /// Rides out a lock or resource the server holds only briefly.
+    pub const TRANSIENT: Self = Self {
+        max_attempts: 4,
+        delay: Duration::from_millis(10),
+    };
+}
+
+/// Returns whether `err` says the server could not do the operation right now.
+pub fn fuse_is_again(err: &FuseError) -> bool {
+    matches!(err, FuseError::ServerError(EAGAIN))
+}
+
+/// Runs `op` until it does not fail with EAGAIN, at most as often as `policy`
+/// allows, calling `sleep` with the policy's delay before each resend.
+///
+/// Once the attempts run out, the last EAGAIN is returned.
+pub fn fuse_retry_again<T>(
+    policy: RetryPolicy,
+    mut op: impl FnMut() -> Result<T>,
+    mut sleep: impl FnMut(Duration),
+) -> Result<T> {
+    let mut attempts = 1;
+    loop {
+        match op() {
+            Err(err) if fuse_is_again(&err) && attempts < policy.max_attempts => {
+                attempts += 1;
+                sleep(policy.delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::vec::Vec;
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    /// A server that is busy for the first `busy` attempts.
+    fn server(busy: u32) -> impl FnMut() -> Result<u32> {
+        let mut attempts = 0;
+        move || {
+            attempts += 1;
+            if attempts <= busy {
+                Err(FuseError::ServerError(EAGAIN))
+            } else {
+                Ok(attempts)
+            }
+        }
+    }
+
+    #[ktest]
+    fn eagain_is_retried_until_success() {
+        let mut slept = Vec::new();
+        let result = fuse_retry_again(RetryPolicy::TRANSIENT, server(1), |delay| slept.push(delay));
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(slept, [RetryPolicy::TRANSIENT.delay]);
+    }
+
+    #[ktest]
+    fn retries_are_bounded() {
+        let mut slept = 0;
+        let result = fuse_retry_again(RetryPolicy::TRANSIENT, server(u32::MAX), |_| slept += 1);
+        assert!(matches!(result, Err(FuseError::ServerError(EAGAIN))));
+        assert_eq!(slept, RetryPolicy::TRANSIENT.max_attempts - 1);
+
+        // An operation not marked retryable sees the first EAGAIN.
+        let result = fuse_retry_again(RetryPolicy::NEVER, server(1), |_| unreachable!());
+        assert!(fuse_is_again(&result.unwrap_err()));
+
+        // Other errors are not retried either.
+        let mut attempts = 0;
+        let result: Result<()> = fuse_retry_again(
+            RetryPolicy::TRANSIENT,
+            || {
+                attempts += 1;
+                Err(FuseError::NoEnt)
+            },
+            |_| (),
+        );
+        assert!(matches!(result, Err(FuseError::NoEnt)));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The virtqueue index of the high-priority queue.
+pub const HIPRIO_QUEUE_INDEX: u16 = 0;
+/// The virtqueue index of the notification queue, if the device has one.
+pub const NOTIFICATION_QUEUE_INDEX: u16 = 1;
+
+/// The number of descriptors asked for in each kind of queue.
+///
+/// Every hiprio and notification descriptor has a buffer of its own. A
+/// request queue has a buffer per two descriptors, as a request takes two
+/// (three with a payload), so the default holds 128 requests at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDepths {
+    pub hiprio: u16,
+    pub notification: u16,
+    pub request: u16,
+}
+
+impl QueueDepths {
+    pub const DEFAULT: Self = Self {
+        hiprio: 32,
+        notification: 32,
+        request: 256,
+    };
+}
+
+impl Default for QueueDepths {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Returns the size of a queue asked to be `depth` descriptors deep, where the
+/// transport allows at most `max`.
+///
+/// Queue sizes are powers of two, so the depth is rounded down to one; it is
+/// at least 1, and `None` if the transport has no such queue.
+pub fn fuse_queue_depth(depth: u16, max: u16) -> Option<u16> {
+    let depth = depth.min(max);
+    if depth == 0 {
+        return None;
+    }
+    Some(1 << depth.ilog2())
+}
+
+/// Returns the virtqueue index of the request queue `index`, which its
+/// completions are signalled on.
+///
+/// The request queues follow the hiprio queue, and the notification queue
+/// if `has_notification_queue`.
+pub fn request_queue_vq(index: usize, has_notification_queue: bool) -> u16 {
+    let base = if has_notification_queue {
+        NOTIFICATION_QUEUE_INDEX + 1
+    } else {
+        HIPRIO_QUEUE_INDEX + 1
+    };
+    base + index as u16
+}
+
+/// Returns the request queues to try for a request submitted on `cpu`, the
+/// preferred one first.
+///
+/// Each CPU prefers queue `cpu % num_queues`, so submitters on different CPUs
+/// mostly take different queue locks instead of all contending for one. The
+/// other queues follow in turn, for when the preferred one is full.
+pub fn request_queue_order(cpu: usize, num_queues: usize) -> impl Iterator<Item = usize> {
+    let preferred = cpu % num_queues;
+    (0..num_queues).map(move |i| (preferred + i) % num_queues)
+}
+
+/// Spreads requests that need no reply from the caller's CPU over the
+/// request queues in turn.
+#[derive(Debug)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the next of `num_queues` queues, skipping those `is_busy` says
+    /// are taken, unless all of them are.
+    pub fn pick(&self, num_queues: usize, is_busy: impl Fn(usize) -> bool) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % num_queues;
+        (0..num_queues)
+            .map(|i| (start + i) % num_queues)
+            .find(|&index| !is_busy(index))
+            .unwrap_or(start)
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::{collections::BTreeSet, vec::Vec};
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn cpus_prefer_distinct_queues() {
+        // With as many queues as CPUs, no two CPUs share a preferred queue lock.
+        let preferred: BTreeSet<usize> = (0..4)
+            .map(|cpu| request_queue_order(cpu, 4).next().unwrap())
+            .collect();
+        assert_eq!(preferred.len(), 4);
+
+        // With fewer queues, the CPUs are spread evenly.
+        let preferred: Vec<usize> = (0..4)
+            .map(|cpu| request_queue_order(cpu, 2).next().unwrap())
+            .collect();
+        assert_eq!(preferred, [0, 1, 0, 1]);
+    }
+
+    #[ktest]
+    fn request_queues_follow_hiprio() {
+        let vqs: BTreeSet<u16> = (0..4).map(|index| request_queue_vq(index, false)).collect();
+        assert_eq!(vqs.len(), 4);
+        assert!(!vqs.contains(&HIPRIO_QUEUE_INDEX));
+        assert_eq!(request_queue_vq(1, false), 2);
+
+        // The notification queue shifts them up by one.
+        let vqs: BTreeSet<u16> = (0..4).map(|index| request_queue_vq(index, true)).collect();
+        assert!(!vqs.contains(&HIPRIO_QUEUE_INDEX));
+        assert!(!vqs.contains(&NOTIFICATION_QUEUE_INDEX));
+        assert_eq!(request_queue_vq(0, true), 2);
+    }
+
+    #[ktest]
+    fn full_queue_falls_back_to_the_others() {
+        let order: Vec<usize> = request_queue_order(5, 4).collect();
+        assert_eq!(order, [1, 2, 3, 0]);
+
+        let order: Vec<usize> = request_queue_order(3, 1).collect();
+        assert_eq!(order, [0]);
+    }
+
+    #[ktest]
+    fn queue_depth_is_clamped_to_a_power_of_two() {
+        assert_eq!(
+            fuse_queue_depth(QueueDepths::DEFAULT.request, 1024),
+            Some(256)
+        );
+        assert_eq!(fuse_queue_depth(128, 1024), Some(128));
+        assert_eq!(fuse_queue_depth(128, 64), Some(64));
+        assert_eq!(fuse_queue_depth(100, 1024), Some(64));
+        assert_eq!(fuse_queue_depth(128, 96), Some(64));
+        assert_eq!(fuse_queue_depth(0, 1024), None);
+        assert_eq!(fuse_queue_depth(128, 0), None);
+    }
+
+    #[ktest]
+    fn round_robin_visits_every_queue() {
+        let queues = RoundRobin::new();
+        let picked: Vec<usize> = (0..6).map(|_| queues.pick(3, |_| false)).collect();
+        assert_eq!(picked, [0, 1, 2, 0, 1, 2]);
+
+        // A queue whose buffer is waited on is passed over, unless all are.
+        assert_eq!(queues.pick(3, |index| index == 0), 1);
+        assert_eq!(queues.pick(3, |_| true), 1);
+    }
+}
@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+use super::fuse::FuseOpcode;
+
+/// How long shutdown waits for the requests in flight, and then for the reply
+/// to FUSE_DESTROY, before it resets the device anyway.
+pub const FUSE_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+const RUNNING: u8 = 0;
+const SHUTTING_DOWN: u8 = 1;
+const SHUT_DOWN: u8 = 2;
+
+/// How far the device has got in shutting down.
+///
+/// Only the first caller of `shutdown` tears the device down; later ones wait
+/// with [`Self::wait_shut_down`] until it has, which takes a bounded time as
+/// each of its waits gives up after [`FUSE_DRAIN_TIMEOUT`].
+#[derive(Debug)]
+pub struct ShutdownState {
+    state: AtomicU8,
+}
+
+impl ShutdownState {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(RUNNING),
+        }
+    }
+
+    /// Starts shutting down, and returns whether this caller is the first to.
+    pub fn begin(&self) -> bool {
+        self.state
+            .compare_exchange(RUNNING, SHUTTING_DOWN, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    /// Records that the device has been reset, after which nothing is sent.
+    pub fn finish(&self) {
+        self.state.store(SHUT_DOWN, Ordering::Release);
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.state.load(Ordering::Acquire) == SHUT_DOWN
+    }
+
+    /// Returns whether a request with `opcode` may still be sent.
+    ///
+    /// Once shutting down has begun, only the FORGETs and FUSE_DESTROY that
+    /// shutdown sends itself are let through; after the reset, nothing is.
+    pub fn admits(&self, opcode: FuseOpcode) -> bool {
+        match self.state.load(Ordering::Acquire) {
+            RUNNING => true,
+            SHUTTING_DOWN => matches!(
+                opcode,
+                FuseOpcode::FuseForget | FuseOpcode::FuseBatchForget | FuseOpcode::FuseDestroy
+            ),
+            _ => false,
+        }
+    }
+
+    /// Waits until the first caller has finished shutting down.
+    pub fn wait_shut_down(&self) {
+        while !self.is_shut_down() {
+            spin_loop();
+        }
+    }
+}
+
+/// Polls `is_done` until it holds or `now` reaches `deadline`, and returns
+/// whether it held.
+pub fn fuse_drain(deadline: u64, now: impl Fn() -> u64, mut is_done: impl FnMut() -> bool) -> bool {
+    loop {
+        if is_done() {
+            return true;
+        }
+        if now() >= deadline {
+            return false;
+        }
+        spin_loop();
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use core::cell::Cell;
+
+    use ostd::prelude::*;
+
+    use super::*;
+
+    #[ktest]
+    fn second_shutdown_is_harmless() {
+        let state = ShutdownState::new();
+        assert!(state.begin());
+        // A concurrent caller does not tear the device down again.
+        assert!(!state.begin());
+        assert!(!state.is_shut_down());
+
+        state.finish();
+        assert!(state.is_shut_down());
+        // A later caller neither starts over nor waits.
+        assert!(!state.begin());
+        state.wait_shut_down();
+        assert!(state.is_shut_down());
+    }
+
+    #[ktest]
+    fn shutdown_rejects_new_requests() {
+        let state = ShutdownState::new();
+        assert!(state.admits(FuseOpcode::FuseRead));
+
+        state.begin();
+        assert!(!state.admits(FuseOpcode::FuseRead));
+        assert!(!state.admits(FuseOpcode::FuseInit));
+        for opcode in [
+            FuseOpcode::FuseForget,
+            FuseOpcode::FuseBatchForget,
+            FuseOpcode::FuseDestroy,
+        ] {
+            assert!(state.admits(opcode));
+        }
+
+        state.finish();
+        assert!(!state.admits(FuseOpcode::FuseDestroy));
+    }
+
+    #[ktest]
+    fn drain_gives_up_at_the_deadline() {
+        let clock = Cell::new(0);
+        let tick = || {
+            clock.set(clock.get() + 1);
+            clock.get()
+        };
+        // A queue that never goes idle is waited for until the deadline only.
+        assert!(!fuse_drain(10, tick, || false));
+        assert_eq!(clock.get(), 10);
+
+        let mut polls = 0;
+        assert!(fuse_drain(
+            u64::MAX,
+            || 0,
+            || {
+                polls += 1;
+                polls == 3
+            }
+        ));
+    }
+}
@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{vec, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Tracks which request buffers hold a request in flight.
+///
+/// Each request queue has a pool of buffers, one per request it can hold at
+/// a time. A buffer is taken with [`BufferSlots::try_acquire`] and given back
+/// when the returned guard is dropped, so a request failing halfway still
+/// frees its buffer. [`BufferSlots::in_use`] lets tests check that none leak.
+///
+/// A request whose reply the completion handler reads keeps its buffer past
+/// the guard: [`SlotGuard::hand_off`] records its descriptor token, and the
+/// handler takes the buffer back with [`BufferSlots::reap`].
+#[derive(Debug)]
+pub struct BufferSlots {
+    /// Whether each buffer of each queue is in use.
+    slots: Vec<Vec<AtomicBool>>,
+    /// The token of the request handed off in each buffer, or [`NO_TOKEN`].
+    tokens: Vec<Vec<AtomicU32>>,
+}
+
+/// Marks a buffer no request has been handed off in.
+const NO_TOKEN: u32 = u32::MAX;
+
+impl BufferSlots {
+    /// Creates the pools of `num_queues` queues, of `pool_size` buffers each.
+    pub fn new(num_queues: usize, pool_size: usize) -> Self {
+        Self {
+            slots: (0..num_queues)
+                .map(|_| (0..pool_size).map(|_| AtomicBool::new(false)).collect())
+                .collect(),
+            tokens: (0..num_queues)
+                .map(|_| (0..pool_size).map(|_| AtomicU32::new(NO_TOKEN)).collect())
+                .collect(),
+        }
+    }
+
+    /// Marks a free buffer of the queue `queue` in use, if it has one.
+    ///
+    /// This does not need the lock of the queue: a buffer stays in use, and
+    /// so out of the device's reach, until the guard is dropped or handed off.
+    pub fn try_acquire(&self, queue: usize) -> Option<SlotGuard<'_>> {
+        let slot = self.slots[queue].iter().position(|slot| {
+            slot.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        Some(SlotGuard {
+            slots: self,
+            queue,
+            slot,
+        })
+    }
+
+    /// Takes back the buffer of the queue `queue` that the request handed off
+    /// as `token` is in, now that the device has used it.
+    ///
+    /// Returns `None` if no request was handed off as `token` on the queue.
+    /// The buffer stays in use until the guard is dropped, so that the reply
+    /// can be read from it first.
+    pub fn reap(&self, queue: usize, token: u16) -> Option<SlotGuard<'_>> {
+        let slot = self.tokens[queue].iter().position(|slot_token| {
+            slot_token
+                .compare_exchange(token as u32, NO_TOKEN, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        Some(SlotGuard {
+            slots: self,
+            queue,
+            slot,
+        })
+    }
+
+    /// Returns whether the queue `queue` has a buffer free for a request.
+    pub fn has_free(&self, queue: usize) -> bool {
+        self.slots[queue]
+            .iter()
+            .any(|slot| !slot.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of buffers in use, over all queues.
+    pub fn in_use(&self) -> usize {
+        self.slots
+            .iter()
+            .flatten()
+            .filter(|slot| slot.load(Ordering::Relaxed))
+            .count()
+    }
+}
+
+/// A request buffer in use, freed on drop.
+#[derive(Debug)]
+pub struct SlotGuard<'a> {
+    slots: &'a BufferSlots,
+    queue: usize,
+    slot: usize,
+}
+
+impl SlotGuard<'_> {
+    /// Returns the request queue the buffer belongs to.
+    pub fn queue(&self) -> usize {
+        self.queue
+    }
+
+    /// Returns the index of the buffer in the pool of its queue.
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    /// Leaves the buffer in use, holding the request added as `token`, until
+    /// [`BufferSlots::reap`] is called with that token.
+    ///
+    /// The caller holds the lock of the queue, so that the request cannot
+    /// complete before its token is recorded.
+    pub fn hand_off(self, token: u16) {
+        self.slots.tokens[self.queue][self.slot].store(token as u32, Ordering::Release);
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        self.slots.slots[self.queue][self.slot].store(false, Ordering::Release);
+    }
+}
+
+/// Tracks which hiprio buffers hold a message the device has not used yet.
+///
+/// A FORGET has no reply to wait for, so its buffer stays taken after the
+/// call that sent it returns; it is freed as the hiprio used ring is reaped.
+/// The buffers posted on the notification queue are tracked the same way.
+#[derive(Debug)]
+pub struct HiprioSlots {
+    /// The descriptor token of the message in each buffer, if any.
+    tokens: Vec<Option<u16>>,
+}
+
+impl HiprioSlots {
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            tokens: vec![None; num_slots],
+        }
+    }
+
+    /// Returns a buffer holding no message, if there is one.
+    pub fn free_slot(&self) -> Option<usize> {
+        self.tokens.iter().position(Option::is_none)
+    }
+
+    /// Records that the buffer `index` holds the message added as `token`.
+    pub fn submitted(&mut self, index: usize, token: u16) {
+        debug_assert!(
+            self.tokens[index].is_none(),
+            "hiprio buffer {} is already in use",
+            index
+        );
+        self.tokens[index] = Some(token);
+    }
+
+    /// Frees the buffer of the message `token`, which the device has used.
+    ///
+    /// Returns the buffer freed, or `None` for a token of no buffer.
+    pub fn reaped(&mut self, token: u16) -> Option<usize> {
+        let index = self.tokens.iter().position(|slot| *slot == Some(token))?;
+        self.tokens[index] = None;
+        Some(index)
+    }
+
+    /// Returns the number of buffers in use.
+    pub fn in_use(&self) -> usize {
+        self.tokens.iter().filter(|slot| slot.is_some()).count()
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::filesystem::error::{FuseError, Result};
+
+    /// Mimics a request that takes a buffer and may fail before completing.
+    fn request(slots: &BufferSlots, queue: usize, fail: bool) -> Result<usize> {
+        let slot = slots.try_acquire(queue).ok_or(FuseError::Busy)?;
+        if fail {
+            return Err(FuseError::Io);
+        }
+        Ok(slot.slot())
+    }
+
+    #[ktest]
+    fn mixed_workload_frees_all_slots() {
+        let slots = BufferSlots::new(2, 2);
+
+        for i in 0..16 {
+            let _ = request(&slots, i % 2, i % 3 == 0);
+        }
+        let held: Vec<SlotGuard> = (0..3).map(|i| slots.try_acquire(i % 2).unwrap()).collect();
+        assert_eq!(slots.in_use(), 3);
+        assert!(request(&slots, 1, false).is_ok());
+        assert_eq!(slots.in_use(), 3);
+
+        drop(held);
+        assert_eq!(slots.in_use(), 0);
+    }
+
+    #[ktest]
+    fn full_pool_hands_out_no_buffer() {
+        let slots = BufferSlots::new(2, 2);
+
+        let first = slots.try_acquire(0).unwrap();
+        let second = slots.try_acquire(0).unwrap();
+        assert_ne!(first.slot(), second.slot());
+        assert!(!slots.has_free(0));
+        assert!(slots.try_acquire(0).is_none());
+        // The other queue has buffers of its own.
+        assert_eq!(slots.try_acquire(1).map(|slot| slot.queue()), Some(1));
+
+        drop(first);
+        assert!(slots.has_free(0));
+        assert!(slots.try_acquire(0).is_some());
+        drop(second);
+        assert_eq!(slots.in_use(), 0);
+    }
+
+    #[ktest]
+    fn handed_off_buffer_is_reaped_by_its_token() {
+        let slots = BufferSlots::new(2, 2);
+
+        slots.try_acquire(0).unwrap().hand_off(3);
+        slots.try_acquire(1).unwrap().hand_off(3);
+        slots.try_acquire(1).unwrap().hand_off(4);
+        assert!(slots.try_acquire(1).is_none());
+        assert_eq!(slots.in_use(), 3);
+
+        // A token of no handed-off request leaves the buffers alone.
+        assert!(slots.reap(1, 5).is_none());
+        let reaped = slots.reap(1, 4).unwrap();
+        assert_eq!((reaped.queue(), reaped.slot()), (1, 1));
+        // The buffer is still in use while its reply is read.
+        assert!(slots.try_acquire(1).is_none());
+        assert!(slots.reap(1, 4).is_none());
+        drop(reaped);
+        assert_eq!(slots.in_use(), 2);
+
+        drop(slots.reap(0, 3));
+        drop(slots.reap(1, 3));
+        assert_eq!(slots.in_use(), 0);
+    }
+
+    #[ktest]
+    fn concurrent_forgets_use_distinct_slots() {
+        let mut slots = HiprioSlots::new(3);
+
+        // Three forgets are sent before the device uses any of them.
+        let sent: Vec<usize> = (0..3)
+            .map(|token| {
+                let index = slots.free_slot().unwrap();
+                slots.submitted(index, token);
+                index
+            })
+            .collect();
+        assert_eq!(sent, [0, 1, 2]);
+        assert_eq!(slots.free_slot(), None);
+
+        // The device uses the second one first; its buffer goes to the next.
+        assert_eq!(slots.reaped(1), Some(1));
+        assert_eq!(slots.reaped(1), None);
+        assert_eq!(slots.free_slot(), Some(1));
+        slots.submitted(1, 3);
+        assert_eq!(slots.in_use(), 3);
+
+        for token in [0, 2, 3] {
+            slots.reaped(token);
+        }
+        assert_eq!(slots.in_use(), 0);
+    }
+}
@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::error::{FuseError, Result};
+use crate::{
+    dma_buf::DmaBuf,
+    queue::{QueueError, VirtQueue},
+};
+
+/// Counters of queue contention, for operators to watch.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    /// The requests that found no free descriptors in their queue.
+    queue_full: AtomicU64,
+}
+
+impl QueueStats {
+    pub const fn new() -> Self {
+        Self {
+            queue_full: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds the buffers of a request to `queue`, counting the failures of a
+    /// full descriptor ring.
+    pub fn add_dma_buf<T: DmaBuf>(
+        &self,
+        queue: &mut VirtQueue,
+        inputs: &[&T],
+        outputs: &[&T],
+    ) -> Result<u16> {
+        queue
+            .add_dma_buf(inputs, outputs)
+            .map_err(|err| self.add_failed(err))
+    }
+
+    fn add_failed(&self, err: QueueError) -> FuseError {
+        let err = FuseError::from(err);
+        if matches!(err, FuseError::Busy) {
+            self.queue_full.fetch_add(1, Ordering::Relaxed);
+        }
+        err
+    }
+
+    pub fn queue_full(&self) -> u64 {
+        self.queue_full.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use alloc::boxed::Box;
+
+    use aster_util::safe_ptr::SafePtr;
+    use ostd::{
+        bus::pci::cfg_space::Bar,
+        io_mem::IoMem,
+        mm::{DmaCoherent, DmaDirection, DmaStream, FrameAllocOptions},
+        prelude::*,
+        trap::IrqCallbackFunction,
+    };
+
+    use super::*;
+    use crate::{
+        device::VirtioDeviceType,
+        queue::{AvailRing, Descriptor, UsedRing},
+        transport::{ConfigManager, DeviceStatus, VirtioTransport, VirtioTransportError},
+    };
+
+    /// A transport that accepts every queue and never notifies a device.
+    #[derive(Debug)]
+    struct NullTransport;
+
+    impl VirtioTransport for NullTransport {
+        fn device_type(&self) -> VirtioDeviceType {
+            VirtioDeviceType::Filesystem
+        }
+
+        fn read_device_features(&self) -> u64 {
+            0
+        }
+
+        fn write_driver_features(
+            &mut self,
+            _: u64,
+        ) -> core::result::Result<(), VirtioTransportError> {
+            Ok(())
+        }
+
+        fn read_device_status(&self) -> DeviceStatus {
+            DeviceStatus::empty()
+        }
+
+        fn write_device_status(
+            &mut self,
+            _: DeviceStatus,
+        ) -> core::result::Result<(), VirtioTransportError> {
+            Ok(())
+        }
+
+        fn device_config_mem(&self) -> Option<IoMem> {
+            None
+        }
+
+        fn device_config_bar(&self) -> Option<(Bar, usize)> {
+            None
+        }
+
+        fn num_queues(&self) -> u16 {
+            1
+        }
+
+        fn set_queue(
+            &mut self,
+            _: u16,
+            _: u16,
+            _: &SafePtr<Descriptor, DmaCoherent>,
+            _: &SafePtr<AvailRing, DmaCoherent>,
+            _: &SafePtr<UsedRing, DmaCoherent>,
+        ) -> core::result::Result<(), VirtioTransportError> {
+            Ok(())
+        }
+
+        fn max_queue_size(&self, _: u16) -> core::result::Result<u16, VirtioTransportError> {
+            Ok(256)
+        }
+
+        fn notify_config(&self, _: usize) -> ConfigManager<u32> {
+            ConfigManager::new(None, None)
+        }
+
+        fn is_legacy_version(&self) -> bool {
+            false
+        }
+
+        fn register_queue_callback(
+            &mut self,
+            _: u16,
+            _: Box<IrqCallbackFunction>,
+            _: bool,
+        ) -> core::result::Result<(), VirtioTransportError> {
+            Ok(())
+        }
+
+        fn register_cfg_callback(
+            &mut self,
+            _: Box<IrqCallbackFunction>,
+        ) -> core::result::Result<(), VirtioTransportError> {
+            Ok(())
+        }
+    }
+
+    #[ktest]
+    fn full_queue_is_busy_and_counted() {
+        let stats = QueueStats::new();
+        let mut queue = VirtQueue::new(0, 4, &mut NullTransport).unwrap();
+        let segment = FrameAllocOptions::new().alloc_segment(1).unwrap();
+        let buffer = DmaStream::map(segment.into(), DmaDirection::Bidirectional, false).unwrap();
+
+        // Two requests of two descriptors each fill the ring.
+        for _ in 0..2 {
+            assert!(stats
+                .add_dma_buf(&mut queue, &[&buffer], &[&buffer])
+                .is_ok());
+        }
+        assert_eq!(stats.queue_full(), 0);
+        let result = stats.add_dma_buf(&mut queue, &[&buffer], &[&buffer]);
+        assert!(matches!(result, Err(FuseError::Busy)));
+        assert_eq!(stats.queue_full(), 1);
+
+        // Other failures are not contention.
+        let result = stats.add_dma_buf::<DmaStream>(&mut queue, &[], &[]);
+        assert!(matches!(
+            result,
+            Err(FuseError::QueueError(QueueError::InvalidArgs))
+        ));
+        assert_eq!(stats.queue_full(), 1);
+    }
+}
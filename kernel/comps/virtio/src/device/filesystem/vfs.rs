@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::{sync::Arc, vec::Vec};
+
+use super::{
+    device::FilesystemDevice,
+    dir::{DirOps, ReaddirStream},
+    error::Result,
+    file::{fuse_read_range, FileOps, O_RDONLY, O_WRONLY},
+    fuse::{FuseAttr, FUSE_ROOT_ID},
+    request::{fuse_write_all, FuseContext, FuseDirentWithName},
+};
+
+/// The synchronous operations a [`VirtioFsInode`] is built from.
+pub trait InodeOps: FileOps + DirOps {
+    fn getattr(&self, ctx: &FuseContext, nodeid: u64) -> Result<FuseAttr>;
+}
+
+/// A node of the shared directory, for path-based callers.
+///
+/// Every call blocks until the server has replied, and is made with the
+/// credentials of its caller, `ctx`. Errors are `FuseError`s, which the
+/// kernel turns into its own `Error`, with the errno the VFS reports, by `?`.
+/// An inode found by [`Self::lookup`] holds a lookup
+/// on the server, which is given up when it is dropped.
+pub struct VirtioFsInode<O: InodeOps = FilesystemDevice> {
+    ops: Arc<O>,
+    nodeid: u64,
+}
+
+impl<O: InodeOps> VirtioFsInode<O> {
+    /// Returns the root of the shared directory.
+    pub fn root(ops: Arc<O>) -> Self {
+        Self {
+            ops,
+            nodeid: FUSE_ROOT_ID,
+        }
+    }
+
+    pub fn nodeid(&self) -> u64 {
+        self.nodeid
+    }
+
+    /// Looks up `name` in this directory.
+    pub fn lookup(&self, ctx: &FuseContext, name: &[u8]) -> Result<Self> {
+        let entry = self.ops.lookup(ctx, self.nodeid, name)?;
+        Ok(Self {
+            ops: self.ops.clone(),
+            nodeid: entry.nodeid,
+        })
+    }
+
+    pub fn metadata(&self, ctx: &FuseContext) -> Result<FuseAttr> {
+        self.ops.getattr(ctx, self.nodeid)
+    }
+
+    /// Reads up to `size` bytes at `offset`; fewer mean the end of the file.
+    pub fn read_at(&self, ctx: &FuseContext, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let fh = self.ops.open(ctx, self.nodeid, O_RDONLY)?;
+        let data = fuse_read_range(offset, size, |offset, size| {
+            self.ops.read_at(ctx, self.nodeid, fh, offset, size)
+        });
+        let released = self.ops.release(ctx, self.nodeid, fh, O_RDONLY);
+        let data = data?;
+        released?;
+        Ok(data)
+    }
+
+    /// Writes all of `data` at `offset` and returns the number of bytes the
+    /// server reported written.
+    pub fn write_at(&self, ctx: &FuseContext, offset: u64, data: &[u8]) -> Result<usize> {
+        let fh = self.ops.open(ctx, self.nodeid, O_WRONLY)?;
+        let written = fuse_write_all(offset, data, |offset, data| {
+            self.ops.write_at(ctx, self.nodeid, fh, offset, data)
+        });
+        let released = self.ops.release(ctx, self.nodeid, fh, O_WRONLY);
+        let written = written?;
+        released?;
+        Ok(written)
+    }
+
+    /// Lists the entries of this directory.
+    pub fn readdir(&self, ctx: &FuseContext) -> Result<Vec<FuseDirentWithName>> {
+        ReaddirStream::open(self.ops.as_ref(), ctx, self.nodeid)?.collect()
+    }
+}
+
+impl<O: InodeOps> Drop for VirtioFsInode<O> {
+    fn drop(&mut self) {
+        // The root is never looked up, so there is nothing to give up.
+        if self.nodeid != FUSE_ROOT_ID {
+            self.ops.abandon(self.nodeid);
+        }
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::filesystem::{
+        error::FuseError,
+        mock::{MockServer, S_IFDIR, S_IFREG},
+    };
+
+    #[ktest]
+    fn lookup_from_root_reads_child_metadata() {
+        let server = Arc::new(MockServer::default());
+        assert_eq!(server.add_file(b"hello", b""), 2);
+        let ctx = FuseContext {
+            uid: 1000,
+            gid: 1000,
+            pid: 42,
+        };
+        let root = VirtioFsInode::root(server.clone());
+        assert_eq!(root.metadata(&ctx).unwrap().mode, S_IFDIR | 0o755);
+        let names: Vec<_> = root
+            .readdir(&ctx)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, [b"hello"]);
+
+        let hello = root.lookup(&ctx, b"hello").unwrap();
+        assert_eq!(hello.write_at(&ctx, 0, b"hello world").unwrap(), 11);
+        let attr = hello.metadata(&ctx).unwrap();
+        assert_eq!((attr.ino, attr.mode, attr.size), (2, S_IFREG | 0o644, 11));
+        assert_eq!(hello.read_at(&ctx, 6, 100).unwrap(), b"world");
+        assert!(matches!(
+            root.lookup(&ctx, b"missing"),
+            Err(FuseError::NoEnt)
+        ));
+        // Every request went with the caller's credentials.
+        assert!(server.callers.borrow().iter().all(|caller| *caller == ctx));
+
+        // Dropping the child gives up its lookup; the root has none.
+        drop(hello);
+        drop(root);
+        assert_eq!(server.inodes.borrow_mut().forget_pending(), [(2, 1)]);
+    }
+}
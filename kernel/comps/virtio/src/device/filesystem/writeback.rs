@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MPL-2.0
+
+use alloc::collections::BTreeMap;
+
+use super::error::{FuseError, Result};
+
+/// The errors of writes that failed after their caller moved on, keyed by
+/// node ID.
+///
+/// Like Linux's `AS_EIO`, an error is reported once, to the next fsync or
+/// close of the node, and then cleared.
+#[derive(Debug, Default)]
+pub struct WritebackErrors {
+    errors: BTreeMap<u64, i32>,
+}
+
+impl WritebackErrors {
+    pub const fn new() -> Self {
+        Self {
+            errors: BTreeMap::new(),
+        }
+    }
+
+    /// Records that a WRITE or FLUSH of `nodeid` nobody waited for failed
+    /// with `errno`.
+    ///
+    /// A node keeps its first error until it is reported.
+    pub fn record(&mut self, nodeid: u64, errno: i32) {
+        self.errors.entry(nodeid).or_insert(errno);
+    }
+
+    /// Takes the pending error of `nodeid`, if any.
+    pub fn take(&mut self, nodeid: u64) -> Result<()> {
+        match self.errors.remove(&nodeid) {
+            Some(errno) => Err(FuseError::from_errno(errno)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_pending(&self, nodeid: u64) -> bool {
+        self.errors.contains_key(&nodeid)
+    }
+}
+
+#[cfg(ktest)]
+mod test {
+    use ostd::prelude::*;
+
+    use super::*;
+    use crate::device::filesystem::{
+        errno::{EIO, ENOSPC},
+        fuse::FuseOutHeader,
+        request::fuse_check_reply,
+    };
+
+    /// Records the reply to an unwaited FLUSH of `nodeid` as the device does.
+    fn flushed(errors: &mut WritebackErrors, nodeid: u64, error: i32) {
+        let headerout = FuseOutHeader {
+            len: size_of::<FuseOutHeader>() as u32,
+            error: -error,
+            unique: 0,
+        };
+        if let Err(err) = fuse_check_reply(&headerout) {
+            errors.record(nodeid, err.errno());
+        }
+    }
+
+    #[ktest]
+    fn failed_flush_is_reported_once() {
+        let mut errors = WritebackErrors::new();
+
+        // The buffered write succeeds, its flush does not.
+        flushed(&mut errors, 42, 0);
+        assert!(!errors.is_pending(42));
+        flushed(&mut errors, 42, EIO);
+        flushed(&mut errors, 42, ENOSPC);
+        assert!(errors.is_pending(42));
+        assert!(errors.take(43).is_ok());
+
+        // The next fsync sees the first error, and the one after it nothing.
+        assert!(matches!(errors.take(42), Err(FuseError::ServerError(EIO))));
+        assert!(errors.take(42).is_ok());
+    }
+}
@@ -2,7 +2,7 @@
 
 use int_to_c_enum::TryFromInt;
 
-use crate::queue::QueueError;
+use crate::{queue::QueueError, transport::VirtioTransportError};
 
 pub mod block;
 pub mod console;
@@ -50,6 +50,16 @@ pub enum VirtioDeviceError {
     QueueUnknownError,
     /// The input virtio capability list contains invalid element
     CapabilityListError,
+    /// The device did not finish initializing in time
+    InitTimeout,
+    /// The device speaks a protocol version the driver does not support
+    UnsupportedVersion,
+    /// The transport failed to set the device up, e.g. its interrupt callbacks
+    TransportError(VirtioTransportError),
+    /// The buffers of the device could not be allocated or mapped
+    BufferAllocError,
+    /// A task serving the device could not be started
+    TaskSpawnError,
 }
 
 impl From<QueueError> for VirtioDeviceError {
@@ -57,3 +67,9 @@ impl From<QueueError> for VirtioDeviceError {
         VirtioDeviceError::QueueUnknownError
     }
 }
+
+impl From<VirtioTransportError> for VirtioDeviceError {
+    fn from(err: VirtioTransportError) -> Self {
+        VirtioDeviceError::TransportError(err)
+    }
+}
@@ -4,7 +4,7 @@
 
 /// Error number.
 #[repr(i32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, int_to_c_enum::TryFromInt)]
 pub enum Errno {
     EPERM = 1,    /* Operation not permitted */
     ENOENT = 2,   /* No such file or directory */
@@ -246,6 +246,26 @@ impl From<aster_block::bio::BioStatus> for Error {
     }
 }
 
+impl From<aster_virtio::device::filesystem::error::FuseError> for Error {
+    fn from(error: aster_virtio::device::filesystem::error::FuseError) -> Self {
+        use aster_virtio::device::filesystem::error::FuseError;
+
+        // A server may reply with an errno this kernel does not know.
+        let errno = Errno::try_from(error.errno()).unwrap_or(Errno::EIO);
+        let msg = match error {
+            FuseError::Io => "Malformed reply from the virtio-fs server",
+            FuseError::TimedOut => "The virtio-fs request timed out",
+            FuseError::Unsupported => "The virtio-fs server does not support the operation",
+            FuseError::Busy => "The virtio-fs request queue is full",
+            FuseError::NoDev => "The virtio-fs device has been shut down",
+            FuseError::ServerError(_) => "The virtio-fs server failed the request",
+            FuseError::Dma(_) | FuseError::QueueError(_) => "The virtio-fs request failed",
+            _ => return Error::new(errno),
+        };
+        Error::with_message(errno, msg)
+    }
+}
+
 impl From<core::str::Utf8Error> for Error {
     fn from(_: core::str::Utf8Error) -> Self {
         Error::with_message(Errno::EINVAL, "Invalid utf-8 string")